@@ -1,6 +1,8 @@
 mod store;
 
-use crate::store::MailStore;
+use crate::store::{
+    attachment_policy, on_commit_command, received_header, Compression, Format, MailStore,
+};
 use anyhow::{anyhow, Context, Result};
 use getopts::Options;
 use log::error;
@@ -18,6 +20,7 @@ use std::net::{IpAddr, Ipv4Addr, TcpStream};
 use std::os::fd::AsFd;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::SystemTime;
 use time::macros::format_description;
 use time::OffsetDateTime;
 
@@ -32,15 +35,27 @@ const OPT_SSL_KEY: &str = "ssl-key";
 const OPT_SSL_CHAIN: &str = "ssl-chain";
 const OPT_BLOCKLIST: &str = "blocklist";
 const OPT_MAILDIR: &str = "maildir";
+const OPT_MBOX: &str = "mbox";
+const OPT_DEDUP: &str = "dedup";
+const OPT_RECIPIENT_MAILBOXES: &str = "recipient-mailboxes";
+const OPT_MAILBOX_QUOTA: &str = "mailbox-quota";
+const OPT_GZIP: &str = "gzip";
+const OPT_REJECT_ATTACHMENT: &str = "reject-attachment";
+const OPT_MAX_OPEN_MESSAGES: &str = "max-open-messages";
+const OPT_ON_COMMIT: &str = "on-commit";
+const OPT_CAPTURE_REJECTED: &str = "capture-rejected";
 const OPT_REMOTE: &str = "remote";
 
 struct Handler<'a> {
     mxdns: &'a MxDns,
     mailstore: MailStore,
+    server_name: String,
+    client_ip: Option<IpAddr>,
 }
 
 impl mailin_embedded::Handler for Handler<'_> {
     fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+        self.client_ip = Some(ip);
         if ip == Ipv4Addr::new(127, 0, 0, 1) {
             return OK;
         }
@@ -58,15 +73,11 @@ impl mailin_embedded::Handler for Handler<'_> {
         }
     }
 
-    fn data_start(
-        &mut self,
-        _domain: &str,
-        _from: &str,
-        _is8bit: bool,
-        _to: &[String],
-    ) -> Response {
-        match self.mailstore.start_message() {
-            Ok(()) => OK,
+    fn data_start(&mut self, domain: &str, from: &str, _is8bit: bool, to: &[String]) -> Response {
+        let ip = self.client_ip.unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+        let received = received_header(domain, ip, &self.server_name, SystemTime::now());
+        match self.mailstore.start_message(from, to, &received) {
+            Ok(res) => res,
             Err(err) => {
                 error!("Start message: {}", err);
                 INTERNAL_ERROR
@@ -80,7 +91,7 @@ impl mailin_embedded::Handler for Handler<'_> {
 
     fn data_end(&mut self) -> Response {
         match self.mailstore.end_message() {
-            Ok(()) => OK,
+            Ok(res) => res,
             Err(err) => {
                 error!("End message: {}", err);
                 INTERNAL_ERROR
@@ -148,6 +159,52 @@ fn main() -> Result<()> {
         "PEM_FILE",
     );
     opts.optopt("", OPT_MAILDIR, "the directory to store mail in", "MAILDIR");
+    opts.optflag(
+        "",
+        OPT_MBOX,
+        "append mail to an mbox file in the mail directory",
+    );
+    opts.optflag(
+        "",
+        OPT_DEDUP,
+        "link messages with a recently seen Message-ID instead of storing a copy",
+    );
+    opts.optflag(
+        "",
+        OPT_RECIPIENT_MAILBOXES,
+        "deliver to a maildir per recipient within the mail directory",
+    );
+    opts.optopt(
+        "",
+        OPT_MAILBOX_QUOTA,
+        "the maximum size of the messages in a recipient maildir",
+        "BYTES",
+    );
+    opts.optflag("", OPT_GZIP, "gzip compress messages in the mail directory");
+    opts.optmulti(
+        "",
+        OPT_REJECT_ATTACHMENT,
+        "reject messages with attachments that have this file extension",
+        "EXTENSION",
+    );
+    opts.optopt(
+        "",
+        OPT_MAX_OPEN_MESSAGES,
+        "the maximum number of messages written to the mail directory at once",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        OPT_ON_COMMIT,
+        "run COMMAND with the path and the size in bytes of each delivered message as arguments",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        OPT_CAPTURE_REJECTED,
+        "keep the first BYTES of rejected messages in the quarantine directory",
+        "BYTES",
+    );
     opts.optopt(
         "r",
         OPT_REMOTE,
@@ -185,9 +242,57 @@ fn main() -> Result<()> {
     let maildir = matches
         .opt_str(OPT_MAILDIR)
         .unwrap_or_else(|| "mail".to_owned());
+    let format = if matches.opt_present(OPT_MBOX) {
+        Format::Mbox
+    } else {
+        Format::Maildir
+    };
+    let compression = if matches.opt_present(OPT_GZIP) {
+        Compression::Gzip
+    } else {
+        Compression::None
+    };
+    let mut mailstore = MailStore::new(maildir)
+        .with_format(format)
+        .with_compression(compression)
+        .with_dedup(matches.opt_present(OPT_DEDUP))
+        .with_recipient_mailboxes(matches.opt_present(OPT_RECIPIENT_MAILBOXES));
+    let rejected_attachments = matches.opt_strs(OPT_REJECT_ATTACHMENT);
+    if !rejected_attachments.is_empty() {
+        mailstore = mailstore.with_policy(attachment_policy(rejected_attachments));
+    }
+    let max_open_messages = matches
+        .opt_str(OPT_MAX_OPEN_MESSAGES)
+        .map(|max| max.parse::<usize>())
+        .transpose()
+        .context("Cannot parse maximum open messages")?;
+    if let Some(max) = max_open_messages {
+        mailstore = mailstore.with_max_open_messages(max);
+    }
+    if let Some(command) = matches.opt_str(OPT_ON_COMMIT) {
+        mailstore = mailstore.with_on_commit(on_commit_command(command));
+    }
+    let mailbox_quota = matches
+        .opt_str(OPT_MAILBOX_QUOTA)
+        .map(|bytes| bytes.parse::<u64>())
+        .transpose()
+        .context("Cannot parse mailbox quota")?;
+    if let Some(bytes) = mailbox_quota {
+        mailstore = mailstore.with_mailbox_quota(bytes);
+    }
+    let capture_rejected = matches
+        .opt_str(OPT_CAPTURE_REJECTED)
+        .map(|bytes| bytes.parse::<usize>())
+        .transpose()
+        .context("Cannot parse bytes of rejected messages to capture")?;
+    if let Some(bytes) = capture_rejected {
+        mailstore = mailstore.with_capture_rejected(bytes);
+    }
     let handler = Handler {
         mxdns: &mxdns,
-        mailstore: MailStore::new(maildir),
+        mailstore,
+        server_name: domain.clone(),
+        client_ip: None,
     };
     let mut server = Server::new(handler);
     server
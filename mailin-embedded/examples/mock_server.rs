@@ -0,0 +1,97 @@
+//! Test the retry behaviour of an SMTP client against a scripted server.
+//!
+//! The server replies to the second RCPT with a temporary failure. The client below
+//! retries recipients that get a 4xx reply, which the transcript of the server shows.
+use mailin_embedded::{MockCommand, MockServer, Response, Server};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+// A minimal client that retries a command once after a temporary failure
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(stream: TcpStream) -> io::Result<Self> {
+        let writer = stream.try_clone()?;
+        let mut client = Self {
+            reader: BufReader::new(stream),
+            writer,
+        };
+        client.reply()?;
+        Ok(client)
+    }
+
+    // Read a reply, which may have several lines, and return its code
+    fn reply(&mut self) -> io::Result<u16> {
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            println!("S: {}", line.trim_end());
+            let code = line.get(..3).and_then(|c| c.parse().ok());
+            match (code, line.as_bytes().get(3)) {
+                (Some(code), Some(b' ')) => return Ok(code),
+                (Some(_), Some(b'-')) => continue,
+                _ => return Err(io::Error::other("Bad reply")),
+            }
+        }
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<u16> {
+        println!("C: {}", command);
+        write!(self.writer, "{}\r\n", command)?;
+        self.reply()
+    }
+
+    fn send_with_retry(&mut self, command: &str) -> io::Result<u16> {
+        match self.send(command)? {
+            code @ 400..=499 => {
+                println!("Temporary failure {}, retrying", code);
+                self.send(command)
+            }
+            code => Ok(code),
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mock = MockServer::new().respond(MockCommand::Rcpt, 2, Response::fixed(451, "Try again"));
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = Server::new(mock.clone());
+    let server = thread::spawn(move || {
+        let (stream, remote) = listener.accept().unwrap();
+        server.execute(stream, remote.ip()).unwrap();
+    });
+
+    let mut client = Client::connect(TcpStream::connect(addr)?)?;
+    client.send("EHLO client.example.com")?;
+    client.send("MAIL FROM:<sender@example.com>")?;
+    for to in ["fish@example.com", "crab@example.com"] {
+        let code = client.send_with_retry(&format!("RCPT TO:<{}>", to))?;
+        assert_eq!(code, 250);
+    }
+    assert_eq!(client.send("DATA")?, 354);
+    assert_eq!(client.send("Subject: Retried\r\n\r\nHello\r\n.")?, 250);
+    client.send("QUIT")?;
+    server.join().unwrap();
+
+    // The second recipient was sent twice
+    let rcpts: Vec<String> = mock
+        .transcript()
+        .into_iter()
+        .filter(|command| command.starts_with("RCPT"))
+        .collect();
+    assert_eq!(
+        rcpts,
+        [
+            "RCPT fish@example.com",
+            "RCPT crab@example.com",
+            "RCPT crab@example.com"
+        ]
+    );
+    println!("Transcript: {:?}", mock.transcript());
+    Ok(())
+}
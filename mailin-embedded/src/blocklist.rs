@@ -0,0 +1,194 @@
+use crate::fcrdns::{Resolver, Verdict};
+use log::debug;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// Rejects clients whose address is listed on a DNS blocklist (DNSBL, RFC 5782).
+///
+/// An address is listed if the blocklist zone has an A record in `127.0.0.0/8` for the
+/// reversed address. DNS errors are treated as temporary and the client is accepted, so
+/// that a resolver or blocklist outage does not refuse all mail.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{BlocklistPolicy, Resolver, Verdict};
+/// use std::io;
+/// use std::net::IpAddr;
+///
+/// // Lists 192.0.2.99 on bl.example.org
+/// struct Dnsbl;
+/// impl Resolver for Dnsbl {
+///     fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+///         Ok(None)
+///     }
+///     fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+///         match name {
+///             "99.2.0.192.bl.example.org" => Ok(vec!["127.0.0.2".parse().unwrap()]),
+///             _ => Ok(vec![]),
+///         }
+///     }
+/// }
+///
+/// let policy = BlocklistPolicy::new(Dnsbl, ["bl.example.org"]);
+/// assert_eq!(policy.check("192.0.2.99".parse().unwrap()), Verdict::Reject);
+/// assert_eq!(policy.check("192.0.2.1".parse().unwrap()), Verdict::Accept);
+/// ```
+pub struct BlocklistPolicy {
+    resolver: Box<dyn Resolver>,
+    zones: Vec<String>,
+}
+
+impl BlocklistPolicy {
+    /// Create a policy that looks up clients on the given blocklist zones
+    pub fn new<R, I, S>(resolver: R, zones: I) -> Self
+    where
+        R: Resolver + 'static,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let zones = zones
+            .into_iter()
+            .map(|zone| zone.into().trim_end_matches('.').to_string())
+            .collect();
+        Self {
+            resolver: Box::new(resolver),
+            zones,
+        }
+    }
+
+    /// Check the address of a connecting client
+    pub fn check(&self, ip: IpAddr) -> Verdict {
+        match self.listed_on(ip) {
+            Some(_) => Verdict::Reject,
+            None => Verdict::Accept,
+        }
+    }
+
+    /// Returns the first blocklist zone that lists the address
+    pub fn listed_on(&self, ip: IpAddr) -> Option<&str> {
+        let reversed = reverse_ip(ip);
+        self.zones
+            .iter()
+            .find(|zone| {
+                let name = format!("{}.{}", reversed, zone);
+                match self.resolver.forward(&name) {
+                    Ok(addrs) => addrs.iter().any(is_listing),
+                    Err(err) => {
+                        debug!("({}) Blocklist lookup on {} failed: {}", ip, zone, err);
+                        false
+                    }
+                }
+            })
+            .map(String::as_str)
+    }
+}
+
+// Blocklists answer with an address in 127.0.0.0/8, anything else is not a listing
+fn is_listing(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.octets()[0] == 127,
+        IpAddr::V6(_) => false,
+    }
+}
+
+// The name of an address in a blocklist zone, reversed octets for IPv4
+// and reversed nibbles for IPv6
+fn reverse_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut reversed = String::with_capacity(63);
+            for byte in ip.octets().iter().rev() {
+                if !reversed.is_empty() {
+                    reversed.push('.');
+                }
+                write!(reversed, "{:x}.{:x}", byte & 0xf, byte >> 4).unwrap();
+            }
+            reversed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct MockResolver {
+        a: HashMap<String, Vec<IpAddr>>,
+        lookups: AtomicUsize,
+    }
+
+    impl MockResolver {
+        fn listed(mut self, name: &str, a: &str) -> Self {
+            self.a.insert(name.to_string(), vec![a.parse().unwrap()]);
+            self
+        }
+    }
+
+    impl Resolver for MockResolver {
+        fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            match name {
+                "3.2.0.192.down.example.org" => Err(io::Error::other("SERVFAIL")),
+                _ => Ok(self.a.get(name).cloned().unwrap_or_default()),
+            }
+        }
+    }
+
+    fn resolver() -> Arc<MockResolver> {
+        let resolver = MockResolver::default()
+            .listed("99.2.0.192.bl.example.org", "127.0.0.2")
+            .listed("98.2.0.192.bl.example.org", "192.0.2.1")
+            .listed(
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.example.org",
+                "127.0.0.4",
+            )
+            .listed("3.2.0.192.other.example.org", "127.0.0.10");
+        Arc::new(resolver)
+    }
+
+    #[test]
+    fn blocklist_hit() {
+        let policy = BlocklistPolicy::new(resolver(), ["bl.example.org."]);
+        for ip in ["192.0.2.99", "2001:db8::1"] {
+            let ip = ip.parse().unwrap();
+            assert_eq!(policy.check(ip), Verdict::Reject, "{ip}");
+            assert_eq!(policy.listed_on(ip), Some("bl.example.org"));
+        }
+    }
+
+    #[test]
+    fn blocklist_miss() {
+        let policy = BlocklistPolicy::new(resolver(), ["bl.example.org"]);
+        // Not listed, and an answer outside 127.0.0.0/8
+        for ip in ["192.0.2.1", "192.0.2.98", "2001:db8::2"] {
+            assert_eq!(policy.check(ip.parse().unwrap()), Verdict::Accept, "{ip}");
+        }
+    }
+
+    #[test]
+    fn blocklist_error() {
+        // A failing blocklist does not stop the next one being checked
+        let resolver = resolver();
+        let policy = BlocklistPolicy::new(resolver.clone(), ["down.example.org"]);
+        assert_eq!(policy.check("192.0.2.3".parse().unwrap()), Verdict::Accept);
+        let zones = ["down.example.org", "other.example.org"];
+        let policy = BlocklistPolicy::new(resolver.clone(), zones);
+        let ip = "192.0.2.3".parse().unwrap();
+        assert_eq!(policy.listed_on(ip), Some("other.example.org"));
+        // The resolver is shared by both policies
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 3);
+    }
+}
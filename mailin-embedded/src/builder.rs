@@ -0,0 +1,406 @@
+use crate::blocklist::BlocklistPolicy;
+use crate::err::Error;
+use crate::fcrdns::FcrdnsPolicy;
+use crate::localize::ResponseTable;
+use crate::ssl::{SslConfig, TicketKeys};
+use crate::Server;
+use mailin::address::Normalization;
+use mailin::{AuthMechanism, ConnInfo, Handler, OptionalCommand, Response};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+/// Collects the configuration of a [`Server`] and checks it in one place.
+///
+/// Each `with_*` method calls the method of the same name on [`Server`]. Unlike those,
+/// errors such as an invalid address or SSL certificate, and options that conflict with
+/// each other, are reported by [`ServerBuilder::build()`].
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Handler, ServerBuilder, SslConfig};
+/// # use mailin_embedded::err::Error;
+///
+/// #[derive(Clone)]
+/// struct MyHandler {}
+/// impl Handler for MyHandler {}
+///
+/// let server = ServerBuilder::new()
+///     .with_name("example.com")
+///     .with_ssl(SslConfig::None)
+///     .with_addr("127.0.0.1:25")
+///     .with_max_message_size(10_000_000)
+///     .build(MyHandler {})?;
+/// # Ok::<(), Error>(())
+/// ```
+pub struct ServerBuilder {
+    // The server being configured, it gets its handler in build()
+    server: Server<NoHandler>,
+    // The first error found while collecting the configuration
+    error: Option<Error>,
+}
+
+// Stands in for the handler until the server is built
+struct NoHandler;
+
+impl Handler for NoHandler {}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    /// Create a builder with the default configuration
+    pub fn new() -> Self {
+        Self {
+            server: Server::new(NoHandler),
+            error: None,
+        }
+    }
+
+    /// See [`Server::with_name()`]
+    pub fn with_name<T: Into<String>>(mut self, name: T) -> Self {
+        self.server.with_name(name);
+        self
+    }
+
+    /// See [`Server::with_banner()`]
+    pub fn with_banner<F>(mut self, banner: F) -> Self
+    where
+        F: Fn(&ConnInfo) -> String + Send + Sync + 'static,
+    {
+        self.server.with_banner(banner);
+        self
+    }
+
+    /// See [`Server::with_ssl()`]. An SSL configuration that cannot be loaded is reported
+    /// by [`Self::build()`].
+    pub fn with_ssl(mut self, ssl_config: SslConfig) -> Self {
+        if let Err(err) = self.server.with_ssl(ssl_config) {
+            self.fail(err);
+        }
+        self
+    }
+
+    /// See [`Server::with_ticket_keys()`]. Must follow [`Self::with_ssl()`].
+    pub fn with_ticket_keys(mut self, keys: TicketKeys) -> Self {
+        if let Err(err) = self.server.with_ticket_keys(keys) {
+            self.fail(err);
+        }
+        self
+    }
+
+    /// See [`Server::with_num_threads()`]
+    pub fn with_num_threads(mut self, num_threads: u32) -> Self {
+        self.server.with_num_threads(num_threads);
+        self
+    }
+
+    /// See [`Server::with_auth()`]
+    pub fn with_auth(mut self, auth: AuthMechanism) -> Self {
+        self.server.with_auth(auth);
+        self
+    }
+
+    /// See [`Server::with_tcp_listener()`]. Cannot be combined with [`Self::with_addr()`].
+    pub fn with_tcp_listener(mut self, listener: TcpListener) -> Self {
+        self.server.with_tcp_listener(listener);
+        self
+    }
+
+    /// See [`Server::with_addr()`]. An invalid address is reported by [`Self::build()`].
+    pub fn with_addr<A: ToSocketAddrs>(mut self, addr: A) -> Self {
+        if let Err(err) = self.server.with_addr(addr) {
+            self.fail(err);
+        }
+        self
+    }
+
+    /// See [`Server::with_max_message_size()`]
+    pub fn with_max_message_size(mut self, max_size: usize) -> Self {
+        self.server.with_max_message_size(max_size);
+        self
+    }
+
+    /// See [`Server::with_max_data_bytes()`]. Must not be smaller than the maximum
+    /// message size.
+    pub fn with_max_data_bytes(mut self, max_data_bytes: usize) -> Self {
+        self.server.with_max_data_bytes(max_data_bytes);
+        self
+    }
+
+    /// See [`Server::with_data_progress_interval()`]
+    pub fn with_data_progress_interval(mut self, bytes: usize) -> Self {
+        self.server.with_data_progress_interval(bytes);
+        self
+    }
+
+    /// See [`Server::with_max_errors()`]
+    pub fn with_max_errors(mut self, max_errors: usize) -> Self {
+        self.server.with_max_errors(max_errors);
+        self
+    }
+
+    /// See [`Server::with_max_auth_mechanisms()`]
+    pub fn with_max_auth_mechanisms(mut self, max_auth_mechanisms: usize) -> Self {
+        self.server.with_max_auth_mechanisms(max_auth_mechanisms);
+        self
+    }
+
+    /// See [`Server::with_max_line_bytes()`]
+    pub fn with_max_line_bytes(mut self, max_line_bytes: usize) -> Self {
+        self.server.with_max_line_bytes(max_line_bytes);
+        self
+    }
+
+    /// See [`Server::with_data_timeout()`]
+    pub fn with_data_timeout(mut self, timeout: Duration) -> Self {
+        self.server.with_data_timeout(timeout);
+        self
+    }
+
+    /// See [`Server::with_etrn()`]
+    pub fn with_etrn(mut self) -> Self {
+        self.server.with_etrn();
+        self
+    }
+
+    /// See [`Server::with_mt_priority()`]
+    pub fn with_mt_priority(mut self) -> Self {
+        self.server.with_mt_priority();
+        self
+    }
+
+    /// See [`Server::with_deliver_by()`]
+    pub fn with_deliver_by(mut self, min_seconds: u32) -> Self {
+        self.server.with_deliver_by(min_seconds);
+        self
+    }
+
+    /// See [`Server::with_data_digest()`]
+    #[cfg(feature = "digest")]
+    pub fn with_data_digest(mut self) -> Self {
+        self.server.with_data_digest();
+        self
+    }
+
+    /// See [`Server::with_restrict_null_sender()`]
+    pub fn with_restrict_null_sender(mut self) -> Self {
+        self.server.with_restrict_null_sender();
+        self
+    }
+
+    /// See [`Server::with_require_fqdn_helo()`]
+    pub fn with_require_fqdn_helo(mut self) -> Self {
+        self.server.with_require_fqdn_helo();
+        self
+    }
+
+    /// See [`Server::with_lenient_line_endings()`]
+    pub fn with_lenient_line_endings(mut self, lenient: bool) -> Self {
+        self.server.with_lenient_line_endings(lenient);
+        self
+    }
+
+    /// See [`Server::with_helo_rejection()`]
+    pub fn with_helo_rejection<S: Into<String>>(mut self, text: S) -> Self {
+        self.server.with_helo_rejection(text);
+        self
+    }
+
+    /// See [`Server::with_normalize_recipients()`]
+    pub fn with_normalize_recipients(mut self, normalization: Normalization) -> Self {
+        self.server.with_normalize_recipients(normalization);
+        self
+    }
+
+    /// See [`Server::with_ehlo_keyword()`]. An invalid keyword is reported by [`Self::build()`].
+    pub fn with_ehlo_keyword(mut self, keyword: &str) -> Self {
+        if let Err(err) = self.server.with_ehlo_keyword(keyword) {
+            self.fail(err);
+        }
+        self
+    }
+
+    /// See [`Server::with_disabled_command()`]
+    pub fn with_disabled_command(mut self, command: OptionalCommand) -> Self {
+        self.server.with_disabled_command(command);
+        self
+    }
+
+    /// See [`Server::with_command_echo()`]
+    pub fn with_command_echo(mut self) -> Self {
+        self.server.with_command_echo();
+        self
+    }
+
+    /// See [`Server::with_auth_user_echo()`]
+    pub fn with_auth_user_echo(mut self) -> Self {
+        self.server.with_auth_user_echo();
+        self
+    }
+
+    /// See [`Server::with_canonical_ip()`]
+    pub fn with_canonical_ip(mut self) -> Self {
+        self.server.with_canonical_ip();
+        self
+    }
+
+    /// See [`Server::with_subnet_limit()`]
+    pub fn with_subnet_limit(
+        mut self,
+        ipv4_prefix_len: u8,
+        ipv6_prefix_len: u8,
+        max_connections: usize,
+    ) -> Self {
+        self.server
+            .with_subnet_limit(ipv4_prefix_len, ipv6_prefix_len, max_connections);
+        self
+    }
+
+    /// See [`Server::with_load_shedding()`]
+    pub fn with_load_shedding(mut self, threshold: usize, response: Response) -> Self {
+        self.server.with_load_shedding(threshold, response);
+        self
+    }
+
+    /// See [`Server::with_fcrdns()`]
+    pub fn with_fcrdns(mut self, policy: FcrdnsPolicy) -> Self {
+        self.server.with_fcrdns(policy);
+        self
+    }
+
+    /// See [`Server::with_blocklist()`]
+    pub fn with_blocklist(mut self, policy: BlocklistPolicy) -> Self {
+        self.server.with_blocklist(policy);
+        self
+    }
+
+    /// See [`Server::with_responses()`]
+    pub fn with_responses<T: ResponseTable + 'static>(mut self, responses: T) -> Self {
+        self.server.with_responses(responses);
+        self
+    }
+
+    /// Check the configuration and create a server with the given handler
+    pub fn build<H: Handler>(self, handler: H) -> Result<Server<H>, Error> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.check()?;
+        let Server {
+            ssl,
+            config,
+            metrics,
+            shutdown,
+            ..
+        } = self.server;
+        Ok(Server {
+            handler,
+            ssl,
+            config,
+            metrics,
+            shutdown,
+        })
+    }
+
+    // Look for options that conflict or cannot work
+    fn check(&self) -> Result<(), Error> {
+        if self.server.config.tcp_listener.is_some()
+            && !self.server.config.socket_address.is_empty()
+        {
+            return Error::bail("Cannot use both a TCP listener and listen addresses");
+        }
+        if self.server.config.num_threads == 0 {
+            return Error::bail("The number of threads must be at least 1");
+        }
+        if self.server.config.max_line_bytes == 0 {
+            return Error::bail("The maximum line length must be at least 1 byte");
+        }
+        if self.server.config.etrn
+            && self
+                .server
+                .config
+                .disabled_commands
+                .contains(&OptionalCommand::Etrn)
+        {
+            return Error::bail("ETRN cannot be both enabled and disabled");
+        }
+        if self.server.config.data_timeout == Some(Duration::ZERO) {
+            return Error::bail("The DATA timeout must not be zero");
+        }
+        if let (Some(message_size), Some(data_bytes)) = (
+            self.server.config.max_message_size,
+            self.server.config.max_data_bytes,
+        ) {
+            if message_size > data_bytes {
+                return Error::bail("The maximum message size is larger than the DATA limit");
+            }
+        }
+        Ok(())
+    }
+
+    // Keep the first error, later errors are often a consequence of it
+    fn fail(&mut self, err: Error) {
+        self.error.get_or_insert(err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EmptyHandler {}
+    impl Handler for EmptyHandler {}
+
+    fn assert_conflict(builder: ServerBuilder) {
+        assert!(builder.build(EmptyHandler {}).is_err());
+    }
+
+    #[test]
+    fn conflicting_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_conflict(
+            ServerBuilder::new()
+                .with_tcp_listener(listener)
+                .with_addr("127.0.0.1:2525"),
+        );
+        assert_conflict(
+            ServerBuilder::new()
+                .with_max_message_size(2000)
+                .with_max_data_bytes(1000),
+        );
+        assert_conflict(ServerBuilder::new().with_num_threads(0));
+        assert_conflict(ServerBuilder::new().with_data_timeout(Duration::ZERO));
+        assert_conflict(ServerBuilder::new().with_addr("not an address"));
+        assert_conflict(ServerBuilder::new().with_ehlo_keyword("STARTTLS"));
+        assert_conflict(
+            ServerBuilder::new()
+                .with_etrn()
+                .with_disabled_command(OptionalCommand::Etrn),
+        );
+        let keys = TicketKeys::new([1; 32]);
+        assert_conflict(
+            ServerBuilder::new()
+                .with_ssl(SslConfig::None)
+                .with_ticket_keys(keys),
+        );
+    }
+
+    #[test]
+    fn build() {
+        let server = ServerBuilder::new()
+            .with_name("example.com")
+            .with_ssl(SslConfig::None)
+            .with_addr("127.0.0.1:2525")
+            .with_max_message_size(1000)
+            .with_max_data_bytes(2000)
+            .with_auth(AuthMechanism::Plain)
+            .build(EmptyHandler {})
+            .unwrap();
+        assert_eq!(server.config.name, "example.com");
+        assert_eq!(server.config.socket_address.len(), 1);
+        assert_eq!(server.config.max_data_bytes, Some(2000));
+    }
+}
@@ -0,0 +1,263 @@
+use log::debug;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// DNS lookups needed by the DNS based policies: [`FcrdnsPolicy`],
+/// [`BlocklistPolicy`](crate::BlocklistPolicy) and [`SpfChecker`](crate::SpfChecker).
+///
+/// Implement this to use a mock resolver in tests or a caching resolver. A resolver
+/// wrapped in an `Arc` also implements `Resolver`, so one resolver can be shared by
+/// several policies.
+///
+/// With the `mxdns` feature enabled, `SystemResolver` is the default implementation.
+/// It is also implemented for `mxdns::MxDns`, which only looks up IPv4 addresses.
+pub trait Resolver: Send + Sync {
+    /// Lookup the PTR record of an address, returns `Ok(None)` if there is none
+    fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>>;
+
+    /// Lookup the addresses of a domain name
+    fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>>;
+
+    /// Lookup the TXT records of a domain name, each record as one string.
+    /// The default returns an `Unsupported` error.
+    fn txt(&self, _name: &str) -> io::Result<Vec<String>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TXT lookups are not supported",
+        ))
+    }
+
+    /// Lookup the host names of the MX records of a domain name.
+    /// The default returns an `Unsupported` error.
+    fn mx(&self, _name: &str) -> io::Result<Vec<String>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "MX lookups are not supported",
+        ))
+    }
+}
+
+// Lets one resolver, e.g a caching one, be shared by several policies
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        (**self).reverse(ip)
+    }
+
+    fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        (**self).forward(name)
+    }
+
+    fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        (**self).txt(name)
+    }
+
+    fn mx(&self, name: &str) -> io::Result<Vec<String>> {
+        (**self).mx(name)
+    }
+}
+
+/// The result of checking a connecting client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Continue with the SMTP session
+    Accept,
+    /// Refuse the connection
+    Reject,
+}
+
+/// Rejects clients whose reverse DNS does not resolve back to their address (FCrDNS).
+///
+/// Loopback clients are always accepted. DNS errors are treated as temporary and the
+/// client is accepted, so that a resolver outage does not refuse all mail.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{FcrdnsPolicy, Resolver, Verdict};
+/// use std::io;
+/// use std::net::IpAddr;
+///
+/// struct Hosts;
+/// impl Resolver for Hosts {
+///     fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+///         Ok(Some("mail.example.com".to_string()))
+///     }
+///     fn forward(&self, _name: &str) -> io::Result<Vec<IpAddr>> {
+///         Ok(vec!["192.0.2.1".parse().unwrap()])
+///     }
+/// }
+///
+/// let policy = FcrdnsPolicy::new(Hosts);
+/// assert_eq!(policy.check("192.0.2.1".parse().unwrap()), Verdict::Accept);
+/// assert_eq!(policy.check("192.0.2.2".parse().unwrap()), Verdict::Reject);
+/// ```
+pub struct FcrdnsPolicy {
+    resolver: Box<dyn Resolver>,
+}
+
+impl FcrdnsPolicy {
+    /// Create a policy that uses the given resolver
+    pub fn new<R: Resolver + 'static>(resolver: R) -> Self {
+        Self {
+            resolver: Box::new(resolver),
+        }
+    }
+
+    /// Check the address of a connecting client
+    pub fn check(&self, ip: IpAddr) -> Verdict {
+        if ip.is_loopback() {
+            return Verdict::Accept;
+        }
+        match self.is_confirmed(ip) {
+            Ok(true) => Verdict::Accept,
+            Ok(false) => Verdict::Reject,
+            Err(err) => {
+                debug!("({}) FCrDNS lookup failed: {}", ip, err);
+                Verdict::Accept
+            }
+        }
+    }
+
+    fn is_confirmed(&self, ip: IpAddr) -> io::Result<bool> {
+        let Some(name) = self.resolver.reverse(ip)? else {
+            return Ok(false);
+        };
+        let forward = self.resolver.forward(&name)?;
+        Ok(forward.contains(&ip))
+    }
+}
+
+/// A [`Resolver`] that queries the first nameserver in `/etc/resolv.conf`, available
+/// with the `mxdns` feature.
+///
+/// Forward lookups return both IPv4 and IPv6 addresses. There is no caching, wrap the
+/// resolver to add it.
+#[cfg(feature = "mxdns")]
+#[derive(Clone)]
+pub struct SystemResolver {
+    dns: mxdns::MxDns,
+}
+
+#[cfg(feature = "mxdns")]
+impl SystemResolver {
+    /// Create a resolver from the system nameserver configuration
+    pub fn new() -> io::Result<Self> {
+        let blocklists: [&str; 0] = [];
+        let dns = mxdns::MxDns::new(blocklists).map_err(io::Error::other)?;
+        Ok(Self { dns })
+    }
+
+    /// Create a resolver that queries the given nameserver
+    pub fn with_nameserver(ip: IpAddr) -> Self {
+        let blocklists: [&str; 0] = [];
+        Self {
+            dns: mxdns::MxDns::with_dns(ip, blocklists),
+        }
+    }
+}
+
+#[cfg(feature = "mxdns")]
+impl Resolver for SystemResolver {
+    fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        self.dns.reverse(ip)
+    }
+
+    fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let mut addrs = self.dns.forward(name)?;
+        addrs.extend(self.dns.forward_dns6(name).map_err(io::Error::other)?);
+        Ok(addrs)
+    }
+
+    fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        self.dns.txt(name)
+    }
+
+    fn mx(&self, name: &str) -> io::Result<Vec<String>> {
+        self.dns.mx(name)
+    }
+}
+
+#[cfg(feature = "mxdns")]
+impl Resolver for mxdns::MxDns {
+    fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        self.reverse_dns(ip).map_err(io::Error::other)
+    }
+
+    fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        self.forward_dns(name).map_err(io::Error::other)
+    }
+
+    fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        self.txt_dns(name).map_err(io::Error::other)
+    }
+
+    fn mx(&self, name: &str) -> io::Result<Vec<String>> {
+        self.mx_dns(name).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MockResolver {
+        ptr: HashMap<IpAddr, String>,
+        a: HashMap<String, Vec<IpAddr>>,
+    }
+
+    impl MockResolver {
+        fn host(mut self, ip: &str, ptr: &str, a: &[&str]) -> Self {
+            self.ptr.insert(ip.parse().unwrap(), ptr.to_string());
+            let a = a.iter().map(|a| a.parse().unwrap()).collect();
+            self.a.insert(ptr.to_string(), a);
+            self
+        }
+    }
+
+    impl Resolver for MockResolver {
+        fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+            Ok(self.ptr.get(&ip).cloned())
+        }
+
+        fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+            match name {
+                "servfail.example.com" => Err(io::Error::other("SERVFAIL")),
+                _ => Ok(self.a.get(name).cloned().unwrap_or_default()),
+            }
+        }
+    }
+
+    fn policy() -> FcrdnsPolicy {
+        let resolver = MockResolver::default()
+            .host("192.0.2.1", "mail.example.com", &["192.0.2.1", "192.0.2.9"])
+            .host("192.0.2.2", "spoofed.example.com", &["198.51.100.1"])
+            .host("2001:db8::1", "mail6.example.com", &["2001:db8::1"])
+            .host("192.0.2.3", "servfail.example.com", &[]);
+        FcrdnsPolicy::new(resolver)
+    }
+
+    #[test]
+    fn confirmed() {
+        let policy = policy();
+        for ip in ["192.0.2.1", "2001:db8::1", "127.0.0.1"] {
+            assert_eq!(policy.check(ip.parse().unwrap()), Verdict::Accept, "{ip}");
+        }
+    }
+
+    #[test]
+    fn unconfirmed() {
+        let policy = policy();
+        // No PTR record, and a PTR record that does not resolve back
+        for ip in ["192.0.2.9", "192.0.2.2"] {
+            assert_eq!(policy.check(ip.parse().unwrap()), Verdict::Reject, "{ip}");
+        }
+    }
+
+    #[test]
+    fn lookup_error() {
+        let policy = policy();
+        assert_eq!(policy.check("192.0.2.3".parse().unwrap()), Verdict::Accept);
+    }
+}
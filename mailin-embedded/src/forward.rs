@@ -0,0 +1,139 @@
+// Handlers that wrap another handler, such as HopLimit, pass most calls straight
+// through. Inside `impl Handler for ...`, forward_handler!(inner: helo, mail) defines
+// helo() and mail() to call the same method on self.inner, so that only the methods
+// the wrapper changes are written out.
+macro_rules! forward_handler {
+    ($field:ident: $($method:ident),+ $(,)?) => {
+        $(forward_handler!(@method $field $method);)+
+    };
+    (@method $field:ident helo) => {
+        fn helo(&mut self, ip: ::std::net::IpAddr, domain: &str) -> ::mailin::Response {
+            self.$field.helo(ip, domain)
+        }
+    };
+    (@method $field:ident mail) => {
+        fn mail(&mut self, ip: ::std::net::IpAddr, domain: &str, from: &str) -> ::mailin::Response {
+            self.$field.mail(ip, domain, from)
+        }
+    };
+    (@method $field:ident rcpt) => {
+        fn rcpt(&mut self, to: &str) -> ::mailin::Response {
+            self.$field.rcpt(to)
+        }
+    };
+    (@method $field:ident data_start) => {
+        fn data_start(
+            &mut self,
+            domain: &str,
+            from: &str,
+            is8bit: bool,
+            to: &[String],
+        ) -> ::mailin::Response {
+            self.$field.data_start(domain, from, is8bit, to)
+        }
+    };
+    (@method $field:ident data) => {
+        fn data(&mut self, buf: &[u8]) -> ::std::io::Result<()> {
+            self.$field.data(buf)
+        }
+    };
+    (@method $field:ident data_progress) => {
+        fn data_progress(&mut self, bytes: usize) {
+            self.$field.data_progress(bytes)
+        }
+    };
+    (@method $field:ident data_digest) => {
+        fn data_digest(&mut self, digest: [u8; 32]) {
+            self.$field.data_digest(digest)
+        }
+    };
+    (@method $field:ident data_end) => {
+        fn data_end(&mut self) -> ::mailin::Response {
+            self.$field.data_end()
+        }
+    };
+    (@method $field:ident data_end_error) => {
+        fn data_end_error(&mut self, reason: ::mailin::Reason) {
+            self.$field.data_end_error(reason)
+        }
+    };
+    (@method $field:ident on_error) => {
+        fn on_error(&mut self, err: ::std::io::Error) -> ::mailin::Response {
+            self.$field.on_error(err)
+        }
+    };
+    (@method $field:ident rset) => {
+        fn rset(&mut self) -> ::mailin::Response {
+            self.$field.rset()
+        }
+    };
+    (@method $field:ident auth_plain) => {
+        fn auth_plain(
+            &mut self,
+            authorization_id: &str,
+            authentication_id: &str,
+            password: &str,
+        ) -> ::mailin::Response {
+            self.$field
+                .auth_plain(authorization_id, authentication_id, password)
+        }
+    };
+    (@method $field:ident auth_login) => {
+        fn auth_login(&mut self, username: &str, password: &str) -> ::mailin::Response {
+            self.$field.auth_login(username, password)
+        }
+    };
+    (@method $field:ident auth_challenge) => {
+        fn auth_challenge(&mut self, mechanism: ::mailin::AuthMechanism) -> Vec<u8> {
+            self.$field.auth_challenge(mechanism)
+        }
+    };
+    (@method $field:ident allow_starttls) => {
+        fn allow_starttls(&mut self, ip: ::std::net::IpAddr) -> bool {
+            self.$field.allow_starttls(ip)
+        }
+    };
+    (@method $field:ident mt_priority) => {
+        fn mt_priority(&mut self, priority: i8) {
+            self.$field.mt_priority(priority)
+        }
+    };
+    (@method $field:ident body_type) => {
+        fn body_type(&mut self, body: ::mailin::BodyType) {
+            self.$field.body_type(body)
+        }
+    };
+    (@method $field:ident secure) => {
+        fn secure(&mut self, secure: bool) {
+            self.$field.secure(secure)
+        }
+    };
+    (@method $field:ident authenticated) => {
+        fn authenticated(&mut self, authenticated: bool) {
+            self.$field.authenticated(authenticated)
+        }
+    };
+    (@method $field:ident deliver_by) => {
+        fn deliver_by(&mut self, deliver_by: ::mailin::DeliverBy) {
+            self.$field.deliver_by(deliver_by)
+        }
+    };
+    (@method $field:ident etrn) => {
+        fn etrn(&mut self, domain: &str) -> ::mailin::Response {
+            self.$field.etrn(domain)
+        }
+    };
+    (@method $field:ident response_delay) => {
+        fn response_delay(
+            &self,
+            response: &::mailin::Response,
+        ) -> Option<::std::time::Duration> {
+            self.$field.response_delay(response)
+        }
+    };
+    (@method $field:ident on_wire) => {
+        fn on_wire(&mut self, direction: ::mailin::Direction, bytes: &[u8]) {
+            self.$field.on_wire(direction, bytes)
+        }
+    };
+}
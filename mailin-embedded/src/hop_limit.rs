@@ -0,0 +1,210 @@
+use log::debug;
+use mailin::{Handler, Reason, Response};
+use std::io;
+
+/// The limit on Received headers recommended by RFC 5321
+pub const DEFAULT_MAX_HOPS: usize = 100;
+
+const TOO_MANY_HOPS: Response = Response::fixed(554, "Too many hops");
+const RECEIVED: &[u8] = b"received";
+// Longer field names are not kept, they cannot be Received
+const MAX_NAME: usize = 64;
+
+/// A [`Handler`] that rejects messages with more `Received` headers than a limit, to
+/// stop mail loops.
+///
+/// Each server that relays a message adds a `Received` header, so the count is the
+/// number of hops the message has taken. Only the header of the message itself is
+/// counted. When the limit is exceeded the client gets a 554 response and the wrapped
+/// handler is told with [`Handler::data_end_error()`].
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Handler, HopLimit, Server, DEFAULT_MAX_HOPS};
+///
+/// #[derive(Clone)]
+/// struct MyHandler {}
+/// impl Handler for MyHandler {}
+///
+/// let handler = HopLimit::new(MyHandler {}, DEFAULT_MAX_HOPS);
+/// let server = Server::new(handler);
+/// ```
+pub struct HopLimit<H: Handler> {
+    inner: H,
+    max_hops: usize,
+    // Counts Received headers during DATA
+    counter: Option<ReceivedCounter>,
+}
+
+impl<H: Handler> HopLimit<H> {
+    /// Wrap a handler so that messages can have at most `max_hops` Received headers
+    pub fn new(inner: H, max_hops: usize) -> Self {
+        Self {
+            inner,
+            max_hops,
+            counter: None,
+        }
+    }
+
+    // The number of Received headers in the message
+    fn hops(&mut self) -> usize {
+        self.counter.take().map_or(0, |counter| counter.hops)
+    }
+}
+
+impl<H: Handler + Clone> Clone for HopLimit<H> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            max_hops: self.max_hops,
+            counter: None,
+        }
+    }
+}
+
+// Counts the header lines that start with "Received:" until the empty line that
+// ends the header, allowing whitespace before the colon as in the obsolete syntax of
+// RFC 5322. The message can arrive in any number of pieces.
+#[derive(Default)]
+struct ReceivedCounter {
+    hops: usize,
+    // The field name at the start of the current line, up to MAX_NAME bytes
+    name: Vec<u8>,
+    // Has the colon after the field name been seen?
+    colon: bool,
+    // Length of the current line without line endings
+    len: usize,
+    in_body: bool,
+}
+
+impl ReceivedCounter {
+    fn update(&mut self, buf: &[u8]) {
+        for &b in buf {
+            if self.in_body {
+                return;
+            }
+            match b {
+                b'\n' => {
+                    if self.len == 0 {
+                        self.in_body = true;
+                    } else if self.colon
+                        && self.name.trim_ascii_end().eq_ignore_ascii_case(RECEIVED)
+                    {
+                        self.hops += 1;
+                    }
+                    self.name.clear();
+                    self.colon = false;
+                    self.len = 0;
+                }
+                b'\r' => (),
+                b => {
+                    if b == b':' {
+                        self.colon = true;
+                    } else if !self.colon && self.name.len() <= MAX_NAME {
+                        self.name.push(b);
+                    }
+                    self.len += 1;
+                }
+            }
+        }
+    }
+}
+
+impl<H: Handler> Handler for HopLimit<H> {
+    fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        self.counter = Some(ReceivedCounter::default());
+        self.inner.data_start(domain, from, is8bit, to)
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(counter) = &mut self.counter {
+            counter.update(buf);
+        }
+        self.inner.data(buf)
+    }
+
+    fn data_end(&mut self) -> Response {
+        let hops = self.hops();
+        if hops > self.max_hops {
+            debug!("Message with {} hops, possible mail loop", hops);
+            self.inner.data_end_error(Reason::Processing);
+            TOO_MANY_HOPS
+        } else {
+            self.inner.data_end()
+        }
+    }
+
+    fn data_end_error(&mut self, reason: Reason) {
+        self.counter = None;
+        self.inner.data_end_error(reason)
+    }
+
+    forward_handler!(
+        inner: helo, mail, rcpt, data_progress, data_digest, on_error, rset, auth_plain, auth_login,
+        allow_starttls, mt_priority, body_type, secure, authenticated, deliver_by, etrn,
+        auth_challenge, response_delay, on_wire
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin::response::OK;
+
+    #[derive(Default)]
+    struct DeliveryHandler {
+        delivered: bool,
+        error: Option<Reason>,
+    }
+
+    impl Handler for DeliveryHandler {
+        fn data_end(&mut self) -> Response {
+            self.delivered = true;
+            OK
+        }
+
+        fn data_end_error(&mut self, reason: Reason) {
+            self.error = Some(reason);
+        }
+    }
+
+    fn deliver(hops: usize) -> (Response, DeliveryHandler) {
+        let mut handler = HopLimit::new(DeliveryHandler::default(), DEFAULT_MAX_HOPS);
+        handler.data_start("a.domain", "ship@sea.com", false, &[]);
+        for hop in 0..hops {
+            let received = format!("Received: from relay{hop}.sea.com by sea.com\r\n");
+            handler.data(received.as_bytes()).unwrap();
+        }
+        handler.data(b"Subject: Loop\r\n").unwrap();
+        handler.data(b"\r\n").unwrap();
+        handler.data(b"Round and round\r\n").unwrap();
+        let res = handler.data_end();
+        (res, handler.inner)
+    }
+
+    #[test]
+    fn too_many_hops() {
+        let (res, inner) = deliver(101);
+        assert_eq!(res, TOO_MANY_HOPS);
+        assert!(!inner.delivered);
+        assert_eq!(inner.error, Some(Reason::Processing));
+    }
+
+    #[test]
+    fn received_in_body() {
+        let mut counter = ReceivedCounter::default();
+        // Split across pieces, and folded onto a second line
+        counter.update(b"RECEIVED: from a\r\n\tby b\r\nRecei");
+        counter.update(b"ved: from c\r\nX-Received: no\r\nReceived : from d\r\n\r\n");
+        counter.update(b"Received: in the body\r\n");
+        assert_eq!(counter.hops, 3);
+    }
+
+    #[test]
+    fn hops_within_limit() {
+        let (res, inner) = deliver(100);
+        assert_eq!(res, OK);
+        assert!(inner.delivered);
+        assert_eq!(inner.error, None);
+    }
+}
@@ -37,16 +37,64 @@ cfg_if::cfg_if! {
     }
 }
 
+#[macro_use]
+mod forward;
+
+mod blocklist;
+mod builder;
+mod fcrdns;
+mod hop_limit;
+mod limit;
+mod local_domains;
+mod localize;
+mod metrics;
+mod mock;
+#[cfg(unix)]
+mod privileges;
+mod relay;
+mod require_headers;
 mod running;
+mod shutdown;
+mod spf;
 mod ssl;
 mod stream;
+mod tee;
 
+pub use crate::blocklist::BlocklistPolicy;
+pub use crate::builder::ServerBuilder;
 use crate::err::Error;
-pub use crate::ssl::SslConfig;
+#[cfg(feature = "mxdns")]
+pub use crate::fcrdns::SystemResolver;
+pub use crate::fcrdns::{FcrdnsPolicy, Resolver, Verdict};
+pub use crate::hop_limit::{HopLimit, DEFAULT_MAX_HOPS};
+use crate::limit::{LoadShedder, SubnetLimiter};
+pub use crate::local_domains::LocalDomainPolicy;
+pub use crate::localize::ResponseTable;
+pub use crate::metrics::{Metrics, MetricsHandle, PhaseGauges};
+pub use crate::mock::{MockCommand, MockServer};
+pub use crate::relay::{RelayData, TlsConnector};
+pub use crate::require_headers::RequireHeaders;
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::spf::{SpfChecker, SpfResult};
+pub use crate::ssl::{SslConfig, TicketKeys};
 pub use crate::stream::{Stdio, Stream};
+pub use crate::tee::TeeData;
+pub use mailin::address::Normalization;
 pub use mailin::response;
-pub use mailin::{Action, AuthMechanism, Handler, Reason, Response};
+use mailin::Extension;
+pub use mailin::{
+    Action, AuthMechanism, BodyType, ConnInfo, DeliverBy, DeliverByMode, Direction, Handler,
+    OptionalCommand, Phase, Reason, Response,
+};
 use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Builds the greeting for a connection
+type Banner = dyn Fn(&ConnInfo) -> String + Send + Sync;
+
+// Default hard limit on the length of a line read from a client
+const DEFAULT_MAX_LINE_BYTES: usize = 1024 * 1024;
 
 /// `Server` is used to configure and start the SMTP server
 pub struct Server<H>
@@ -54,13 +102,88 @@ where
     H: Handler,
 {
     handler: H,
-    name: String,
     ssl: Option<SslImpl>,
+    config: Config,
+    metrics: MetricsHandle,
+    shutdown: ShutdownHandle,
+}
+
+// The configuration of a server other than its handler and SSL, turned into session
+// options by running::session_builder()
+pub(crate) struct Config {
+    name: String,
+    banner: Option<Arc<Banner>>,
     num_threads: u32,
     auth: Vec<AuthMechanism>,
     tcp_listener: Option<TcpListener>,
     socket_address: Vec<SocketAddr>,
     max_message_size: Option<usize>,
+    max_data_bytes: Option<usize>,
+    data_progress_interval: Option<usize>,
+    max_errors: Option<usize>,
+    max_auth_mechanisms: Option<usize>,
+    etrn: bool,
+    mt_priority: bool,
+    deliver_by: Option<u32>,
+    #[cfg(feature = "digest")]
+    data_digest: bool,
+    restrict_null_sender: bool,
+    require_fqdn_helo: bool,
+    lenient_line_endings: bool,
+    helo_rejection: Option<String>,
+    normalize_recipients: Option<Normalization>,
+    extensions: Vec<Extension>,
+    disabled_commands: Vec<OptionalCommand>,
+    echo_commands: bool,
+    echo_auth_user: bool,
+    canonical_ip: bool,
+    max_line_bytes: usize,
+    data_timeout: Option<Duration>,
+    subnet_limit: Option<SubnetLimiter>,
+    load_shedding: Option<LoadShedder>,
+    fcrdns: Option<Arc<FcrdnsPolicy>>,
+    blocklist: Option<Arc<BlocklistPolicy>>,
+    responses: Option<Arc<dyn ResponseTable>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: "localhost".to_owned(),
+            banner: None,
+            num_threads: 4,
+            auth: Vec::with_capacity(4),
+            tcp_listener: None,
+            socket_address: Vec::with_capacity(4),
+            max_message_size: None,
+            max_data_bytes: None,
+            data_progress_interval: None,
+            max_errors: None,
+            max_auth_mechanisms: None,
+            etrn: false,
+            mt_priority: false,
+            deliver_by: None,
+            #[cfg(feature = "digest")]
+            data_digest: false,
+            restrict_null_sender: false,
+            require_fqdn_helo: false,
+            lenient_line_endings: false,
+            helo_rejection: None,
+            normalize_recipients: None,
+            extensions: Vec::new(),
+            disabled_commands: Vec::new(),
+            echo_commands: false,
+            echo_auth_user: false,
+            canonical_ip: false,
+            max_line_bytes: DEFAULT_MAX_LINE_BYTES,
+            data_timeout: None,
+            subnet_limit: None,
+            load_shedding: None,
+            fcrdns: None,
+            blocklist: None,
+            responses: None,
+        }
+    }
 }
 
 impl<H> Server<H>
@@ -71,13 +194,10 @@ where
     pub fn new(handler: H) -> Self {
         Self {
             handler,
-            name: "localhost".to_owned(),
             ssl: None,
-            num_threads: 4,
-            auth: Vec::with_capacity(4),
-            tcp_listener: None,
-            socket_address: Vec::with_capacity(4),
-            max_message_size: None,
+            config: Config::default(),
+            metrics: MetricsHandle::default(),
+            shutdown: ShutdownHandle::default(),
         }
     }
 
@@ -86,7 +206,17 @@ where
     where
         T: Into<String>,
     {
-        self.name = name.into();
+        self.config.name = name.into();
+        self
+    }
+
+    /// Build the greeting sent to each client with the given function, e.g to include
+    /// the address of the client. See [`mailin::SessionBuilder::banner()`].
+    pub fn with_banner<F>(&mut self, banner: F) -> &mut Self
+    where
+        F: Fn(&ConnInfo) -> String + Send + Sync + 'static,
+    {
+        self.config.banner = Some(Arc::new(banner));
         self
     }
 
@@ -96,27 +226,96 @@ where
         Ok(self)
     }
 
+    /// Encrypt TLS session tickets with the given keys instead of keys generated when
+    /// the server starts, so that servers behind a load balancer resume each other's
+    /// sessions. Call after [`Server::with_ssl()`], there must be a certificate.
+    ///
+    /// Only supported by the default `rtls` feature. The `openssl` crate has no safe API
+    /// to set ticket keys, so with the `ossl` feature this gives an error.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use mailin_embedded::{Server, SslConfig, TicketKeys};
+    /// # use mailin_embedded::Handler;
+    /// # #[derive(Clone)]
+    /// # struct MyHandler;
+    /// # impl Handler for MyHandler {}
+    ///
+    /// let mut server = Server::new(MyHandler);
+    /// server.with_ssl(SslConfig::SelfSigned {
+    ///     cert_path: "cert.pem".to_string(),
+    ///     key_path: "key.pem".to_string(),
+    /// })?;
+    /// // Shared by all servers, and rotated by the application
+    /// let keys = TicketKeys::new([7; 32]).with_old_key([3; 32]);
+    /// server.with_ticket_keys(keys)?;
+    /// # Ok::<(), mailin_embedded::err::Error>(())
+    /// ```
+    pub fn with_ticket_keys(&mut self, keys: TicketKeys) -> Result<&mut Self, Error> {
+        match &mut self.ssl {
+            Some(ssl) => ssl.set_ticket_keys(&keys)?,
+            None => return Error::bail("Ticket keys need a certificate for STARTTLS"),
+        }
+        Ok(self)
+    }
+
     /// Set the size of the threadpool which is equal to the maximum number of
     /// concurrent SMTP sessions.
     pub fn with_num_threads(&mut self, num_threads: u32) -> &mut Self {
-        self.num_threads = num_threads;
+        self.config.num_threads = num_threads;
         self
     }
 
     /// Add an authentication mechanism that will supported by the server
     pub fn with_auth(&mut self, auth: AuthMechanism) -> &mut Self {
-        self.auth.push(auth);
+        self.config.auth.push(auth);
         self
     }
 
     /// Set a tcp listener from an already open socket
     pub fn with_tcp_listener(&mut self, listener: TcpListener) -> &mut Self {
-        self.tcp_listener = Some(listener);
+        self.config.tcp_listener = Some(listener);
         self
     }
 
+    /// Open the listening socket, e.g on the privileged port 25 while running as root,
+    /// then switch the process to the given user and group. Only available on unix.
+    ///
+    /// The process keeps no way to regain root, anything that needs root, such as
+    /// reading a private key only root can read, must be done before this is called.
+    /// ```no_run
+    /// # use mailin_embedded::{Server, Handler};
+    /// # use mailin_embedded::err::Error;
+    /// # #[derive(Clone)]
+    /// # struct EmptyHandler {}
+    /// # impl Handler for EmptyHandler {}
+    /// # let mut server = Server::new(EmptyHandler {});
+    /// server.bind_and_drop_privileges("0.0.0.0:25", "mail", "mail")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[cfg(unix)]
+    pub fn bind_and_drop_privileges<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        user: &str,
+        group: &str,
+    ) -> Result<&mut Self, Error> {
+        let addrs: Vec<SocketAddr> = addr
+            .to_socket_addrs()
+            .map_err(|e| Error::with_source("Invalid socket address", e))?
+            .collect();
+        let listener = running::bind(&addrs)
+            .map_err(|e| Error::with_source("Cannot open listen address", e))?;
+        privileges::drop_privileges(user, group)?;
+        self.config.tcp_listener = Some(listener);
+        Ok(self)
+    }
+
     /// Add ip addresses and ports to listen on.
     /// Returns an error if the given socket addresses are not valid.
+    ///
+    /// IPv6 addresses are bound dual-stack, so listening on `[::]:25` also accepts IPv4
+    /// connections. See [`Self::with_canonical_ip()`] for the addresses of these clients.
     /// ```
     /// # use mailin_embedded::{Server, Handler};
     /// # use mailin_embedded::err::Error;
@@ -132,7 +331,7 @@ where
             .to_socket_addrs()
             .map_err(|e| Error::with_source("Invalid socket address", e))?
         {
-            self.socket_address.push(addr);
+            self.config.socket_address.push(addr);
         }
         Ok(self)
     }
@@ -142,11 +341,243 @@ where
     /// Will be reported to the client on helo/ehlo and will cause
     /// to fail when receiving too much data.
     pub fn with_max_message_size(&mut self, max_size: usize) -> &mut Self {
-        self.max_message_size = Some(max_size);
+        self.config.max_message_size = Some(max_size);
+        self
+    }
+
+    /// Specify a hard limit on the number of bytes received during DATA.
+    ///
+    /// When exceeded, the client gets a 552 response and the connection is closed.
+    pub fn with_max_data_bytes(&mut self, max_data_bytes: usize) -> &mut Self {
+        self.config.max_data_bytes = Some(max_data_bytes);
+        self
+    }
+
+    /// Call [`Handler::data_progress()`] each time the given number of bytes has been
+    /// received during DATA, to follow large transfers while they happen.
+    pub fn with_data_progress_interval(&mut self, bytes: usize) -> &mut Self {
+        self.config.data_progress_interval = Some(bytes);
+        self
+    }
+
+    /// Specify the number of consecutive invalid commands allowed before the connection is closed.
+    ///
+    /// When reached, the client gets a 500 response and the connection is closed.
+    pub fn with_max_errors(&mut self, max_errors: usize) -> &mut Self {
+        self.config.max_errors = Some(max_errors);
+        self
+    }
+
+    /// Specify the number of different authentication mechanisms a client may try.
+    ///
+    /// AUTH commands with further mechanisms get a 503 response until authentication succeeds.
+    pub fn with_max_auth_mechanisms(&mut self, max_auth_mechanisms: usize) -> &mut Self {
+        self.config.max_auth_mechanisms = Some(max_auth_mechanisms);
+        self
+    }
+
+    /// Specify a hard limit on the number of bytes buffered while reading a line.
+    ///
+    /// This bounds the memory used by a connection. A client sending a longer line gets
+    /// a 500 response and the connection is closed. The default is 1MiB.
+    pub fn with_max_line_bytes(&mut self, max_line_bytes: usize) -> &mut Self {
+        self.config.max_line_bytes = max_line_bytes;
+        self
+    }
+
+    /// Close connections that stop sending during DATA for longer than the given time.
+    ///
+    /// The timeout measures inactivity, a slow client that keeps sending is not
+    /// disconnected. On timeout the client gets a 451 response and the connection is
+    /// closed. Outside of DATA the usual five minute idle timeout applies.
+    pub fn with_data_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.data_timeout = Some(timeout);
         self
     }
 
-    /// Start the SMTP server and run forever
+    /// Enable the ETRN command, see [`Handler::etrn()`]
+    pub fn with_etrn(&mut self) -> &mut Self {
+        self.config.etrn = true;
+        self
+    }
+
+    /// Enable the MT-PRIORITY parameter of MAIL, see [`Handler::mt_priority()`]
+    pub fn with_mt_priority(&mut self) -> &mut Self {
+        self.config.mt_priority = true;
+        self
+    }
+
+    /// Enable the BY parameter of MAIL with the minimum deadline in seconds, or 0 for
+    /// none, see [`Handler::deliver_by()`]
+    pub fn with_deliver_by(&mut self, min_seconds: u32) -> &mut Self {
+        self.config.deliver_by = Some(min_seconds);
+        self
+    }
+
+    /// Compute the SHA-256 digest of each message, see [`Handler::data_digest()`].
+    /// Requires the `digest` feature.
+    #[cfg(feature = "digest")]
+    pub fn with_data_digest(&mut self) -> &mut Self {
+        self.config.data_digest = true;
+        self
+    }
+
+    /// Only allow one recipient for messages with a null sender, as used for bounces.
+    /// Further recipients are rejected with a 550 response.
+    pub fn with_restrict_null_sender(&mut self) -> &mut Self {
+        self.config.restrict_null_sender = true;
+        self
+    }
+
+    /// Reject HELO and EHLO with a 504 response unless the domain is fully qualified or
+    /// an address literal, e.g to refuse `EHLO localhost` from remote clients.
+    pub fn with_require_fqdn_helo(&mut self) -> &mut Self {
+        self.config.require_fqdn_helo = true;
+        self
+    }
+
+    /// Accept lines terminated by a bare LF, and a lone `.` before the client closes the
+    /// connection as the end of the data. This helps with broken clients but allows SMTP
+    /// smuggling if mail is relayed, see [`SessionBuilder::lenient_line_endings()`].
+    ///
+    /// [`SessionBuilder::lenient_line_endings()`]: mailin::SessionBuilder::lenient_line_endings
+    pub fn with_lenient_line_endings(&mut self, lenient: bool) -> &mut Self {
+        self.config.lenient_line_endings = lenient;
+        self
+    }
+
+    /// Specify the text of the 530 response to clients that use HELO instead of EHLO when
+    /// authentication is enabled, see [`SessionBuilder::helo_rejection()`].
+    ///
+    /// [`SessionBuilder::helo_rejection()`]: mailin::SessionBuilder::helo_rejection
+    pub fn with_helo_rejection<S: Into<String>>(&mut self, text: S) -> &mut Self {
+        self.config.helo_rejection = Some(text.into());
+        self
+    }
+
+    /// Normalize recipient addresses before they are passed to [`Handler::rcpt()`], e.g
+    /// to lowercase the domain. The envelope keeps the addresses as sent.
+    pub fn with_normalize_recipients(&mut self, normalization: Normalization) -> &mut Self {
+        self.config.normalize_recipients = Some(normalization);
+        self
+    }
+
+    /// Advertise a custom keyword in the EHLO response, such as `NO-SOLICITING`.
+    ///
+    /// The keyword may be followed by parameters separated by spaces. Keywords that are
+    /// not valid or that belong to an extension implemented by mailin are rejected,
+    /// see [`mailin::Extension::custom()`].
+    pub fn with_ehlo_keyword(&mut self, keyword: &str) -> Result<&mut Self, Error> {
+        let extension = Extension::custom(keyword)
+            .map_err(|e| Error::with_source("Invalid EHLO keyword", e))?;
+        self.config.extensions.push(extension);
+        Ok(self)
+    }
+
+    /// Disable a command, which then gets a 502 response.
+    ///
+    /// See [`SessionBuilder::disable_command()`](mailin::SessionBuilder::disable_command).
+    pub fn with_disabled_command(&mut self, command: OptionalCommand) -> &mut Self {
+        self.config.disabled_commands.push(command);
+        self
+    }
+
+    /// Include the command verb in syntax error and bad sequence responses, for debugging.
+    ///
+    /// See [`SessionBuilder::enable_command_echo()`](mailin::SessionBuilder::enable_command_echo).
+    pub fn with_command_echo(&mut self) -> &mut Self {
+        self.config.echo_commands = true;
+        self
+    }
+
+    /// Include the authenticated username in successful AUTH responses.
+    ///
+    /// See [`SessionBuilder::enable_auth_user_echo()`](mailin::SessionBuilder::enable_auth_user_echo).
+    pub fn with_auth_user_echo(&mut self) -> &mut Self {
+        self.config.echo_auth_user = true;
+        self
+    }
+
+    /// Pass the IPv4 address of IPv4 clients that connect to an IPv6 listener, e.g
+    /// `192.0.2.1` instead of the IPv4-mapped `::ffff:192.0.2.1`.
+    ///
+    /// This applies to the address given to the handler as well as to the subnet limit
+    /// and FCrDNS checks. By default the address is passed as reported by the socket.
+    pub fn with_canonical_ip(&mut self) -> &mut Self {
+        self.config.canonical_ip = true;
+        self
+    }
+
+    /// Limit the number of concurrent connections from the same subnet.
+    ///
+    /// Connections are grouped by the IPv4 or IPv6 network prefix of the client
+    /// address e.g a /24 for IPv4 and a /64 for IPv6. Once `max_connections` are active
+    /// from a subnet, new connections from that subnet get a 421 response and are closed.
+    pub fn with_subnet_limit(
+        &mut self,
+        ipv4_prefix_len: u8,
+        ipv6_prefix_len: u8,
+        max_connections: usize,
+    ) -> &mut Self {
+        self.config.subnet_limit = Some(SubnetLimiter::new(
+            ipv4_prefix_len,
+            ipv6_prefix_len,
+            max_connections,
+        ));
+        self
+    }
+
+    /// Tempfail new mail transactions while the server is busy.
+    ///
+    /// While more than `threshold` connections are active, MAIL commands get the given
+    /// response, e.g a 451, instead of being passed to the handler. The connection stays
+    /// open and other commands work as usual, so clients can retry later.
+    pub fn with_load_shedding(&mut self, threshold: usize, response: Response) -> &mut Self {
+        self.config.load_shedding = Some(LoadShedder::new(threshold, response));
+        self
+    }
+
+    /// Refuse connections from clients whose reverse DNS is not forward confirmed.
+    ///
+    /// Refused clients get a 554 response and the connection is closed. See [`FcrdnsPolicy`].
+    pub fn with_fcrdns(&mut self, policy: FcrdnsPolicy) -> &mut Self {
+        self.config.fcrdns = Some(Arc::new(policy));
+        self
+    }
+
+    /// Refuse connections from clients whose address is on a DNS blocklist.
+    ///
+    /// Refused clients get a 554 response and the connection is closed. See
+    /// [`BlocklistPolicy`].
+    pub fn with_blocklist(&mut self, policy: BlocklistPolicy) -> &mut Self {
+        self.config.blocklist = Some(Arc::new(policy));
+        self
+    }
+
+    /// Replace the text of the responses sent to clients, e.g. to localize them.
+    ///
+    /// See [`ResponseTable`].
+    pub fn with_responses<T: ResponseTable + 'static>(&mut self, responses: T) -> &mut Self {
+        self.config.responses = Some(Arc::new(responses));
+        self
+    }
+
+    /// Get a snapshot of the connection, message and authentication counters
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+
+    /// Get a handle to the counters that can be used after the server has started
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Get a handle that stops the server started with [`Server::serve()`]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Start the SMTP server and run until shut down with a [`ShutdownHandle`]
     pub fn serve(self) -> Result<(), Error>
     where
         H: Clone + Send,
@@ -0,0 +1,146 @@
+use mailin::Response;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Limits the number of concurrent connections from the same subnet
+#[derive(Clone)]
+pub(crate) struct SubnetLimiter {
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    max_connections: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Holds a connection slot for a subnet until dropped
+pub(crate) struct SubnetGuard {
+    subnet: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl SubnetLimiter {
+    pub(crate) fn new(ipv4_prefix: u8, ipv6_prefix: u8, max_connections: usize) -> Self {
+        Self {
+            ipv4_prefix: ipv4_prefix.min(32),
+            ipv6_prefix: ipv6_prefix.min(128),
+            max_connections,
+            active: Arc::default(),
+        }
+    }
+
+    // Reserve a connection slot for the subnet of the given address.
+    // Returns None if the subnet has reached its limit.
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Option<SubnetGuard> {
+        let subnet = self.subnet(ip);
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        let count = active.entry(subnet).or_insert(0);
+        if *count >= self.max_connections {
+            return None;
+        }
+        *count += 1;
+        Some(SubnetGuard {
+            subnet,
+            active: self.active.clone(),
+        })
+    }
+
+    fn subnet(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.ipv4_prefix))
+                    .unwrap_or(0);
+                IpAddr::V4((u32::from(v4) & mask).into())
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.ipv6_prefix))
+                    .unwrap_or(0);
+                IpAddr::V6((u128::from(v6) & mask).into())
+            }
+        }
+    }
+}
+
+impl Drop for SubnetGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = active.get_mut(&self.subnet) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.subnet);
+            }
+        }
+    }
+}
+
+// Tempfails new mail transactions while more than a threshold of connections are active
+#[derive(Clone)]
+pub(crate) struct LoadShedder {
+    threshold: usize,
+    response: Response,
+    active: Arc<AtomicUsize>,
+}
+
+// Counts a connection as active until dropped
+pub(crate) struct LoadGuard {
+    active: Arc<AtomicUsize>,
+}
+
+impl LoadShedder {
+    pub(crate) fn new(threshold: usize, response: Response) -> Self {
+        Self {
+            threshold,
+            response,
+            active: Arc::default(),
+        }
+    }
+
+    pub(crate) fn enter(&self) -> LoadGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        LoadGuard {
+            active: self.active.clone(),
+        }
+    }
+
+    // The response to a MAIL command, if the server is overloaded
+    pub(crate) fn check(&self) -> Option<&Response> {
+        let is_overloaded = self.active.load(Ordering::SeqCst) > self.threshold;
+        is_overloaded.then_some(&self.response)
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn same_subnet() {
+        let limiter = SubnetLimiter::new(24, 64, 1);
+        let first = limiter.acquire(ip("192.0.2.10"));
+        assert!(first.is_some());
+        assert!(limiter.acquire(ip("192.0.2.99")).is_none());
+        assert!(limiter.acquire(ip("192.0.3.10")).is_some());
+        drop(first);
+        assert!(limiter.acquire(ip("192.0.2.99")).is_some());
+    }
+
+    #[test]
+    fn ipv6_subnet() {
+        let limiter = SubnetLimiter::new(24, 64, 1);
+        let _first = limiter.acquire(ip("2001:db8::1")).unwrap();
+        assert!(limiter.acquire(ip("2001:db8::ffff")).is_none());
+        assert!(limiter.acquire(ip("2001:db8:0:1::1")).is_some());
+    }
+}
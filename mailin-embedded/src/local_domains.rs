@@ -0,0 +1,160 @@
+use log::debug;
+use mailin::response::{OK, RELAYING_DENIED};
+use mailin::Response;
+
+/// Only accepts recipients in local domains, unless the client has authenticated, so
+/// that the server is not an open relay.
+///
+/// A handler calls [`LocalDomainPolicy::rcpt()`] from
+/// [`Handler::rcpt()`](crate::Handler::rcpt()) with the state given to
+/// [`Handler::authenticated()`](crate::Handler::authenticated()). Domains are case
+/// insensitive and a recipient without a domain, such as `postmaster`, is local.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::response::OK;
+/// use mailin_embedded::{Handler, LocalDomainPolicy, Response, Server};
+/// use std::sync::Arc;
+///
+/// #[derive(Clone)]
+/// struct MyHandler {
+///     policy: Arc<LocalDomainPolicy>,
+///     authenticated: bool,
+/// }
+///
+/// impl Handler for MyHandler {
+///     fn authenticated(&mut self, authenticated: bool) {
+///         self.authenticated = authenticated;
+///     }
+///
+///     fn rcpt(&mut self, to: &str) -> Response {
+///         let res = self.policy.rcpt(to, self.authenticated);
+///         if res.is_error {
+///             return res;
+///         }
+///         // Check the mailbox exists
+///         OK
+///     }
+/// }
+///
+/// let policy = LocalDomainPolicy::new(&["example.com", "example.org"]);
+/// let handler = MyHandler {
+///     policy: Arc::new(policy),
+///     authenticated: false,
+/// };
+/// let server = Server::new(handler);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LocalDomainPolicy {
+    domains: Vec<String>,
+}
+
+impl LocalDomainPolicy {
+    /// Create a policy where recipients outside the given domains need authentication
+    pub fn new(domains: &[&str]) -> Self {
+        Self {
+            domains: domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Is the recipient in one of the local domains?
+    pub fn is_local(&self, to: &str) -> bool {
+        match to.rsplit_once('@') {
+            Some((_, domain)) => self.domains.iter().any(|d| d.eq_ignore_ascii_case(domain)),
+            None => true,
+        }
+    }
+
+    /// Check a recipient, returns [`RELAYING_DENIED`] if it is not local and the
+    /// client has not authenticated, otherwise [`OK`]
+    pub fn rcpt(&self, to: &str, authenticated: bool) -> Response {
+        if authenticated || self.is_local(to) {
+            OK
+        } else {
+            debug!("Relaying to {} denied", to);
+            RELAYING_DENIED
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AuthMechanism, Handler};
+    use mailin::response::{AUTH_OK, INVALID_CREDENTIALS};
+    use mailin::SessionBuilder;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    fn policy() -> LocalDomainPolicy {
+        LocalDomainPolicy::new(&["sea.com"])
+    }
+
+    #[test]
+    fn local_domain() {
+        let policy = policy();
+        assert_eq!(policy.rcpt("fish@sea.com", false), OK);
+        assert_eq!(policy.rcpt("Fish@SEA.COM", false), OK);
+        assert_eq!(policy.rcpt("postmaster", false), OK);
+    }
+
+    #[test]
+    fn foreign_domain() {
+        let policy = policy();
+        assert_eq!(policy.rcpt("bird@sky.com", false), RELAYING_DENIED);
+        assert_eq!(policy.rcpt("fish@sub.sea.com", false), RELAYING_DENIED);
+    }
+
+    #[derive(Clone)]
+    struct RelayHandler {
+        policy: Arc<LocalDomainPolicy>,
+        authenticated: bool,
+    }
+
+    impl Handler for RelayHandler {
+        fn authenticated(&mut self, authenticated: bool) {
+            self.authenticated = authenticated;
+        }
+
+        fn rcpt(&mut self, to: &str) -> Response {
+            self.policy.rcpt(to, self.authenticated)
+        }
+
+        fn auth_plain(&mut self, _: &str, authentication_id: &str, password: &str) -> Response {
+            if authentication_id == "test" && password == "1234" {
+                AUTH_OK
+            } else {
+                INVALID_CREDENTIALS
+            }
+        }
+    }
+
+    #[test]
+    fn authenticated_relay() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .insecure_enable_plaintext_auth();
+        let mut session = builder.build(ip, handler());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain AHRlc3QAMTIzNA==\r\n");
+        assert_eq!(res, AUTH_OK);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.process(b"rcpt to:<bird@sky.com>\r\n"), OK);
+        // Without authentication only local recipients are accepted
+        let mut session = SessionBuilder::new("some.name").build(ip, handler());
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<bird@sky.com>\r\n");
+        assert_eq!(res, RELAYING_DENIED);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n"), OK);
+    }
+
+    fn handler() -> RelayHandler {
+        RelayHandler {
+            policy: Arc::new(policy()),
+            authenticated: false,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use mailin::Response;
+use std::borrow::Cow;
+
+/// A `ResponseTable` supplies the human-readable text of responses sent to clients.
+///
+/// This allows the built-in English text to be replaced, e.g. with a translation.
+/// Only the text is changed, the response codes and the behaviour of the server
+/// remain the same.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{ResponseTable, Response};
+/// use mailin_embedded::response::GOODBYE;
+///
+/// struct French;
+///
+/// impl ResponseTable for French {
+///     fn text(&self, response: &Response) -> Option<String> {
+///         (response == &GOODBYE).then(|| "Au revoir".to_string())
+///     }
+/// }
+/// ```
+pub trait ResponseTable: Send + Sync {
+    /// Get the text to send for the given response, or `None` to keep the default text.
+    ///
+    /// Built-in responses can be identified by comparing against the constants in
+    /// [`response`](crate::response) or by their code.
+    fn text(&self, response: &Response) -> Option<String>;
+}
+
+// Apply the response table, if any, to the given response
+pub(crate) fn localize<'a>(
+    responses: Option<&dyn ResponseTable>,
+    response: &'a Response,
+) -> Cow<'a, Response> {
+    match responses.and_then(|table| table.text(response)) {
+        Some(text) => Cow::Owned(response.with_text(text)),
+        None => Cow::Borrowed(response),
+    }
+}
@@ -0,0 +1,334 @@
+use mailin::{Handler, Phase, Reason, Response};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A snapshot of the counters maintained by a [`Server`](crate::Server)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of connections handled
+    pub connections: u64,
+    /// Number of messages accepted at the end of DATA
+    pub messages_accepted: u64,
+    /// Number of messages rejected or aborted during DATA
+    pub messages_rejected: u64,
+    /// Number of recipients accepted
+    pub recipients_accepted: u64,
+    /// Number of recipients rejected
+    pub recipients_rejected: u64,
+    /// Number of bytes received from clients
+    pub bytes_received: u64,
+    /// Number of successful authentications
+    pub auth_successes: u64,
+    /// Number of failed authentications
+    pub auth_failures: u64,
+    /// Number of open connections in each phase of the SMTP session
+    pub phases: PhaseGauges,
+}
+
+/// The number of open connections in each [`Phase`] of the SMTP session, e.g to tell
+/// clients that hold connections open in DATA from a storm of failed authentications.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhaseGauges {
+    /// Connected but not greeted with HELO or EHLO
+    pub connected: u64,
+    /// Greeted and not in a mail transaction
+    pub greeted: u64,
+    /// Authenticating with AUTH
+    pub authenticating: u64,
+    /// Started a mail transaction with MAIL
+    pub mail: u64,
+    /// Giving recipients with RCPT
+    pub rcpt: u64,
+    /// Sending a message after DATA
+    pub data: u64,
+}
+
+impl PhaseGauges {
+    /// The number of open connections in a phase, closed sessions are not counted
+    pub fn get(&self, phase: Phase) -> u64 {
+        match phase {
+            Phase::Connected => self.connected,
+            Phase::Greeted => self.greeted,
+            Phase::Authenticating => self.authenticating,
+            Phase::Mail => self.mail,
+            Phase::Rcpt => self.rcpt,
+            Phase::Data => self.data,
+            _ => 0,
+        }
+    }
+}
+
+/// A handle to the counters of a [`Server`](crate::Server) that remains usable
+/// after the server has started.
+#[derive(Clone, Default)]
+pub struct MetricsHandle {
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) connections: AtomicU64,
+    pub(crate) messages_accepted: AtomicU64,
+    pub(crate) messages_rejected: AtomicU64,
+    pub(crate) recipients_accepted: AtomicU64,
+    pub(crate) recipients_rejected: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) auth_successes: AtomicU64,
+    pub(crate) auth_failures: AtomicU64,
+    // Gauges indexed by phase_index()
+    phases: [AtomicU64; 6],
+}
+
+impl MetricsHandle {
+    /// Take a snapshot of the current counter values
+    pub fn snapshot(&self) -> Metrics {
+        let c = &self.counters;
+        Metrics {
+            connections: c.connections.load(Ordering::Relaxed),
+            messages_accepted: c.messages_accepted.load(Ordering::Relaxed),
+            messages_rejected: c.messages_rejected.load(Ordering::Relaxed),
+            recipients_accepted: c.recipients_accepted.load(Ordering::Relaxed),
+            recipients_rejected: c.recipients_rejected.load(Ordering::Relaxed),
+            bytes_received: c.bytes_received.load(Ordering::Relaxed),
+            auth_successes: c.auth_successes.load(Ordering::Relaxed),
+            auth_failures: c.auth_failures.load(Ordering::Relaxed),
+            phases: PhaseGauges {
+                connected: c.phases[0].load(Ordering::Relaxed),
+                greeted: c.phases[1].load(Ordering::Relaxed),
+                authenticating: c.phases[2].load(Ordering::Relaxed),
+                mail: c.phases[3].load(Ordering::Relaxed),
+                rcpt: c.phases[4].load(Ordering::Relaxed),
+                data: c.phases[5].load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+impl Counters {
+    pub(crate) fn incr(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    // Increment one of two counters depending on the outcome of a response
+    fn outcome(&self, res: &Response, ok: &AtomicU64, failed: &AtomicU64) {
+        Self::incr(if res.is_error { failed } else { ok }, 1);
+    }
+}
+
+// Index of the gauge for a phase, closed sessions have no gauge
+fn phase_index(phase: Phase) -> Option<usize> {
+    match phase {
+        Phase::Connected => Some(0),
+        Phase::Greeted => Some(1),
+        Phase::Authenticating => Some(2),
+        Phase::Mail => Some(3),
+        Phase::Rcpt => Some(4),
+        Phase::Data => Some(5),
+        _ => None,
+    }
+}
+
+// Moves a connection between the phase gauges, the connection leaves the gauges
+// when this is dropped
+pub(crate) struct PhaseTracker {
+    metrics: MetricsHandle,
+    gauge: Option<usize>,
+}
+
+impl PhaseTracker {
+    pub(crate) fn new(metrics: MetricsHandle) -> Self {
+        let mut tracker = Self {
+            metrics,
+            gauge: None,
+        };
+        tracker.set(Phase::Connected);
+        tracker
+    }
+
+    pub(crate) fn set(&mut self, phase: Phase) {
+        let gauge = phase_index(phase);
+        if gauge == self.gauge {
+            return;
+        }
+        let phases = &self.metrics.counters().phases;
+        if let Some(old) = self.gauge {
+            phases[old].fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(new) = gauge {
+            phases[new].fetch_add(1, Ordering::Relaxed);
+        }
+        self.gauge = gauge;
+    }
+}
+
+impl Drop for PhaseTracker {
+    fn drop(&mut self) {
+        self.set(Phase::Closed);
+    }
+}
+
+// Handler that counts the outcome of the calls made to the inner handler
+pub(crate) struct MetricsHandler<H: Handler> {
+    inner: H,
+    metrics: MetricsHandle,
+}
+
+impl<H: Handler> MetricsHandler<H> {
+    pub(crate) fn new(inner: H, metrics: MetricsHandle) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<H: Handler> Handler for MetricsHandler<H> {
+    fn rcpt(&mut self, to: &str) -> Response {
+        let res = self.inner.rcpt(to);
+        let c = self.metrics.counters();
+        c.outcome(&res, &c.recipients_accepted, &c.recipients_rejected);
+        res
+    }
+
+    fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        let res = self.inner.data_start(domain, from, is8bit, to);
+        if res.is_error {
+            Counters::incr(&self.metrics.counters().messages_rejected, 1);
+        }
+        res
+    }
+
+    fn data_end(&mut self) -> Response {
+        let res = self.inner.data_end();
+        let c = self.metrics.counters();
+        c.outcome(&res, &c.messages_accepted, &c.messages_rejected);
+        res
+    }
+
+    fn data_end_error(&mut self, reason: Reason) {
+        Counters::incr(&self.metrics.counters().messages_rejected, 1);
+        self.inner.data_end_error(reason)
+    }
+
+    fn auth_plain(
+        &mut self,
+        authorization_id: &str,
+        authentication_id: &str,
+        password: &str,
+    ) -> Response {
+        let res = self
+            .inner
+            .auth_plain(authorization_id, authentication_id, password);
+        let c = self.metrics.counters();
+        c.outcome(&res, &c.auth_successes, &c.auth_failures);
+        res
+    }
+
+    fn auth_login(&mut self, username: &str, password: &str) -> Response {
+        let res = self.inner.auth_login(username, password);
+        let c = self.metrics.counters();
+        c.outcome(&res, &c.auth_successes, &c.auth_failures);
+        res
+    }
+
+    forward_handler!(
+        inner: helo, mail, data, data_progress, data_digest, on_error, rset, allow_starttls,
+        mt_priority, body_type, secure, authenticated, deliver_by, etrn, auth_challenge,
+        response_delay, on_wire
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::MemoryStream;
+    use crate::Server;
+    use mailin::response::{NO_MAILBOX, OK};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct RcptHandler {}
+    impl Handler for RcptHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            if to == "fish@sea.com" {
+                OK
+            } else {
+                NO_MAILBOX
+            }
+        }
+    }
+
+    #[test]
+    fn delivered_and_rejected() {
+        let session = b"helo a.domain\r\n\
+            mail from:<ship@sea.com>\r\n\
+            rcpt to:<kraken@sea.com>\r\n\
+            rcpt to:<fish@sea.com>\r\n\
+            data\r\n\
+            Hello World\r\n\
+            .\r\n\
+            quit\r\n";
+        let (stream, _output) = MemoryStream::new(session);
+        let server = Server::new(RcptHandler {});
+        let metrics = server.metrics_handle();
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        server.execute(stream, ip).unwrap();
+        let expected = Metrics {
+            connections: 1,
+            messages_accepted: 1,
+            recipients_accepted: 1,
+            recipients_rejected: 1,
+            bytes_received: session.len() as u64,
+            ..Default::default()
+        };
+        assert_eq!(metrics.snapshot(), expected);
+    }
+
+    // Wait for a gauge to reach a value, the server updates it after replying
+    fn wait_for(metrics: &MetricsHandle, phase: Phase, value: u64) {
+        let start = Instant::now();
+        while metrics.snapshot().phases.get(phase) != value {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{phase:?} != {value}"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn paused_in_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(RcptHandler {});
+        let metrics = server.metrics_handle();
+        let server = thread::spawn(move || {
+            let (stream, remote) = listener.accept().unwrap();
+            server.execute(stream, remote.ip()).unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        wait_for(&metrics, Phase::Connected, 1);
+        client
+            .write_all(b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\n")
+            .unwrap();
+        wait_for(&metrics, Phase::Rcpt, 1);
+        // The client stops sending part way through the message
+        client.write_all(b"data\r\nSubject: slow\r\n").unwrap();
+        wait_for(&metrics, Phase::Data, 1);
+        let expected = PhaseGauges {
+            data: 1,
+            ..Default::default()
+        };
+        assert_eq!(metrics.snapshot().phases, expected);
+        client.write_all(b"\r\nHello\r\n.\r\nquit\r\n").unwrap();
+        server.join().unwrap();
+        assert_eq!(metrics.snapshot().phases, PhaseGauges::default());
+        assert_eq!(metrics.snapshot().messages_accepted, 1);
+    }
+}
@@ -0,0 +1,233 @@
+use mailin::response::{OK, TRANSACTION_FAILED};
+use mailin::{Handler, Response};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Commands that a [`MockServer`] can be scripted to respond to, the commands that
+/// reach a [`Handler`]
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MockCommand {
+    /// HELO or EHLO
+    Helo,
+    /// MAIL FROM
+    Mail,
+    /// RCPT TO
+    Rcpt,
+    /// DATA, before the message is sent
+    Data,
+    /// The end of the message data
+    DataEnd,
+    /// RSET
+    Rset,
+}
+
+/// A handler that sends scripted responses, to test how SMTP clients deal with errors
+/// such as a `451` to RCPT or a `421` in the middle of DATA.
+///
+/// Commands without a scripted response are accepted. Occurrences of a command are
+/// counted per connection, from 1.
+///
+/// `MockServer` is a [`Handler`], so it only scripts the replies that a handler
+/// decides, see [`MockCommand`]. The session still follows the SMTP state machine:
+/// after an error response to RCPT the recipient is not added, and commands sent out
+/// of sequence are rejected before the mock sees them. The greeting, the EHLO
+/// extensions and the replies to NOOP, QUIT, VRFY, AUTH and STARTTLS come from the
+/// session and cannot be scripted.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::response::NO_SERVICE;
+/// use mailin_embedded::{MockCommand, MockServer, Response, Server};
+///
+/// let mock = MockServer::new()
+///     .respond(MockCommand::Rcpt, 2, Response::fixed(451, "Try again later"))
+///     .fail_data(1, 10, NO_SERVICE);
+/// let mut server = Server::new(mock.clone());
+/// server.with_name("mock.example.com");
+/// // Run the server and connect the client under test, then check what it sent
+/// let sent: Vec<String> = mock.transcript();
+/// ```
+#[derive(Clone, Default)]
+pub struct MockServer {
+    responses: Arc<HashMap<(MockCommand, usize), Response>>,
+    // Fail the nth message after a number of data lines
+    data_failures: Arc<HashMap<usize, (usize, Response)>>,
+    transcript: Arc<Mutex<Vec<String>>>,
+    // Per connection state
+    counts: HashMap<MockCommand, usize>,
+    data_lines: usize,
+}
+
+impl MockServer {
+    /// Create a mock that accepts every command
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reply to the `nth` time the command is sent on a connection with the given
+    /// response instead of accepting it
+    pub fn respond(mut self, command: MockCommand, nth: usize, response: Response) -> Self {
+        Arc::make_mut(&mut self.responses).insert((command, nth), response);
+        self
+    }
+
+    /// Fail the `nth` message on a connection when its data reaches the given number of
+    /// lines. The response is sent immediately, a 421 also closes the connection.
+    pub fn fail_data(mut self, nth: usize, lines: usize, response: Response) -> Self {
+        Arc::make_mut(&mut self.data_failures).insert(nth, (lines, response));
+        self
+    }
+
+    /// The commands received by this mock and its clones, in order, e.g
+    /// `RCPT fish@sea.com`. The end of message data is shown as `.`
+    pub fn transcript(&self) -> Vec<String> {
+        self.transcript
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Record a command and return its scripted response, if any
+    fn command(&mut self, command: MockCommand, line: String, default: Response) -> Response {
+        self.transcript
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(line);
+        let count = self.counts.entry(command).or_default();
+        *count += 1;
+        self.responses
+            .get(&(command, *count))
+            .cloned()
+            .unwrap_or(default)
+    }
+}
+
+impl Handler for MockServer {
+    fn helo(&mut self, _ip: IpAddr, domain: &str) -> Response {
+        self.command(MockCommand::Helo, format!("HELO {}", domain), OK)
+    }
+
+    fn mail(&mut self, _ip: IpAddr, _domain: &str, from: &str) -> Response {
+        self.command(MockCommand::Mail, format!("MAIL {}", from), OK)
+    }
+
+    fn rcpt(&mut self, to: &str) -> Response {
+        self.command(MockCommand::Rcpt, format!("RCPT {}", to), OK)
+    }
+
+    fn data_start(
+        &mut self,
+        _domain: &str,
+        _from: &str,
+        _is8bit: bool,
+        _to: &[String],
+    ) -> Response {
+        self.data_lines = 0;
+        self.command(MockCommand::Data, "DATA".to_string(), OK)
+    }
+
+    fn data(&mut self, _buf: &[u8]) -> io::Result<()> {
+        self.data_lines += 1;
+        let nth = self
+            .counts
+            .get(&MockCommand::Data)
+            .copied()
+            .unwrap_or_default();
+        match self.data_failures.get(&nth) {
+            Some((lines, _)) if self.data_lines >= *lines => {
+                Err(io::Error::other("Scripted data failure"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn data_end(&mut self) -> Response {
+        self.command(MockCommand::DataEnd, ".".to_string(), OK)
+    }
+
+    fn on_error(&mut self, _err: io::Error) -> Response {
+        let nth = self
+            .counts
+            .get(&MockCommand::Data)
+            .copied()
+            .unwrap_or_default();
+        match self.data_failures.get(&nth) {
+            Some((_, response)) => response.clone(),
+            None => TRANSACTION_FAILED,
+        }
+    }
+
+    fn rset(&mut self) -> Response {
+        self.command(MockCommand::Rset, "RSET".to_string(), OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::MemoryStream;
+    use crate::Server;
+    use mailin::response::NO_SERVICE;
+    use std::net::Ipv4Addr;
+
+    fn run(mock: &MockServer, input: &[u8]) -> String {
+        let (stream, output) = MemoryStream::new(input);
+        let server = Server::new(mock.clone());
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        _ = server.execute(stream, ip);
+        let output = output.lock().unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn scripted_rcpt() {
+        let mock =
+            MockServer::new().respond(MockCommand::Rcpt, 2, Response::fixed(451, "Try later"));
+        let output = run(
+            &mock,
+            b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\n\
+            rcpt to:<crab@sea.com>\r\nrcpt to:<crab@sea.com>\r\nquit\r\n",
+        );
+        assert_eq!(
+            output,
+            "220 localhost ESMTP\r\n250 OK\r\n250 OK\r\n250 OK\r\n451 Try later\r\n250 OK\r\n\
+            221 Goodbye\r\n"
+        );
+        assert_eq!(
+            mock.transcript(),
+            vec![
+                "HELO a.domain",
+                "MAIL ship@sea.com",
+                "RCPT fish@sea.com",
+                "RCPT crab@sea.com",
+                "RCPT crab@sea.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn scripted_data_failure() {
+        let mock = MockServer::new().fail_data(1, 2, NO_SERVICE);
+        let output = run(
+            &mock,
+            b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\ndata\r\n\
+            Subject: one\r\n\r\nnot sent\r\n.\r\nquit\r\n",
+        );
+        assert!(
+            output.ends_with("354 Start mail input; end with <CRLF>.<CRLF>\r\n421 Service not available, closing connection\r\n"),
+            "{output}"
+        );
+        // Only the scripted message fails
+        let mock = MockServer::new().fail_data(2, 1, NO_SERVICE);
+        let output = run(
+            &mock,
+            b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\ndata\r\n\
+            Subject: one\r\n.\r\nquit\r\n",
+        );
+        assert!(output.ends_with("250 OK\r\n221 Goodbye\r\n"), "{output}");
+        assert_eq!(mock.transcript().last().unwrap(), ".");
+    }
+}
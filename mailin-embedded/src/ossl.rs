@@ -1,4 +1,4 @@
-use crate::ssl::SslConfig;
+use crate::ssl::{SslConfig, TicketKeys};
 use crate::stream::Stream;
 use crate::Error;
 use openssl::error::ErrorStack;
@@ -7,9 +7,10 @@ use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream};
 use openssl::x509::X509;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 // Openssl wrapper
 #[derive(Clone)]
@@ -24,7 +25,11 @@ impl From<ErrorStack> for Error {
     }
 }
 
-impl<S: Stream> Stream for SslStream<S> {}
+impl<S: Stream> Stream for SslStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+}
 
 impl SslImpl {
     pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
@@ -49,7 +54,16 @@ impl SslImpl {
                 let builder = ssl_builder(cert_path, key_path)?;
                 Some(builder)
             }
-            _ => None,
+            SslConfig::Pem { cert, key, chain } => {
+                let mut builder = pem_builder(&cert, &key)?;
+                if let Some(chain_pem) = chain {
+                    for cert in X509::stack_from_pem(&chain_pem)? {
+                        builder.add_extra_chain_cert(cert)?;
+                    }
+                }
+                Some(builder)
+            }
+            SslConfig::None => None,
         };
         let ssl = builder.map(|b| SslImpl {
             acceptor: Arc::new(b.build()),
@@ -57,6 +71,11 @@ impl SslImpl {
         Ok(ssl)
     }
 
+    // The openssl crate has no safe API to set the session ticket keys
+    pub fn set_ticket_keys(&mut self, _keys: &TicketKeys) -> Result<(), Error> {
+        Error::bail("Ticket keys are not supported with the ossl feature")
+    }
+
     pub fn accept<S: Stream>(&self, stream: S) -> Result<impl Stream, Error> {
         let ret = self
             .acceptor
@@ -67,11 +86,15 @@ impl SslImpl {
 }
 
 fn ssl_builder(cert_path: String, key_path: String) -> Result<SslAcceptorBuilder, Error> {
-    let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
     let cert_pem = slurp(cert_path)?;
-    let cert = X509::from_pem(&cert_pem)?;
     let key_pem = slurp(key_path)?;
-    let pkey = PKey::private_key_from_pem(&key_pem)?;
+    pem_builder(&cert_pem, &key_pem)
+}
+
+fn pem_builder(cert_pem: &[u8], key_pem: &[u8]) -> Result<SslAcceptorBuilder, Error> {
+    let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
+    let cert = X509::from_pem(cert_pem)?;
+    let pkey = PKey::private_key_from_pem(key_pem)?;
     builder.set_private_key(&pkey)?;
     builder.set_certificate(&cert)?;
     builder.check_private_key()?;
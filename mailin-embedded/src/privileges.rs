@@ -0,0 +1,25 @@
+use crate::err::Error;
+use nix::unistd::{setgid, setuid, Group, Uid, User};
+
+// Switch the process to the given user and group, e.g after binding to a privileged port
+pub(crate) fn drop_privileges(user: &str, group: &str) -> Result<(), Error> {
+    let uid = User::from_name(user)
+        .map_err(|e| Error::with_source("Cannot look up user", e))?
+        .ok_or_else(|| Error::new(format!("Unknown user {}", user)))?
+        .uid;
+    let gid = Group::from_name(group)
+        .map_err(|e| Error::with_source("Cannot look up group", e))?
+        .ok_or_else(|| Error::new(format!("Unknown group {}", group)))?
+        .gid;
+    // The supplementary groups of root would otherwise be kept
+    #[cfg(not(target_vendor = "apple"))]
+    nix::unistd::setgroups(&[gid])
+        .map_err(|e| Error::with_source("Cannot set supplementary groups", e))?;
+    // The group has to be changed while the process still has the privilege to do so
+    setgid(gid).map_err(|e| Error::with_source("Cannot change group", e))?;
+    setuid(uid).map_err(|e| Error::with_source("Cannot change user", e))?;
+    if !uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Error::bail("Root privileges were not dropped");
+    }
+    Ok(())
+}
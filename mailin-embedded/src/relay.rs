@@ -0,0 +1,590 @@
+use crate::stream::Stream;
+use bufstream_fresh::BufStream;
+use log::{debug, error};
+use mailin::{Handler, Reason, Response};
+use std::io::{self, BufRead, IoSlice, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const UPSTREAM_UNAVAILABLE: Response = Response::fixed(451, "Upstream server unavailable");
+
+// How long to wait for the upstream server unless configured
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Message lines are collected up to this size to avoid a write per line
+const DATA_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Upgrades a connection to an upstream server to TLS after STARTTLS. It is given
+/// the connection and the host name of the upstream server.
+pub type TlsConnector = dyn Fn(TcpStream, &str) -> io::Result<Box<dyn Stream + Send>> + Send + Sync;
+
+/// A [`Handler`] that relays each accepted message to an upstream SMTP server.
+///
+/// At [`Handler::data_start()`] the relay takes a connection to the upstream server
+/// and replays the envelope with MAIL and RCPT. The data of the message is streamed
+/// to the upstream server as it arrives and the upstream's reply at the end of the
+/// data becomes the reply to the client. If the upstream rejects the sender or any
+/// recipient, the client gets the rejection at the start of the data so that no
+/// recipient is silently dropped. An upstream that cannot be reached gives a 451
+/// response.
+///
+/// Connections can be kept open and reused for later messages, across all sessions
+/// of the server. The relay does not decide which senders and recipients to accept,
+/// combine it with another handler using [`TeeData`](crate::TeeData).
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Handler, RelayData, Server, TeeData};
+///
+/// #[derive(Clone)]
+/// struct Policy {}
+/// impl Handler for Policy {}
+///
+/// let relay = RelayData::new("smarthost.example.com:25")
+///     .with_name("mx.example.com")
+///     .with_reuse(true);
+/// let server = Server::new(TeeData::new(Policy {}, relay));
+/// ```
+pub struct RelayData {
+    address: String,
+    name: String,
+    timeout: Duration,
+    reuse: bool,
+    tls: Option<Arc<TlsConnector>>,
+    // Connections kept open for reuse, shared by all clones
+    idle: Arc<Mutex<Vec<Upstream>>>,
+    // The connection used for the current message
+    upstream: Option<Upstream>,
+}
+
+impl RelayData {
+    /// Relay messages to the upstream server at `address`, given as `host:port`
+    pub fn new<S: Into<String>>(address: S) -> Self {
+        Self {
+            address: address.into(),
+            name: "localhost".to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            reuse: false,
+            tls: None,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            upstream: None,
+        }
+    }
+
+    /// Set the name sent to the upstream server with EHLO
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set how long to wait for the upstream server to respond
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep connections to the upstream server open and reuse them for later
+    /// messages, instead of closing them after each message
+    pub fn with_reuse(mut self, reuse: bool) -> Self {
+        self.reuse = reuse;
+        self
+    }
+
+    /// Require STARTTLS on connections to the upstream server, using the given
+    /// function to upgrade the connection. Messages are not relayed to an upstream
+    /// server that does not offer STARTTLS.
+    pub fn with_starttls<F>(mut self, connector: F) -> Self
+    where
+        F: Fn(TcpStream, &str) -> io::Result<Box<dyn Stream + Send>> + Send + Sync + 'static,
+    {
+        self.tls = Some(Arc::new(connector));
+        self
+    }
+
+    // The host name of the upstream server, without the port
+    fn host(&self) -> &str {
+        let host = self
+            .address
+            .rsplit_once(':')
+            .map_or(self.address.as_str(), |(host, _)| host);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    // Take an idle connection that still works or open a new one
+    fn connection(&self) -> io::Result<Upstream> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            match idle {
+                Some(mut upstream) => match upstream.command("RSET") {
+                    Ok(reply) if reply.is_positive() => return Ok(upstream),
+                    _ => debug!("Discarding stale connection to {}", self.address),
+                },
+                None => return self.connect(),
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Upstream> {
+        let tcp = TcpStream::connect(&self.address)?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        let mut upstream = Upstream::new(Box::new(tcp.try_clone()?));
+        upstream.expect(Upstream::reply, 220)?;
+        let ehlo = format!("EHLO {}", self.name);
+        let reply = upstream.expect(|u| u.command(&ehlo), 250)?;
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(upstream),
+        };
+        if !reply.has_extension("STARTTLS") {
+            return Err(io::Error::other("Upstream server does not offer STARTTLS"));
+        }
+        upstream.expect(|u| u.command("STARTTLS"), 220)?;
+        // Nothing is buffered, the server waits for the TLS handshake
+        drop(upstream);
+        let mut upstream = Upstream::new(tls(tcp, self.host())?);
+        upstream.expect(|u| u.command(&ehlo), 250)?;
+        Ok(upstream)
+    }
+
+    // Start a transaction on the upstream server. An Ok result with an error
+    // response is a rejection by the upstream server.
+    fn start(&mut self, from: &str, is8bit: bool, to: &[String]) -> io::Result<Response> {
+        let mut upstream = self.connection()?;
+        let body = if is8bit { " BODY=8BITMIME" } else { "" };
+        let mut commands = vec![format!("MAIL FROM:<{}>{}", from, body)];
+        commands.extend(to.iter().map(|rcpt| format!("RCPT TO:<{}>", rcpt)));
+        commands.push("DATA".to_string());
+        for command in commands {
+            let reply = upstream.command(&command)?;
+            if !reply.is_positive() {
+                debug!("Upstream rejected {}: {}", command, reply.text());
+                self.release(upstream);
+                return Ok(reply.response());
+            }
+        }
+        self.upstream = Some(upstream);
+        Ok(mailin::response::OK)
+    }
+
+    // The transaction on the upstream server is over, keep or close the connection
+    fn release(&self, mut upstream: Upstream) {
+        if self.reuse {
+            self.idle.lock().unwrap().push(upstream);
+        } else {
+            // The message has been handled, a failed QUIT changes nothing
+            let _ = upstream.command("QUIT");
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Response> {
+        let mut upstream = self
+            .upstream
+            .take()
+            .ok_or_else(|| io::Error::other("No upstream transaction"))?;
+        upstream.end_data()?;
+        let reply = upstream.reply()?;
+        self.release(upstream);
+        Ok(reply.response())
+    }
+}
+
+impl Clone for RelayData {
+    fn clone(&self) -> Self {
+        Self {
+            address: self.address.clone(),
+            name: self.name.clone(),
+            timeout: self.timeout,
+            reuse: self.reuse,
+            tls: self.tls.clone(),
+            idle: self.idle.clone(),
+            upstream: None,
+        }
+    }
+}
+
+impl Handler for RelayData {
+    fn data_start(&mut self, _domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        self.start(from, is8bit, to).unwrap_or_else(|err| {
+            error!("Cannot relay to {}: {}", self.address, err);
+            UPSTREAM_UNAVAILABLE
+        })
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        let upstream = self
+            .upstream
+            .as_mut()
+            .ok_or_else(|| io::Error::other("No upstream transaction"))?;
+        upstream.data_line(buf)
+    }
+
+    fn data_end(&mut self) -> Response {
+        self.finish().unwrap_or_else(|err| {
+            error!("Cannot relay to {}: {}", self.address, err);
+            UPSTREAM_UNAVAILABLE
+        })
+    }
+
+    fn data_end_error(&mut self, _reason: Reason) {
+        // A DATA command cannot be aborted, closing the connection discards the message
+        self.upstream = None;
+    }
+}
+
+// A connection to the upstream server
+struct Upstream {
+    stream: BufStream<Box<dyn Stream + Send>>,
+    // Message data not yet written, allocated once per connection
+    pending: Vec<u8>,
+}
+
+impl Upstream {
+    fn new(stream: Box<dyn Stream + Send>) -> Self {
+        Self {
+            stream: BufStream::new(stream),
+            pending: Vec::with_capacity(DATA_BUFFER_BYTES),
+        }
+    }
+
+    fn command(&mut self, command: &str) -> io::Result<Reply> {
+        self.stream.write_all(command.as_bytes())?;
+        self.stream.write_all(b"\r\n")?;
+        self.stream.flush()?;
+        self.reply()
+    }
+
+    // Write a line of the message. The session removed the dot stuffing, it is added
+    // back without copying the line. Lines are collected until one does not fit, which
+    // is written together with them in one vectored write.
+    fn data_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let stuffing: &[u8] = if line.starts_with(b".") { b"." } else { b"" };
+        if self.pending.len() + stuffing.len() + line.len() <= DATA_BUFFER_BYTES {
+            self.pending.extend_from_slice(stuffing);
+            self.pending.extend_from_slice(line);
+            return Ok(());
+        }
+        // Commands are flushed when sent, so only message data is written here
+        let mut bufs = [
+            IoSlice::new(&self.pending),
+            IoSlice::new(stuffing),
+            IoSlice::new(line),
+        ];
+        write_all_vectored(self.stream.get_mut(), &mut bufs)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Write the rest of the message and the end of data
+    fn end_data(&mut self) -> io::Result<()> {
+        let mut bufs = [IoSlice::new(&self.pending), IoSlice::new(b".\r\n")];
+        write_all_vectored(self.stream.get_mut(), &mut bufs)?;
+        self.pending.clear();
+        self.stream.get_mut().flush()
+    }
+
+    // Read a possibly multiline reply
+    fn reply(&mut self) -> io::Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.to_string()))?;
+            let more = line.as_bytes().get(3) == Some(&b'-');
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if !more {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+
+    // Read a reply that must have the given code
+    fn expect<F>(&mut self, read: F, code: u16) -> io::Result<Reply>
+    where
+        F: FnOnce(&mut Self) -> io::Result<Reply>,
+    {
+        let reply = read(self)?;
+        if reply.code == code {
+            Ok(reply)
+        } else {
+            Err(io::Error::other(format!(
+                "Unexpected reply {} {}",
+                reply.code,
+                reply.text()
+            )))
+        }
+    }
+}
+
+// Write::write_all_vectored() is not stable
+fn write_all_vectored(out: &mut dyn Write, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    // 2xx and 3xx replies let the transaction continue
+    fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+
+    fn text(&self) -> &str {
+        self.lines.first().map_or("", String::as_str)
+    }
+
+    fn has_extension(&self, keyword: &str) -> bool {
+        self.lines.iter().skip(1).any(|line| {
+            line.split_whitespace()
+                .next()
+                .is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+        })
+    }
+
+    // The response for the client
+    fn response(&self) -> Response {
+        match self.code {
+            // The upstream closing its connection does not close the client's
+            421 => UPSTREAM_UNAVAILABLE,
+            code => Response::custom(code, self.text().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    // An upstream server that accepts one connection, answers each message with the
+    // given replies in turn and returns everything it received
+    fn mock_upstream(replies: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut replies = replies.into_iter();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Vec::new();
+            let mut in_data = false;
+            writer.write_all(b"220 mock\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let reply = if in_data {
+                    in_data = line != ".\r\n";
+                    if in_data {
+                        None
+                    } else {
+                        replies.next()
+                    }
+                } else if line.starts_with("EHLO") {
+                    Some("250-mock\r\n250 8BITMIME")
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    Some("354 Go ahead")
+                } else if line.starts_with("QUIT") {
+                    Some("221 Bye")
+                } else {
+                    Some("250 OK")
+                };
+                let quit = line.starts_with("QUIT");
+                received.push(line);
+                if let Some(reply) = reply {
+                    writer
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .unwrap();
+                }
+                if quit {
+                    break;
+                }
+            }
+            received
+        });
+        (address, handle)
+    }
+
+    fn relay_message(relay: &mut RelayData) -> Response {
+        let to = vec!["fish@sea.com".to_string()];
+        let res = relay.data_start("a.domain", "ship@sea.com", false, &to);
+        if res.is_error {
+            return res;
+        }
+        relay.data(b"Subject: Relay\r\n").unwrap();
+        relay.data(b"\r\n").unwrap();
+        relay.data(b".dot\r\n").unwrap();
+        relay.data_end()
+    }
+
+    #[test]
+    fn relay_message_upstream() {
+        let (address, upstream) = mock_upstream(vec!["250 Queued as 1234"]);
+        let mut relay = RelayData::new(address);
+        let res = relay_message(&mut relay);
+        assert_eq!(res.code, 250);
+        assert_eq!(res.text(), "Queued as 1234");
+        let received = upstream.join().unwrap();
+        assert_eq!(
+            received,
+            vec![
+                "EHLO localhost\r\n",
+                "MAIL FROM:<ship@sea.com>\r\n",
+                "RCPT TO:<fish@sea.com>\r\n",
+                "DATA\r\n",
+                "Subject: Relay\r\n",
+                "\r\n",
+                "..dot\r\n",
+                ".\r\n",
+                "QUIT\r\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn reuse_after_rejection() {
+        let (address, upstream) = mock_upstream(vec!["554 Spam", "250 Queued"]);
+        let mut relay = RelayData::new(address)
+            .with_reuse(true)
+            .with_timeout(Duration::from_secs(5));
+        let res = relay_message(&mut relay);
+        assert_eq!(res.code, 554);
+        assert_eq!(res.text(), "Spam");
+        // A clone shares the idle connection
+        let mut clone = relay.clone();
+        assert_eq!(relay_message(&mut clone).code, 250);
+        drop(relay);
+        drop(clone);
+        let received = upstream.join().unwrap();
+        assert_eq!(received.iter().filter(|l| l.starts_with("EHLO")).count(), 1);
+        assert_eq!(received.iter().filter(|l| l.starts_with("RSET")).count(), 1);
+        assert_eq!(received.last().unwrap(), ".\r\n");
+    }
+
+    #[test]
+    fn upstream_unavailable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut relay = RelayData::new(address);
+        assert_eq!(relay_message(&mut relay), UPSTREAM_UNAVAILABLE);
+    }
+
+    // The address and length of each buffer of a write
+    type Buffers = Vec<(usize, usize)>;
+
+    // Records the buffers of each write, and the bytes
+    #[derive(Debug, Default, Clone)]
+    struct RecordingStream {
+        writes: Arc<Mutex<Vec<Buffers>>>,
+        bytes: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl io::Read for RecordingStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for RecordingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            let bufs: Vec<&IoSlice> = bufs.iter().filter(|buf| !buf.is_empty()).collect();
+            let write = bufs
+                .iter()
+                .map(|buf| (buf.as_ptr() as usize, buf.len()))
+                .collect();
+            self.writes.lock().unwrap().push(write);
+            let mut bytes = self.bytes.lock().unwrap();
+            for buf in &bufs {
+                bytes.extend_from_slice(buf);
+            }
+            Ok(bufs.iter().map(|buf| buf.len()).sum())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for RecordingStream {}
+
+    #[test]
+    fn large_lines_not_copied() {
+        let stream = RecordingStream::default();
+        let mut upstream = Upstream::new(Box::new(stream.clone()));
+        let mut large = vec![b'x'; 64 * 1024];
+        large[0] = b'.';
+        large.extend_from_slice(b"\r\n");
+        upstream.data_line(b"Subject: Large\r\n").unwrap();
+        upstream.data_line(b"\r\n").unwrap();
+        for _ in 0..4 {
+            upstream.data_line(&large).unwrap();
+        }
+        let writes = stream.writes.lock().unwrap();
+        // Each large line is written from the buffer of the caller after its restored
+        // dot, the small lines go with the first one
+        assert_eq!(writes.len(), 4);
+        assert_eq!(writes[0].len(), 3);
+        assert_eq!(writes[0][0].1, 18);
+        for write in writes.iter() {
+            assert_eq!(write[write.len() - 2].1, 1);
+            assert_eq!(
+                write[write.len() - 1],
+                (large.as_ptr() as usize, large.len())
+            );
+        }
+    }
+
+    #[test]
+    fn lines_batched() {
+        let stream = RecordingStream::default();
+        let mut upstream = Upstream::new(Box::new(stream.clone()));
+        let capacity = upstream.pending.capacity();
+        let mut line = vec![b'x'; 98];
+        line.extend_from_slice(b"\r\n");
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            line[0] = if i % 10 == 0 { b'.' } else { b'x' };
+            upstream.data_line(&line).unwrap();
+            if i % 10 == 0 {
+                expected.push(b'.');
+            }
+            expected.extend_from_slice(&line);
+        }
+        upstream.end_data().unwrap();
+        expected.extend_from_slice(b".\r\n");
+        assert_eq!(*stream.bytes.lock().unwrap(), expected);
+        // One write per buffer full instead of one per line, and the buffer is not
+        // reallocated
+        let writes = stream.writes.lock().unwrap();
+        assert_eq!(writes.len(), expected.len().div_ceil(DATA_BUFFER_BYTES));
+        assert_eq!(upstream.pending.capacity(), capacity);
+    }
+}
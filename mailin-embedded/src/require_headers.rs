@@ -0,0 +1,194 @@
+use log::debug;
+use mailin::{Handler, Reason, Response};
+use mime_event::{Event, EventParser};
+use std::io;
+use std::io::Write;
+
+const MISSING_HEADER: Response = Response::fixed(550, "Missing required header");
+
+/// A [`Handler`] that rejects messages without the given header fields, such as the
+/// `From` and `Date` fields required by RFC 5322.
+///
+/// Only the header of the message itself is checked, not the headers of MIME parts.
+/// Header names are case insensitive. When a field is missing the client gets a 550
+/// response and the wrapped handler is told with [`Handler::data_end_error()`].
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Handler, RequireHeaders, Server};
+///
+/// #[derive(Clone)]
+/// struct MyHandler {}
+/// impl Handler for MyHandler {}
+///
+/// let handler = RequireHeaders::new(MyHandler {}, &["From", "Date"]);
+/// let server = Server::new(handler);
+/// ```
+pub struct RequireHeaders<H: Handler> {
+    inner: H,
+    required: Vec<String>,
+    // Parses the message during DATA
+    parser: Option<EventParser<io::Sink, SeenHeaders>>,
+}
+
+impl<H: Handler> RequireHeaders<H> {
+    /// Wrap a handler so that messages must contain the given header fields
+    pub fn new(inner: H, required: &[&str]) -> Self {
+        Self {
+            inner,
+            required: required.iter().map(|name| name.to_string()).collect(),
+            parser: None,
+        }
+    }
+
+    // The names of the required headers that were not seen
+    fn missing(&mut self) -> Vec<&str> {
+        let seen = match self.parser.take() {
+            Some(parser) => parser.end(),
+            None => SeenHeaders::default(),
+        };
+        self.required
+            .iter()
+            .filter(|name| !seen.contains(name))
+            .map(|name| name.as_str())
+            .collect()
+    }
+}
+
+impl<H: Handler + Clone> Clone for RequireHeaders<H> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            required: self.required.clone(),
+            parser: None,
+        }
+    }
+}
+
+impl<H: Handler> Handler for RequireHeaders<H> {
+    fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        self.parser = Some(EventParser::new(io::sink(), SeenHeaders::default()));
+        self.inner.data_start(domain, from, is8bit, to)
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(parser) = &mut self.parser {
+            // A header that cannot be parsed does not count as seen
+            if let Err(err) = parser.write_all(buf) {
+                debug!("Cannot parse message header: {}", err);
+            }
+        }
+        self.inner.data(buf)
+    }
+
+    fn data_end(&mut self) -> Response {
+        let missing = self.missing();
+        if missing.is_empty() {
+            self.inner.data_end()
+        } else {
+            debug!("Message without required headers: {}", missing.join(", "));
+            self.inner.data_end_error(Reason::Processing);
+            MISSING_HEADER
+        }
+    }
+
+    fn data_end_error(&mut self, reason: Reason) {
+        self.parser = None;
+        self.inner.data_end_error(reason)
+    }
+
+    forward_handler!(
+        inner: helo, mail, rcpt, data_progress, data_digest, on_error, rset, auth_plain, auth_login,
+        allow_starttls, mt_priority, body_type, secure, authenticated, deliver_by, etrn,
+        auth_challenge, response_delay, on_wire
+    );
+}
+
+// Collects the names of the fields in the message header
+#[derive(Default)]
+struct SeenHeaders {
+    names: Vec<Vec<u8>>,
+    in_body: bool,
+}
+
+impl SeenHeaders {
+    fn contains(&self, name: &str) -> bool {
+        self.names
+            .iter()
+            .any(|seen| seen.eq_ignore_ascii_case(name.as_bytes()))
+    }
+}
+
+impl mime_event::Handler for SeenHeaders {
+    fn event(&mut self, ev: Event) {
+        match ev {
+            Event::Header(header) if !self.in_body => {
+                if let Some(name) = header.name() {
+                    self.names.push(name.to_vec());
+                }
+            }
+            Event::BodyStart { .. } | Event::MultipartStart(_) => self.in_body = true,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin::response::OK;
+
+    #[derive(Default)]
+    struct DeliveryHandler {
+        delivered: bool,
+        error: Option<Reason>,
+    }
+
+    impl Handler for DeliveryHandler {
+        fn data_end(&mut self) -> Response {
+            self.delivered = true;
+            OK
+        }
+
+        fn data_end_error(&mut self, reason: Reason) {
+            self.error = Some(reason);
+        }
+    }
+
+    fn deliver(message: &[u8]) -> (Response, DeliveryHandler) {
+        let mut handler = RequireHeaders::new(DeliveryHandler::default(), &["From", "Date"]);
+        handler.data_start("a.domain", "ship@sea.com", false, &[]);
+        for line in message.split_inclusive(|c| *c == b'\n') {
+            handler.data(line).unwrap();
+        }
+        let res = handler.data_end();
+        (res, handler.inner)
+    }
+
+    #[test]
+    fn missing_date() {
+        let (res, inner) = deliver(
+            b"FROM: ship@sea.com\r\n\
+            Subject: No date\r\n\
+            \r\n\
+            Date: in the body does not count\r\n",
+        );
+        assert_eq!(res.code, 550);
+        assert!(!inner.delivered);
+        assert_eq!(inner.error, Some(Reason::Processing));
+    }
+
+    #[test]
+    fn required_headers_present() {
+        let (res, inner) = deliver(
+            b"from: ship@sea.com\r\n\
+            DATE: Mon, 22 Mar 1994 13:34:51 +0000\r\n\
+            Subject: Complete\r\n\
+            \r\n\
+            Hello\r\n",
+        );
+        assert_eq!(res.code, 250);
+        assert!(inner.delivered);
+        assert_eq!(inner.error, None);
+    }
+}
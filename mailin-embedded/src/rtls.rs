@@ -1,10 +1,15 @@
-use crate::ssl::SslConfig;
+use crate::ssl::{SslConfig, TicketKeys};
 use crate::{Error, Stream};
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use rustls::crypto::aws_lc_rs::Ticketer;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::ProducesTickets;
 use rustls::{Error as TLSError, ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
 use std::fs;
-use std::io::BufReader;
+use std::io::{self, BufRead, BufReader};
 use std::sync::Arc;
+use std::time::Duration;
 
 // Rustls wrapper
 #[derive(Clone)]
@@ -12,7 +17,11 @@ pub struct SslImpl {
     tls_config: Arc<ServerConfig>,
 }
 
-impl<S: Stream> Stream for StreamOwned<ServerConnection, S> {}
+impl<S: Stream> Stream for StreamOwned<ServerConnection, S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
 
 impl From<TLSError> for Error {
     fn from(error: TLSError) -> Self {
@@ -49,12 +58,36 @@ impl SslImpl {
                     .with_single_cert(certs, key)?;
                 Some(config)
             }
-            _ => None,
+            SslConfig::Pem { cert, key, chain } => {
+                let mut certs = parse_certs(&cert)?;
+                if let Some(chain) = chain {
+                    certs.append(&mut parse_certs(&chain)?);
+                }
+                let key = parse_key(&key)?;
+                let config = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, key)?;
+                Some(config)
+            }
+            SslConfig::None => None,
         };
-        let ret = config.map(|c| SslImpl {
-            tls_config: Arc::new(c),
-        });
-        Ok(ret)
+        let Some(mut config) = config else {
+            return Ok(None);
+        };
+        // Session tickets let clients that reconnect often skip the full handshake. The
+        // ticket keys are shared by all connections and, unless given, rotated by rustls.
+        config.ticketer = Ticketer::new()?;
+        Ok(Some(SslImpl {
+            tls_config: Arc::new(config),
+        }))
+    }
+
+    // Encrypt session tickets with keys given by the application
+    pub fn set_ticket_keys(&mut self, keys: &TicketKeys) -> Result<(), Error> {
+        let mut config = ServerConfig::clone(&self.tls_config);
+        config.ticketer = Arc::new(KeyTicketer::new(keys)?);
+        self.tls_config = Arc::new(config);
+        Ok(())
     }
 
     pub fn accept<S: Stream>(&self, stream: S) -> Result<impl Stream, Error> {
@@ -64,17 +97,98 @@ impl SslImpl {
     }
 }
 
+// Encrypts session tickets with keys from the application, a ticket is the nonce
+// followed by the AES-256-GCM ciphertext
+struct KeyTicketer {
+    // The current key first
+    keys: Vec<LessSafeKey>,
+    lifetime: u32,
+}
+
+impl KeyTicketer {
+    fn new(ticket_keys: &TicketKeys) -> Result<Self, Error> {
+        let keys = std::iter::once(&ticket_keys.current)
+            .chain(&ticket_keys.old)
+            .map(|key| UnboundKey::new(&AES_256_GCM, key).map(LessSafeKey::new))
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::new("Bad ticket key"))?;
+        let lifetime = ticket_keys.lifetime.as_secs();
+        Ok(Self {
+            keys,
+            lifetime: u32::try_from(lifetime).unwrap_or(u32::MAX),
+        })
+    }
+}
+
+impl fmt::Debug for KeyTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyTicketer")
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProducesTickets for KeyTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        aws_lc_rs::rand::fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        self.keys[0]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .ok()?;
+        let mut ticket = nonce.to_vec();
+        ticket.append(&mut sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, sealed) = cipher.split_at_checked(NONCE_LEN)?;
+        self.keys.iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut buf = sealed.to_vec();
+            let plain = key.open_in_place(nonce, Aad::empty(), &mut buf).ok()?;
+            Some(plain.to_vec())
+        })
+    }
+}
+
 fn load_certs(filename: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
     let certfile = fs::File::open(filename)?;
-    let mut reader = BufReader::new(certfile);
+    read_certs(&mut BufReader::new(certfile))
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    read_certs(&mut BufReader::new(pem))
+}
+
+fn read_certs(mut reader: &mut dyn BufRead) -> Result<Vec<CertificateDer<'static>>, Error> {
     let ret: Result<Vec<_>, _> = rustls_pemfile::certs(&mut reader).collect();
     ret.map_err(|_| Error::new("Unparseable certificates"))
 }
 
 fn load_key(filename: &str) -> Result<PrivateKeyDer<'static>, Error> {
-    // Prefer to load pkcs8 keys
     let keyfile = fs::File::open(filename)?;
-    let mut reader = BufReader::new(keyfile);
+    read_key(&mut BufReader::new(keyfile))
+}
+
+fn parse_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, Error> {
+    read_key(&mut BufReader::new(pem))
+}
+
+fn read_key(mut reader: &mut dyn BufRead) -> Result<PrivateKeyDer<'static>, Error> {
+    // Prefer to load pkcs8 keys
     let Some(key) = rustls_pemfile::private_key(&mut reader)
         .map_err(|_| Error::new("Unparseable PKCS8 key"))?
     else {
@@ -82,3 +196,307 @@ fn load_key(filename: &str) -> Result<PrivateKeyDer<'static>, Error> {
     };
     Ok(key)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockServer, Server};
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use rustls::pki_types::{ServerName, UnixTime};
+    use rustls::{
+        ClientConfig, ClientConnection, Connection, DigitallySignedStruct, HandshakeKind,
+        SignatureScheme,
+    };
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    // Trusts any certificate, the test certificate is self-signed
+    #[derive(Debug)]
+    struct AnyCert(CryptoProvider);
+
+    impl ServerCertVerifier for AnyCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, TLSError> {
+            verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, TLSError> {
+            verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    #[test]
+    fn pem_in_memory() {
+        let ssl_config = SslConfig::Pem {
+            cert: include_bytes!("../testdata/cert.pem").to_vec(),
+            key: include_bytes!("../testdata/key.pem").to_vec(),
+            chain: None,
+        };
+        let ssl = SslImpl::setup(ssl_config).unwrap();
+        assert!(ssl.is_some());
+    }
+
+    #[test]
+    fn pem_bad_key() {
+        let ssl_config = SslConfig::Pem {
+            cert: include_bytes!("../testdata/cert.pem").to_vec(),
+            key: b"not a key".to_vec(),
+            chain: None,
+        };
+        assert!(SslImpl::setup(ssl_config).is_err());
+    }
+
+    // Send the pending TLS records from one side to the other, returns false if none
+    fn transfer(from: &mut Connection, to: &mut Connection) -> bool {
+        let mut records = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut records).unwrap();
+        }
+        if records.is_empty() {
+            return false;
+        }
+        to.read_tls(&mut records.as_slice()).unwrap();
+        to.process_new_packets().unwrap();
+        true
+    }
+
+    // Exchange TLS records until neither side has more to send
+    fn complete_handshake(client: &mut Connection, server: &mut Connection) {
+        while transfer(client, server) | transfer(server, client) {}
+    }
+
+    fn connect(ssl: &SslImpl, client_config: &Arc<ClientConfig>) -> Option<HandshakeKind> {
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client =
+            Connection::from(ClientConnection::new(client_config.clone(), name).unwrap());
+        let mut server = Connection::from(ServerConnection::new(ssl.tls_config.clone()).unwrap());
+        complete_handshake(&mut client, &mut server);
+        client.handshake_kind()
+    }
+
+    #[test]
+    fn session_resumption() {
+        let ssl_config = SslConfig::Pem {
+            cert: include_bytes!("../testdata/cert.pem").to_vec(),
+            key: include_bytes!("../testdata/key.pem").to_vec(),
+            chain: None,
+        };
+        let ssl = SslImpl::setup(ssl_config).unwrap().unwrap();
+        assert!(ssl.tls_config.ticketer.enabled());
+        let verifier = Arc::new(AnyCert(rustls::crypto::aws_lc_rs::default_provider()));
+        for version in [&rustls::version::TLS12, &rustls::version::TLS13] {
+            let client_config = ClientConfig::builder_with_protocol_versions(&[version])
+                .dangerous()
+                .with_custom_certificate_verifier(verifier.clone())
+                .with_no_client_auth();
+            let client_config = Arc::new(client_config);
+            assert_eq!(connect(&ssl, &client_config), Some(HandshakeKind::Full));
+            // The same client reconnecting, e.g to a clone of the server
+            let ssl = ssl.clone();
+            assert_eq!(connect(&ssl, &client_config), Some(HandshakeKind::Resumed));
+        }
+    }
+
+    #[test]
+    fn shared_ticket_keys() {
+        let setup = |keys: TicketKeys| {
+            let mut ssl = SslImpl::setup(test_ssl_config()).unwrap().unwrap();
+            ssl.set_ticket_keys(&keys).unwrap();
+            ssl
+        };
+        let first = setup(TicketKeys::new([1; 32]));
+        let client_config = client_config();
+        assert_eq!(connect(&first, &client_config), Some(HandshakeKind::Full));
+        // Separate servers with the same key resume each other's sessions
+        let second = setup(TicketKeys::new([1; 32]));
+        assert_eq!(
+            connect(&second, &client_config),
+            Some(HandshakeKind::Resumed)
+        );
+        // Including after the key has been rotated
+        let rotated = setup(TicketKeys::new([2; 32]).with_old_key([1; 32]));
+        assert_eq!(
+            connect(&rotated, &client_config),
+            Some(HandshakeKind::Resumed)
+        );
+        let other = setup(TicketKeys::new([3; 32]));
+        assert_eq!(connect(&other, &client_config), Some(HandshakeKind::Full));
+    }
+
+    fn test_ssl_config() -> SslConfig {
+        SslConfig::Pem {
+            cert: include_bytes!("../testdata/cert.pem").to_vec(),
+            key: include_bytes!("../testdata/key.pem").to_vec(),
+            chain: None,
+        }
+    }
+
+    fn client_config() -> Arc<ClientConfig> {
+        let verifier = Arc::new(AnyCert(rustls::crypto::aws_lc_rs::default_provider()));
+        let client_config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        Arc::new(client_config)
+    }
+
+    // Run a TLS enabled server for one connection, returns the client side
+    fn start_server(mock: &MockServer, ssl_config: SslConfig) -> (TcpStream, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(mock.clone());
+        server.with_ssl(ssl_config).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, remote) = listener.accept().unwrap();
+            _ = server.execute(stream, remote.ip());
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    // Read replies until one starts with the given text
+    fn read_until(reader: &mut impl BufRead, start: &str) -> String {
+        let mut replies = String::new();
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0, "{replies}");
+            replies.push_str(&line);
+            if line.starts_with(start) {
+                return replies;
+            }
+        }
+    }
+
+    fn tls_client(
+        stream: TcpStream,
+        client_config: Arc<ClientConfig>,
+        name: &'static str,
+    ) -> BufReader<StreamOwned<ClientConnection, TcpStream>> {
+        let name = ServerName::try_from(name).unwrap();
+        let client = ClientConnection::new(client_config, name).unwrap();
+        BufReader::new(StreamOwned::new(client, stream))
+    }
+
+    #[test]
+    fn starttls_discards_plaintext_pipeline() {
+        let mock = MockServer::new();
+        let (mut stream, server) = start_server(&mock, test_ssl_config());
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        read_until(&mut reader, "220 ");
+        // Commands injected after STARTTLS, e.g by a man in the middle
+        stream
+            .write_all(b"EHLO a.domain\r\nSTARTTLS\r\nMAIL FROM:<evil@sea.com>\r\n")
+            .unwrap();
+        read_until(&mut reader, "250 ");
+        read_until(&mut reader, "220 Ready to start TLS");
+        let mut tls = tls_client(stream, client_config(), "localhost");
+        // The first reply over TLS is for the first command sent over TLS
+        tls.get_mut()
+            .write_all(b"EHLO b.domain\r\nQUIT\r\n")
+            .unwrap();
+        let replies = read_until(&mut tls, "221 ");
+        assert!(replies.starts_with("250-"), "{replies}");
+        assert_eq!(replies.lines().filter(|l| l.starts_with("250 ")).count(), 1);
+        server.join().unwrap();
+        assert_eq!(mock.transcript(), ["HELO a.domain", "HELO b.domain"]);
+    }
+
+    #[test]
+    fn starttls_pipeline_after_handshake() {
+        let mock = MockServer::new();
+        let (mut stream, server) = start_server(&mock, test_ssl_config());
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        read_until(&mut reader, "220 ");
+        stream.write_all(b"EHLO a.domain\r\nSTARTTLS\r\n").unwrap();
+        read_until(&mut reader, "220 Ready to start TLS");
+        let mut tls = tls_client(stream, client_config(), "localhost");
+        // Sent with the end of the handshake, before any reply over TLS
+        tls.get_mut()
+            .write_all(
+                b"EHLO b.domain\r\nMAIL FROM:<ship@sea.com>\r\nRCPT TO:<fish@sea.com>\r\nQUIT\r\n",
+            )
+            .unwrap();
+        let replies = read_until(&mut tls, "221 ");
+        assert!(replies.starts_with("250-"), "{replies}");
+        assert!(!replies.contains("STARTTLS"), "{replies}");
+        assert!(
+            replies.ends_with("250 OK\r\n250 OK\r\n221 Goodbye\r\n"),
+            "{replies}"
+        );
+        server.join().unwrap();
+        assert_eq!(
+            mock.transcript(),
+            [
+                "HELO a.domain",
+                "HELO b.domain",
+                "MAIL ship@sea.com",
+                "RCPT fish@sea.com"
+            ]
+        );
+    }
+
+    #[cfg(feature = "self-signed")]
+    #[test]
+    fn generated_self_signed() {
+        let ssl_config = SslConfig::generate_self_signed(["localhost", "mx.example.com"]).unwrap();
+        // Trust the generated certificate, which is verified against the server name
+        let mut roots = rustls::RootCertStore::empty();
+        if let SslConfig::Pem { cert, .. } = &ssl_config {
+            for cert in parse_certs(cert).unwrap() {
+                roots.add(cert).unwrap();
+            }
+        }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mock = MockServer::new();
+        let (mut stream, server) = start_server(&mock, ssl_config);
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        read_until(&mut reader, "220 ");
+        stream.write_all(b"EHLO a.domain\r\nSTARTTLS\r\n").unwrap();
+        read_until(&mut reader, "220 Ready to start TLS");
+        let mut tls = tls_client(stream, Arc::new(client_config), "mx.example.com");
+        tls.get_mut()
+            .write_all(b"EHLO b.domain\r\nQUIT\r\n")
+            .unwrap();
+        let replies = read_until(&mut tls, "221 ");
+        assert!(replies.starts_with("250-"), "{replies}");
+        server.join().unwrap();
+        assert_eq!(mock.transcript(), ["HELO a.domain", "HELO b.domain"]);
+    }
+}
@@ -1,4 +1,9 @@
+use crate::blocklist::BlocklistPolicy;
 use crate::err::Error;
+use crate::fcrdns::{FcrdnsPolicy, Verdict};
+use crate::limit::{LoadShedder, SubnetGuard, SubnetLimiter};
+use crate::localize::{localize, ResponseTable};
+use crate::metrics::{Counters, MetricsHandle, MetricsHandler, PhaseTracker};
 cfg_if::cfg_if! {
     if #[cfg(feature = "ossl")] {
         use crate::ossl::SslImpl;
@@ -6,17 +11,31 @@ cfg_if::cfg_if! {
         use crate::rtls::SslImpl;
     }
 }
+use crate::shutdown::ShutdownHandle;
 use crate::stream::Stream;
-use crate::Server;
+use crate::{Config, Server};
 use bufstream_fresh::BufStream;
 use log::{debug, error, info};
-use mailin::{Action, Handler, Response, Session, SessionBuilder};
+use mailin::response::{
+    BLOCKLISTED_CLIENT, DATA_TIMEOUT, LINE_TOO_LONG, NO_SERVICE, UNCONFIRMED_REVERSE_DNS,
+};
+use mailin::{Action, ConnInfo, Direction, Handler, Phase, Response, Session, SessionBuilder};
 use scoped_threadpool::Pool;
-use std::io::{BufRead, Write};
-use std::net::{IpAddr, TcpListener, TcpStream};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::any::Any;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
 const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+// Refusals are written by the accept loop, which must not wait long for a client
+const PAUSED_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PAUSED: Response = Response::fixed(421, "Service temporarily unavailable");
+const HANDLER_PANICKED: Response = Response::fixed(421, "Internal error, closing connection");
 
 enum SessionResult {
     Finished,
@@ -32,69 +51,214 @@ where
     session_builder: SessionBuilder,
     ssl: Option<SslImpl>,
     num_threads: u32,
+    shutdown: ShutdownHandle,
+    shared: Shared,
 }
 
-pub(crate) fn serve<H>(config: Server<H>) -> Result<(), Error>
-where
-    H: Handler + Clone + Send,
-{
+// Server resources shared by all connections
+struct Shared {
+    subnet_limit: Option<SubnetLimiter>,
+    load_shedding: Option<LoadShedder>,
+    fcrdns: Option<Arc<FcrdnsPolicy>>,
+    blocklist: Option<Arc<BlocklistPolicy>>,
+    responses: Option<Arc<dyn ResponseTable>>,
+    max_line_bytes: usize,
+    data_timeout: Option<Duration>,
+    canonical_ip: bool,
+    requires_auth: bool,
+    metrics: MetricsHandle,
+}
+
+impl Shared {
+    fn new(config: &mut Config, metrics: MetricsHandle) -> Self {
+        Self {
+            subnet_limit: config.subnet_limit.take(),
+            load_shedding: config.load_shedding.take(),
+            fcrdns: config.fcrdns.take(),
+            blocklist: config.blocklist.take(),
+            responses: config.responses.take(),
+            max_line_bytes: config.max_line_bytes,
+            data_timeout: config.data_timeout,
+            canonical_ip: config.canonical_ip,
+            requires_auth: !config.auth.is_empty(),
+            metrics,
+        }
+    }
+
+    // The address of a client as seen by the rest of the server
+    fn remote_ip(&self, ip: IpAddr) -> IpAddr {
+        if self.canonical_ip {
+            ip.to_canonical()
+        } else {
+            ip
+        }
+    }
+}
+
+// Bind to the first of the addresses that works
+pub(crate) fn bind(addrs: &[SocketAddr]) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in addrs {
+        match bind_addr(*addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to listen on")))
+}
+
+// IPv6 sockets accept IPv4 connections as well, whatever the system default
+fn bind_addr(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        if let Err(err) = socket.set_only_v6(false) {
+            debug!("Cannot listen dual-stack on {}: {}", addr, err);
+        }
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+// The session options of a server
+fn session_builder(config: &Config, start_tls: bool) -> SessionBuilder {
     let mut session_builder = SessionBuilder::new(config.name.clone());
-    if config.ssl.is_some() {
+    if start_tls {
         session_builder.enable_start_tls();
     }
     for auth in &config.auth {
-        session_builder
-            .enable_auth(auth.clone())
-            .insecure_enable_plaintext_auth();
+        session_builder.enable_auth(auth.clone());
+    }
+    if let Some(max_message_size) = config.max_message_size {
+        session_builder.max_message_size(max_message_size);
+    }
+    if let Some(max_data_bytes) = config.max_data_bytes {
+        session_builder.max_data_bytes(max_data_bytes);
+    }
+    if let Some(interval) = config.data_progress_interval {
+        session_builder.data_progress_interval(interval);
+    }
+    if let Some(max_errors) = config.max_errors {
+        session_builder.max_errors(max_errors);
+    }
+    if let Some(max_auth_mechanisms) = config.max_auth_mechanisms {
+        session_builder.max_auth_mechanisms(max_auth_mechanisms);
+    }
+    if config.etrn {
+        session_builder.enable_etrn();
+    }
+    if config.mt_priority {
+        session_builder.enable_mt_priority();
+    }
+    if let Some(min_seconds) = config.deliver_by {
+        session_builder.enable_deliver_by(min_seconds);
+    }
+    #[cfg(feature = "digest")]
+    if config.data_digest {
+        session_builder.enable_data_digest();
+    }
+    if config.restrict_null_sender {
+        session_builder.restrict_null_sender();
+    }
+    if config.require_fqdn_helo {
+        session_builder.require_fqdn_helo();
+    }
+    if config.lenient_line_endings {
+        session_builder.lenient_line_endings(true);
+    }
+    if let Some(text) = &config.helo_rejection {
+        session_builder.helo_rejection(text.as_str());
+    }
+    if let Some(normalization) = config.normalize_recipients {
+        session_builder.normalize_recipients(normalization);
+    }
+    for extension in &config.extensions {
+        session_builder.enable_extension(extension.clone());
+    }
+    for command in &config.disabled_commands {
+        session_builder.disable_command(*command);
+    }
+    if let Some(banner) = config.banner.clone() {
+        session_builder.banner(move |info: &ConnInfo| banner(info));
+    }
+    if config.echo_commands {
+        session_builder.enable_command_echo();
+    }
+    if config.echo_auth_user {
+        session_builder.enable_auth_user_echo();
+    }
+    session_builder
+}
+
+pub(crate) fn serve<H>(server: Server<H>) -> Result<(), Error>
+where
+    H: Handler + Clone + Send,
+{
+    let Server {
+        handler,
+        ssl,
+        mut config,
+        metrics,
+        shutdown,
+    } = server;
+    let shared = Shared::new(&mut config, metrics);
+    let mut session_builder = session_builder(&config, ssl.is_some());
+    // Unlike execute(), serve() has always offered AUTH before STARTTLS
+    if !config.auth.is_empty() {
+        session_builder.insecure_enable_plaintext_auth();
     }
     let listen = if let Some(listener) = config.tcp_listener {
         listener
     } else {
         let addr = config.socket_address;
-        TcpListener::bind(&addr[..])
-            .map_err(|err| Error::with_source("Cannot open listen address", err))?
+        bind(&addr).map_err(|err| Error::with_source("Cannot open listen address", err))?
     };
-    if let Some(max_message_size) = config.max_message_size {
-        session_builder.max_message_size(max_message_size);
-    }
     let server_state = ServerState {
         listener: listen,
-        handler: config.handler,
+        handler,
         session_builder,
-        ssl: config.ssl,
+        ssl,
         num_threads: config.num_threads,
+        shutdown,
+        shared,
     };
     run(&config.name, &server_state)
 }
 
 pub(crate) fn execute<H, S: Stream>(
-    config: Server<H>,
-    stream: S,
+    server: Server<H>,
+    mut stream: S,
     remote: IpAddr,
 ) -> Result<(), Error>
 where
     H: Handler,
 {
-    let mut session_builder = SessionBuilder::new(config.name.clone());
-    if config.ssl.is_some() {
-        session_builder.enable_start_tls();
-    }
-    for auth in &config.auth {
-        session_builder.enable_auth(auth.clone());
-    }
-    if let Some(max_message_size) = config.max_message_size {
-        session_builder.max_message_size(max_message_size);
-    }
+    let Server {
+        handler,
+        ssl,
+        mut config,
+        metrics,
+        ..
+    } = server;
+    let shared = Shared::new(&mut config, metrics);
+    let session_builder = session_builder(&config, ssl.is_some());
     info!("{} SMTP running", &config.name);
+    let remote = shared.remote_ip(remote);
 
+    Counters::incr(&shared.metrics.counters().connections, 1);
+    let Ok(_subnet_slot) = acquire_subnet_slot(&shared, remote, &mut stream) else {
+        return Ok(());
+    };
+    if !is_fcrdns_confirmed(&shared, remote, &mut stream)
+        || is_blocklisted(&shared, remote, &mut stream)
+    {
+        return Ok(());
+    }
     let bufstream = BufStream::new(stream);
-    if let Err(err) = start_session(
-        &session_builder,
-        remote,
-        bufstream,
-        config.ssl,
-        config.handler,
-    ) {
+    if let Err(err) = run_session(&session_builder, remote, bufstream, ssl, handler, &shared) {
         debug!("Cannot start session: {}", err);
     }
 
@@ -107,16 +271,28 @@ where
 {
     let mut pool = Pool::new(server_state.num_threads);
     let localaddr = server_state.listener.local_addr()?;
+    let shutdown = &server_state.shutdown;
+    shutdown.listening(localaddr);
+    if shutdown.is_shutdown() {
+        return Ok(());
+    }
     info!("{} SMTP started on {}", name, localaddr);
+    // Leaving the scope waits for the sessions in progress
     pool.scoped(|scoped| {
         for conn in server_state.listener.incoming() {
+            if shutdown.is_shutdown() {
+                info!("{} SMTP shutting down", name);
+                break;
+            }
             match conn {
+                Ok(stream) if shutdown.is_paused() => refuse_paused(stream, &server_state.shared),
                 Ok(stream) => {
                     let builder = server_state.session_builder.clone();
                     let acceptor = server_state.ssl.clone();
                     let handler_clone = server_state.handler.clone();
+                    let shared = &server_state.shared;
                     scoped.execute(move || {
-                        handle_connection(stream, &builder, acceptor, handler_clone)
+                        handle_connection(stream, &builder, acceptor, handler_clone, shared)
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
@@ -126,35 +302,87 @@ where
     Ok(())
 }
 
-fn handle_session<H, S>(session: &mut Session<H>, stream: &mut S) -> Result<SessionResult, Error>
+fn handle_session<H, S>(
+    session: &mut Session<H>,
+    stream: &mut BufStream<S>,
+    phase: &mut PhaseTracker,
+    shared: &Shared,
+) -> Result<SessionResult, Error>
 where
-    S: BufRead + Write,
+    S: Stream,
     H: Handler,
 {
     let mut line = Vec::with_capacity(80);
+    let mut in_data = false;
     loop {
         line.clear();
-        let num_bytes = stream
-            .read_until(b'\n', &mut line)
-            .inspect_err(|_| session.io_error())?;
+        let read =
+            Read::take(&mut *stream, shared.max_line_bytes as u64).read_until(b'\n', &mut line);
+        let num_bytes = match read {
+            Err(e) if in_data && is_timeout(&e) => {
+                session.io_error();
+                send_response(session, stream, &DATA_TIMEOUT, shared)?;
+                return Error::bail("Timeout during DATA");
+            }
+            read => read.inspect_err(|_| session.io_error())?,
+        };
         if num_bytes == 0 {
             break;
         }
-        let res = session.process(&line);
+        if num_bytes == shared.max_line_bytes && !line.ends_with(b"\n") {
+            Counters::incr(&shared.metrics.counters().bytes_received, num_bytes as u64);
+            session.io_error();
+            send_response(session, stream, &LINE_TOO_LONG, shared)?;
+            return Error::bail("Line too long");
+        }
+        Counters::incr(&shared.metrics.counters().bytes_received, num_bytes as u64);
+        session.on_wire(Direction::Received, &line);
+        // A panic in the handler ends this connection only, the session cannot be used
+        // after it so the handler is not called again
+        let res = match shed_load(session, &line, shared) {
+            Some(res) => res.clone(),
+            None => match panic::catch_unwind(AssertUnwindSafe(|| session.process(&line))) {
+                Ok(res) => res,
+                Err(panic) => {
+                    error!("Handler panicked: {}", panic_message(&*panic));
+                    write_response(
+                        stream,
+                        &localize(shared.responses.as_deref(), &HANDLER_PANICKED),
+                    )?;
+                    return Error::bail("Handler panicked");
+                }
+            },
+        };
+        phase.set(session.phase());
+        if res.action != Action::NoReply {
+            if let Some(delay) = session.response_delay(&res) {
+                thread::sleep(delay);
+            }
+            // 354 is only sent to start DATA, the next reply ends it
+            let starts_data = res.code == 354;
+            if let Some(data_timeout) = shared.data_timeout {
+                if starts_data != in_data {
+                    in_data = starts_data;
+                    let timeout = if in_data { data_timeout } else { FIVE_MINUTES };
+                    stream.get_ref().set_read_timeout(Some(timeout)).ok();
+                }
+            }
+        }
         match res.action {
             Action::Reply => {
-                write_response(stream, &res).inspect_err(|_| session.io_error())?;
+                send_response(session, stream, &res, shared).inspect_err(|_| session.io_error())?;
             }
             Action::Close => {
-                write_response(stream, &res).inspect_err(|_| session.io_error())?;
+                send_response(session, stream, &res, shared).inspect_err(|_| session.io_error())?;
                 if res.is_error {
                     return Error::bail("SMTP error");
                 } else {
                     return Ok(SessionResult::Finished);
                 }
             }
+            Action::Drop => return Error::bail("Connection dropped"),
             Action::UpgradeTls => {
-                write_response(stream, &res).inspect_err(|_| session.io_error())?;
+                send_response(session, stream, &res, shared).inspect_err(|_| session.io_error())?;
                 return Ok(SessionResult::UpgradeTls);
             }
             Action::NoReply => (),
@@ -164,6 +392,53 @@ where
     Error::bail("Unexpected Eof")
 }
 
+// While the server is overloaded, MAIL gets the load shedding response instead of
+// starting a transaction. A client that still has to authenticate gets the usual error.
+fn shed_load<'a, H: Handler>(
+    session: &Session<H>,
+    line: &[u8],
+    shared: &'a Shared,
+) -> Option<&'a Response> {
+    let is_mail = line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"mail ");
+    let can_start = !shared.requires_auth || session.is_authenticated();
+    if !is_mail || !can_start || session.phase() != Phase::Greeted {
+        return None;
+    }
+    shared.load_shedding.as_ref().and_then(LoadShedder::check)
+}
+
+// The message given to panic!(), if it is a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+// Did a read fail because the read timeout expired?
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// Write a response to the client, letting the handler see the bytes on the wire
+fn send_response<H: Handler>(
+    session: &mut Session<H>,
+    writer: &mut dyn Write,
+    res: &Response,
+    shared: &Shared,
+) -> Result<(), Error> {
+    let buf = localize(shared.responses.as_deref(), res).buffer()?;
+    session.on_wire(Direction::Sent, &buf);
+    writer.write_all(&buf)?;
+    writer
+        .flush()
+        .map_err(|e| Error::with_source("Cannot write response", e))
+}
+
 fn write_response(mut writer: &mut dyn Write, res: &Response) -> Result<(), Error> {
     res.write_to(&mut writer)?;
     writer
@@ -180,43 +455,674 @@ fn upgrade_tls<S: Stream>(stream: S, ssl: Option<SslImpl>) -> Result<impl Stream
     }
 }
 
+// Run a session, a panic in the handler outside of Session::process(), e.g in
+// response_delay() or the banner, closes the connection without a reply
+fn run_session<H: Handler, S: Stream>(
+    session_builder: &SessionBuilder,
+    remote: IpAddr,
+    stream: BufStream<S>,
+    ssl: Option<SslImpl>,
+    handler: H,
+    shared: &Shared,
+) -> Result<(), Error> {
+    let session =
+        AssertUnwindSafe(|| start_session(session_builder, remote, stream, ssl, handler, shared));
+    panic::catch_unwind(session).unwrap_or_else(|panic| {
+        error!("({}) Handler panicked: {}", remote, panic_message(&*panic));
+        Error::bail("Handler panicked")
+    })
+}
+
 fn start_session<H: Handler, S: Stream>(
     session_builder: &SessionBuilder,
     remote: IpAddr,
     mut stream: BufStream<S>,
     ssl: Option<SslImpl>,
     handler: H,
+    shared: &Shared,
 ) -> Result<(), Error> {
+    let _load_guard = shared.load_shedding.as_ref().map(LoadShedder::enter);
+    let handler = MetricsHandler::new(handler, shared.metrics.clone());
     let mut session = session_builder.build(remote, handler);
-    write_response(&mut stream, &session.greeting())?;
-    let res = handle_session(&mut session, &mut stream)?;
+    let mut phase = PhaseTracker::new(shared.metrics.clone());
+    let greeting = session.greeting();
+    send_response(&mut session, &mut stream, &greeting, shared)?;
+    let res = handle_session(&mut session, &mut stream, &mut phase, shared)?;
     if let SessionResult::UpgradeTls = res {
+        // Commands pipelined after STARTTLS are still in the read buffer, they were sent
+        // in plaintext and are lost here (RFC 3207 section 4.2). Reading continues from
+        // the TLS stream only, which still sees commands pipelined after the handshake.
         let inner_stream = stream
             .into_inner()
             .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?;
         let tls = upgrade_tls(inner_stream, ssl)?;
         session.tls_active();
         let mut buf_tls = BufStream::new(tls);
-        handle_session(&mut session, &mut buf_tls)?;
+        handle_session(&mut session, &mut buf_tls, &mut phase, shared)?;
     }
     Ok(())
 }
 
+// Refuse a connection while the server is paused
+fn refuse_paused(mut stream: TcpStream, shared: &Shared) {
+    debug!("Connection refused while paused");
+    stream.set_write_timeout(Some(PAUSED_WRITE_TIMEOUT)).ok();
+    write_response(&mut stream, &localize(shared.responses.as_deref(), &PAUSED)).ok();
+}
+
+// Reserve a slot in the subnet of the remote address, refusing the connection if
+// the subnet limit has been reached
+fn acquire_subnet_slot<S: Write>(
+    shared: &Shared,
+    remote: IpAddr,
+    stream: &mut S,
+) -> Result<Option<SubnetGuard>, ()> {
+    let subnet_limit = shared.subnet_limit.as_ref();
+    match subnet_limit.map(|limiter| limiter.acquire(remote)) {
+        Some(None) => {
+            debug!("({}) Subnet connection limit reached", remote);
+            write_response(stream, &localize(shared.responses.as_deref(), &NO_SERVICE)).ok();
+            Err(())
+        }
+        slot => Ok(slot.flatten()),
+    }
+}
+
+// Check the remote address against the FCrDNS policy, if there is one
+fn is_fcrdns_confirmed<S: Write>(shared: &Shared, remote: IpAddr, stream: &mut S) -> bool {
+    match shared.fcrdns.as_ref().map(|policy| policy.check(remote)) {
+        Some(Verdict::Reject) => {
+            debug!("({}) Reverse DNS not confirmed", remote);
+            let responses = shared.responses.as_deref();
+            write_response(stream, &localize(responses, &UNCONFIRMED_REVERSE_DNS)).ok();
+            false
+        }
+        _ => true,
+    }
+}
+
+// Check the remote address against the blocklist policy, if there is one
+fn is_blocklisted<S: Write>(shared: &Shared, remote: IpAddr, stream: &mut S) -> bool {
+    match shared.blocklist.as_ref().map(|policy| policy.check(remote)) {
+        Some(Verdict::Reject) => {
+            debug!("({}) Address on blocklist", remote);
+            let responses = shared.responses.as_deref();
+            write_response(stream, &localize(responses, &BLOCKLISTED_CLIENT)).ok();
+            true
+        }
+        _ => false,
+    }
+}
+
 fn handle_connection<H: Handler>(
-    stream: TcpStream,
+    mut stream: TcpStream,
     session_builder: &SessionBuilder,
     ssl: Option<SslImpl>,
     handler: H,
+    shared: &Shared,
 ) {
+    Counters::incr(&shared.metrics.counters().connections, 1);
     let remote = stream
         .peer_addr()
-        .map(|saddr| saddr.ip())
+        .map(|saddr| shared.remote_ip(saddr.ip()))
         .unwrap_or_else(|_| "0.0.0.0".parse().unwrap());
     debug!("New connection from {}", remote);
     stream.set_read_timeout(Some(FIVE_MINUTES)).ok();
     stream.set_write_timeout(Some(FIVE_MINUTES)).ok();
+    let Ok(_subnet_slot) = acquire_subnet_slot(shared, remote, &mut stream) else {
+        return;
+    };
+    if !is_fcrdns_confirmed(shared, remote, &mut stream)
+        || is_blocklisted(shared, remote, &mut stream)
+    {
+        return;
+    }
     let bufstream = BufStream::new(stream);
-    if let Err(err) = start_session(session_builder, remote, bufstream, ssl, handler) {
+    if let Err(err) = run_session(session_builder, remote, bufstream, ssl, handler, shared) {
         debug!("({}) Cannot start session: {}", remote, err);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::MemoryStream;
+    use crate::{AuthMechanism, Resolver};
+    use mailin::response::{DROP_CONNECTION, NO_MAILBOX, OK};
+    use std::io::BufReader;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    // Handler that delays every response once a recipient was rejected, and records the
+    // codes of the delayed responses
+    #[derive(Clone, Default)]
+    struct TarpitHandler {
+        flagged: bool,
+        delayed: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl Handler for TarpitHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            if to == "fish@sea.com" {
+                OK
+            } else {
+                self.flagged = true;
+                NO_MAILBOX
+            }
+        }
+
+        fn response_delay(&self, response: &Response) -> Option<Duration> {
+            if self.flagged {
+                self.delayed.lock().unwrap().push(response.code);
+            }
+            self.flagged.then_some(DELAY)
+        }
+    }
+
+    struct French;
+
+    impl ResponseTable for French {
+        fn text(&self, response: &Response) -> Option<String> {
+            match response.code {
+                220 => Some("bienvenue".to_string()),
+                221 => Some("au revoir".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn localized_responses() {
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_responses(French);
+        let (stream, output) = MemoryStream::new(b"helo a.domain\r\nquit\r\n");
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        server.execute(stream, ip).unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            "220 bienvenue\r\n250 OK\r\n221 au revoir\r\n"
+        );
+    }
+
+    // Drops clients that say HELO with a known scanner name
+    #[derive(Clone)]
+    struct DropHandler;
+
+    impl Handler for DropHandler {
+        fn helo(&mut self, _ip: IpAddr, domain: &str) -> Response {
+            if domain == "scanner" {
+                DROP_CONNECTION
+            } else {
+                OK
+            }
+        }
+    }
+
+    #[test]
+    fn drop_connection() {
+        let server = Server::new(DropHandler);
+        let (stream, output) = MemoryStream::new(b"helo scanner\r\nquit\r\n");
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        server.execute(stream, ip).unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output), "220 localhost ESMTP\r\n");
+    }
+
+    #[test]
+    fn dot_at_eof() {
+        let input = b"helo a.domain\r\n\
+            mail from:<ship@sea.com>\r\n\
+            rcpt to:<fish@sea.com>\r\n\
+            data\r\n\
+            Hello\r\n.";
+        for lenient in [false, true] {
+            let (stream, output) = MemoryStream::new(input);
+            let mut server = Server::new(TarpitHandler::default());
+            server.with_lenient_line_endings(lenient);
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+            server.execute(stream, ip).unwrap();
+            let output = output.lock().unwrap();
+            let delivered = String::from_utf8_lossy(&output)
+                .ends_with("354 Start mail input; end with <CRLF>.<CRLF>\r\n250 OK\r\n");
+            assert_eq!(delivered, lenient);
+        }
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut input = b"helo a.domain\r\n".to_vec();
+        input.extend(std::iter::repeat_n(b'x', 2000));
+        let (stream, output) = MemoryStream::new(&input);
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_max_line_bytes(1000);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        server.execute(stream, ip).unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            "220 localhost ESMTP\r\n250 OK\r\n500 Line too long, closing connection\r\n"
+        );
+    }
+
+    #[test]
+    fn subnet_limit() {
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_subnet_limit(24, 64, 1);
+        let limiter = server.config.subnet_limit.clone().unwrap();
+        let _active = limiter.acquire(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let (stream, output) = MemoryStream::new(b"helo a.domain\r\n");
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 200));
+        server.execute(stream, ip).unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            "421 Service not available, closing connection\r\n"
+        );
+    }
+
+    #[test]
+    fn load_shedding() {
+        const TEMPFAIL: Response = Response::fixed(451, "Busy, try again later");
+        fn run_session(shedder: &LoadShedder) -> String {
+            let mut server = Server::new(TarpitHandler::default());
+            server.config.load_shedding = Some(shedder.clone());
+            let session = b"helo a.domain\r\nmail from:<ship@sea.com>\r\nnoop\r\nquit\r\n";
+            let (stream, output) = MemoryStream::new(session);
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+            server.execute(stream, ip).unwrap();
+            let output = output.lock().unwrap();
+            String::from_utf8_lossy(&output).into_owned()
+        }
+
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_load_shedding(1, TEMPFAIL);
+        let shedder = server.config.load_shedding.unwrap();
+        assert_eq!(
+            run_session(&shedder),
+            "220 localhost ESMTP\r\n250 OK\r\n250 OK\r\n250 OK\r\n221 Goodbye\r\n"
+        );
+        // Another connection is active, the session itself makes it two
+        let active = shedder.enter();
+        assert_eq!(
+            run_session(&shedder),
+            "220 localhost ESMTP\r\n250 OK\r\n451 Busy, try again later\r\n250 OK\r\n221 Goodbye\r\n"
+        );
+        drop(active);
+        assert!(run_session(&shedder).contains("250 OK\r\n250 OK\r\n250 OK"));
+    }
+
+    #[test]
+    fn load_shedding_reply() {
+        const TEMPFAIL: Response = Response::fixed(451, "Busy, try again later");
+        let session = b"ehlo a.domain\r\nmail from:<ship@sea.com>\r\nquit\r\n";
+        for auth in [false, true] {
+            let delayed = Arc::new(Mutex::new(Vec::new()));
+            let handler = TarpitHandler {
+                flagged: true,
+                delayed: delayed.clone(),
+            };
+            let mut server = Server::new(handler);
+            server.with_load_shedding(0, TEMPFAIL);
+            if auth {
+                server.with_auth(AuthMechanism::Plain);
+            }
+            let (stream, _) = MemoryStream::new(session);
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+            server.execute(stream, ip).unwrap();
+            // The shed reply is delayed like any other, and not sent before AUTH
+            let codes = delayed.lock().unwrap().clone();
+            let mail_code = if auth { 503 } else { 451 };
+            assert_eq!(codes, vec![250, mail_code, 221]);
+        }
+    }
+
+    #[test]
+    fn data_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, remote) = listener.accept().unwrap();
+            let mut server = Server::new(TarpitHandler::default());
+            server.with_data_timeout(Duration::from_millis(200));
+            server.execute(stream, remote.ip()).unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(FIVE_MINUTES)).unwrap();
+        client
+            .write_all(b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<fish@sea.com>\r\ndata\r\nSubject: stalled\r\n")
+            .unwrap();
+        // Stall without sending the rest of the message
+        let start = Instant::now();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(output.contains("354 "), "{output}");
+        assert!(
+            output.ends_with("451 Timeout waiting for data, closing connection\r\n"),
+            "{output}"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_tcp_listener(listener);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve().unwrap());
+        let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert!(line.starts_with("220 "), "{line}");
+        client.get_mut().write_all(b"helo a.domain\r\n").unwrap();
+        line.clear();
+        client.read_line(&mut line).unwrap();
+        shutdown.shutdown();
+        assert!(shutdown.is_shutdown());
+        // The session in progress is allowed to finish
+        client
+            .get_mut()
+            .write_all(b"mail from:<ship@sea.com>\r\nquit\r\n")
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "250 OK\r\n221 Goodbye\r\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pause() {
+        fn greeting(addr: SocketAddr) -> String {
+            let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            line
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(TarpitHandler::default());
+        server.with_tcp_listener(listener);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve().unwrap());
+        assert!(!handle.is_paused());
+        assert_eq!(greeting(addr), "220 localhost ESMTP\r\n");
+        handle.pause();
+        assert!(handle.is_paused());
+        assert_eq!(greeting(addr), "421 Service temporarily unavailable\r\n");
+        handle.resume();
+        assert!(!handle.is_paused());
+        assert_eq!(greeting(addr), "220 localhost ESMTP\r\n");
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    // Panics when a recipient would crash it
+    #[derive(Clone)]
+    struct PanicHandler;
+
+    impl Handler for PanicHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            assert_ne!(to, "crash@sea.com", "recipient not handled");
+            OK
+        }
+    }
+
+    // Panics when asked to delay a response
+    #[derive(Clone)]
+    struct PanicDelayHandler;
+
+    impl Handler for PanicDelayHandler {
+        fn response_delay(&self, response: &Response) -> Option<Duration> {
+            assert_ne!(response.code, 250, "delay not handled");
+            None
+        }
+    }
+
+    #[test]
+    fn handler_panic_outside_process() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(PanicDelayHandler);
+        server.with_tcp_listener(listener).with_num_threads(1);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve().unwrap());
+        for _ in 0..2 {
+            let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+            client.get_mut().write_all(b"helo a.domain\r\n").unwrap();
+            let mut replies = String::new();
+            while client.read_line(&mut replies).unwrap() > 0 {}
+            // The connection is closed, the only worker thread is still running
+            assert_eq!(replies, "220 localhost ESMTP\r\n");
+        }
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn handler_panic() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(PanicHandler);
+        server.with_tcp_listener(listener);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve().unwrap());
+        let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+        let session = b"helo a.domain\r\nmail from:<ship@sea.com>\r\nrcpt to:<crash@sea.com>\r\n";
+        client.get_mut().write_all(session).unwrap();
+        let mut replies = String::new();
+        while client.read_line(&mut replies).unwrap() > 0 {}
+        assert_eq!(
+            replies,
+            "220 localhost ESMTP\r\n250 OK\r\n250 OK\r\n421 Internal error, closing connection\r\n"
+        );
+        // Other connections are not affected
+        let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut greeting = String::new();
+        client.read_line(&mut greeting).unwrap();
+        assert_eq!(greeting, "220 localhost ESMTP\r\n");
+        drop(client);
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    // Records the address of each client that says HELO
+    #[derive(Clone, Default)]
+    struct HeloIpHandler {
+        ips: Arc<Mutex<Vec<IpAddr>>>,
+    }
+
+    impl Handler for HeloIpHandler {
+        fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+            self.ips.lock().unwrap().push(ip);
+            OK
+        }
+    }
+
+    // Connect to a dual-stack listener over IPv4 and then over IPv6
+    fn dual_stack_ips(canonical_ip: bool) -> Vec<IpAddr> {
+        let listener = bind(&["[::]:0".parse().unwrap()]).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handler = HeloIpHandler::default();
+        let ips = handler.ips.clone();
+        let mut server = Server::new(handler);
+        server.with_tcp_listener(listener);
+        if canonical_ip {
+            server.with_canonical_ip();
+        }
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.serve().unwrap());
+        for client_addr in [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()] {
+            let mut client = TcpStream::connect(SocketAddr::new(client_addr, port)).unwrap();
+            client.write_all(b"helo a.domain\r\nquit\r\n").unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            assert!(output.ends_with("221 Goodbye\r\n"), "{output}");
+        }
+        shutdown.shutdown();
+        server.join().unwrap();
+        let ips = ips.lock().unwrap();
+        ips.clone()
+    }
+
+    #[test]
+    fn ipv6() {
+        let mapped: IpAddr = "::ffff:127.0.0.1".parse().unwrap();
+        assert_eq!(
+            dual_stack_ips(false),
+            vec![mapped, Ipv6Addr::LOCALHOST.into()]
+        );
+        assert_eq!(
+            dual_stack_ips(true),
+            vec![
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                Ipv6Addr::LOCALHOST.into()
+            ]
+        );
+    }
+
+    // Resolver where every address has a PTR record that resolves to 192.0.2.1
+    struct OneHostResolver;
+
+    impl Resolver for OneHostResolver {
+        fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+            Ok(Some("mail.example.com".to_string()))
+        }
+
+        fn forward(&self, _name: &str) -> io::Result<Vec<IpAddr>> {
+            Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))])
+        }
+    }
+
+    #[test]
+    fn fcrdns() {
+        let run = |ip| {
+            let mut server = Server::new(TarpitHandler::default());
+            server.with_fcrdns(FcrdnsPolicy::new(OneHostResolver));
+            let (stream, output) = MemoryStream::new(b"quit\r\n");
+            server.execute(stream, IpAddr::V4(ip)).unwrap();
+            let output = output.lock().unwrap();
+            String::from_utf8_lossy(&output).into_owned()
+        };
+        let output = run(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(output.starts_with("220 "), "{output}");
+        let output = run(Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(
+            output,
+            "554 Reverse DNS not confirmed, closing connection\r\n"
+        );
+    }
+
+    // Resolver for a blocklist that lists 192.0.2.99
+    struct BlocklistResolver;
+
+    impl Resolver for BlocklistResolver {
+        fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+            match name {
+                "99.2.0.192.bl.example.org" => Ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))]),
+                _ => Ok(vec![]),
+            }
+        }
+    }
+
+    #[test]
+    fn blocklist() {
+        let run = |ip| {
+            let mut server = Server::new(TarpitHandler::default());
+            server.with_blocklist(BlocklistPolicy::new(BlocklistResolver, ["bl.example.org"]));
+            let (stream, output) = MemoryStream::new(b"quit\r\n");
+            server.execute(stream, IpAddr::V4(ip)).unwrap();
+            let output = output.lock().unwrap();
+            String::from_utf8_lossy(&output).into_owned()
+        };
+        let output = run(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(output.starts_with("220 "), "{output}");
+        let output = run(Ipv4Addr::new(192, 0, 2, 99));
+        assert_eq!(
+            output,
+            "554 Client address is on a blocklist, closing connection\r\n"
+        );
+    }
+
+    type Wire = Vec<(Direction, Vec<u8>)>;
+
+    // Handler that records the bytes on the wire
+    #[derive(Default)]
+    struct WireTapHandler {
+        wire: Arc<Mutex<Wire>>,
+    }
+
+    impl Handler for WireTapHandler {
+        fn on_wire(&mut self, direction: Direction, bytes: &[u8]) {
+            self.wire.lock().unwrap().push((direction, bytes.to_vec()));
+        }
+    }
+
+    #[test]
+    fn wire_tap() {
+        let handler = WireTapHandler::default();
+        let wire = handler.wire.clone();
+        let (stream, output) = MemoryStream::new(b"helo a.domain\r\nquit\r\n");
+        let server = Server::new(handler);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        server.execute(stream, ip).unwrap();
+        let wire = wire.lock().unwrap();
+        let expected = vec![
+            (Direction::Sent, b"220 localhost ESMTP\r\n".to_vec()),
+            (Direction::Received, b"helo a.domain\r\n".to_vec()),
+            (Direction::Sent, b"250 OK\r\n".to_vec()),
+            (Direction::Received, b"quit\r\n".to_vec()),
+            (Direction::Sent, b"221 Goodbye\r\n".to_vec()),
+        ];
+        assert_eq!(*wire, expected);
+        let sent: Vec<u8> = wire
+            .iter()
+            .filter(|(direction, _)| *direction == Direction::Sent)
+            .flat_map(|(_, bytes)| bytes.clone())
+            .collect();
+        assert_eq!(*output.lock().unwrap(), sent);
+    }
+
+    // Returns the codes of the delayed responses and the duration of the session
+    fn run_session(input: &[u8]) -> (Vec<u16>, Duration) {
+        let (stream, _output) = MemoryStream::new(input);
+        let handler = TarpitHandler::default();
+        let delayed = handler.delayed.clone();
+        let server = Server::new(handler);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let start = Instant::now();
+        server.execute(stream, ip).unwrap();
+        let elapsed = start.elapsed();
+        let delayed = delayed.lock().unwrap().clone();
+        (delayed, elapsed)
+    }
+
+    #[test]
+    fn response_delay() {
+        let (delayed, _) = run_session(
+            b"helo a.domain\r\n\
+            mail from:<ship@sea.com>\r\n\
+            rcpt to:<fish@sea.com>\r\n\
+            quit\r\n",
+        );
+        assert!(delayed.is_empty());
+        // The rejected recipient and the quit are delayed
+        let (delayed, elapsed) = run_session(
+            b"helo a.domain\r\n\
+            mail from:<ship@sea.com>\r\n\
+            rcpt to:<kraken@sea.com>\r\n\
+            quit\r\n",
+        );
+        assert_eq!(delayed, vec![NO_MAILBOX.code, 221]);
+        assert!(elapsed >= DELAY * 2);
+    }
+}
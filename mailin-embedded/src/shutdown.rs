@@ -0,0 +1,82 @@
+use log::debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Stops a running server, see [`crate::Server::shutdown_handle()`].
+///
+/// After [`ShutdownHandle::shutdown()`] the server stops accepting connections. Sessions
+/// that are in progress run until the client quits or times out, then
+/// [`crate::Server::serve()`] returns.
+///
+/// For brief maintenance the server can be paused instead. While paused, new connections
+/// get a 421 response and are closed, sessions in progress are not affected.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    paused: AtomicBool,
+    // The address the server is listening on, once it has started
+    listen_addr: Mutex<Option<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to shut down. Can be called from any thread, more than once.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        let listen_addr = *self
+            .inner
+            .listen_addr
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Wake up the accept loop with a connection of our own
+        if let Some(addr) = listen_addr.map(connectable) {
+            if let Err(err) = TcpStream::connect(addr) {
+                debug!("Cannot wake up listener on {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Has a shutdown been requested?
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Refuse new connections until [`ShutdownHandle::resume()`] is called
+    pub fn pause(&self) {
+        self.inner.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Accept new connections again after [`ShutdownHandle::pause()`]
+    pub fn resume(&self) {
+        self.inner.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Is the server refusing new connections?
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn listening(&self, addr: SocketAddr) {
+        let mut listen_addr = self
+            .inner
+            .listen_addr
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *listen_addr = Some(addr);
+    }
+}
+
+// A wildcard listen address cannot be connected to on all platforms, use loopback instead
+fn connectable(mut addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        _ => (),
+    }
+    addr
+}
@@ -0,0 +1,591 @@
+use crate::fcrdns::Resolver;
+use log::debug;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+// The number of mechanisms and modifiers that cause DNS lookups, RFC 7208 section 4.6.4
+const MAX_LOOKUPS: usize = 10;
+// The number of lookups that may find no records, RFC 7208 section 4.6.4
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// The result of an SPF check, as defined in RFC 7208 section 2.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    /// The domain has no SPF record
+    None,
+    /// The domain makes no assertion about the client
+    Neutral,
+    /// The client is authorized to send mail for the domain
+    Pass,
+    /// The client is not authorized to send mail for the domain
+    Fail,
+    /// The client is probably not authorized to send mail for the domain
+    SoftFail,
+    /// A DNS lookup failed, a later retry may succeed
+    TempError,
+    /// The SPF record of the domain cannot be interpreted
+    PermError,
+}
+
+impl SpfResult {
+    /// The name of the result as used in the `Authentication-Results` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Neutral => "neutral",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+            Self::SoftFail => "softfail",
+            Self::TempError => "temperror",
+            Self::PermError => "permerror",
+        }
+    }
+
+    /// Format an `Authentication-Results` header (RFC 8601) for the result, where
+    /// `authserv_id` is the name of this server and `domain` is the checked domain.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin_embedded::SpfResult;
+    ///
+    /// let header = SpfResult::Pass.authentication_results("mx.example.org", "example.com");
+    /// assert_eq!(
+    ///     header,
+    ///     "Authentication-Results: mx.example.org; spf=pass smtp.mailfrom=example.com\r\n"
+    /// );
+    /// ```
+    pub fn authentication_results(&self, authserv_id: &str, domain: &str) -> String {
+        format!(
+            "Authentication-Results: {}; spf={} smtp.mailfrom={}\r\n",
+            authserv_id,
+            self.as_str(),
+            domain
+        )
+    }
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Why an SPF evaluation stopped early
+enum Failure {
+    Temp(io::Error),
+    Perm(String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Temp(err)
+    }
+}
+
+fn perm<T, S: Into<String>>(msg: S) -> Result<T, Failure> {
+    Err(Failure::Perm(msg.into()))
+}
+
+/// Checks whether a client is authorized to send mail for a domain with SPF (RFC 7208).
+///
+/// Call [`SpfChecker::check()`] from [`Handler::mail()`] with the address of the client
+/// and the domain of the MAIL FROM address, or the HELO domain for a null sender. The
+/// `all`, `include`, `a`, `mx`, `ptr`, `ip4`, `ip6` and `exists` mechanisms and the
+/// `redirect` modifier are supported. Macros are not supported, a record that uses
+/// them gives [`SpfResult::PermError`].
+///
+/// The checker uses [`Resolver::txt()`] and [`Resolver::mx()`]. A record that needs more
+/// than 10 DNS lookups, or more than 2 lookups that find no records, gives
+/// [`SpfResult::PermError`].
+///
+/// [`Handler::mail()`]: crate::Handler::mail
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Resolver, SpfChecker, SpfResult};
+/// use std::io;
+/// use std::net::IpAddr;
+///
+/// struct Dns;
+/// impl Resolver for Dns {
+///     fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+///         Ok(None)
+///     }
+///     fn forward(&self, _name: &str) -> io::Result<Vec<IpAddr>> {
+///         Ok(Vec::new())
+///     }
+///     fn txt(&self, _name: &str) -> io::Result<Vec<String>> {
+///         Ok(vec!["v=spf1 ip4:192.0.2.0/24 -all".to_string()])
+///     }
+/// }
+///
+/// let spf = SpfChecker::new(Dns);
+/// assert_eq!(spf.check("192.0.2.1".parse().unwrap(), "example.com"), SpfResult::Pass);
+/// assert_eq!(spf.check("198.51.100.1".parse().unwrap(), "example.com"), SpfResult::Fail);
+/// ```
+pub struct SpfChecker {
+    resolver: Box<dyn Resolver>,
+}
+
+impl SpfChecker {
+    /// Create a checker that uses the given resolver
+    pub fn new<R: Resolver + 'static>(resolver: R) -> Self {
+        Self {
+            resolver: Box::new(resolver),
+        }
+    }
+
+    /// Check whether the client at the given address may send mail for the domain
+    pub fn check(&self, ip: IpAddr, domain: &str) -> SpfResult {
+        let mut lookups = Lookups::default();
+        match self.check_host(ip, domain, &mut lookups) {
+            Ok(res) => res,
+            Err(Failure::Temp(err)) => {
+                debug!("({}) SPF lookup for {} failed: {}", ip, domain, err);
+                SpfResult::TempError
+            }
+            Err(Failure::Perm(msg)) => {
+                debug!("({}) SPF record of {} is invalid: {}", ip, domain, msg);
+                SpfResult::PermError
+            }
+        }
+    }
+
+    // The check_host() function of RFC 7208 section 4
+    fn check_host(
+        &self,
+        ip: IpAddr,
+        domain: &str,
+        lookups: &mut Lookups,
+    ) -> Result<SpfResult, Failure> {
+        let records: Vec<String> = not_found_empty(self.resolver.txt(domain))?
+            .into_iter()
+            .filter(|txt| is_spf(txt))
+            .collect();
+        let record = match records.as_slice() {
+            [] => return Ok(SpfResult::None),
+            [record] => record,
+            _ => return perm("multiple SPF records"),
+        };
+        let mut redirect = None;
+        for term in record.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = modifier(term) {
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return perm("multiple redirect modifiers");
+                    }
+                    redirect = Some(value);
+                }
+                continue;
+            }
+            let (qualifier, mechanism) = qualifier(term);
+            if self.matches(ip, domain, mechanism, lookups)? {
+                return Ok(qualifier);
+            }
+        }
+        match redirect {
+            Some(target) => {
+                lookups.count()?;
+                match self.check_host(ip, domain_spec(target)?, lookups)? {
+                    SpfResult::None => perm(format!("redirect to {} without SPF", target)),
+                    res => Ok(res),
+                }
+            }
+            None => Ok(SpfResult::Neutral),
+        }
+    }
+
+    fn matches(
+        &self,
+        ip: IpAddr,
+        domain: &str,
+        mechanism: &str,
+        lookups: &mut Lookups,
+    ) -> Result<bool, Failure> {
+        let (name, target, cidr) = split_mechanism(mechanism);
+        match name.to_ascii_lowercase().as_str() {
+            "all" if target.is_none() && cidr.is_none() => Ok(true),
+            "ip4" | "ip6" => {
+                let Some(target) = target else {
+                    return perm(format!("{} without an address", name));
+                };
+                let net: IpAddr = target
+                    .parse()
+                    .or_else(|_| perm(format!("bad address {}", target)))?;
+                let len = network_length(net, cidr)?;
+                let v6 = name.eq_ignore_ascii_case("ip6");
+                if v6 != net.is_ipv6() {
+                    return perm(format!("{} with address {}", name, net));
+                }
+                Ok(in_network(ip, net, len))
+            }
+            "a" => {
+                lookups.count()?;
+                let target = target.map_or(Ok(domain), domain_spec)?;
+                let (v4_len, v6_len) = dual_cidr(cidr)?;
+                self.address_matches(ip, target, v4_len, v6_len, lookups)
+            }
+            "mx" => {
+                lookups.count()?;
+                let target = target.map_or(Ok(domain), domain_spec)?;
+                let (v4_len, v6_len) = dual_cidr(cidr)?;
+                let hosts = lookups.void(not_found_empty(self.resolver.mx(target))?)?;
+                if hosts.len() > MAX_LOOKUPS {
+                    return perm(format!("too many MX records for {}", target));
+                }
+                for host in hosts {
+                    if self.address_matches(ip, &host, v4_len, v6_len, lookups)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "ptr" if cidr.is_none() => {
+                lookups.count()?;
+                let target = target.map_or(Ok(domain), domain_spec)?;
+                let Some(name) = self.resolver.reverse(ip)? else {
+                    lookups.void(Vec::<String>::new())?;
+                    return Ok(false);
+                };
+                let name = name.trim_end_matches('.');
+                if !is_subdomain(name, target) {
+                    return Ok(false);
+                }
+                Ok(not_found_empty(self.resolver.forward(name))?.contains(&ip))
+            }
+            "include" if cidr.is_none() => {
+                lookups.count()?;
+                let Some(target) = target else {
+                    return perm("include without a domain");
+                };
+                match self.check_host(ip, domain_spec(target)?, lookups)? {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    _ => perm(format!("include of {} without SPF", target)),
+                }
+            }
+            "exists" if cidr.is_none() => {
+                lookups.count()?;
+                let Some(target) = target else {
+                    return perm("exists without a domain");
+                };
+                let addrs = not_found_empty(self.resolver.forward(domain_spec(target)?))?;
+                Ok(!lookups.void(addrs)?.is_empty())
+            }
+            _ => perm(format!("unknown mechanism {}", mechanism)),
+        }
+    }
+
+    // Does the domain resolve to an address in the same network as the client?
+    fn address_matches(
+        &self,
+        ip: IpAddr,
+        domain: &str,
+        v4_len: u8,
+        v6_len: u8,
+        lookups: &mut Lookups,
+    ) -> Result<bool, Failure> {
+        let addrs = not_found_empty(self.resolver.forward(domain))?;
+        let addrs = lookups.void(addrs)?;
+        let len = if ip.is_ipv4() { v4_len } else { v6_len };
+        Ok(addrs.into_iter().any(|addr| in_network(ip, addr, len)))
+    }
+}
+
+// A domain that does not exist has no records
+fn not_found_empty<T>(res: io::Result<Vec<T>>) -> io::Result<Vec<T>> {
+    match res {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        res => res,
+    }
+}
+
+fn is_spf(txt: &str) -> bool {
+    let version = txt.split(' ').next().unwrap_or_default();
+    version.eq_ignore_ascii_case("v=spf1")
+}
+
+// Counts the DNS lookups of one check
+#[derive(Default)]
+struct Lookups {
+    terms: usize,
+    voids: usize,
+}
+
+impl Lookups {
+    // Counts a mechanism or modifier that does a DNS lookup
+    fn count(&mut self) -> Result<(), Failure> {
+        self.terms += 1;
+        if self.terms > MAX_LOOKUPS {
+            perm("too many DNS lookups")
+        } else {
+            Ok(())
+        }
+    }
+
+    // Counts a lookup that found no records
+    fn void<T>(&mut self, records: Vec<T>) -> Result<Vec<T>, Failure> {
+        if records.is_empty() {
+            self.voids += 1;
+            if self.voids > MAX_VOID_LOOKUPS {
+                return perm("too many void DNS lookups");
+            }
+        }
+        Ok(records)
+    }
+}
+
+// Splits a modifier, name=value, into its parts
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    is_name.then_some((name, value))
+}
+
+fn qualifier(term: &str) -> (SpfResult, &str) {
+    match term.as_bytes().first() {
+        Some(b'+') => (SpfResult::Pass, &term[1..]),
+        Some(b'-') => (SpfResult::Fail, &term[1..]),
+        Some(b'~') => (SpfResult::SoftFail, &term[1..]),
+        Some(b'?') => (SpfResult::Neutral, &term[1..]),
+        _ => (SpfResult::Pass, term),
+    }
+}
+
+// Splits a mechanism into its name, the argument after ':' and the CIDR lengths after '/'
+fn split_mechanism(mechanism: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (name, rest) = match mechanism.find([':', '/']) {
+        Some(i) => mechanism.split_at(i),
+        None => return (mechanism, None, None),
+    };
+    let Some(arg) = rest.strip_prefix(':') else {
+        return (name, None, Some(rest));
+    };
+    // An ip6 argument contains ':', so only the last '/' starts the CIDR length
+    let split = if name.eq_ignore_ascii_case("ip6") || name.eq_ignore_ascii_case("ip4") {
+        arg.rfind('/')
+    } else {
+        arg.find('/')
+    };
+    match split {
+        Some(i) => (name, Some(&arg[..i]), Some(&arg[i..])),
+        None => (name, Some(arg), None),
+    }
+}
+
+fn domain_spec(domain: &str) -> Result<&str, Failure> {
+    if domain.contains('%') {
+        perm(format!("macros are not supported: {}", domain))
+    } else if domain.is_empty() {
+        perm("empty domain")
+    } else {
+        Ok(domain)
+    }
+}
+
+fn is_subdomain(name: &str, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    match name.len().checked_sub(domain.len()) {
+        Some(0) => name.eq_ignore_ascii_case(domain),
+        Some(i) => name.as_bytes()[i - 1] == b'.' && name[i..].eq_ignore_ascii_case(domain),
+        None => false,
+    }
+}
+
+fn parse_length(len: &str, max: u8) -> Result<u8, Failure> {
+    // Leading zeros are not allowed
+    match len.parse::<u8>() {
+        Ok(n) if n <= max && (len == "0" || !len.starts_with(['0', '+'])) => Ok(n),
+        _ => perm(format!("bad CIDR length {}", len)),
+    }
+}
+
+// The CIDR length of an ip4 or ip6 mechanism
+fn network_length(net: IpAddr, cidr: Option<&str>) -> Result<u8, Failure> {
+    let max = if net.is_ipv4() { 32 } else { 128 };
+    match cidr {
+        None => Ok(max),
+        Some(cidr) => parse_length(&cidr[1..], max),
+    }
+}
+
+// The IPv4 and IPv6 CIDR lengths of an a or mx mechanism, e.g /24//64
+fn dual_cidr(cidr: Option<&str>) -> Result<(u8, u8), Failure> {
+    let Some(cidr) = cidr else {
+        return Ok((32, 128));
+    };
+    let (v4, v6) = match cidr.split_once("//") {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (cidr, None),
+    };
+    let v4_len = match v4.strip_prefix('/') {
+        Some(len) => parse_length(len, 32)?,
+        None if v4.is_empty() => 32,
+        None => return perm(format!("bad CIDR length {}", cidr)),
+    };
+    let v6_len = match v6 {
+        Some(len) => parse_length(len, 128)?,
+        None => 128,
+    };
+    Ok((v4_len, v6_len))
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MockResolver {
+        txt: HashMap<&'static str, Vec<String>>,
+        a: HashMap<&'static str, Vec<IpAddr>>,
+        mx: HashMap<&'static str, Vec<String>>,
+    }
+
+    impl MockResolver {
+        fn txt(mut self, domain: &'static str, txt: &str) -> Self {
+            self.txt.entry(domain).or_default().push(txt.to_string());
+            self
+        }
+
+        fn a(mut self, domain: &'static str, ip: &str) -> Self {
+            self.a.entry(domain).or_default().push(ip.parse().unwrap());
+            self
+        }
+
+        fn mx(mut self, domain: &'static str, host: &str) -> Self {
+            self.mx.entry(domain).or_default().push(host.to_string());
+            self
+        }
+    }
+
+    impl Resolver for MockResolver {
+        fn reverse(&self, _ip: IpAddr) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn forward(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+            Ok(self.a.get(name).cloned().unwrap_or_default())
+        }
+
+        fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+            match name {
+                "servfail.example" => Err(io::Error::other("SERVFAIL")),
+                _ => Ok(self.txt.get(name).cloned().unwrap_or_default()),
+            }
+        }
+
+        fn mx(&self, name: &str) -> io::Result<Vec<String>> {
+            Ok(self.mx.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn checker() -> SpfChecker {
+        let resolver = MockResolver::default()
+            .txt(
+                "sea.com",
+                "v=spf1 ip4:192.0.2.0/24 a mx include:sky.com ~all",
+            )
+            .txt("sea.com", "not an spf record")
+            .a("sea.com", "198.51.100.10")
+            .mx("sea.com", "mx.sea.com")
+            .a("mx.sea.com", "198.51.100.20")
+            .txt("sky.com", "v=spf1 ip6:2001:db8::/32 -all")
+            .txt("land.com", "v=spf1 redirect=sky.com")
+            .txt("lake.com", "v=spf1 ?all")
+            .txt("bad.com", "v=spf1 ip4:192.0.2.300 -all")
+            .txt("macro.com", "v=spf1 exists:%{i}.spf.macro.com -all")
+            .txt("twice.com", "v=spf1 -all")
+            .txt("twice.com", "v=spf1 +all")
+            .txt("loop.com", "v=spf1 include:loop.com -all")
+            .txt("temp.com", "v=spf1 include:servfail.example -all")
+            .txt(
+                "void.com",
+                "v=spf1 a:x.void.com a:y.void.com ip4:192.0.2.0/24 -all",
+            )
+            .txt(
+                "voids.com",
+                "v=spf1 a:x.void.com mx:y.void.com exists:z.void.com +all",
+            );
+        SpfChecker::new(resolver)
+    }
+
+    fn check(ip: &str, domain: &str) -> SpfResult {
+        checker().check(ip.parse().unwrap(), domain)
+    }
+
+    #[test]
+    fn spf_verdicts() {
+        assert_eq!(check("192.0.2.77", "sea.com"), SpfResult::Pass);
+        assert_eq!(check("198.51.100.10", "sea.com"), SpfResult::Pass);
+        assert_eq!(check("198.51.100.20", "sea.com"), SpfResult::Pass);
+        assert_eq!(check("2001:db8::25", "sea.com"), SpfResult::Pass);
+        assert_eq!(check("203.0.113.1", "sea.com"), SpfResult::SoftFail);
+        assert_eq!(check("203.0.113.1", "sky.com"), SpfResult::Fail);
+        assert_eq!(check("2001:db8::25", "land.com"), SpfResult::Pass);
+        assert_eq!(check("203.0.113.1", "land.com"), SpfResult::Fail);
+        assert_eq!(check("203.0.113.1", "lake.com"), SpfResult::Neutral);
+        assert_eq!(check("203.0.113.1", "nospf.com"), SpfResult::None);
+    }
+
+    #[test]
+    fn spf_errors() {
+        for domain in ["bad.com", "macro.com", "twice.com", "loop.com"] {
+            assert_eq!(check("192.0.2.1", domain), SpfResult::PermError, "{domain}");
+        }
+        assert_eq!(check("192.0.2.1", "temp.com"), SpfResult::TempError);
+        assert_eq!(check("192.0.2.1", "servfail.example"), SpfResult::TempError);
+    }
+
+    #[test]
+    fn void_lookups() {
+        // Two lookups that find nothing are allowed, a third is an error
+        assert_eq!(check("192.0.2.1", "void.com"), SpfResult::Pass);
+        assert_eq!(check("192.0.2.1", "voids.com"), SpfResult::PermError);
+    }
+
+    #[test]
+    fn mechanism_parts() {
+        assert_eq!(split_mechanism("all"), ("all", None, None));
+        assert_eq!(split_mechanism("a/24"), ("a", None, Some("/24")));
+        assert_eq!(
+            split_mechanism("mx:sea.com/24//64"),
+            ("mx", Some("sea.com"), Some("/24//64"))
+        );
+        assert_eq!(
+            split_mechanism("ip6:2001:db8::/32"),
+            ("ip6", Some("2001:db8::"), Some("/32"))
+        );
+        assert!(matches!(dual_cidr(Some("/24//64")), Ok((24, 64))));
+        assert!(matches!(dual_cidr(Some("//64")), Ok((32, 64))));
+        assert!(dual_cidr(Some("/33")).is_err());
+        assert!(in_network(
+            "192.0.2.200".parse().unwrap(),
+            "192.0.2.0".parse().unwrap(),
+            24
+        ));
+        assert!(in_network(
+            "203.0.113.1".parse().unwrap(),
+            "192.0.2.0".parse().unwrap(),
+            0
+        ));
+    }
+}
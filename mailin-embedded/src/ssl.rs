@@ -1,4 +1,13 @@
+use std::time::Duration;
+
 /// `SslConfig` is used to configure the STARTTLS configuration of the server
+///
+/// Clients can resume TLS sessions with session tickets, which skips the full handshake
+/// for clients that reconnect often. The ticket keys are shared by all connections to
+/// the server. By default they are generated when the server starts, use
+/// [`Server::with_ticket_keys()`] to share keys between servers.
+///
+/// [`Server::with_ticket_keys()`]: crate::Server::with_ticket_keys
 pub enum SslConfig {
     /// Do not support STARTTLS
     None,
@@ -18,4 +27,88 @@ pub enum SslConfig {
         /// Path to CA bundle
         chain_path: String,
     },
+    /// Use PEM encoded certificate material held in memory
+    Pem {
+        /// PEM encoded certificate
+        cert: Vec<u8>,
+        /// PEM encoded private key
+        key: Vec<u8>,
+        /// PEM encoded CA bundle, if the certificate is from an authority
+        chain: Option<Vec<u8>>,
+    },
+}
+
+impl SslConfig {
+    /// Generate a self-signed certificate for the given hostnames, held in memory.
+    ///
+    /// Clients do not trust the certificate unless told to, it is meant for tests and
+    /// local development where creating certificate files is an extra step.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin_embedded::{Server, SslConfig};
+    /// # use mailin_embedded::Handler;
+    /// # #[derive(Clone)]
+    /// # struct MyHandler;
+    /// # impl Handler for MyHandler {}
+    ///
+    /// let ssl_config = SslConfig::generate_self_signed(["localhost"])?;
+    /// let mut server = Server::new(MyHandler);
+    /// server.with_ssl(ssl_config)?;
+    /// # Ok::<(), mailin_embedded::err::Error>(())
+    /// ```
+    #[cfg(feature = "self-signed")]
+    pub fn generate_self_signed<I, S>(hostnames: I) -> Result<Self, crate::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let hostnames: Vec<String> = hostnames.into_iter().map(Into::into).collect();
+        let generated = rcgen::generate_simple_self_signed(hostnames)
+            .map_err(|e| crate::Error::with_source("Cannot generate a certificate", e))?;
+        Ok(SslConfig::Pem {
+            cert: generated.cert.pem().into_bytes(),
+            key: generated.signing_key.serialize_pem().into_bytes(),
+            chain: None,
+        })
+    }
+}
+
+/// Keys that encrypt TLS session tickets, see [`Server::with_ticket_keys()`].
+///
+/// New tickets are encrypted with the current key, tickets encrypted with the current
+/// or an old key are accepted. To rotate keys, make the current key an old key and add
+/// a new current key. Drop the old key once the tickets it encrypted have expired.
+///
+/// [`Server::with_ticket_keys()`]: crate::Server::with_ticket_keys
+#[derive(Clone)]
+// The keys are only used by rustls
+#[cfg_attr(feature = "ossl", allow(dead_code))]
+pub struct TicketKeys {
+    pub(crate) current: [u8; 32],
+    pub(crate) old: Vec<[u8; 32]>,
+    pub(crate) lifetime: Duration,
+}
+
+impl TicketKeys {
+    /// Encrypt tickets with the given 256 bit key, tickets are valid for 6 hours
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            current: key,
+            old: Vec::new(),
+            lifetime: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+
+    /// Also accept tickets encrypted with an old key
+    pub fn with_old_key(mut self, key: [u8; 32]) -> Self {
+        self.old.push(key);
+        self
+    }
+
+    /// The lifetime of tickets sent to clients
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
 }
@@ -1,11 +1,21 @@
 use std::fmt::Debug;
-use std::io::{stdin, stdout, Read, StdinLock, StdoutLock, Write};
+use std::io::{self, stdin, stdout, Read, StdinLock, StdoutLock, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 
 /// The stream of a connection
-pub trait Stream: Read + Write + Debug + 'static {}
+pub trait Stream: Read + Write + Debug + 'static {
+    /// Set how long a read can wait for data, streams without timeouts ignore this
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-impl Stream for TcpStream {}
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
 
 /// Stdio as a [`Stream`]
 #[derive(Debug)]
@@ -45,3 +55,46 @@ impl Stdio {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::Stream;
+    use std::io::{self, Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    // In-memory stream that reads from a fixed input and records the output
+    #[derive(Debug)]
+    pub(crate) struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl MemoryStream {
+        pub(crate) fn new(input: &[u8]) -> (Self, Arc<Mutex<Vec<u8>>>) {
+            let output = Arc::new(Mutex::new(Vec::new()));
+            let stream = Self {
+                input: Cursor::new(input.to_vec()),
+                output: output.clone(),
+            };
+            (stream, output)
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for MemoryStream {}
+}
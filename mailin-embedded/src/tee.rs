@@ -0,0 +1,307 @@
+use mailin::{BodyType, DeliverBy, Handler, Reason, Response};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+
+/// A [`Handler`] that passes each message to two handlers, e.g to store it and to
+/// send it to an analysis pipeline, without reading it twice.
+///
+/// The mail transaction, from [`Handler::helo()`] to [`Handler::data_end()`], goes to
+/// both handlers, as does [`Handler::rset()`]. An error from either handler fails the
+/// command: the error from the first handler is returned without calling the second.
+/// If the data fails, the other handler is told with [`Handler::data_end_error()`].
+/// At the end of the data the second handler decides first, so that the first handler
+/// does not keep a message that the client will send again. Calls outside of the
+/// transaction, such as AUTH and STARTTLS, are decided by the first handler.
+///
+/// To pass messages to more than two handlers, nest them.
+///
+/// # Examples
+/// ```
+/// use mailin_embedded::{Handler, Server, TeeData};
+///
+/// #[derive(Clone)]
+/// struct Store {}
+/// impl Handler for Store {}
+///
+/// #[derive(Clone)]
+/// struct Analysis {}
+/// impl Handler for Analysis {}
+///
+/// let handler = TeeData::new(Store {}, TeeData::new(Analysis {}, Analysis {}));
+/// let server = Server::new(handler);
+/// ```
+#[derive(Clone)]
+pub struct TeeData<A: Handler, B: Handler> {
+    first: A,
+    second: B,
+    // The second handler failed to take the data, it gets the error
+    second_failed: bool,
+}
+
+impl<A: Handler, B: Handler> TeeData<A, B> {
+    /// Pass messages to both handlers
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            second_failed: false,
+        }
+    }
+}
+
+impl<A: Handler, B: Handler> Handler for TeeData<A, B> {
+    fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
+        let res = self.first.helo(ip, domain);
+        either_error(res, || self.second.helo(ip, domain))
+    }
+
+    fn mail(&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
+        let res = self.first.mail(ip, domain, from);
+        either_error(res, || self.second.mail(ip, domain, from))
+    }
+
+    fn rcpt(&mut self, to: &str) -> Response {
+        let res = self.first.rcpt(to);
+        either_error(res, || self.second.rcpt(to))
+    }
+
+    fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+        self.second_failed = false;
+        let res = self.first.data_start(domain, from, is8bit, to);
+        if res.is_error {
+            return res;
+        }
+        let second = self.second.data_start(domain, from, is8bit, to);
+        if second.is_error {
+            self.first.data_end_error(Reason::Processing);
+            return second;
+        }
+        res
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        // On error both handlers are told with data_end_error()
+        self.first.data(buf)?;
+        self.second
+            .data(buf)
+            .inspect_err(|_| self.second_failed = true)
+    }
+
+    fn data_progress(&mut self, bytes: usize) {
+        self.first.data_progress(bytes);
+        self.second.data_progress(bytes)
+    }
+
+    fn data_digest(&mut self, digest: [u8; 32]) {
+        self.first.data_digest(digest);
+        self.second.data_digest(digest)
+    }
+
+    fn data_end(&mut self) -> Response {
+        let second = self.second.data_end();
+        if second.is_error {
+            self.first.data_end_error(Reason::Processing);
+            return second;
+        }
+        self.first.data_end()
+    }
+
+    fn data_end_error(&mut self, reason: Reason) {
+        self.first.data_end_error(reason.clone());
+        self.second.data_end_error(reason)
+    }
+
+    fn on_error(&mut self, err: io::Error) -> Response {
+        if mem::take(&mut self.second_failed) {
+            self.second.on_error(err)
+        } else {
+            self.first.on_error(err)
+        }
+    }
+
+    fn rset(&mut self) -> Response {
+        let res = self.first.rset();
+        let second = self.second.rset();
+        if second.is_error && !res.is_error {
+            second
+        } else {
+            res
+        }
+    }
+
+    fn mt_priority(&mut self, priority: i8) {
+        self.first.mt_priority(priority);
+        self.second.mt_priority(priority)
+    }
+
+    fn body_type(&mut self, body: BodyType) {
+        self.first.body_type(body);
+        self.second.body_type(body)
+    }
+
+    fn secure(&mut self, secure: bool) {
+        self.first.secure(secure);
+        self.second.secure(secure)
+    }
+
+    fn authenticated(&mut self, authenticated: bool) {
+        self.first.authenticated(authenticated);
+        self.second.authenticated(authenticated)
+    }
+
+    fn deliver_by(&mut self, deliver_by: DeliverBy) {
+        self.first.deliver_by(deliver_by);
+        self.second.deliver_by(deliver_by)
+    }
+
+    forward_handler!(
+        first: auth_plain, auth_login, allow_starttls, etrn, auth_challenge, response_delay, on_wire
+    );
+}
+
+// The first error response, the second handler is only called if the first succeeded
+fn either_error<F: FnOnce() -> Response>(first: Response, second: F) -> Response {
+    if first.is_error {
+        return first;
+    }
+    let second = second();
+    if second.is_error {
+        second
+    } else {
+        first
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin::response::{NO_MAILBOX, NO_STORAGE, OK};
+
+    // Collects messages in memory, optionally rejecting them at the end of the data
+    #[derive(Default)]
+    struct MemoryHandler {
+        data: Vec<u8>,
+        reject: bool,
+        delivered: bool,
+        error: Option<Reason>,
+        rsets: usize,
+        fail_data: bool,
+        io_errors: usize,
+    }
+
+    impl Handler for MemoryHandler {
+        fn on_error(&mut self, _err: io::Error) -> Response {
+            self.io_errors += 1;
+            NO_STORAGE
+        }
+
+        fn rset(&mut self) -> Response {
+            self.rsets += 1;
+            OK
+        }
+
+        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+            if self.fail_data {
+                return Err(io::Error::other("Disk full"));
+            }
+            self.data.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn data_end(&mut self) -> Response {
+            if self.reject {
+                NO_STORAGE
+            } else {
+                self.delivered = true;
+                OK
+            }
+        }
+
+        fn data_end_error(&mut self, reason: Reason) {
+            self.error = Some(reason);
+        }
+    }
+
+    fn deliver(tee: &mut TeeData<MemoryHandler, MemoryHandler>) -> Response {
+        tee.data_start("a.domain", "ship@sea.com", false, &[]);
+        tee.data(b"Subject: Tee\r\n").unwrap();
+        tee.data(b"\r\n").unwrap();
+        tee.data(b"Hello\r\n").unwrap();
+        tee.data_end()
+    }
+
+    #[test]
+    fn identical_bytes() {
+        let mut tee = TeeData::new(MemoryHandler::default(), MemoryHandler::default());
+        assert_eq!(deliver(&mut tee), OK);
+        assert_eq!(tee.first.data, b"Subject: Tee\r\n\r\nHello\r\n");
+        assert_eq!(tee.first.data, tee.second.data);
+        assert!(tee.first.delivered);
+        assert!(tee.second.delivered);
+    }
+
+    #[test]
+    fn rejected_by_first() {
+        let first = MemoryHandler {
+            reject: true,
+            ..Default::default()
+        };
+        let mut tee = TeeData::new(first, MemoryHandler::default());
+        assert_eq!(deliver(&mut tee), NO_STORAGE);
+        // The second handler decided first
+        assert!(tee.second.delivered);
+        assert!(!tee.first.delivered);
+    }
+
+    #[test]
+    fn rejected_by_second() {
+        let second = MemoryHandler {
+            reject: true,
+            ..Default::default()
+        };
+        let mut tee = TeeData::new(MemoryHandler::default(), second);
+        assert_eq!(deliver(&mut tee), NO_STORAGE);
+        // The client will retry, the first handler must not keep the message
+        assert!(!tee.first.delivered);
+        assert_eq!(tee.first.error, Some(Reason::Processing));
+    }
+
+    #[test]
+    fn transaction_to_both() {
+        let mut tee = TeeData::new(MemoryHandler::default(), MemoryHandler::default());
+        assert_eq!(tee.rset(), OK);
+        assert_eq!(tee.first.rsets, 1);
+        assert_eq!(tee.second.rsets, 1);
+        let mut tee = TeeData::new(MemoryHandler::default(), RejectRcpt);
+        assert_eq!(tee.rcpt("fish@sea.com"), NO_MAILBOX);
+    }
+
+    struct RejectRcpt;
+
+    impl Handler for RejectRcpt {
+        fn rcpt(&mut self, _to: &str) -> Response {
+            NO_MAILBOX
+        }
+    }
+
+    #[test]
+    fn error_to_failed_handler() {
+        let second = MemoryHandler {
+            fail_data: true,
+            ..Default::default()
+        };
+        let mut tee = TeeData::new(MemoryHandler::default(), second);
+        tee.data_start("a.domain", "ship@sea.com", false, &[]);
+        let err = tee.data(b"Subject: Tee\r\n").unwrap_err();
+        assert_eq!(tee.on_error(err), NO_STORAGE);
+        assert_eq!(tee.first.io_errors, 0);
+        assert_eq!(tee.second.io_errors, 1);
+        // The next error comes from the first handler
+        tee.first.fail_data = true;
+        let err = tee.data(b"Subject: Tee\r\n").unwrap_err();
+        tee.on_error(err);
+        assert_eq!(tee.first.io_errors, 1);
+        assert_eq!(tee.second.io_errors, 1);
+    }
+}
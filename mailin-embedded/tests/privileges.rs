@@ -0,0 +1,30 @@
+// Dropping privileges changes the whole process, so this test has a binary of its own
+#![cfg(unix)]
+
+use mailin_embedded::{Handler, Server};
+use nix::unistd::{getegid, geteuid, Group, User};
+
+#[derive(Clone)]
+struct EmptyHandler {}
+impl Handler for EmptyHandler {}
+
+#[test]
+fn bind_and_drop_privileges() {
+    if !geteuid().is_root() {
+        eprintln!("Skipped, dropping privileges needs root");
+        return;
+    }
+    let user = User::from_name("nobody").unwrap().unwrap();
+    let group = Group::from_gid(user.gid).unwrap().unwrap();
+    let mut server = Server::new(EmptyHandler {});
+    server
+        .bind_and_drop_privileges("127.0.0.1:0", &user.name, &group.name)
+        .unwrap();
+    assert_eq!(geteuid(), user.uid);
+    assert_eq!(getegid(), group.gid);
+    // Unknown users are reported
+    let mut server = Server::new(EmptyHandler {});
+    assert!(server
+        .bind_and_drop_privileges("127.0.0.1:0", "no-such-user", &group.name)
+        .is_err());
+}
@@ -1,11 +1,15 @@
+mod spamd;
 mod store;
 
-use crate::store::MailStore;
+use crate::spamd::{SpamdData, DEFAULT_MAX_SIZE};
+use crate::store::{
+    attachment_policy, on_commit_command, received_header, Compression, Format, MailStore,
+};
 use anyhow::{anyhow, Context, Result};
 use getopts::Options;
-use log::error;
+use log::{error, info};
 use mailin_embedded::response::{BAD_HELLO, BLOCKED_IP, INTERNAL_ERROR, OK};
-use mailin_embedded::{Reason, Response, Server, SslConfig};
+use mailin_embedded::{Reason, Response, Server, ShutdownHandle, SslConfig};
 use mxdns::MxDns;
 use simplelog::{
     ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
@@ -16,6 +20,8 @@ use std::io;
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, TcpListener};
 use std::path::Path;
+use std::thread;
+use std::time::SystemTime;
 use time::macros::format_description;
 use time::OffsetDateTime;
 
@@ -25,6 +31,8 @@ const DEFAULT_ADDRESS: &str = "127.0.0.1:8025";
 // Command line option names
 const OPT_HELP: &str = "help";
 const OPT_ADDRESS: &str = "address";
+const OPT_USER: &str = "user";
+const OPT_GROUP: &str = "group";
 const OPT_LOG: &str = "log";
 const OPT_SERVER: &str = "server";
 const OPT_SSL_CERT: &str = "ssl-cert";
@@ -32,15 +40,33 @@ const OPT_SSL_KEY: &str = "ssl-key";
 const OPT_SSL_CHAIN: &str = "ssl-chain";
 const OPT_BLOCKLIST: &str = "blocklist";
 const OPT_MAILDIR: &str = "maildir";
+const OPT_MBOX: &str = "mbox";
+const OPT_DEDUP: &str = "dedup";
+const OPT_RECIPIENT_MAILBOXES: &str = "recipient-mailboxes";
+const OPT_MAILBOX_QUOTA: &str = "mailbox-quota";
+const OPT_GZIP: &str = "gzip";
+const OPT_REJECT_ATTACHMENT: &str = "reject-attachment";
+const OPT_MAX_OPEN_MESSAGES: &str = "max-open-messages";
+const OPT_ON_COMMIT: &str = "on-commit";
+const OPT_CAPTURE_REJECTED: &str = "capture-rejected";
+const OPT_SPAMD: &str = "spamd";
+const OPT_SPAM_REJECT: &str = "spam-reject";
+const OPT_SPAMD_MAX_SIZE: &str = "spamd-max-size";
+
+const SPAM_REJECTED: Response = Response::fixed(550, "Message rejected as spam");
 
 #[derive(Clone)]
 struct Handler<'a> {
     mxdns: &'a MxDns,
     mailstore: MailStore,
+    server_name: String,
+    client_ip: Option<IpAddr>,
+    spamd: Option<SpamdData>,
 }
 
 impl mailin_embedded::Handler for Handler<'_> {
     fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+        self.client_ip = Some(ip);
         if ip == Ipv4Addr::new(127, 0, 0, 1) {
             return OK;
         }
@@ -58,15 +84,11 @@ impl mailin_embedded::Handler for Handler<'_> {
         }
     }
 
-    fn data_start(
-        &mut self,
-        _domain: &str,
-        _from: &str,
-        _is8bit: bool,
-        _to: &[String],
-    ) -> Response {
-        match self.mailstore.start_message() {
-            Ok(()) => OK,
+    fn data_start(&mut self, domain: &str, from: &str, _is8bit: bool, to: &[String]) -> Response {
+        let ip = self.client_ip.unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+        let received = received_header(domain, ip, &self.server_name, SystemTime::now());
+        match self.mailstore.start_message(from, to, &received) {
+            Ok(res) => res,
             Err(err) => {
                 error!("Start message: {}", err);
                 INTERNAL_ERROR
@@ -75,12 +97,33 @@ impl mailin_embedded::Handler for Handler<'_> {
     }
 
     fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.mailstore.write_all(buf)
+        match &mut self.spamd {
+            Some(spamd) if !spamd.is_skipped() => {
+                if spamd.fits(buf.len()) {
+                    return spamd.write_all(buf);
+                }
+                // Like spamc, a message too large to score is delivered unchecked
+                info!("Message too large for spamd, delivering it unchecked");
+                let message = spamd.skip();
+                write_lines(&mut self.mailstore, &message)?;
+                self.mailstore.write_all(buf)
+            }
+            _ => self.mailstore.write_all(buf),
+        }
     }
 
     fn data_end(&mut self) -> Response {
+        match self.spam_check() {
+            Ok(Some(res)) => return res,
+            Ok(None) => (),
+            Err(err) => {
+                error!("Spam check: {}", err);
+                self.mailstore.end_error(Reason::Processing);
+                return INTERNAL_ERROR;
+            }
+        }
         match self.mailstore.end_message() {
-            Ok(()) => OK,
+            Ok(res) => res,
             Err(err) => {
                 error!("End message: {}", err);
                 INTERNAL_ERROR
@@ -89,10 +132,54 @@ impl mailin_embedded::Handler for Handler<'_> {
     }
 
     fn data_end_error(&mut self, reason: Reason) {
+        if let Some(spamd) = &mut self.spamd {
+            spamd.take_message();
+        }
         self.mailstore.end_error(reason)
     }
 }
 
+impl Handler<'_> {
+    // Score the message collected by spamd, if enabled, and either reject it or
+    // write it to the mailstore with X-Spam headers added.
+    fn spam_check(&mut self) -> io::Result<Option<Response>> {
+        let Some(spamd) = &mut self.spamd else {
+            return Ok(None);
+        };
+        if spamd.is_skipped() {
+            spamd.take_message();
+            return Ok(None);
+        }
+        let verdict = spamd.check();
+        let message = spamd.take_message();
+        let verdict = match verdict {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                // Deliver the message untagged rather than lose it
+                error!("Cannot contact spamd: {}", err);
+                write_lines(&mut self.mailstore, &message)?;
+                return Ok(None);
+            }
+        };
+        if spamd.is_rejected(&verdict) {
+            info!("Rejecting spam with score {}", verdict.score);
+            self.mailstore.end_error(Reason::Processing);
+            return Ok(Some(SPAM_REJECTED));
+        }
+        write_lines(&mut self.mailstore, verdict.headers().as_bytes())?;
+        write_lines(&mut self.mailstore, &message)?;
+        Ok(None)
+    }
+}
+
+// Write a buffer to the mailstore one line at a time
+fn write_lines(mailstore: &mut MailStore, buf: &[u8]) -> io::Result<()> {
+    for line in buf.split_inclusive(|c| *c == b'\n') {
+        mailstore.write_all(line)?;
+    }
+    Ok(())
+}
+
 fn setup_logger(log_dir: Option<String>) -> Result<()> {
     let log_level = LevelFilter::Info;
     // Try to create a terminal logger, if this fails use a simple logger to stdout
@@ -137,6 +224,18 @@ fn main() -> Result<()> {
     let mut opts = getopts::Options::new();
     opts.optflag("h", OPT_HELP, "print this help menu");
     opts.optopt("a", OPT_ADDRESS, "the address to listen on", "ADDRESS");
+    opts.optopt(
+        "u",
+        OPT_USER,
+        "the user to run as after opening the address",
+        "USER",
+    );
+    opts.optopt(
+        "g",
+        OPT_GROUP,
+        "the group to run as, the same as the user if omitted",
+        "GROUP",
+    );
     opts.optopt("l", OPT_LOG, "the directory to write logs to", "LOG_DIR");
     opts.optopt("s", OPT_SERVER, "the name of the mailserver", "SERVER");
     opts.optmulti("", OPT_BLOCKLIST, "use blocklist", "BLOCKLIST");
@@ -149,6 +248,70 @@ fn main() -> Result<()> {
         "PEM_FILE",
     );
     opts.optopt("", OPT_MAILDIR, "the directory to store mail in", "MAILDIR");
+    opts.optflag(
+        "",
+        OPT_MBOX,
+        "append mail to an mbox file in the mail directory",
+    );
+    opts.optflag(
+        "",
+        OPT_DEDUP,
+        "link messages with a recently seen Message-ID instead of storing a copy",
+    );
+    opts.optflag(
+        "",
+        OPT_RECIPIENT_MAILBOXES,
+        "deliver to a maildir per recipient in the mailboxes directory",
+    );
+    opts.optopt(
+        "",
+        OPT_MAILBOX_QUOTA,
+        "the maximum size of the messages in a recipient maildir",
+        "BYTES",
+    );
+    opts.optflag("", OPT_GZIP, "gzip compress messages in the mail directory");
+    opts.optmulti(
+        "",
+        OPT_REJECT_ATTACHMENT,
+        "reject messages with attachments that have this file extension",
+        "EXTENSION",
+    );
+    opts.optopt(
+        "",
+        OPT_MAX_OPEN_MESSAGES,
+        "the maximum number of messages written to the mail directory at once",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        OPT_ON_COMMIT,
+        "run COMMAND with the path and the size in bytes of each delivered message as arguments",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        OPT_CAPTURE_REJECTED,
+        "keep the first BYTES of rejected messages in the quarantine directory",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        OPT_SPAMD,
+        "score messages with the spamd server at this address",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "",
+        OPT_SPAM_REJECT,
+        "reject messages with a spamd score of at least SCORE instead of tagging them",
+        "SCORE",
+    );
+    opts.optopt(
+        "",
+        OPT_SPAMD_MAX_SIZE,
+        "deliver messages larger than BYTES without scoring them (default 512000)",
+        "BYTES",
+    );
     let matches = opts
         .parse(&args[1..])
         .context("Cannot parse command line")?;
@@ -180,9 +343,76 @@ fn main() -> Result<()> {
     let maildir = matches
         .opt_str(OPT_MAILDIR)
         .unwrap_or_else(|| "mail".to_owned());
+    let format = if matches.opt_present(OPT_MBOX) {
+        Format::Mbox
+    } else {
+        Format::Maildir
+    };
+    let compression = if matches.opt_present(OPT_GZIP) {
+        Compression::Gzip
+    } else {
+        Compression::None
+    };
+    let spam_reject = matches
+        .opt_str(OPT_SPAM_REJECT)
+        .map(|score| score.parse::<f32>())
+        .transpose()
+        .context("Cannot parse spam reject score")?;
+    let spamd_max_size = matches
+        .opt_str(OPT_SPAMD_MAX_SIZE)
+        .map(|bytes| bytes.parse::<usize>())
+        .transpose()
+        .context("Cannot parse spamd maximum size")?
+        .unwrap_or(DEFAULT_MAX_SIZE);
+    let spamd = matches.opt_str(OPT_SPAMD).map(|addr| {
+        let spamd = SpamdData::new(addr).with_max_size(spamd_max_size);
+        match spam_reject {
+            Some(score) => spamd.with_reject_score(score),
+            None => spamd,
+        }
+    });
+    let mut mailstore = MailStore::new(maildir)
+        .with_format(format)
+        .with_compression(compression)
+        .with_dedup(matches.opt_present(OPT_DEDUP))
+        .with_recipient_mailboxes(matches.opt_present(OPT_RECIPIENT_MAILBOXES));
+    let rejected_attachments = matches.opt_strs(OPT_REJECT_ATTACHMENT);
+    if !rejected_attachments.is_empty() {
+        mailstore = mailstore.with_policy(attachment_policy(rejected_attachments));
+    }
+    let max_open_messages = matches
+        .opt_str(OPT_MAX_OPEN_MESSAGES)
+        .map(|max| max.parse::<usize>())
+        .transpose()
+        .context("Cannot parse maximum open messages")?;
+    if let Some(max) = max_open_messages {
+        mailstore = mailstore.with_max_open_messages(max);
+    }
+    if let Some(command) = matches.opt_str(OPT_ON_COMMIT) {
+        mailstore = mailstore.with_on_commit(on_commit_command(command));
+    }
+    let mailbox_quota = matches
+        .opt_str(OPT_MAILBOX_QUOTA)
+        .map(|bytes| bytes.parse::<u64>())
+        .transpose()
+        .context("Cannot parse mailbox quota")?;
+    if let Some(bytes) = mailbox_quota {
+        mailstore = mailstore.with_mailbox_quota(bytes);
+    }
+    let capture_rejected = matches
+        .opt_str(OPT_CAPTURE_REJECTED)
+        .map(|bytes| bytes.parse::<usize>())
+        .transpose()
+        .context("Cannot parse bytes of rejected messages to capture")?;
+    if let Some(bytes) = capture_rejected {
+        mailstore = mailstore.with_capture_rejected(bytes);
+    }
     let handler = Handler {
         mxdns: &mxdns,
-        mailstore: MailStore::new(maildir),
+        mailstore,
+        server_name: domain.clone(),
+        client_ip: None,
+        spamd,
     };
     let mut server = Server::new(handler);
     server
@@ -193,13 +423,73 @@ fn main() -> Result<()> {
     let addr = matches
         .opt_str(OPT_ADDRESS)
         .unwrap_or_else(|| DEFAULT_ADDRESS.to_owned());
-    let listener = TcpListener::bind(addr)?;
-    server.with_tcp_listener(listener);
+    match matches.opt_str(OPT_USER) {
+        Some(user) => {
+            let group = matches.opt_str(OPT_GROUP).unwrap_or_else(|| user.clone());
+            bind_and_drop_privileges(&mut server, &addr, &user, &group)?;
+        }
+        None => {
+            let listener = TcpListener::bind(addr)?;
+            server.with_tcp_listener(listener);
+        }
+    }
 
     let log_directory = matches.opt_str(OPT_LOG);
     setup_logger(log_directory)?;
 
+    // Before the server starts its threads, so that they inherit the signal mask
+    shutdown_on_signal(server.shutdown_handle())?;
     server
         .serve()
         .map_err(|e| anyhow!("Cannot start server: {}", e))
 }
+
+#[cfg(unix)]
+fn bind_and_drop_privileges(
+    server: &mut Server<Handler>,
+    addr: &str,
+    user: &str,
+    group: &str,
+) -> Result<()> {
+    server
+        .bind_and_drop_privileges(addr, user, group)
+        .map_err(|e| anyhow!("Cannot drop privileges: {}", e))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn bind_and_drop_privileges(
+    _server: &mut Server<Handler>,
+    _addr: &str,
+    _user: &str,
+    _group: &str,
+) -> Result<()> {
+    Err(anyhow!("Changing user is only supported on unix"))
+}
+
+// Shut down gracefully on SIGTERM or SIGINT, letting sessions in progress finish
+#[cfg(unix)]
+fn shutdown_on_signal(shutdown: ShutdownHandle) -> Result<()> {
+    use nix::sys::signal::{SigSet, Signal};
+
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    // Block the signals in all threads, they are received by the thread below
+    signals
+        .thread_block()
+        .context("Cannot block shutdown signals")?;
+    thread::spawn(move || match signals.wait() {
+        Ok(signal) => {
+            info!("Received {}, shutting down", signal);
+            shutdown.shutdown();
+        }
+        Err(err) => error!("Cannot wait for shutdown signals: {}", err),
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn shutdown_on_signal(_shutdown: ShutdownHandle) -> Result<()> {
+    Ok(())
+}
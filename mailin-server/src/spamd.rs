@@ -0,0 +1,250 @@
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+const SPAMD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages larger than this are not scored, the same default as spamc
+pub const DEFAULT_MAX_SIZE: usize = 500 * 1024;
+
+/// Collects a message and has it scored by a spamd server (SpamAssassin) when complete.
+///
+/// The spamd protocol requires the message length up front, so the message is
+/// buffered until `check()` is called. At most the maximum size is buffered, a
+/// larger message is not scored.
+#[derive(Clone)]
+pub struct SpamdData {
+    addr: String,
+    reject_score: Option<f32>,
+    max_size: usize,
+    buf: Vec<u8>,
+    skipped: bool,
+}
+
+/// The result of scoring a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub is_spam: bool,
+    pub score: f32,
+    pub threshold: f32,
+}
+
+impl SpamdData {
+    pub fn new<S: Into<String>>(addr: S) -> Self {
+        Self {
+            addr: addr.into(),
+            reject_score: None,
+            max_size: DEFAULT_MAX_SIZE,
+            buf: Vec::new(),
+            skipped: false,
+        }
+    }
+
+    /// Only score messages of at most the given number of bytes
+    pub fn with_max_size(mut self, bytes: usize) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Would the message still be scored with the given number of bytes added?
+    pub fn fits(&self, len: usize) -> bool {
+        self.buf.len() + len <= self.max_size
+    }
+
+    /// Reject messages with a score at or above the given score, instead of tagging them
+    pub fn with_reject_score(mut self, score: f32) -> Self {
+        self.reject_score = Some(score);
+        self
+    }
+
+    /// Should a message with the given verdict be rejected?
+    pub fn is_rejected(&self, verdict: &Verdict) -> bool {
+        self.reject_score
+            .filter(|reject| verdict.score >= *reject)
+            .is_some()
+    }
+
+    /// Take the message collected so far, leaving an empty buffer for the next message
+    pub fn take_message(&mut self) -> Vec<u8> {
+        self.skipped = false;
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Stop collecting a message that is too large to score, returns the part collected
+    /// so far. The message is not scored until `take_message()` is called.
+    pub fn skip(&mut self) -> Vec<u8> {
+        self.skipped = true;
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Was the current message skipped?
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// Send the collected message to spamd and return its verdict
+    pub fn check(&self) -> io::Result<Verdict> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(SPAMD_TIMEOUT))?;
+        stream.set_write_timeout(Some(SPAMD_TIMEOUT))?;
+        write!(
+            stream,
+            "CHECK SPAMC/1.5\r\nContent-length: {}\r\n\r\n",
+            self.buf.len()
+        )?;
+        stream.write_all(&self.buf)?;
+        stream.flush()?;
+        stream.shutdown(Shutdown::Write)?;
+        read_verdict(BufReader::new(stream))
+    }
+}
+
+impl Write for SpamdData {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.fits(buf.len()) {
+            return Err(io::Error::other("message too large for spamd"));
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Verdict {
+    /// Headers to add to a message that has been tagged
+    pub fn headers(&self) -> String {
+        let flag = if self.is_spam { "YES" } else { "NO" };
+        format!(
+            "X-Spam-Flag: {}\r\nX-Spam-Score: {:.1}\r\nX-Spam-Status: {}, score={:.1} required={:.1}\r\n",
+            flag,
+            self.score,
+            if self.is_spam { "Yes" } else { "No" },
+            self.score,
+            self.threshold
+        )
+    }
+}
+
+// Read a spamd response of the form:
+//   SPAMD/1.1 0 EX_OK
+//   Spam: True ; 15.0 / 5.0
+fn read_verdict<R: BufRead>(reader: R) -> io::Result<Verdict> {
+    let mut lines = reader.lines();
+    let status = lines.next().transpose()?.unwrap_or_default();
+    let mut status_fields = status.split_whitespace();
+    match (status_fields.next(), status_fields.next()) {
+        (Some(protocol), Some("0")) if protocol.starts_with("SPAMD/") => (),
+        _ => return Err(invalid(&status)),
+    }
+    for line in lines {
+        let line = line?;
+        if let Some(spam) = line.strip_prefix("Spam:") {
+            return parse_spam_header(spam).ok_or_else(|| invalid(&line));
+        }
+    }
+    Err(invalid("missing Spam header"))
+}
+
+fn parse_spam_header(value: &str) -> Option<Verdict> {
+    let (flag, scores) = value.split_once(';')?;
+    let (score, threshold) = scores.split_once('/')?;
+    let is_spam = match flag.trim().to_ascii_lowercase().as_str() {
+        "true" | "yes" => true,
+        "false" | "no" => false,
+        _ => return None,
+    };
+    Some(Verdict {
+        is_spam,
+        score: score.trim().parse().ok()?,
+        threshold: threshold.trim().parse().ok()?,
+    })
+}
+
+fn invalid(response: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected spamd response: {response}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Start a spamd responder that gives a fixed score and returns the received request
+    fn mock_spamd(score: f32) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).unwrap();
+            let is_spam = if score >= 5.0 { "True" } else { "False" };
+            write!(
+                stream,
+                "SPAMD/1.1 0 EX_OK\r\nSpam: {is_spam} ; {score:.1} / 5.0\r\n\r\n"
+            )
+            .unwrap();
+            request
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn tag_ham() {
+        let (addr, spamd) = mock_spamd(1.5);
+        let mut data = SpamdData::new(addr).with_reject_score(10.0);
+        data.write_all(b"Subject: Hello\r\n\r\nHello World\r\n")
+            .unwrap();
+        let verdict = data.check().unwrap();
+        let expected = Verdict {
+            is_spam: false,
+            score: 1.5,
+            threshold: 5.0,
+        };
+        assert_eq!(verdict, expected);
+        assert!(!data.is_rejected(&verdict));
+        assert!(verdict.headers().starts_with("X-Spam-Flag: NO\r\n"));
+        let request = spamd.join().unwrap();
+        assert_eq!(
+            request,
+            b"CHECK SPAMC/1.5\r\nContent-length: 31\r\n\r\nSubject: Hello\r\n\r\nHello World\r\n"
+        );
+    }
+
+    #[test]
+    fn reject_spam() {
+        let (addr, spamd) = mock_spamd(15.0);
+        let mut data = SpamdData::new(addr).with_reject_score(10.0);
+        data.write_all(b"Subject: Buy now\r\n\r\n").unwrap();
+        let verdict = data.check().unwrap();
+        assert!(verdict.is_spam);
+        assert!(data.is_rejected(&verdict));
+        spamd.join().unwrap();
+    }
+
+    #[test]
+    fn max_size() {
+        let mut data = SpamdData::new("127.0.0.1:0").with_max_size(20);
+        data.write_all(b"Subject: Hello\r\n\r\n").unwrap();
+        assert!(!data.fits(3));
+        assert!(data.write_all(b"Hi\r\n").is_err());
+        assert_eq!(data.skip().len(), 18);
+        assert!(data.is_skipped());
+        data.take_message();
+        assert!(!data.is_skipped());
+    }
+
+    #[test]
+    fn bad_response() {
+        let response = "SPAMD/1.1 76 Bad header line\r\n\r\n";
+        assert!(read_verdict(response.as_bytes()).is_err());
+    }
+}
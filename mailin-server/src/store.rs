@@ -1,33 +1,162 @@
-use log::info;
-use mailin_embedded::Reason;
-use mime_event::MessageParser;
+use flate2::write::GzEncoder;
+use log::{error, info};
+use mailin_embedded::response::{INTERNAL_ERROR, NO_STORAGE, OK};
+use mailin_embedded::{Reason, Response};
+use mime_event::{Message, MessageParser};
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::process;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::SystemTime;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+// Name of the mailbox file, within the mail directory, used for mbox delivery
+const MBOX_FILE: &str = "mbox";
+
+// Directory, within the mail directory, for samples of rejected messages
+const QUARANTINE_DIR: &str = "quarantine";
+
+// Directory, within the mail directory, that holds the recipient mailboxes
+const MAILBOXES_DIR: &str = "mailboxes";
+
+// Number of recently delivered Message-IDs remembered for de-duplication
+const DEDUP_CAPACITY: usize = 1024;
+
+const ATTACHMENT_REJECTED: Response = Response::fixed(554, "Attachment type not allowed");
+
+const TOO_MANY_MESSAGES: Response = Response::fixed(451, "Too many messages in progress");
+
+/// The format used to deliver messages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One file per message in the new/ directory
+    #[default]
+    Maildir,
+    /// Messages are appended to a single mbox file
+    Mbox,
+}
+
+/// Compression applied to stored messages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Messages are stored as received
+    #[default]
+    None,
+    /// Messages are stored gzip compressed, in files with a .gz suffix
+    Gzip,
+}
+
+/// Generates the file names of delivered messages, which must be unique within the
+/// mail directory
+pub trait NameGen: Send + Sync {
+    fn name(&self, now: SystemTime) -> String;
+}
+
+/// The source of the current time, so that time dependent behaviour can be tested
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock
+#[derive(Default)]
+pub(crate) struct SystemClock;
+
+/// The default file names: the time in milliseconds, the process ID and a counter.
+/// The counter keeps names unique if the clock goes backwards.
+#[derive(Default)]
+pub struct TimeNameGen {
+    counter: AtomicU32,
+}
+
+/// Decides whether to accept a parsed message, returning the response for the client
+/// when the message is rejected
+pub type Policy = dyn FnMut(&Message) -> Result<(), Response> + Send;
+
+/// Called with the path and size of each delivered message, e.g to pass it on for
+/// processing
+///
+/// The size argument is a breaking change, callbacks used to take only the path.
+pub type OnCommit = dyn FnMut(&Path, usize) + Send;
 
 pub struct MailStore {
     dir: PathBuf,
-    counter: Arc<AtomicU32>,
+    format: Format,
+    compression: Compression,
+    names: Arc<dyn NameGen>,
+    clock: Arc<dyn Clock>,
+    recent: Option<Arc<Mutex<RecentIds>>>,
+    policy: Option<Arc<Mutex<Policy>>>,
+    on_commit: Option<Arc<Mutex<OnCommit>>>,
+    open: Option<Arc<OpenMessages>>,
+    // Bytes of rejected messages kept in the quarantine directory
+    capture_rejected: Option<usize>,
+    recipient_mailboxes: bool,
+    mailbox_quota: Option<u64>,
     state: Option<State>,
 }
 
+// Counts the messages being written, shared by all clones of a MailStore
+struct OpenMessages {
+    count: AtomicUsize,
+    max: usize,
+}
+
+// Held while a message is being written, the count is decremented when dropped
+struct Permit(Arc<OpenMessages>);
+
+// The files of recently delivered messages by Message-ID, least recently used first
+#[derive(Default)]
+struct RecentIds {
+    entries: VecDeque<(Vec<u8>, PathBuf)>,
+}
+
 struct State {
+    details: Details,
+    parser: MessageParser<Sink>,
+    _permit: Option<Permit>,
+}
+
+// Where a message is written and what is known about it besides its content
+struct Details {
     path: PathBuf,
-    parser: MessageParser<BufWriter<File>>,
+    from: String,
+    to: Vec<String>,
+    // Bytes of the message received so far
+    size: usize,
+    // The start of the message, kept in case it is rejected
+    captured: Vec<u8>,
+}
+
+// The on-disk destination of a message, the parser always sees uncompressed data
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
 }
 
 impl Clone for MailStore {
     fn clone(&self) -> Self {
         Self {
             dir: self.dir.clone(),
-            counter: self.counter.clone(),
+            format: self.format,
+            compression: self.compression,
+            names: self.names.clone(),
+            clock: self.clock.clone(),
+            recent: self.recent.clone(),
+            policy: self.policy.clone(),
+            on_commit: self.on_commit.clone(),
+            open: self.open.clone(),
+            capture_rejected: self.capture_rejected,
+            recipient_mailboxes: self.recipient_mailboxes,
+            mailbox_quota: self.mailbox_quota,
             state: None,
         }
     }
@@ -40,47 +169,340 @@ impl MailStore {
     {
         Self {
             dir: dir.into(),
-            counter: Arc::new(AtomicU32::new(0)),
+            format: Format::default(),
+            compression: Compression::default(),
+            names: Arc::new(TimeNameGen::default()),
+            clock: Arc::new(SystemClock),
+            recent: None,
+            policy: None,
+            on_commit: None,
+            open: None,
+            capture_rejected: None,
+            recipient_mailboxes: false,
+            mailbox_quota: None,
             state: None,
         }
     }
 
-    pub fn start_message(&mut self) -> io::Result<()> {
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Compress messages as they are written to disk. Only applies to maildir delivery.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Use a different generator for message file names, e.g to get predictable names
+    #[cfg(test)]
+    pub fn with_name_gen<N: NameGen + 'static>(mut self, names: N) -> Self {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// Read the time from a different clock, e.g to get predictable dates
+    #[cfg(test)]
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Hardlink messages with a recently seen Message-ID to the existing file instead of
+    /// writing another copy. Only applies to maildir delivery.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.recent = dedup.then(Arc::default);
+        self
+    }
+
+    /// Check each message with the given policy before it is delivered. A message the
+    /// policy rejects is discarded.
+    pub fn with_policy<P>(mut self, policy: P) -> Self
+    where
+        P: FnMut(&Message) -> Result<(), Response> + Send + 'static,
+    {
+        self.policy = Some(Arc::new(Mutex::new(policy)));
+        self
+    }
+
+    /// Call the given function with the path of each message after it is delivered: the
+    /// file in new/ for maildir delivery or the mailbox file for mbox delivery. The size is
+    /// the number of bytes of the message as received, without prepended headers.
+    pub fn with_on_commit<F>(mut self, on_commit: F) -> Self
+    where
+        F: FnMut(&Path, usize) + Send + 'static,
+    {
+        self.on_commit = Some(Arc::new(Mutex::new(on_commit)));
+        self
+    }
+
+    /// Limit the number of messages written at the same time, across all clones of the
+    /// store. Further messages get a 451 response until a message is finished.
+    pub fn with_max_open_messages(mut self, max: usize) -> Self {
+        self.open = Some(Arc::new(OpenMessages {
+            count: AtomicUsize::new(0),
+            max,
+        }));
+        self
+    }
+
+    /// Keep up to the given number of bytes of each rejected message, with its envelope,
+    /// in the quarantine/ directory for abuse analysis. Messages are rejected by the
+    /// policy or by [`MailStore::end_error()`], a client that disconnects does not count.
+    pub fn with_capture_rejected(mut self, bytes: usize) -> Self {
+        self.capture_rejected = Some(bytes);
+        self
+    }
+
+    /// Deliver a copy of each message to every recipient, in a maildir named after the
+    /// recipient within the mailboxes/ directory. Messages without recipients are
+    /// delivered to the mail directory itself. Only applies to maildir delivery.
+    pub fn with_recipient_mailboxes(mut self, recipient_mailboxes: bool) -> Self {
+        self.recipient_mailboxes = recipient_mailboxes;
+        self
+    }
+
+    /// Refuse to deliver to a recipient mailbox if the messages in it would take more
+    /// than the given number of bytes. Only applies to recipient mailboxes.
+    pub fn with_mailbox_quota(mut self, bytes: u64) -> Self {
+        self.mailbox_quota = Some(bytes);
+        self
+    }
+
+    /// Start a new message from the given envelope. The `prepend_headers`, e.g. a Received
+    /// header, are stored and parsed before the data of the message. They must be complete
+    /// header lines ending with CRLF. Returns the response for the client.
+    pub fn start_message(
+        &mut self,
+        from: &str,
+        to: &[String],
+        prepend_headers: &[u8],
+    ) -> io::Result<Response> {
+        // An unfinished message is abandoned, releasing its permit
+        self.state = None;
+        let permit = match &self.open {
+            Some(open) => match OpenMessages::acquire(open) {
+                Some(permit) => Some(permit),
+                None => {
+                    info!("Too many messages in progress");
+                    return Ok(TOO_MANY_MESSAGES);
+                }
+            },
+            None => None,
+        };
         let mut path = self.dir.clone();
         path.push("tmp");
         fs::create_dir_all(&path)?;
-        let message_file = self.message_file();
+        let compression = match self.format {
+            Format::Maildir => self.compression,
+            Format::Mbox => Compression::None,
+        };
+        let mut message_file = self.names.name(self.clock.now());
+        if compression == Compression::Gzip {
+            message_file.push_str(".gz");
+        }
         path.push(message_file);
         info!("Writing message to {:#?}", path);
-        let file = File::create(&path)?;
-        let writer = BufWriter::new(file);
+        let file = BufWriter::new(File::create(&path)?);
+        let writer = match compression {
+            Compression::None => Sink::Plain(file),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+        };
+        let mut parser = MessageParser::new(writer);
+        for line in prepend_headers.split_inclusive(|c| *c == b'\n') {
+            parser.write_all(line)?;
+        }
         self.state.replace(State {
-            path,
-            parser: MessageParser::new(writer),
+            details: Details {
+                path,
+                from: from.to_owned(),
+                to: to.to_vec(),
+                size: 0,
+                captured: Vec::new(),
+            },
+            parser,
+            _permit: permit,
         });
-        Ok(())
+        Ok(OK)
     }
 
-    pub fn end_message(&mut self) -> io::Result<()> {
-        self.state
-            .take()
-            .map(|state| {
-                let message = state.parser.end();
-                info!("{:#?}", message);
-                commit_message(&state.path)
+    /// Deliver the message, unless the policy rejects it. Returns the response for
+    /// the client, the worst of the responses for each recipient when recipients have
+    /// their own mailboxes.
+    pub fn end_message(&mut self) -> io::Result<Response> {
+        let responses = self.end_message_recipients()?;
+        Ok(worst_response(&responses))
+    }
+
+    /// Deliver the message, unless the policy rejects it. When recipients have their own
+    /// mailboxes, returns a response for each recipient in envelope order, as an LMTP
+    /// server replies to DATA. Otherwise returns one response for the message.
+    pub fn end_message_recipients(&mut self) -> io::Result<Vec<Response>> {
+        let Some(state) = self.state.take() else {
+            return Ok(vec![OK]);
+        };
+        let (message, sink) = state.parser.finish();
+        sink.finish()?;
+        info!("{:#?}", message);
+        let details = state.details;
+        let recipients = match self.format {
+            Format::Maildir if self.recipient_mailboxes => details.to.len().max(1),
+            _ => 1,
+        };
+        if let Err(res) = self.check_policy(&message) {
+            info!("Message rejected by policy");
+            fs::remove_file(&details.path)?;
+            self.quarantine(&details, &format!("{} {}", res.code, res.text()));
+            return Ok(vec![res; recipients]);
+        }
+        if self.format == Format::Maildir && self.recipient_mailboxes && !details.to.is_empty() {
+            return self.deliver_recipients(&details);
+        }
+        let dest = match self.format {
+            Format::Maildir => self.commit_maildir(&details.path, &message)?,
+            Format::Mbox => append_mbox(&details.path, &details.from, self.clock.now())?,
+        };
+        self.committed(&dest, details.size);
+        Ok(vec![OK])
+    }
+
+    // Link the message into the mailbox of each recipient, returns the response for each
+    fn deliver_recipients(&self, details: &Details) -> io::Result<Vec<Response>> {
+        let bytes = fs::metadata(&details.path)?.len();
+        let responses = details
+            .to
+            .iter()
+            .map(|to| {
+                let mailbox = self.dir.join(MAILBOXES_DIR).join(mailbox_name(to));
+                match self.deliver_mailbox(&details.path, &mailbox, bytes) {
+                    Ok(Some(dest)) => {
+                        self.committed(&dest, details.size);
+                        OK
+                    }
+                    Ok(None) => {
+                        info!("Mailbox of {} is full", to);
+                        NO_STORAGE
+                    }
+                    Err(err) => {
+                        error!("Cannot deliver to {}: {}", to, err);
+                        INTERNAL_ERROR
+                    }
+                }
             })
-            .unwrap_or(Ok(()))
+            .collect();
+        fs::remove_file(&details.path)?;
+        Ok(responses)
+    }
+
+    // Returns the path of the delivered message, or None if the mailbox is over quota
+    fn deliver_mailbox(
+        &self,
+        tmp_path: &Path,
+        mailbox: &Path,
+        bytes: u64,
+    ) -> io::Result<Option<PathBuf>> {
+        if let Some(quota) = self.mailbox_quota {
+            if maildir_bytes(mailbox)? + bytes > quota {
+                return Ok(None);
+            }
+        }
+        let filename = tmp_path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+        let mut dest = mailbox.join("new");
+        fs::create_dir_all(&dest)?;
+        dest.push(filename);
+        if fs::hard_link(tmp_path, &dest).is_err() {
+            fs::copy(tmp_path, &dest)?;
+        }
+        Ok(Some(dest))
+    }
+
+    fn committed(&self, dest: &Path, size: usize) {
+        info!("Delivered {} bytes to {:#?}", size, dest);
+        if let Some(on_commit) = &self.on_commit {
+            let mut on_commit = on_commit.lock().unwrap_or_else(|e| e.into_inner());
+            on_commit(dest, size);
+        }
     }
 
     pub fn end_error(&mut self, reason: Reason) {
         if let Some(state) = self.state.take() {
             info!("Aborting message due to {:#?}", reason);
-            _ = fs::remove_file(&state.path);
+            _ = fs::remove_file(&state.details.path);
+            if !matches!(reason, Reason::Eof | Reason::IoError) {
+                self.quarantine(&state.details, &format!("{:?}", reason));
+            }
+        }
+    }
+
+    // Keep the start of a rejected message, if enabled
+    fn quarantine(&self, details: &Details, reason: &str) {
+        if self.capture_rejected.is_none() {
+            return;
+        }
+        if let Err(err) = write_quarantine(&self.dir, details, reason) {
+            error!("Cannot quarantine rejected message: {}", err);
+        }
+    }
+
+    fn check_policy(&self, message: &Message) -> Result<(), Response> {
+        match &self.policy {
+            Some(policy) => {
+                let mut policy = policy.lock().unwrap_or_else(|e| e.into_inner());
+                policy(message)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Returns the path of the delivered message
+    fn commit_maildir(&self, tmp_path: &Path, message: &Message) -> io::Result<PathBuf> {
+        let message_id = message.top().and_then(|p| p.header.message_id.as_deref());
+        let (Some(recent), Some(message_id)) = (&self.recent, message_id) else {
+            return commit_message(tmp_path);
+        };
+        let mut recent = recent.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(existing) = recent.get(message_id) {
+            let dest = new_path(tmp_path)?;
+            // The existing file may have been moved or deleted by a mail reader
+            if fs::hard_link(&existing, &dest).is_ok() {
+                info!("Duplicate message linked to {:#?}", existing);
+                fs::remove_file(tmp_path)?;
+                return Ok(dest);
+            }
         }
+        let dest = commit_message(tmp_path)?;
+        recent.insert(message_id, dest.clone());
+        Ok(dest)
     }
+}
+
+impl OpenMessages {
+    fn acquire(open: &Arc<Self>) -> Option<Permit> {
+        open.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < open.max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| Permit(open.clone()))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
 
-    fn message_file(&self) -> String {
-        let mut filename = SystemTime::now()
+impl NameGen for TimeNameGen {
+    fn name(&self, now: SystemTime) -> String {
+        let mut filename = now
             .duration_since(SystemTime::UNIX_EPOCH)
             .map(|d| d.as_millis().to_string())
             .unwrap_or_else(|_| "0000".to_string());
@@ -95,10 +517,20 @@ impl MailStore {
 
 impl Write for MailStore {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.state
-            .as_mut()
-            .map(|state| state.parser.write(buf))
-            .unwrap_or_else(|| Ok(buf.len()))
+        match &mut self.state {
+            Some(state) => {
+                let written = state.parser.write(buf)?;
+                state.details.size += written;
+                if let Some(capture) = self.capture_rejected {
+                    let wanted = capture
+                        .saturating_sub(state.details.captured.len())
+                        .min(written);
+                    state.details.captured.extend_from_slice(&buf[..wanted]);
+                }
+                Ok(written)
+            }
+            None => Ok(buf.len()),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -109,7 +541,61 @@ impl Write for MailStore {
     }
 }
 
-fn commit_message(tmp_path: &Path) -> io::Result<()> {
+impl Sink {
+    // Write any buffered data and, if compressing, the end of the compressed stream
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Sink::Plain(file) => file,
+            Sink::Gzip(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(file) => file.write(buf),
+            Sink::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.flush(),
+            Sink::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl RecentIds {
+    // Get the file of a message, marking it as recently used
+    fn get(&mut self, message_id: &[u8]) -> Option<PathBuf> {
+        let i = self.entries.iter().position(|(id, _)| id == message_id)?;
+        let entry = self.entries.remove(i)?;
+        let path = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(path)
+    }
+
+    fn insert(&mut self, message_id: &[u8], path: PathBuf) {
+        self.entries.retain(|(id, _)| id != message_id);
+        if self.entries.len() >= DEDUP_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((message_id.to_vec(), path));
+    }
+}
+
+// Move a message from tmp/ to new/ and return its new path
+fn commit_message(tmp_path: &Path) -> io::Result<PathBuf> {
+    let dest = new_path(tmp_path)?;
+    fs::rename(tmp_path, &dest)?;
+    Ok(dest)
+}
+
+// The path in new/ for a message in tmp/, creating new/ if needed
+fn new_path(tmp_path: &Path) -> io::Result<PathBuf> {
     let filename = tmp_path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
     let mut dest = tmp_path.to_path_buf();
     dest.pop();
@@ -117,5 +603,625 @@ fn commit_message(tmp_path: &Path) -> io::Result<()> {
     dest.push("new");
     fs::create_dir_all(&dest)?;
     dest.push(filename);
-    fs::rename(tmp_path, dest)
+    Ok(dest)
+}
+
+// The response for a message with several recipients: the first permanent failure,
+// else the first temporary failure, else success
+fn worst_response(responses: &[Response]) -> Response {
+    responses
+        .iter()
+        .rev()
+        .max_by_key(|res| res.code / 100)
+        .cloned()
+        .unwrap_or(OK)
+}
+
+// The directory of a recipient mailbox, the lowercased address with the bytes that are
+// not safe in a file name percent-encoded, so that distinct addresses never share one
+fn mailbox_name(to: &str) -> String {
+    if to.is_empty() {
+        return "%".to_string();
+    }
+    let mut name = String::with_capacity(to.len());
+    for (i, b) in to.bytes().enumerate() {
+        match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'@' | b'+' | b'-' | b'_' => name.push(b.into()),
+            b'A'..=b'Z' => name.push(b.to_ascii_lowercase().into()),
+            b'.' if i > 0 => name.push('.'),
+            _ => name.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    name
+}
+
+// The bytes taken by the messages in a maildir
+fn maildir_bytes(maildir: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for sub in ["new", "cur"] {
+        let entries = match fs::read_dir(maildir.join(sub)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            bytes += entry?.metadata()?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+// Write the envelope and the captured start of a rejected message to the quarantine
+// directory, in a file named like the message
+fn write_quarantine(dir: &Path, details: &Details, reason: &str) -> io::Result<()> {
+    let name = details
+        .path
+        .file_name()
+        .ok_or(io::ErrorKind::InvalidInput)?;
+    // The captured bytes are not compressed
+    let name = name.to_string_lossy();
+    let mut path = dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&path)?;
+    path.push(name.trim_end_matches(".gz"));
+    let mut file = BufWriter::new(File::create(&path)?);
+    writeln!(file, "Rejected: {}", reason)?;
+    writeln!(file, "Envelope-From: <{}>", details.from)?;
+    for to in &details.to {
+        writeln!(file, "Envelope-To: <{}>", to)?;
+    }
+    writeln!(file, "Received-Bytes: {}", details.size)?;
+    writeln!(file)?;
+    file.write_all(&details.captured)?;
+    file.flush()?;
+    info!(
+        "Quarantined {} bytes in {:#?}",
+        details.captured.len(),
+        path
+    );
+    Ok(())
+}
+
+// Append the message to the mbox file next to the tmp directory and remove the tmp file,
+// returns the path of the mbox file
+fn append_mbox(tmp_path: &Path, from: &str, now: SystemTime) -> io::Result<PathBuf> {
+    let mut mbox_path = tmp_path.to_path_buf();
+    mbox_path.pop();
+    mbox_path.pop();
+    mbox_path.push(MBOX_FILE);
+    let mbox = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&mbox_path)?;
+    // Exclusive lock against concurrent deliveries, released when the file is closed
+    mbox.lock()?;
+    let mut writer = BufWriter::new(&mbox);
+    let sender = if from.is_empty() {
+        "MAILER-DAEMON"
+    } else {
+        from
+    };
+    writeln!(writer, "From {} {}", sender, mbox_date(now))?;
+    let mut reader = BufReader::new(File::open(tmp_path)?);
+    let mut line = Vec::with_capacity(80);
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let content = line
+            .strip_suffix(b"\r\n")
+            .or_else(|| line.strip_suffix(b"\n"))
+            .unwrap_or(&line);
+        // mboxrd escaping of From_ lines at any quoting depth
+        if is_from_line(content) {
+            writer.write_all(b">")?;
+        }
+        writer.write_all(content)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    fs::remove_file(tmp_path)?;
+    Ok(mbox_path)
+}
+
+// Does the line start with "From ", possibly quoted with any number of '>'
+fn is_from_line(line: &[u8]) -> bool {
+    let quotes = line.iter().take_while(|c| **c == b'>').count();
+    line[quotes..].starts_with(b"From ")
+}
+
+/// A Received trace header (RFC 5321 section 4.4) for a message that arrived with SMTP
+/// from the client at the given address at the time `now`
+pub fn received_header(helo_domain: &str, ip: IpAddr, server: &str, now: SystemTime) -> Vec<u8> {
+    let date_format = format_description!(
+        "[weekday repr:short], [day padding:none] [month repr:short] [year] [hour]:[minute]:[second] +0000"
+    );
+    let now = OffsetDateTime::from(now);
+    let date = now.format(&date_format).unwrap_or_else(|_| now.to_string());
+    // An address literal, RFC 5321 section 4.1.3
+    let ip = match ip {
+        IpAddr::V4(ip) => format!("[{ip}]"),
+        IpAddr::V6(ip) => format!("[IPv6:{ip}]"),
+    };
+    format!("Received: from {helo_domain} ({ip}) by {server} with SMTP; {date}\r\n").into_bytes()
+}
+
+/// A policy that rejects messages with attachments whose file names end with one of
+/// the given extensions, e.g "exe". Extensions are case insensitive.
+pub fn attachment_policy(extensions: Vec<String>) -> impl FnMut(&Message) -> Result<(), Response> {
+    let suffixes: Vec<String> = extensions
+        .iter()
+        .map(|ext| format!(".{}", ext.trim_start_matches('.').to_ascii_lowercase()))
+        .collect();
+    move |message| {
+        let rejected = message.attachments().any(|part| {
+            let filename = part
+                .content_disposition
+                .as_ref()
+                .and_then(|d| d.parameters.get(b"filename".as_slice()));
+            filename.is_some_and(|name| {
+                let name = name.to_ascii_lowercase();
+                suffixes
+                    .iter()
+                    .any(|suffix| name.ends_with(suffix.as_bytes()))
+            })
+        });
+        if rejected {
+            Err(ATTACHMENT_REJECTED)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs a command with the path and the size of each delivered message as its arguments.
+/// The command runs in the background, delivery does not wait for it.
+///
+/// The size is a second argument added after the path, commands that expect exactly
+/// one argument have to be updated.
+pub fn on_commit_command(command: String) -> impl FnMut(&Path, usize) {
+    move |path, size| match Command::new(&command)
+        .arg(path)
+        .arg(size.to_string())
+        .spawn()
+    {
+        // Reap the command when it exits
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(err) => error!("Cannot run {}: {}", command, err),
+    }
+}
+
+// The date in the asctime format used by mbox From_ lines
+fn mbox_date(now: SystemTime) -> String {
+    let date_format = format_description!(
+        "[weekday repr:short] [month repr:short] [day padding:space] [hour]:[minute]:[second] [year]"
+    );
+    let now = OffsetDateTime::from(now);
+    now.format(&date_format).unwrap_or_else(|_| now.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin_embedded::{Handler, Server, Stream};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // A fresh directory for a test, unique per crate as this module is shared
+    fn test_dir(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "{}-{}-{}",
+            env!("CARGO_PKG_NAME"),
+            name,
+            process::id()
+        ));
+        _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn deliver(store: &mut MailStore, from: &str, message: &[u8]) {
+        store.start_message(from, &[], b"").unwrap();
+        for line in message.split_inclusive(|c| *c == b'\n') {
+            store.write_all(line).unwrap();
+        }
+        store.end_message().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dedup() {
+        use std::collections::HashSet;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("dedup");
+        let mut store = MailStore::new(&dir).with_dedup(true);
+        let message = b"Message-ID: <fanout.1@sea.com>\r\nSubject: fan out\r\n\r\nHello\r\n";
+        deliver(&mut store.clone(), "ship@sea.com", message);
+        deliver(&mut store.clone(), "ship@sea.com", message);
+        deliver(
+            &mut store,
+            "ship@sea.com",
+            b"Message-ID: <other.2@sea.com>\r\n\r\nHello\r\n",
+        );
+        let files: Vec<PathBuf> = fs::read_dir(dir.join("new"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 3);
+        // The duplicate is a second name for the same underlying file
+        let inodes: HashSet<u64> = files
+            .iter()
+            .map(|f| fs::metadata(f).unwrap().ino())
+            .collect();
+        assert_eq!(inodes.len(), 2);
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Names messages msg.0, msg.1, ...
+    #[derive(Default)]
+    struct SequenceNameGen {
+        next: AtomicU32,
+    }
+
+    impl NameGen for SequenceNameGen {
+        fn name(&self, _now: SystemTime) -> String {
+            format!("msg.{}", self.next.fetch_add(1, Ordering::Relaxed))
+        }
+    }
+
+    // Always returns the same time
+    struct MockClock(SystemTime);
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    #[test]
+    fn mock_clock() {
+        let dir = test_dir("clock");
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let store = MailStore::new(&dir).with_clock(MockClock(now));
+        deliver(&mut store.clone(), "ship@sea.com", b"Subject: one\r\n\r\n");
+        let name = fs::read_dir(dir.join("new"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .file_name();
+        assert!(name.to_str().unwrap().starts_with("1700000000000."));
+        let mut store = store.with_format(Format::Mbox);
+        deliver(&mut store, "ship@sea.com", b"Subject: two\r\n\r\n");
+        let mbox = fs::read_to_string(dir.join(MBOX_FILE)).unwrap();
+        assert!(mbox.starts_with("From ship@sea.com Tue Nov 14 22:13:20 2023\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn maildir_delivery() {
+        let dir = test_dir("maildir");
+        let mut store = MailStore::new(&dir).with_name_gen(SequenceNameGen::default());
+        deliver(&mut store.clone(), "ship@sea.com", b"Subject: one\r\n\r\n");
+        deliver(&mut store, "ship@sea.com", b"Subject: two\r\n\r\n");
+        let second = fs::read(dir.join("new").join("msg.1")).unwrap();
+        assert_eq!(second, b"Subject: two\r\n\r\n");
+        assert!(dir.join("new").join("msg.0").is_file());
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A client that sends a fixed session and ignores the replies
+    #[derive(Debug)]
+    struct Client(io::Cursor<Vec<u8>>);
+
+    impl io::Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for Client {}
+
+    // Receives messages over SMTP into the store
+    struct StoreHandler(MailStore);
+
+    impl Handler for StoreHandler {
+        fn data_start(
+            &mut self,
+            domain: &str,
+            from: &str,
+            _is8bit: bool,
+            to: &[String],
+        ) -> Response {
+            let received = received_header(domain, LOCALHOST, "mail.sea.com", SystemTime::now());
+            self.0.start_message(from, to, &received).unwrap()
+        }
+
+        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.0.write_all(buf)
+        }
+
+        fn data_end(&mut self) -> Response {
+            self.0.end_message().unwrap()
+        }
+    }
+
+    #[test]
+    fn on_commit() {
+        let dir = test_dir("on_commit");
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let paths = committed.clone();
+        let store = MailStore::new(&dir)
+            .with_name_gen(SequenceNameGen::default())
+            .with_on_commit(move |path, size| {
+                paths.lock().unwrap().push((path.to_path_buf(), size))
+            });
+        deliver(&mut store.clone(), "ship@sea.com", b"Subject: one\r\n\r\n");
+        // The size of the data after dot-unstuffing, not counting prepended headers
+        let session = b"helo a.domain\r\n\
+            mail from:<ship@sea.com>\r\n\
+            rcpt to:<fish@sea.com>\r\n\
+            data\r\n\
+            Subject: two\r\n\
+            \r\n\
+            ..dot\r\n\
+            .\r\n\
+            quit\r\n";
+        Server::new(StoreHandler(store))
+            .execute(Client(io::Cursor::new(session.to_vec())), LOCALHOST)
+            .unwrap();
+        let committed = committed.lock().unwrap();
+        assert_eq!(
+            *committed,
+            vec![
+                (dir.join("new").join("msg.0"), 16),
+                (dir.join("new").join("msg.1"), 22)
+            ]
+        );
+        assert!(committed.iter().all(|(path, _)| path.is_file()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prepend_headers() {
+        let dir = test_dir("prepend");
+        let mut store = MailStore::new(&dir).with_name_gen(SequenceNameGen::default());
+        let received =
+            b"Received: from a.domain by mail.sea.com; Mon, 1 Jan 2024 00:00:00 +0000\r\n";
+        store.start_message("ship@sea.com", &[], received).unwrap();
+        store.write_all(b"Subject: traced\r\n").unwrap();
+        store.write_all(b"\r\n").unwrap();
+        store.write_all(b"Hello\r\n").unwrap();
+        store.end_message().unwrap();
+        let stored = fs::read(dir.join("new").join("msg.0")).unwrap();
+        assert!(stored.starts_with(received));
+        assert_eq!(
+            &stored[received.len()..],
+            b"Subject: traced\r\n\r\nHello\r\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn policy() {
+        let dir = test_dir("policy");
+        let mut store =
+            MailStore::new(&dir).with_policy(attachment_policy(vec!["exe".to_string()]));
+        let message = b"Subject: Tool\r\n\
+            Content-Type: multipart/mixed; boundary=\"tool\"\r\n\
+            \r\n\
+            --tool\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Run this\r\n\
+            --tool\r\n\
+            Content-Type: application/octet-stream\r\n\
+            Content-Disposition: attachment; filename=\"SETUP.EXE\"\r\n\
+            \r\n\
+            TVqQAAMAAAAEAAAA\r\n\
+            --tool--\r\n";
+        store.start_message("ship@sea.com", &[], b"").unwrap();
+        for line in message.split_inclusive(|c| *c == b'\n') {
+            store.write_all(line).unwrap();
+        }
+        assert_eq!(store.end_message().unwrap(), ATTACHMENT_REJECTED);
+        assert!(!dir.join("new").exists());
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        // Other messages are delivered
+        store.start_message("ship@sea.com", &[], b"").unwrap();
+        store.write_all(b"Subject: Text\r\n\r\n").unwrap();
+        assert_eq!(store.end_message().unwrap(), OK);
+        assert_eq!(fs::read_dir(dir.join("new")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mailbox_full() {
+        let dir = test_dir("mailbox_full");
+        let mut store = MailStore::new(&dir)
+            .with_name_gen(SequenceNameGen::default())
+            .with_recipient_mailboxes(true)
+            .with_mailbox_quota(100);
+        let message = b"Subject: fan out\r\n\r\nA message of sixty bytes, or so.......\r\n";
+        assert_eq!(message.len(), 60);
+        let mut send = |to: &[&str]| {
+            let to: Vec<String> = to.iter().map(|to| to.to_string()).collect();
+            store.start_message("ship@sea.com", &to, b"").unwrap();
+            store.write_all(message).unwrap();
+            store.end_message_recipients().unwrap()
+        };
+        assert_eq!(send(&["Fish@sea.com"]), vec![OK]);
+        // The mailbox of fish has no room for a second message
+        assert_eq!(
+            send(&["fish@sea.com", "crab@sea.com"]),
+            vec![NO_STORAGE, OK]
+        );
+        let delivered = |mailbox: &str, name: &str| {
+            dir.join(MAILBOXES_DIR)
+                .join(mailbox)
+                .join("new")
+                .join(name)
+                .exists()
+        };
+        assert!(delivered("fish@sea.com", "msg.0"));
+        assert!(!delivered("fish@sea.com", "msg.1"));
+        assert!(delivered("crab@sea.com", "msg.1"));
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        // SMTP gets the worst response
+        let to = vec!["whale@sea.com".to_string(), "crab@sea.com".to_string()];
+        store.start_message("ship@sea.com", &to, b"").unwrap();
+        store.write_all(message).unwrap();
+        assert_eq!(store.end_message().unwrap(), NO_STORAGE);
+        assert!(delivered("whale@sea.com", "msg.2"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mailbox_names() {
+        assert_eq!(mailbox_name("Fish.Cake@Sea.com"), "fish.cake@sea.com");
+        assert_eq!(mailbox_name("../tmp/x@sea.com"), "%2E.%2Ftmp%2Fx@sea.com");
+        assert_eq!(mailbox_name("postmaster"), "postmaster");
+        assert_ne!(mailbox_name("a/b@sea.com"), mailbox_name("a_b@sea.com"));
+        assert_eq!(mailbox_name("100%@sea.com"), "100%25@sea.com");
+        assert_eq!(mailbox_name(""), "%");
+    }
+
+    #[test]
+    fn capture_rejected() {
+        let dir = test_dir("capture_rejected");
+        let mut store = MailStore::new(&dir)
+            .with_name_gen(SequenceNameGen::default())
+            .with_capture_rejected(25);
+        let to = vec!["fish@sea.com".to_string(), "crab@sea.com".to_string()];
+        let received = received_header("a.domain", LOCALHOST, "mail.sea.com", SystemTime::now());
+        store.start_message("ship@sea.com", &to, &received).unwrap();
+        store.write_all(b"Subject: Buy now\r\n").unwrap();
+        store.write_all(b"\r\n").unwrap();
+        store.write_all(b"Cheap watches\r\n").unwrap();
+        store.end_error(Reason::MaxSizeExceeded);
+        let captured = fs::read(dir.join(QUARANTINE_DIR).join("msg.0")).unwrap();
+        assert_eq!(
+            captured,
+            b"Rejected: MaxSizeExceeded\n\
+            Envelope-From: <ship@sea.com>\n\
+            Envelope-To: <fish@sea.com>\n\
+            Envelope-To: <crab@sea.com>\n\
+            Received-Bytes: 35\n\
+            \n\
+            Subject: Buy now\r\n\r\nCheap"
+        );
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        // A client that disconnects is not rejected
+        store.start_message("ship@sea.com", &to, b"").unwrap();
+        store.write_all(b"Subject: Lost\r\n").unwrap();
+        store.end_error(Reason::Eof);
+        assert!(!dir.join(QUARANTINE_DIR).join("msg.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_open_messages() {
+        let dir = test_dir("max_open_messages");
+        let mut first = MailStore::new(&dir).with_max_open_messages(2);
+        let mut second = first.clone();
+        let mut third = first.clone();
+        assert_eq!(first.start_message("ship@sea.com", &[], b"").unwrap(), OK);
+        assert_eq!(second.start_message("ship@sea.com", &[], b"").unwrap(), OK);
+        assert_eq!(
+            third.start_message("ship@sea.com", &[], b"").unwrap(),
+            TOO_MANY_MESSAGES
+        );
+        // Released when a message is delivered
+        first.write_all(b"Subject: First\r\n\r\n").unwrap();
+        assert_eq!(first.end_message().unwrap(), OK);
+        assert_eq!(third.start_message("ship@sea.com", &[], b"").unwrap(), OK);
+        assert_eq!(
+            first.start_message("ship@sea.com", &[], b"").unwrap(),
+            TOO_MANY_MESSAGES
+        );
+        // Released when a message is aborted
+        second.end_error(Reason::Eof);
+        assert_eq!(first.start_message("ship@sea.com", &[], b"").unwrap(), OK);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn received() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let received = |ip| String::from_utf8(received_header("a.domain", ip, "mail.sea.com", now));
+        assert_eq!(
+            received(LOCALHOST).unwrap(),
+            "Received: from a.domain ([127.0.0.1]) by mail.sea.com with SMTP; \
+            Sun, 9 Sep 2001 01:46:40 +0000\r\n"
+        );
+        assert_eq!(
+            received(IpAddr::V6(Ipv6Addr::LOCALHOST)).unwrap(),
+            "Received: from a.domain ([IPv6:::1]) by mail.sea.com with SMTP; \
+            Sun, 9 Sep 2001 01:46:40 +0000\r\n"
+        );
+    }
+
+    #[test]
+    fn gzip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = test_dir("gzip");
+        let mut store = MailStore::new(&dir).with_compression(Compression::Gzip);
+        let message = b"Subject: squeeze\r\n\r\nHello\r\nHello again\r\n";
+        deliver(&mut store, "ship@sea.com", message);
+        let files: Vec<PathBuf> = fs::read_dir(dir.join("new"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "gz");
+        let mut stored = Vec::new();
+        GzDecoder::new(File::open(&files[0]).unwrap())
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, message);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mbox_delivery() {
+        let dir = test_dir("mbox");
+        let mut store = MailStore::new(&dir).with_format(Format::Mbox);
+        deliver(
+            &mut store,
+            "ship@sea.com",
+            b"Subject: first\r\n\r\nFrom the sea\r\n>From the deep\r\n",
+        );
+        deliver(&mut store, "", b"Subject: second\r\n\r\nHello\r\n");
+        let mbox = fs::read_to_string(dir.join(MBOX_FILE)).unwrap();
+        let separators: Vec<&str> = mbox.lines().filter(|l| l.starts_with("From ")).collect();
+        assert_eq!(separators.len(), 2);
+        assert!(separators[0].starts_with("From ship@sea.com "));
+        assert!(separators[1].starts_with("From MAILER-DAEMON "));
+        assert!(mbox.contains("\n>From the sea\n>>From the deep\n\n"));
+        assert!(mbox.ends_with("Subject: second\n\nHello\n\n"));
+        assert!(!mbox.contains('\r'));
+        // Temporary files are removed after delivery
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,55 @@
+//! Drive an SMTP session without a network connection.
+//!
+//! The client input is read in small chunks from a `Cursor`, as it might arrive
+//! from an event loop, and the responses are collected into a buffer.
+use mailin::{Action, Handler, SessionBuilder};
+use std::io::{self, BufRead, BufReader, Cursor};
+use std::net::{IpAddr, Ipv4Addr};
+
+struct PrintHandler {}
+
+impl Handler for PrintHandler {
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        print!("data: {}", String::from_utf8_lossy(buf));
+        Ok(())
+    }
+}
+
+fn main() -> io::Result<()> {
+    let input = b"EHLO client.example.com\r\n\
+        MAIL FROM:<sender@example.com>\r\n\
+        RCPT TO:<recipient@example.com>\r\n\
+        DATA\r\n\
+        Subject: Hello\r\n\
+        \r\n\
+        Hello from a cursor\r\n\
+        .\r\n\
+        QUIT\r\n";
+    // A small buffer splits the input part way through lines
+    let mut reader = BufReader::with_capacity(16, Cursor::new(&input[..]));
+    let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut session = SessionBuilder::new("server.example.com").build(addr, PrintHandler {});
+    let mut output = Vec::new();
+    session.greeting().write_to(&mut output)?;
+    'session: loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            session.eof();
+            break;
+        }
+        let len = chunk.len();
+        let responses = session.feed(chunk);
+        reader.consume(len);
+        for response in responses {
+            // A dropped connection gets no reply
+            if response.action != Action::Drop {
+                response.write_to(&mut output)?;
+            }
+            if response.closes() {
+                break 'session;
+            }
+        }
+    }
+    print!("{}", String::from_utf8_lossy(&output));
+    Ok(())
+}
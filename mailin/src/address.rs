@@ -0,0 +1,315 @@
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Length limits from RFC 5321 section 4.5.3.1
+const MAX_LOCAL_PART: usize = 64;
+const MAX_DOMAIN: usize = 255;
+const MAX_LABEL: usize = 63;
+
+/// A mailbox address that has been validated against the RFC 5321 syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    /// The part before the '@', including quotes if it is a quoted string
+    pub local_part: String,
+    /// The part after the '@'
+    pub domain: Domain,
+}
+
+/// The domain of a mailbox address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Domain {
+    /// A domain name e.g example.com
+    Name(String),
+    /// An address literal e.g `[192.0.2.1]` or `[IPv6:2001:db8::1]`
+    Literal(IpAddr),
+}
+
+/// How addresses are normalized, see [`normalize()`]. The domain is always lowercased.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Normalization {
+    /// Lowercase the local part, which RFC 5321 allows to be case sensitive
+    pub lowercase_local_part: bool,
+    /// Remove a `+tag` from the local part, e.g `user+news` becomes `user`
+    pub strip_tag: bool,
+}
+
+/// Reason a mailbox address is invalid
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    /// The address is empty, e.g the null reverse path `<>`
+    Empty,
+    /// There is no '@' separating the local part and domain
+    MissingAt,
+    /// The local part is malformed or too long
+    InvalidLocalPart,
+    /// The domain is malformed or too long
+    InvalidDomain,
+}
+
+/// Parse and validate a mailbox address.
+///
+/// Surrounding angle brackets are removed before validation. The null path `<>`
+/// is reported as [`AddressError::Empty`], handlers that accept a null reverse
+/// path should check for it first.
+///
+/// # Examples
+/// ```
+/// use mailin::address::{parse, AddressError, Domain};
+///
+/// let address = parse("<user@example.com>").unwrap();
+/// assert_eq!(address.local_part, "user");
+/// assert_eq!(address.domain, Domain::Name("example.com".to_string()));
+/// assert_eq!(parse("<>"), Err(AddressError::Empty));
+/// ```
+pub fn parse(path: &str) -> Result<Address, AddressError> {
+    let path = path
+        .strip_prefix('<')
+        .and_then(|p| p.strip_suffix('>'))
+        .unwrap_or(path);
+    if path.is_empty() {
+        return Err(AddressError::Empty);
+    }
+    let (local_part, domain) = path.rsplit_once('@').ok_or(AddressError::MissingAt)?;
+    if !is_local_part(local_part) {
+        return Err(AddressError::InvalidLocalPart);
+    }
+    let domain = parse_domain(domain).ok_or(AddressError::InvalidDomain)?;
+    Ok(Address {
+        local_part: local_part.to_string(),
+        domain,
+    })
+}
+
+/// Normalize an address for mailbox lookup.
+///
+/// The domain is lowercased, the local part is changed as configured. A quoted local
+/// part is left as it is. The address does not have to be valid.
+///
+/// # Examples
+/// ```
+/// use mailin::address::{normalize, Normalization};
+///
+/// let normalization = Normalization {
+///     strip_tag: true,
+///     ..Default::default()
+/// };
+/// assert_eq!(normalize("User+News@Example.COM", normalization), "User@example.com");
+/// ```
+pub fn normalize(path: &str, normalization: Normalization) -> String {
+    let (local_part, domain) = match path.rsplit_once('@') {
+        Some((local_part, domain)) => (local_part, Some(domain)),
+        None => (path, None),
+    };
+    let mut normalized = if local_part.starts_with('"') {
+        local_part.to_string()
+    } else {
+        let local_part = match local_part.split_once('+') {
+            Some((user, _)) if normalization.strip_tag && !user.is_empty() => user,
+            _ => local_part,
+        };
+        if normalization.lowercase_local_part {
+            local_part.to_ascii_lowercase()
+        } else {
+            local_part.to_string()
+        }
+    };
+    if let Some(domain) = domain {
+        normalized.push('@');
+        normalized.push_str(&domain.to_ascii_lowercase());
+    }
+    normalized
+}
+
+fn is_local_part(local_part: &str) -> bool {
+    if local_part.is_empty() || local_part.len() > MAX_LOCAL_PART {
+        return false;
+    }
+    match local_part
+        .strip_prefix('"')
+        .and_then(|l| l.strip_suffix('"'))
+    {
+        Some(quoted) => is_quoted_content(quoted),
+        None => is_dot_string(local_part),
+    }
+}
+
+// Dot-string = Atom *("."  Atom)
+fn is_dot_string(s: &str) -> bool {
+    s.split('.')
+        .all(|atom| !atom.is_empty() && atom.bytes().all(is_atext))
+}
+
+// The content of a Quoted-string, made of qtextSMTP and quoted-pairSMTP
+fn is_quoted_content(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => match bytes.next() {
+                Some(32..=126) => (),
+                _ => return false,
+            },
+            b'"' => return false,
+            32..=126 => (),
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn is_atext(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&c)
+}
+
+fn parse_domain(domain: &str) -> Option<Domain> {
+    if let Some(literal) = domain.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        return parse_address_literal(literal).map(Domain::Literal);
+    }
+    if domain.is_empty() || domain.len() > MAX_DOMAIN {
+        return None;
+    }
+    domain
+        .split('.')
+        .all(is_label)
+        .then(|| Domain::Name(domain.to_string()))
+}
+
+fn is_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+}
+
+pub(crate) fn parse_address_literal(literal: &str) -> Option<IpAddr> {
+    match literal.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+            literal[5..].parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+        }
+        _ => literal.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.domain {
+            Domain::Name(name) => write!(f, "{}@{}", self.local_part, name),
+            Domain::Literal(IpAddr::V4(ip)) => write!(f, "{}@[{}]", self.local_part, ip),
+            Domain::Literal(IpAddr::V6(ip)) => write!(f, "{}@[IPv6:{}]", self.local_part, ip),
+        }
+    }
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            AddressError::Empty => "Empty address",
+            AddressError::MissingAt => "Address has no domain",
+            AddressError::InvalidLocalPart => "Invalid local part",
+            AddressError::InvalidDomain => "Invalid domain",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for AddressError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        for (path, local_part, domain) in [
+            ("ship@sea.com", "ship", "sea.com"),
+            ("<ship@sea.com>", "ship", "sea.com"),
+            (
+                "first.last+tag@mail.sea-side.com",
+                "first.last+tag",
+                "mail.sea-side.com",
+            ),
+            ("\"two words\"@sea.com", "\"two words\"", "sea.com"),
+            ("\"a\\\"b\"@sea.com", "\"a\\\"b\"", "sea.com"),
+            (
+                "!#$%&'*+-/=?^_`{|}~@localhost",
+                "!#$%&'*+-/=?^_`{|}~",
+                "localhost",
+            ),
+        ] {
+            let address = parse(path).unwrap();
+            assert_eq!(address.local_part, local_part);
+            assert_eq!(address.domain, Domain::Name(domain.to_string()));
+        }
+    }
+
+    #[test]
+    fn address_literal() {
+        let address = parse("postmaster@[192.0.2.1]").unwrap();
+        assert_eq!(
+            address.domain,
+            Domain::Literal("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(address.to_string(), "postmaster@[192.0.2.1]");
+        let address = parse("postmaster@[IPv6:2001:db8::1]").unwrap();
+        assert_eq!(
+            address.domain,
+            Domain::Literal("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(address.to_string(), "postmaster@[IPv6:2001:db8::1]");
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse("<>"), Err(AddressError::Empty));
+        assert_eq!(parse(""), Err(AddressError::Empty));
+    }
+
+    #[test]
+    fn malformed() {
+        let long_local = format!("{}@sea.com", "a".repeat(65));
+        let long_label = format!("ship@{}.com", "a".repeat(64));
+        for (path, err) in [
+            ("ship", AddressError::MissingAt),
+            ("@sea.com", AddressError::InvalidLocalPart),
+            (".ship@sea.com", AddressError::InvalidLocalPart),
+            ("sh..ip@sea.com", AddressError::InvalidLocalPart),
+            ("sh ip@sea.com", AddressError::InvalidLocalPart),
+            ("\"unterminated@sea.com", AddressError::InvalidLocalPart),
+            (&long_local, AddressError::InvalidLocalPart),
+            ("ship@", AddressError::InvalidDomain),
+            ("ship@sea..com", AddressError::InvalidDomain),
+            ("ship@-sea.com", AddressError::InvalidDomain),
+            ("ship@sea_side.com", AddressError::InvalidDomain),
+            ("ship@[300.0.0.1]", AddressError::InvalidDomain),
+            ("ship@[2001:db8::1]", AddressError::InvalidDomain),
+            (&long_label, AddressError::InvalidDomain),
+        ] {
+            assert_eq!(parse(path), Err(err), "{path}");
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        let all = Normalization {
+            lowercase_local_part: true,
+            strip_tag: true,
+        };
+        for (path, normalization, expected) in [
+            (
+                "User@Example.COM",
+                Normalization::default(),
+                "User@example.com",
+            ),
+            ("User+Tag@Example.COM", all, "user@example.com"),
+            ("\"Two+Words\"@SEA.com", all, "\"Two+Words\"@sea.com"),
+            ("+tag@sea.com", all, "+tag@sea.com"),
+            ("Postmaster", all, "postmaster"),
+        ] {
+            assert_eq!(normalize(path, normalization), expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt::Write;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The envelope of a message that could not be delivered
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    /// Name of the server generating the report, used in the Reporting-MTA field
+    pub reporting_mta: String,
+    /// The reverse path (MAIL FROM) of the original message, the bounce is sent here
+    pub from: String,
+    /// The ENVID parameter given with MAIL FROM, if any
+    pub envid: Option<String>,
+    /// The header of the original message, returned to the sender if not empty
+    pub headers: Vec<u8>,
+}
+
+/// A recipient that could not be delivered to
+#[derive(Debug, Clone, Default)]
+pub struct FailedRecipient {
+    /// The recipient address
+    pub address: String,
+    /// The ORCPT parameter given with RCPT TO, e.g. "rfc822;user@example.com"
+    pub original_recipient: Option<String>,
+    /// Enhanced status code (RFC 3463) e.g. "5.1.1"
+    pub status: String,
+}
+
+impl FailedRecipient {
+    /// Create a failed recipient with a generic permanent failure status
+    pub fn new<S: Into<String>>(address: S) -> Self {
+        Self {
+            address: address.into(),
+            original_recipient: None,
+            status: "5.0.0".to_string(),
+        }
+    }
+}
+
+/// Generate a bounce message as a `multipart/report` delivery status notification (RFC 3464).
+///
+/// The `diagnostic` is the SMTP reply that caused the failure e.g "550 Mailbox unavailable".
+///
+/// The caller decides whether a bounce should be sent at all. No bounce should be generated
+/// for a message with a null reverse path, or for recipients whose NOTIFY parameter does not
+/// include FAILURE.
+pub fn generate_bounce(
+    original_envelope: &Envelope,
+    failed_recipients: &[FailedRecipient],
+    diagnostic: &str,
+) -> Vec<u8> {
+    let mta = &original_envelope.reporting_mta;
+    let boundary = boundary(mta);
+    let mut out = String::new();
+    // Writing to a String cannot fail
+    let _ = write!(
+        out,
+        "From: Mail Delivery System <MAILER-DAEMON@{mta}>\r\n\
+         To: <{}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n \
+         boundary=\"{boundary}\"\r\n\
+         \r\n\
+         This is a MIME-encapsulated message.\r\n\
+         \r\n",
+        original_envelope.from
+    );
+
+    // Human readable explanation
+    let _ = write!(
+        out,
+        "--{boundary}\r\n\
+         Content-Type: text/plain; charset=us-ascii\r\n\
+         \r\n\
+         This is the mail system at host {mta}.\r\n\
+         \r\n\
+         Your message could not be delivered to the following recipients:\r\n\
+         \r\n"
+    );
+    for rcpt in failed_recipients {
+        let _ = write!(out, "<{}>: {diagnostic}\r\n", rcpt.address);
+    }
+    out.push_str("\r\n");
+
+    // Machine readable delivery status
+    let _ = write!(
+        out,
+        "--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {mta}\r\n"
+    );
+    if let Some(envid) = &original_envelope.envid {
+        let _ = write!(out, "Original-Envelope-Id: {envid}\r\n");
+    }
+    for rcpt in failed_recipients {
+        out.push_str("\r\n");
+        let _ = write!(out, "Final-Recipient: rfc822; {}\r\n", rcpt.address);
+        if let Some(orcpt) = &rcpt.original_recipient {
+            let _ = write!(out, "Original-Recipient: {orcpt}\r\n");
+        }
+        let _ = write!(
+            out,
+            "Action: failed\r\n\
+             Status: {}\r\n\
+             Diagnostic-Code: smtp; {diagnostic}\r\n",
+            rcpt.status
+        );
+    }
+    out.push_str("\r\n");
+
+    let mut out = out.into_bytes();
+    if !original_envelope.headers.is_empty() {
+        let part = format!("--{boundary}\r\nContent-Type: text/rfc822-headers\r\n\r\n");
+        out.extend_from_slice(part.as_bytes());
+        out.extend_from_slice(&original_envelope.headers);
+        if !original_envelope.headers.ends_with(b"\r\n") {
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    out.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    out
+}
+
+// Create a MIME boundary that is unlikely to appear in the report
+fn boundary(mta: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{}.{}/{}", nanos, process::id(), mta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounce() {
+        let envelope = Envelope {
+            reporting_mta: "mx.example.com".to_string(),
+            from: "ship@sea.com".to_string(),
+            envid: Some("QQ314159".to_string()),
+            headers: b"Subject: Hello\r\nFrom: ship@sea.com\r\n".to_vec(),
+        };
+        let failed = vec![
+            FailedRecipient {
+                original_recipient: Some("rfc822;Fish@sea.com".to_string()),
+                status: "5.1.1".to_string(),
+                ..FailedRecipient::new("fish@sea.com")
+            },
+            FailedRecipient::new("kraken@sea.com"),
+        ];
+        let bounce = generate_bounce(&envelope, &failed, "550 Mailbox unavailable");
+        let bounce = String::from_utf8(bounce).unwrap();
+
+        assert!(bounce.starts_with("From: Mail Delivery System <MAILER-DAEMON@mx.example.com>\r\n"));
+        assert!(bounce.contains("To: <ship@sea.com>\r\n"));
+        assert!(bounce.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+        let status = bounce
+            .split("Content-Type: message/delivery-status\r\n\r\n")
+            .nth(1)
+            .unwrap();
+        assert!(status.starts_with(
+            "Reporting-MTA: dns; mx.example.com\r\n\
+             Original-Envelope-Id: QQ314159\r\n\
+             \r\n\
+             Final-Recipient: rfc822; fish@sea.com\r\n\
+             Original-Recipient: rfc822;Fish@sea.com\r\n\
+             Action: failed\r\n\
+             Status: 5.1.1\r\n\
+             Diagnostic-Code: smtp; 550 Mailbox unavailable\r\n\
+             \r\n\
+             Final-Recipient: rfc822; kraken@sea.com\r\n\
+             Action: failed\r\n\
+             Status: 5.0.0\r\n"
+        ));
+        assert_eq!(bounce.matches("Action: failed\r\n").count(), 2);
+        assert!(bounce.contains("Content-Type: text/rfc822-headers\r\n\r\nSubject: Hello\r\n"));
+        assert!(bounce.ends_with("--\r\n"));
+    }
+}
@@ -1,14 +1,25 @@
+use crate::address::{self, Normalization};
 use crate::parser::{decode_sasl_login, decode_sasl_plain, parse, parse_auth_response};
 use crate::response::*;
 
-use crate::smtp::Cmd;
-use crate::{AuthMechanism, Handler, Reason, Response};
+use crate::smtp::{Cmd, SessionBuilder};
+use crate::{
+    AuthMechanism, BodyType, DeliverByMode, Extension, Handler, OptionalCommand, Phase, Reason,
+    Response,
+};
 use either::*;
-use log::{error, trace};
-use std::borrow::BorrowMut;
-use std::net::IpAddr;
+use log::{debug, error, trace};
+#[cfg(feature = "digest")]
+use sha2::{Digest, Sha256};
+use std::borrow::{BorrowMut, Cow};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
 use ternop::ternary;
 
+// Longest verb that is echoed in error responses
+const MAX_VERB: usize = 16;
+
 #[cfg(test)]
 #[derive(Debug)]
 pub(crate) enum SmtpState {
@@ -29,6 +40,7 @@ enum TlsState {
     Active,
 }
 
+#[derive(PartialEq)]
 enum AuthState {
     Unavailable,
     RequiresAuth,
@@ -39,6 +51,9 @@ trait State<H: Handler>: Send + Sync {
     #[cfg(test)]
     fn id(&self) -> SmtpState;
 
+    // The phase of the session reported to users
+    fn phase(&self) -> Phase;
+
     // Handle an incoming command and return the next state
     fn handle(
         self: Box<Self>,
@@ -55,6 +70,12 @@ trait State<H: Handler>: Send + Sync {
         parse(line).map(Left).unwrap_or_else(Right)
     }
 
+    // Does this state parse lines as commands? Otherwise lines are data or
+    // credentials that must not be echoed back to the client.
+    fn parses_commands(&self) -> bool {
+        true
+    }
+
     fn io_error(&mut self, _handler: &mut H) {}
 
     fn eof(&mut self, _handler: &mut H) {}
@@ -71,7 +92,7 @@ fn next_state<F, H>(
 where
     F: FnOnce() -> Box<dyn State<H>>,
 {
-    if res.action == Action::Close {
+    if res.closes() {
         (res, None)
     } else if res.is_error {
         (res, Some(current))
@@ -90,7 +111,7 @@ where
     S: State<H> + 'static,
     F: FnOnce(S) -> Box<dyn State<H>>,
 {
-    if res.action == Action::Close {
+    if res.closes() {
         (res, None)
     } else if res.is_error {
         (res, Some(current))
@@ -110,27 +131,72 @@ fn default_handler<H: Handler>(
         Cmd::Helo { domain } => handle_helo(current, fsm, handler, domain),
         Cmd::Ehlo { domain } => handle_ehlo(current, fsm, handler, domain),
         Cmd::Noop => (OK, Some(current)),
-        _ => unhandled(current),
+        _ => unhandled(current, fsm, cmd),
     }
 }
 
-fn unhandled<H>(current: Box<dyn State<H>>) -> (Response, Option<Box<dyn State<H>>>) {
-    (BAD_SEQUENCE_COMMANDS, Some(current))
+fn unhandled<H: Handler>(
+    current: Box<dyn State<H>>,
+    fsm: &StateMachine<H>,
+    cmd: &Cmd,
+) -> (Response, Option<Box<dyn State<H>>>) {
+    let res = echo_verb(fsm.echo_commands, BAD_SEQUENCE_COMMANDS, cmd.verb());
+    (res, Some(current))
 }
 
+// Add the command verb to an error response, if enabled for debugging
+fn echo_verb(enabled: bool, res: Response, verb: Option<&str>) -> Response {
+    match verb {
+        Some(verb) if enabled => {
+            let text = format!("{} ({})", res.text(), verb.to_ascii_uppercase());
+            res.with_text(text)
+        }
+        _ => res,
+    }
+}
+
+// The verb at the start of a command line, if it looks like one
+fn line_verb(line: &[u8]) -> Option<&str> {
+    let verb = line
+        .split(|c| matches!(c, b' ' | b'\r' | b'\n'))
+        .next()
+        .filter(|v| {
+            !v.is_empty() && v.len() <= MAX_VERB && v.iter().all(u8::is_ascii_alphabetic)
+        })?;
+    str::from_utf8(verb).ok()
+}
+
+// Is a HELO domain a name with more than one label, or an address literal (RFC 5321
+// section 4.1.3)?
+fn is_fqdn_or_literal(domain: &str) -> bool {
+    if let Some(literal) = domain.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        return match literal.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+                literal[5..].parse::<Ipv6Addr>().is_ok()
+            }
+            _ => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+    }
+    let name = domain.strip_suffix('.').unwrap_or(domain);
+    name.split('.').count() > 1 && name.split('.').all(|label| !label.is_empty())
+}
+
+// The state is reset whatever the handler responds
 fn handle_rset<H: Handler>(
     fsm: &StateMachine<H>,
+    handler: &mut H,
     domain: &str,
 ) -> (Response, Option<Box<dyn State<H>>>) {
+    let res = handler.rset();
     match fsm.auth_state {
         AuthState::Unavailable => (
-            OK,
+            res,
             Some(Box::new(Hello {
                 domain: domain.to_string(),
             })),
         ),
         _ => (
-            OK,
+            res,
             Some(Box::new(HelloAuth {
                 domain: domain.to_string(),
             })),
@@ -138,6 +204,22 @@ fn handle_rset<H: Handler>(
     }
 }
 
+// RFC 3207: state established before TLS, including authentication, is discarded.
+// The Idle state holds no domain or transaction.
+fn handle_start_tls<H: Handler>(
+    current: Box<dyn State<H>>,
+    fsm: &mut StateMachine<H>,
+    handler: &mut H,
+) -> (Response, Option<Box<dyn State<H>>>) {
+    if !handler.allow_starttls(fsm.ip) {
+        return (TLS_NOT_AVAILABLE, Some(current));
+    }
+    if fsm.auth_state == AuthState::Authenticated {
+        fsm.auth_state = AuthState::RequiresAuth;
+    }
+    (START_TLS, Some(Box::new(Idle {})))
+}
+
 fn handle_helo<H: Handler>(
     current: Box<dyn State<H>>,
     fsm: &StateMachine<H>,
@@ -145,6 +227,9 @@ fn handle_helo<H: Handler>(
     domain: &str,
 ) -> (Response, Option<Box<dyn State<H>>>) {
     match fsm.auth_state {
+        AuthState::Unavailable if fsm.require_fqdn_helo && !is_fqdn_or_literal(domain) => {
+            (NEED_FQDN, Some(current))
+        }
         AuthState::Unavailable => {
             let res = handler.helo(fsm.ip, domain);
             next_state(current, res, || {
@@ -154,8 +239,14 @@ fn handle_helo<H: Handler>(
             })
         }
         _ => {
-            // If authentication is required the client should be using EHLO
-            (BAD_HELLO, Some(current))
+            // If authentication is required the client should be using EHLO, STARTTLS is
+            // only mentioned when it can be used
+            let res = match &fsm.helo_rejection {
+                Some(res) => res.clone(),
+                None if fsm.tls == TlsState::Inactive => EHLO_REQUIRED,
+                None => EHLO_REQUIRED_NO_TLS,
+            };
+            (res, Some(current))
         }
     }
 }
@@ -166,6 +257,9 @@ fn handle_ehlo<H: Handler>(
     handler: &mut H,
     domain: &str,
 ) -> (Response, Option<Box<dyn State<H>>>) {
+    if fsm.require_fqdn_helo && !is_fqdn_or_literal(domain) {
+        return (NEED_FQDN, Some(current));
+    }
     let mut res = handler.helo(fsm.ip, domain);
     if res.code == 250 {
         res = fsm.ehlo_response();
@@ -184,6 +278,21 @@ fn handle_ehlo<H: Handler>(
     }
 }
 
+// The mechanism used by an AUTH command
+fn auth_mechanism(cmd: &Cmd) -> Option<AuthMechanism> {
+    match cmd {
+        Cmd::AuthPlain { .. } | Cmd::AuthPlainEmpty => Some(AuthMechanism::Plain),
+        Cmd::AuthLogin { .. } | Cmd::AuthLoginEmpty => Some(AuthMechanism::Login),
+        _ => None,
+    }
+}
+
+// The first challenge sent to the client for an authentication mechanism
+fn auth_challenge<H: Handler>(handler: &mut H, mechanism: AuthMechanism) -> Response {
+    let challenge = handler.auth_challenge(mechanism);
+    Response::custom(334, base64::encode(&challenge))
+}
+
 fn authenticate_plain<H: Handler>(
     fsm: &mut StateMachine<H>,
     handler: &mut H,
@@ -192,12 +301,8 @@ fn authenticate_plain<H: Handler>(
     password: &str,
 ) -> Response {
     let auth_res = handler.auth_plain(authorization_id, authentication_id, password);
-    fsm.auth_state = ternary!(
-        auth_res.code == 235,
-        AuthState::Authenticated,
-        AuthState::RequiresAuth
-    );
-    auth_res
+    fsm.auth_result(auth_res.code == 235);
+    echo_auth_user(fsm.echo_auth_user, auth_res, authentication_id)
 }
 
 fn authenticate_login<H: Handler>(
@@ -207,12 +312,22 @@ fn authenticate_login<H: Handler>(
     password: &str,
 ) -> Response {
     let auth_res = handler.auth_login(username, password);
-    fsm.auth_state = ternary!(
-        auth_res.code == 235,
-        AuthState::Authenticated,
-        AuthState::RequiresAuth
-    );
-    auth_res
+    fsm.auth_result(auth_res.code == 235);
+    echo_auth_user(fsm.echo_auth_user, auth_res, username)
+}
+
+// Add the authenticated username to a successful AUTH response, if enabled
+fn echo_auth_user(enabled: bool, res: Response, username: &str) -> Response {
+    if !enabled || res.code != 235 {
+        return res;
+    }
+    // The username comes from the client, it must not be able to break the reply line
+    let username: String = username
+        .chars()
+        .map(|c| ternary!(c.is_control(), '?', c))
+        .collect();
+    let text = format!("{} ({})", res.text(), username);
+    res.with_text(text)
 }
 
 //------------------------------------------------------------------------------
@@ -225,6 +340,10 @@ impl<H: Handler> State<H> for Idle {
         SmtpState::Idle
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Connected
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine<H>,
@@ -236,7 +355,7 @@ impl<H: Handler> State<H> for Idle {
                 fsm.tls = TlsState::Active;
                 (EMPTY_RESPONSE, Some(self))
             }
-            Cmd::Rset => (OK, Some(self)),
+            Cmd::Rset => (handler.rset(), Some(self)),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -254,6 +373,10 @@ impl<H: Handler> State<H> for Hello {
         SmtpState::Hello
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine<H>,
@@ -263,9 +386,23 @@ impl<H: Handler> State<H> for Hello {
         match cmd {
             Cmd::Mail {
                 reverse_path,
-                is8bit,
+                body,
                 size,
+                priority,
+                deliver_by,
             } => {
+                // BINARYMIME needs BDAT (RFC 3030), which is not supported
+                if body == Some(BodyType::BinaryMime) {
+                    return (PARAMETER_NOT_RECOGNIZED, Some(self));
+                }
+                let priority = match priority {
+                    Some(_) if !fsm.mt_priority => return (PARAMETER_NOT_RECOGNIZED, Some(self)),
+                    Some(priority) => match i8::try_from(priority) {
+                        Ok(priority) if (-9..=9).contains(&priority) => Some(priority),
+                        _ => return (INVALID_PRIORITY, Some(self)),
+                    },
+                    None => None,
+                };
                 if let Some(max_message_size) = fsm.max_message_size {
                     if let Some(size) = size {
                         if size > max_message_size {
@@ -273,18 +410,43 @@ impl<H: Handler> State<H> for Hello {
                         }
                     }
                 }
+                if let Some(deliver_by) = deliver_by {
+                    let Some(min_seconds) = fsm.deliver_by else {
+                        return (PARAMETER_NOT_RECOGNIZED, Some(self));
+                    };
+                    match deliver_by {
+                        // RFC 2852 section 4.1, a returned message needs time to deliver
+                        Ok(by)
+                            if by.mode == DeliverByMode::Notify
+                                || i64::from(by.seconds) >= i64::from(min_seconds.max(1)) =>
+                        {
+                            handler.deliver_by(by)
+                        }
+                        _ => return (INVALID_DELIVER_BY, Some(self)),
+                    }
+                }
+                if let Some(priority) = priority {
+                    handler.mt_priority(priority);
+                }
+                if let Some(body) = body {
+                    handler.body_type(body);
+                }
+                handler.secure(fsm.is_secure());
+                handler.authenticated(fsm.is_authenticated());
                 let res = handler.mail(fsm.ip, &self.domain, reverse_path);
                 transform_state(self, res, |s| {
                     Box::new(Mail {
                         domain: s.domain,
                         reverse_path: reverse_path.to_owned(),
-                        is8bit,
+                        is8bit: body.is_some_and(BodyType::is8bit),
                     })
                 })
             }
-            Cmd::StartTls if fsm.tls == TlsState::Inactive => (START_TLS, Some(Box::new(Idle {}))),
+            Cmd::StartTls if fsm.tls == TlsState::Inactive => handle_start_tls(self, fsm, handler),
             Cmd::Vrfy => (VERIFY_RESPONSE, Some(self)),
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
+            Cmd::Etrn { domain } if fsm.etrn => (handler.etrn(domain), Some(self)),
+            Cmd::Etrn { .. } => (COMMAND_NOT_IMPLEMENTED, Some(self)),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -302,14 +464,26 @@ impl<H: Handler> State<H> for HelloAuth {
         SmtpState::HelloAuth
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeted
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine<H>,
         handler: &mut H,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State<H>>>) {
+        if let Some(mechanism) = auth_mechanism(&cmd) {
+            if fsm.is_authenticated() {
+                return (ALREADY_AUTHENTICATED, Some(self));
+            }
+            if !fsm.attempt_auth(mechanism) {
+                return (TOO_MANY_AUTH_MECHANISMS, Some(self));
+            }
+        }
         match cmd {
-            Cmd::StartTls => (START_TLS, Some(Box::new(Idle {}))),
+            Cmd::StartTls => handle_start_tls(self, fsm, handler),
             Cmd::AuthPlain {
                 ref authorization_id,
                 ref authentication_id,
@@ -322,7 +496,7 @@ impl<H: Handler> State<H> for HelloAuth {
             Cmd::AuthPlainEmpty if fsm.allow_auth_plain() => {
                 let domain = self.domain.clone();
                 (
-                    EMPTY_AUTH_CHALLENGE,
+                    auth_challenge(handler, AuthMechanism::Plain),
                     Some(Box::new(Auth {
                         domain,
                         mechanism: AuthMechanism::Plain,
@@ -344,7 +518,7 @@ impl<H: Handler> State<H> for HelloAuth {
             Cmd::AuthLoginEmpty if fsm.allow_auth_login() => {
                 let domain = self.domain.clone();
                 (
-                    USERNAME_AUTH_CHALLENGE,
+                    auth_challenge(handler, AuthMechanism::Login),
                     Some(Box::new(Auth {
                         domain,
                         mechanism: AuthMechanism::Login,
@@ -352,7 +526,9 @@ impl<H: Handler> State<H> for HelloAuth {
                     })),
                 )
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
+            Cmd::Etrn { domain } if fsm.etrn => (handler.etrn(domain), Some(self)),
+            Cmd::Etrn { .. } => (COMMAND_NOT_IMPLEMENTED, Some(self)),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -372,6 +548,10 @@ impl<H: Handler> State<H> for Auth {
         SmtpState::Auth
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Authenticating
+    }
+
     fn handle(
         mut self: Box<Self>,
         fsm: &mut StateMachine<H>,
@@ -379,6 +559,12 @@ impl<H: Handler> State<H> for Auth {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State<H>>>) {
         match cmd {
+            Cmd::AuthAbort => (
+                AUTH_ABORTED,
+                Some(Box::new(HelloAuth {
+                    domain: self.domain,
+                })),
+            ),
             Cmd::AuthResponse { response } => match self.mechanism {
                 AuthMechanism::Plain => {
                     let creds = decode_sasl_plain(response);
@@ -421,16 +607,23 @@ impl<H: Handler> State<H> for Auth {
                     }
                 }
             },
-            _ => unhandled(self),
+            _ => unhandled(self, fsm, &cmd),
         }
     }
 
     fn process_line<'a>(&mut self, _handler: &mut H, line: &'a [u8]) -> Either<Cmd<'a>, Response> {
         trace!("> {}", String::from_utf8_lossy(line));
+        if line == b"*\r\n" {
+            return Left(Cmd::AuthAbort);
+        }
         parse_auth_response(line)
             .map(|r| Left(Cmd::AuthResponse { response: r }))
             .unwrap_or_else(Right)
     }
+
+    fn parses_commands(&self) -> bool {
+        false
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -447,6 +640,10 @@ impl<H: Handler> State<H> for Mail {
         SmtpState::Mail
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Mail
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine<H>,
@@ -455,18 +652,23 @@ impl<H: Handler> State<H> for Mail {
     ) -> (Response, Option<Box<dyn State<H>>>) {
         match cmd {
             Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
-                transform_state(self, res, |s| {
-                    let fp = vec![forward_path.to_owned()];
-                    Box::new(Rcpt {
-                        domain: s.domain,
-                        reverse_path: s.reverse_path,
-                        is8bit: s.is8bit,
-                        forward_path: fp,
-                    })
-                })
+                let res = handler.rcpt(&fsm.recipient(forward_path));
+                if res.closes() {
+                    return (res, None);
+                }
+                // Rejected recipients also move the transaction on so that
+                // some recipients can be rejected without failing all
+                let mut next = Box::new(Rcpt {
+                    domain: self.domain,
+                    reverse_path: self.reverse_path,
+                    is8bit: self.is8bit,
+                    forward_path: Vec::new(),
+                    rejected: 0,
+                });
+                next.add_recipient(forward_path, &res);
+                (res, Some(next))
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -478,7 +680,20 @@ struct Rcpt {
     domain: String,
     reverse_path: String,
     is8bit: bool,
+    // Accepted recipients
     forward_path: Vec<String>,
+    // Number of rejected recipients
+    rejected: usize,
+}
+
+impl Rcpt {
+    fn add_recipient(&mut self, forward_path: &str, res: &Response) {
+        if res.is_error {
+            self.rejected += 1;
+        } else {
+            self.forward_path.push(forward_path.to_owned());
+        }
+    }
 }
 
 impl<H: Handler> State<H> for Rcpt {
@@ -487,43 +702,66 @@ impl<H: Handler> State<H> for Rcpt {
         SmtpState::Rcpt
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Rcpt
+    }
+
     fn handle(
-        self: Box<Self>,
+        mut self: Box<Self>,
         fsm: &mut StateMachine<H>,
         handler: &mut H,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State<H>>>) {
         match cmd {
+            Cmd::Data if self.forward_path.is_empty() => (NO_VALID_RECIPIENTS, Some(self)),
             Cmd::Data => {
+                debug!(
+                    "{} recipients accepted, {} rejected",
+                    self.forward_path.len(),
+                    self.rejected
+                );
                 let res = handler.data_start(
                     &self.domain,
                     &self.reverse_path,
                     self.is8bit,
                     &self.forward_path,
                 );
-                let res = ternary!(res.is_error, res, START_DATA);
+                // A 354 from the handler replaces the default prompt
+                let res = match res.code {
+                    354 => res,
+                    _ => ternary!(res.is_error, res, START_DATA),
+                };
                 transform_state(self, res, |s| {
                     Box::new(Data {
                         domain: s.domain,
                         has_error: false,
+                        rejection: None,
+                        after_crlf: true,
                         size_allowed: fsm.max_message_size,
+                        received_allowed: fsm.max_data_bytes,
+                        progress: fsm.data_progress_interval.map(Progress::new),
+                        #[cfg(feature = "digest")]
+                        digest: fsm.data_digest.then(Sha256::new),
                     })
                 })
             }
+            Cmd::Rcpt { forward_path }
+                if fsm.restrict_null_sender
+                    && self.reverse_path.is_empty()
+                    && !self.forward_path.is_empty() =>
+            {
+                self.add_recipient(forward_path, &NULL_SENDER_RECIPIENTS);
+                (NULL_SENDER_RECIPIENTS, Some(self))
+            }
             Cmd::Rcpt { forward_path } => {
-                let res = handler.rcpt(forward_path);
-                transform_state(self, res, |s| {
-                    let mut fp = s.forward_path;
-                    fp.push(forward_path.to_owned());
-                    Box::new(Rcpt {
-                        domain: s.domain,
-                        reverse_path: s.reverse_path,
-                        is8bit: s.is8bit,
-                        forward_path: fp,
-                    })
-                })
+                let res = handler.rcpt(&fsm.recipient(forward_path));
+                if res.closes() {
+                    return (res, None);
+                }
+                self.add_recipient(forward_path, &res);
+                (res, Some(self))
             }
-            Cmd::Rset => handle_rset(fsm, &self.domain),
+            Cmd::Rset => handle_rset(fsm, handler, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
     }
@@ -534,7 +772,44 @@ impl<H: Handler> State<H> for Rcpt {
 struct Data {
     domain: String,
     has_error: bool,
+    // Response to an error that is only reported at the end of the data
+    rejection: Option<Response>,
+    // The previous line ended with CRLF, the terminator has to follow a CRLF
+    after_crlf: bool,
     size_allowed: Option<usize>,
+    // Remaining bytes that can be received before the connection is closed
+    received_allowed: Option<usize>,
+    progress: Option<Progress>,
+    #[cfg(feature = "digest")]
+    digest: Option<Sha256>,
+}
+
+// Counts message bytes to report progress at every interval
+struct Progress {
+    interval: usize,
+    received: usize,
+    next_report: usize,
+}
+
+impl Progress {
+    fn new(interval: usize) -> Self {
+        Self {
+            interval,
+            received: 0,
+            next_report: interval,
+        }
+    }
+
+    // Returns the bytes received so far when another interval is complete
+    fn add(&mut self, bytes: usize) -> Option<usize> {
+        self.received += bytes;
+        if self.received < self.next_report {
+            return None;
+        }
+        // A long line can complete several intervals, report once
+        self.next_report = self.received - self.received % self.interval + self.interval;
+        Some(self.received)
+    }
 }
 
 impl<H: Handler> State<H> for Data {
@@ -543,23 +818,42 @@ impl<H: Handler> State<H> for Data {
         SmtpState::Data
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Data
+    }
+
     fn handle(
         self: Box<Self>,
-        _fsm: &mut StateMachine<H>,
+        fsm: &mut StateMachine<H>,
         handler: &mut H,
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State<H>>>) {
         match cmd {
+            Cmd::DataLimitExceeded => (DATA_LIMIT_EXCEEDED, None),
             Cmd::DataEnd => {
                 let res = if self.has_error {
                     // the error was already reported, do not send it twice
-                    EMPTY_RESPONSE
+                    self.rejection.unwrap_or(EMPTY_RESPONSE)
                 } else {
+                    #[cfg(feature = "digest")]
+                    if let Some(digest) = self.digest {
+                        handler.data_digest(digest.finalize().into());
+                    }
                     handler.data_end()
                 };
-                transform_state(self, res, |s| Box::new(Hello { domain: s.domain }))
+                // The transaction is over even if the message was rejected
+                if res.closes() {
+                    (res, None)
+                } else {
+                    (
+                        res,
+                        Some(Box::new(Hello {
+                            domain: self.domain,
+                        })),
+                    )
+                }
             }
-            _ => unhandled(self),
+            _ => unhandled(self, fsm, &cmd),
         }
     }
 
@@ -568,10 +862,30 @@ impl<H: Handler> State<H> for Data {
         handler: &mut H,
         mut line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        if line == b".\r\n" {
+        // Only CRLF.CRLF ends the data, a dot after a bare LF could smuggle in a message
+        let after_crlf = mem::replace(&mut self.after_crlf, line.ends_with(b"\r\n"));
+        if after_crlf && line == b".\r\n" {
             trace!("> _data_");
-            Left(Cmd::DataEnd)
-        } else if self.has_error {
+            return Left(Cmd::DataEnd);
+        }
+        if let Some(allowed) = &mut self.received_allowed {
+            match allowed.checked_sub(line.len()) {
+                Some(remaining) => *allowed = remaining,
+                None => {
+                    if !self.has_error {
+                        self.has_error = true;
+                        handler.data_end_error(Reason::MaxSizeExceeded);
+                    }
+                    return Left(Cmd::DataLimitExceeded);
+                }
+            }
+        }
+        if !self.has_error && has_bare_line_ending(line) {
+            self.has_error = true;
+            self.rejection = Some(BARE_LINE_ENDING);
+            handler.data_end_error(Reason::BareLineEnding);
+        }
+        if self.has_error {
             // there was an error, stop processing
             Right(EMPTY_RESPONSE)
         } else {
@@ -591,17 +905,31 @@ impl<H: Handler> State<H> for Data {
                 }
             }
             match handler.data(line) {
-                Ok(_) => Right(EMPTY_RESPONSE),
+                Ok(_) => {
+                    #[cfg(feature = "digest")]
+                    if let Some(digest) = &mut self.digest {
+                        digest.update(line);
+                    }
+                    if let Some(received) = self.progress.as_mut().and_then(|p| p.add(line.len())) {
+                        handler.data_progress(received);
+                    }
+                    Right(EMPTY_RESPONSE)
+                }
                 Err(e) => {
                     error!("Error saving message: {}", e);
                     self.has_error = true;
+                    let res = handler.on_error(e);
                     handler.data_end_error(Reason::Processing);
-                    Right(TRANSACTION_FAILED)
+                    Right(res)
                 }
             }
         }
     }
 
+    fn parses_commands(&self) -> bool {
+        false
+    }
+
     fn io_error(&mut self, handler: &mut H) {
         if !self.has_error {
             self.has_error = true;
@@ -616,6 +944,12 @@ impl<H: Handler> State<H> for Data {
         }
     }
 }
+// A CR or LF that is not part of the CRLF ending the line
+fn has_bare_line_ending(line: &[u8]) -> bool {
+    let content = line.strip_suffix(b"\r\n").unwrap_or(line);
+    content.iter().any(|c| *c == b'\r' || *c == b'\n')
+}
+
 //------------------------------------------------------------------------------
 
 pub(crate) struct StateMachine<H: Handler> {
@@ -628,16 +962,30 @@ pub(crate) struct StateMachine<H: Handler> {
     auth_login: bool,
     insecure_allow_plaintext_auth: bool,
     max_message_size: Option<usize>,
+    max_data_bytes: Option<usize>,
+    data_progress_interval: Option<usize>,
+    etrn: bool,
+    mt_priority: bool,
+    deliver_by: Option<u32>,
+    #[cfg(feature = "digest")]
+    data_digest: bool,
+    restrict_null_sender: bool,
+    require_fqdn_helo: bool,
+    normalize_recipients: Option<Normalization>,
+    echo_commands: bool,
+    echo_auth_user: bool,
+    max_auth_mechanisms: Option<usize>,
+    // Distinct mechanisms attempted since the last successful authentication
+    auth_attempts: Vec<AuthMechanism>,
+    custom_extensions: Vec<Extension>,
+    helo_rejection: Option<Response>,
+    disabled_commands: Vec<OptionalCommand>,
 }
 
 impl<H: Handler> StateMachine<H> {
-    pub fn new(
-        ip: IpAddr,
-        auth_mechanisms: Vec<AuthMechanism>,
-        allow_start_tls: bool,
-        insecure_allow_plaintext_auth: bool,
-        max_message_size: Option<usize>,
-    ) -> Self {
+    pub fn new(ip: IpAddr, config: &SessionBuilder) -> Self {
+        let auth_mechanisms = config.auth_mechanisms.clone();
+        let allow_start_tls = config.start_tls_extension;
         let auth_state = ternary!(
             auth_mechanisms.is_empty(),
             AuthState::Unavailable,
@@ -654,8 +1002,36 @@ impl<H: Handler> StateMachine<H> {
             smtp: Some(Box::new(Idle {})),
             auth_plain,
             auth_login,
-            insecure_allow_plaintext_auth,
-            max_message_size,
+            insecure_allow_plaintext_auth: config.insecure_allow_plaintext_auth
+                || config.plaintext_auth_filter.is_some_and(|allow| allow(ip)),
+            max_message_size: config.max_message_size,
+            max_data_bytes: config.max_data_bytes,
+            data_progress_interval: config.data_progress_interval,
+            etrn: config.etrn && !config.disabled_commands.contains(&OptionalCommand::Etrn),
+            mt_priority: config.mt_priority,
+            deliver_by: config.deliver_by,
+            #[cfg(feature = "digest")]
+            data_digest: config.data_digest,
+            restrict_null_sender: config.restrict_null_sender,
+            require_fqdn_helo: config.require_fqdn_helo,
+            normalize_recipients: config.normalize_recipients,
+            echo_commands: config.echo_commands,
+            echo_auth_user: config.echo_auth_user,
+            max_auth_mechanisms: config.max_auth_mechanisms,
+            auth_attempts: Vec::new(),
+            custom_extensions: config.custom_extensions.clone(),
+            helo_rejection: config.helo_rejection.as_ref().map(|text| {
+                EHLO_REQUIRED.with_text(text.lines().next().unwrap_or_default().to_string())
+            }),
+            disabled_commands: config.disabled_commands.clone(),
+        }
+    }
+
+    // The recipient address as it is passed to the handler
+    fn recipient<'a>(&self, forward_path: &'a str) -> Cow<'a, str> {
+        match self.normalize_recipients {
+            Some(normalization) => Cow::Owned(address::normalize(forward_path, normalization)),
+            None => Cow::Borrowed(forward_path),
         }
     }
 
@@ -674,10 +1050,25 @@ impl<H: Handler> StateMachine<H> {
         handler: &mut H,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
+        let disabled = line_verb(line).is_some_and(|verb| {
+            self.disabled_commands
+                .iter()
+                .any(|command| verb.eq_ignore_ascii_case(command.verb()))
+        });
         match self.smtp {
             Some(ref mut s) => {
                 let s: &mut dyn State<H> = s.borrow_mut();
-                s.process_line(handler, line)
+                let parsed = if disabled && s.parses_commands() {
+                    Right(COMMAND_DISABLED)
+                } else {
+                    s.process_line(handler, line)
+                };
+                match parsed {
+                    Right(res) if res.is_error && s.parses_commands() => {
+                        Right(echo_verb(self.echo_commands, res, line_verb(line)))
+                    }
+                    parsed => parsed,
+                }
             }
             None => Right(INVALID_STATE),
         }
@@ -697,29 +1088,79 @@ impl<H: Handler> StateMachine<H> {
         }
     }
 
+    pub fn phase(&self) -> Phase {
+        self.smtp.as_ref().map_or(Phase::Closed, |s| s.phase())
+    }
+
     #[cfg(test)]
     pub fn current_state(&self) -> SmtpState {
         let id = self.smtp.as_ref().map(|s| s.id());
         id.unwrap_or(SmtpState::Invalid)
     }
 
-    fn ehlo_response(&self) -> Response {
-        let mut extensions = vec!["8BITMIME".to_string()];
+    // Record an attempt to authenticate with the given mechanism, returns false if
+    // the client has already tried too many different mechanisms
+    fn attempt_auth(&mut self, mechanism: AuthMechanism) -> bool {
+        if !self.auth_mechanisms.contains(&mechanism) || self.auth_attempts.contains(&mechanism) {
+            return true;
+        }
+        match self.max_auth_mechanisms {
+            Some(max) if self.auth_attempts.len() >= max => false,
+            _ => {
+                self.auth_attempts.push(mechanism);
+                true
+            }
+        }
+    }
+
+    fn auth_result(&mut self, success: bool) {
+        if success {
+            self.auth_state = AuthState::Authenticated;
+            self.auth_attempts.clear();
+        } else {
+            self.auth_state = AuthState::RequiresAuth;
+        }
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.auth_state == AuthState::Authenticated
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.tls == TlsState::Active
+    }
+
+    // Extensions that would be advertised in response to EHLO in the current state
+    pub fn supported_extensions(&self) -> Vec<Extension> {
+        let mut extensions = vec![Extension::EightBitMime];
         if let Some(max_message_size) = self.max_message_size {
-            extensions.push(format!("SIZE {max_message_size}"));
+            extensions.push(Extension::Size(max_message_size));
         }
         if self.tls == TlsState::Inactive {
-            extensions.push("STARTTLS".to_string());
+            extensions.push(Extension::StartTls);
         }
-
         if self.allow_auth() && !self.auth_mechanisms.is_empty() {
-            let mut auth_available = "AUTH".to_string();
-            for auth in &self.auth_mechanisms {
-                auth_available += " ";
-                auth_available += auth.extension();
-            }
-            extensions.push(auth_available);
+            extensions.push(Extension::Auth(self.auth_mechanisms.clone()));
+        }
+        if self.etrn {
+            extensions.push(Extension::Etrn);
+        }
+        if self.mt_priority {
+            extensions.push(Extension::MtPriority);
         }
+        if let Some(min_seconds) = self.deliver_by {
+            extensions.push(Extension::DeliverBy(min_seconds));
+        }
+        extensions.extend(self.custom_extensions.iter().cloned());
+        extensions
+    }
+
+    fn ehlo_response(&self) -> Response {
+        let extensions = self
+            .supported_extensions()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
         Response::dynamic(250, "server offers extensions:".to_string(), extensions)
     }
 
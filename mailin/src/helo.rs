@@ -0,0 +1,77 @@
+use crate::address::parse_address_literal;
+use std::net::IpAddr;
+
+/// The identity a client claims with HELO or EHLO
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeloIdentity {
+    /// A domain name e.g mail.example.com
+    Domain(String),
+    /// An address literal e.g `[192.0.2.1]` or `[IPv6:2001:db8::1]`
+    AddressLiteral(IpAddr),
+}
+
+/// Parse the domain given to [`crate::Handler::helo()`].
+///
+/// A client that has no domain name sends its IP address as an address literal,
+/// which can be compared with the IP address of the connection:
+/// ```
+/// use mailin::helo::{parse_identity, HeloIdentity};
+/// use std::net::IpAddr;
+///
+/// let ip: IpAddr = "192.0.2.1".parse().unwrap();
+/// let spoofed = match parse_identity("[192.0.2.7]") {
+///     HeloIdentity::AddressLiteral(literal) => literal != ip,
+///     HeloIdentity::Domain(_) => false,
+/// };
+/// assert!(spoofed);
+/// ```
+/// Anything that is not a valid address literal is returned as a domain.
+pub fn parse_identity(domain: &str) -> HeloIdentity {
+    domain
+        .strip_prefix('[')
+        .and_then(|d| d.strip_suffix(']'))
+        .and_then(parse_address_literal)
+        .map(HeloIdentity::AddressLiteral)
+        .unwrap_or_else(|| HeloIdentity::Domain(domain.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_literal() {
+        assert_eq!(
+            parse_identity("[192.0.2.1]"),
+            HeloIdentity::AddressLiteral("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            parse_identity("[IPv6:2001:db8::1]"),
+            HeloIdentity::AddressLiteral("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(
+            parse_identity("[ipv6:::1]"),
+            HeloIdentity::AddressLiteral("::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn domain() {
+        assert_eq!(
+            parse_identity("mail.example.com"),
+            HeloIdentity::Domain("mail.example.com".to_string())
+        );
+        // Malformed literals are not address literals
+        for domain in [
+            "[192.0.2]",
+            "[2001:db8::1]",
+            "192.0.2.1]",
+            "[IPv6:192.0.2.1]",
+        ] {
+            assert_eq!(
+                parse_identity(domain),
+                HeloIdentity::Domain(domain.to_string())
+            );
+        }
+    }
+}
@@ -31,6 +31,7 @@
 //!         write_response(tcp_connection, &res)?;
 //!         close(tcp_connection);
 //!     }
+//!     Action::Drop => close(tcp_connection), // Close without a response
 //!     Action::NoReply => (), // No response needed
 //! }
 //! ```
@@ -38,17 +39,30 @@
 #![forbid(unsafe_code)]
 #![forbid(missing_docs)]
 
+use std::error;
+use std::fmt;
 use std::io;
 use std::net::IpAddr;
+use std::time::Duration;
+/// Address validates the syntax of mailbox addresses.
+pub mod address;
+/// Dsn generates delivery status notifications for mail that could not be delivered.
+pub mod dsn;
 mod fsm;
+/// Helo parses the identity that clients claim in HELO and EHLO commands.
+pub mod helo;
 mod parser;
+/// Replay replays recorded SMTP sessions, to test handlers against real clients.
+pub mod replay;
 /// Response contains a selection of SMTP responses for use in handlers.
 pub mod response;
 mod smtp;
+/// Verp encodes recipients into return paths (Variable Envelope Return Path).
+pub mod verp;
 
 pub use crate::{
     response::{Action, Response},
-    smtp::{Session, SessionBuilder},
+    smtp::{ConnInfo, Session, SessionBuilder},
 };
 
 /// A `Handler` makes decisions about incoming mail commands.
@@ -89,17 +103,26 @@ pub trait Handler {
         response::OK
     }
 
-    /// Called when a mail message is started
+    /// Called when a mail message is started.
+    ///
+    /// When authentication is enabled with [`SessionBuilder::enable_auth()`], a mail
+    /// transaction can only start after the client has authenticated. Handlers that
+    /// decide whether to relay can rely on this, see [`Session::is_authenticated()`].
     fn mail(&mut self, _ip: IpAddr, _domain: &str, _from: &str) -> Response {
         response::OK
     }
 
-    /// Called when a mail recipient is set
+    /// Called when a mail recipient is set.
+    ///
+    /// As with [`Self::mail()`], the client has authenticated if authentication is enabled.
     fn rcpt(&mut self, _to: &str) -> Response {
         response::OK
     }
 
     /// Called when a data command is received
+    ///
+    /// Return a `354` response to change the text of the prompt sent to the client,
+    /// any other success response sends [`response::START_DATA`].
     fn data_start(
         &mut self,
         _domain: &str,
@@ -115,7 +138,18 @@ pub trait Handler {
         Ok(())
     }
 
+    /// Called during DATA each time another interval of message bytes has been received,
+    /// with the number of bytes received so far.
+    ///
+    /// This is in addition to [`Self::data()`], to report on large transfers while they
+    /// happen. The interval has to be set with [`SessionBuilder::data_progress_interval()`].
+    fn data_progress(&mut self, _bytes: usize) {}
+
     /// Called at the end of receiving data
+    ///
+    /// This is the final decision on the message and the place for policies that need the
+    /// whole message, e.g content scanning. Returning an error response (4xx or 5xx) rejects
+    /// the message for all recipients. Either way the session is ready for a new transaction.
     fn data_end(&mut self) -> Response {
         response::OK
     }
@@ -127,6 +161,24 @@ pub trait Handler {
     /// Either [`Self::data_end()`] or [`Self::data_end_error()`] is called but never both.
     fn data_end_error(&mut self, _reason: Reason) {}
 
+    /// Called when [`Self::data()`] returns an error, with that error. Returns the response
+    /// for the client, by default 554.
+    ///
+    /// This is the place to record why a message failed, e.g a full disk or a timeout in a
+    /// content scanner, and to choose a more specific response such as a temporary 452.
+    /// [`Self::data_end_error()`] is called afterwards with [`Reason::Processing`].
+    fn on_error(&mut self, _err: io::Error) -> Response {
+        response::TRANSACTION_FAILED
+    }
+
+    /// Called when the client sends RSET, after any mail transaction in progress has
+    /// been discarded.
+    ///
+    /// The session is reset whatever the response, which would normally be a 250.
+    fn rset(&mut self) -> Response {
+        response::OK
+    }
+
     /// Called when a plain authentication request is received
     fn auth_plain(
         &mut self,
@@ -141,6 +193,162 @@ pub trait Handler {
     fn auth_login(&mut self, _username: &str, _password: &str) -> Response {
         response::INVALID_CREDENTIALS
     }
+
+    /// Called to get the first challenge sent to a client that starts authentication
+    /// without an initial response.
+    ///
+    /// The challenge is base64 encoded before it is sent. The default is an empty
+    /// challenge for PLAIN and `Username:` for LOGIN.
+    fn auth_challenge(&mut self, mechanism: AuthMechanism) -> Vec<u8> {
+        match mechanism {
+            AuthMechanism::Plain => Vec::new(),
+            AuthMechanism::Login => b"Username:".to_vec(),
+        }
+    }
+
+    /// Called when the client sends STARTTLS, to decide whether TLS is allowed for
+    /// the client at the given address.
+    ///
+    /// Returning false refuses TLS with a 454 response and the session continues
+    /// without encryption.
+    fn allow_starttls(&mut self, _ip: IpAddr) -> bool {
+        true
+    }
+
+    /// Called with the SHA-256 digest of the message, before [`Handler::data_end()`].
+    ///
+    /// The digest covers the data passed to [`Handler::data()`]. It has to be enabled
+    /// with `SessionBuilder::enable_data_digest()`, which requires the `digest` feature.
+    fn data_digest(&mut self, _digest: [u8; 32]) {}
+
+    /// Called with the MT-PRIORITY parameter of MAIL (RFC 6710), before [`Handler::mail()`].
+    ///
+    /// Only called once the parameters of MAIL have been accepted, a MAIL rejected for
+    /// its SIZE or BY parameter does not set a priority. The priority is between -9 and
+    /// 9. MT-PRIORITY has to be enabled with [`SessionBuilder::enable_mt_priority()`].
+    fn mt_priority(&mut self, _priority: i8) {}
+
+    /// Called with the BODY parameter of MAIL (RFC 6152), before [`Handler::mail()`].
+    ///
+    /// Only called when the client declares the body type. A relay can use this to
+    /// decide whether the message may be forwarded to a server without 8BITMIME. The
+    /// same information is passed to [`Handler::data_start()`] as `is8bit`.
+    fn body_type(&mut self, _body: BodyType) {}
+
+    /// Called with the BY parameter of MAIL (RFC 2852), before [`Handler::mail()`].
+    ///
+    /// The client asks for the message to be delivered within the given time. DELIVERBY
+    /// has to be enabled with [`SessionBuilder::enable_deliver_by()`].
+    fn deliver_by(&mut self, _deliver_by: DeliverBy) {}
+
+    /// Called with whether the connection uses TLS, before [`Handler::mail()`].
+    ///
+    /// The connection cannot change during a mail transaction, so the value also holds
+    /// for the [`Handler::rcpt()`] and DATA calls that follow. A handler can use it to
+    /// log how messages arrived or to reject mail sent in plaintext. The state is also
+    /// available from [`Session::is_secure()`].
+    fn secure(&mut self, _secure: bool) {}
+
+    /// Called with whether the client has authenticated with AUTH, before
+    /// [`Handler::mail()`].
+    ///
+    /// AUTH is not allowed during a mail transaction, so the value also holds for the
+    /// [`Handler::rcpt()`] and DATA calls that follow, e.g to only relay mail for clients
+    /// that authenticated. The state is also available from
+    /// [`Session::is_authenticated()`].
+    fn authenticated(&mut self, _authenticated: bool) {}
+
+    /// Called when the client asks for queued mail to be delivered with ETRN (RFC 1985).
+    ///
+    /// The `domain` is the argument given by the client, e.g `example.com`, `@example.com`
+    /// or `#queue`. ETRN has to be enabled with [`SessionBuilder::enable_etrn()`].
+    fn etrn(&mut self, _domain: &str) -> Response {
+        response::COMMAND_NOT_IMPLEMENTED
+    }
+
+    /// Called before a response is sent to the client.
+    ///
+    /// Returning a duration delays the response, e.g to slow down a client once it is
+    /// suspected of abuse (tarpitting). The delay is applied by the code doing the I/O.
+    fn response_delay(&self, _response: &Response) -> Option<Duration> {
+        None
+    }
+
+    /// Called with the raw bytes read from, or written to, the client.
+    ///
+    /// The bytes are seen after TLS decryption, which makes this useful for debugging
+    /// protocol problems with a specific client. Like [`Handler::response_delay()`], this
+    /// is called by the code doing the I/O.
+    fn on_wire(&mut self, _direction: Direction, _bytes: &[u8]) {}
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The phase of an SMTP session, see [`Session::phase()`]
+pub enum Phase {
+    /// The client has connected but not sent HELO or EHLO
+    Connected,
+    /// The client has sent HELO or EHLO and can start a mail transaction
+    Greeted,
+    /// The client is authenticating with AUTH
+    Authenticating,
+    /// A mail transaction has started with MAIL
+    Mail,
+    /// Recipients are being given with RCPT
+    Rcpt,
+    /// The message is being received after DATA
+    Data,
+    /// The session has ended, e.g after QUIT, and accepts no more commands
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Direction of bytes on the wire
+pub enum Direction {
+    /// Bytes received from the client
+    Received,
+    /// Bytes sent to the client
+    Sent,
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Body type declared with the BODY parameter of MAIL
+pub enum BodyType {
+    /// 7bit ASCII text, the default when no BODY parameter is given
+    SevenBit,
+    /// 8bit MIME content (RFC 6152)
+    EightBitMime,
+    /// Binary MIME content (RFC 3030), only allowed with BDAT which is not supported
+    BinaryMime,
+}
+
+impl BodyType {
+    /// True if the body may contain bytes outside 7bit ASCII
+    pub fn is8bit(self) -> bool {
+        self != BodyType::SevenBit
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The BY parameter of MAIL, a deadline for delivering the message (RFC 2852)
+pub struct DeliverBy {
+    /// Seconds from the MAIL command until the deadline, negative if the deadline has
+    /// already passed, which is only allowed with [`DeliverByMode::Notify`]
+    pub seconds: i32,
+    /// What to do when the message cannot be delivered in time
+    pub mode: DeliverByMode,
+    /// The client asked for delivery status notifications to be traced
+    pub trace: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a server does with a message that misses its deliver-by deadline
+pub enum DeliverByMode {
+    /// Return the message as undeliverable, `R`
+    Return,
+    /// Notify the sender and continue delivery, `N`
+    Notify,
 }
 
 #[non_exhaustive]
@@ -155,6 +363,8 @@ pub enum Reason {
     Processing,
     /// The max size limit is exceeded (can only happen when activated).
     MaxSizeExceeded,
+    /// The message contains a CR or LF that is not part of a CRLF line ending.
+    BareLineEnding,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -177,6 +387,152 @@ impl AuthMechanism {
     }
 }
 
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Commands that can be disabled, see [`SessionBuilder::disable_command()`].
+///
+/// The commands needed to submit mail, e.g EHLO, MAIL, AUTH and STARTTLS, cannot be
+/// disabled.
+pub enum OptionalCommand {
+    /// HELO, clients must use EHLO instead
+    Helo,
+    /// VRFY, verify a mailbox name
+    Vrfy,
+    /// EXPN, expand a mailing list
+    Expn,
+    /// HELP
+    Help,
+    /// ETRN, remote queue processing
+    Etrn,
+}
+
+impl OptionalCommand {
+    // The verb of the command
+    pub(crate) fn verb(&self) -> &'static str {
+        match self {
+            OptionalCommand::Helo => "HELO",
+            OptionalCommand::Vrfy => "VRFY",
+            OptionalCommand::Expn => "EXPN",
+            OptionalCommand::Help => "HELP",
+            OptionalCommand::Etrn => "ETRN",
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// SMTP extensions advertised in response to EHLO
+pub enum Extension {
+    /// 8bit MIME transport (RFC 6152)
+    EightBitMime,
+    /// Message size declaration with the maximum size in bytes (RFC 1870)
+    Size(usize),
+    /// Secure SMTP over TLS (RFC 3207)
+    StartTls,
+    /// Authentication with the given mechanisms (RFC 4954)
+    Auth(Vec<AuthMechanism>),
+    /// Remote queue processing (RFC 1985)
+    Etrn,
+    /// Message transfer priorities (RFC 6710)
+    MtPriority,
+    /// Deliver by deadlines with the minimum deadline in seconds, 0 for none (RFC 2852)
+    DeliverBy(u32),
+    /// A keyword that mailin does not implement, e.g a vendor or policy keyword,
+    /// see [`Extension::custom()`]
+    Custom(String),
+}
+
+/// Reason a custom EHLO keyword is rejected
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeywordError {
+    /// The keyword or its parameters contain invalid characters
+    Syntax,
+    /// The keyword is one of the extensions implemented by mailin
+    Builtin,
+}
+
+// Keywords of the extensions implemented by mailin
+const BUILTIN_KEYWORDS: [&str; 7] = [
+    "8BITMIME",
+    "SIZE",
+    "STARTTLS",
+    "AUTH",
+    "ETRN",
+    "MT-PRIORITY",
+    "DELIVERBY",
+];
+
+impl Extension {
+    /// Create an extension for a custom EHLO keyword, optionally followed by parameters
+    /// separated by spaces e.g `NO-SOLICITING` or `XVENDOR FEATURE1 FEATURE2`.
+    ///
+    /// The keyword must follow the syntax of RFC 5321 and must not be one of the
+    /// extensions that mailin implements, these are advertised when they are enabled.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Extension, KeywordError};
+    ///
+    /// assert!(Extension::custom("NO-SOLICITING").is_ok());
+    /// assert_eq!(Extension::custom("STARTTLS"), Err(KeywordError::Builtin));
+    /// assert_eq!(Extension::custom("X\r\n250 AUTH"), Err(KeywordError::Syntax));
+    /// ```
+    pub fn custom(keyword: &str) -> Result<Self, KeywordError> {
+        let mut words = keyword.split(' ');
+        let name = words.next().unwrap_or_default();
+        // ehlo-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+        let is_keyword = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        // ehlo-param = 1*(%d33-126)
+        let is_params = words.all(|p| !p.is_empty() && p.bytes().all(|c| (33..=126).contains(&c)));
+        if !is_keyword || !is_params {
+            return Err(KeywordError::Syntax);
+        }
+        if BUILTIN_KEYWORDS
+            .iter()
+            .any(|builtin| name.eq_ignore_ascii_case(builtin))
+        {
+            return Err(KeywordError::Builtin);
+        }
+        Ok(Extension::Custom(keyword.to_string()))
+    }
+}
+
+impl fmt::Display for KeywordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            KeywordError::Syntax => "Invalid EHLO keyword",
+            KeywordError::Builtin => "EHLO keyword is built in",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for KeywordError {}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extension::EightBitMime => write!(f, "8BITMIME"),
+            Extension::Size(max_size) => write!(f, "SIZE {max_size}"),
+            Extension::StartTls => write!(f, "STARTTLS"),
+            Extension::Etrn => write!(f, "ETRN"),
+            Extension::MtPriority => write!(f, "MT-PRIORITY"),
+            Extension::DeliverBy(0) => write!(f, "DELIVERBY"),
+            Extension::DeliverBy(min) => write!(f, "DELIVERBY {min}"),
+            Extension::Custom(keyword) => f.write_str(keyword),
+            Extension::Auth(mechanisms) => {
+                write!(f, "AUTH")?;
+                for mechanism in mechanisms {
+                    write!(f, " {}", mechanism.extension())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
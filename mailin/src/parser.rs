@@ -1,12 +1,14 @@
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
 use nom::character::{is_alphanumeric, is_digit};
-use nom::combinator::{map, map_res, value};
+use nom::combinator::{map, map_res, opt, recognize, value};
+use nom::multi::fold_many0;
 use nom::sequence::{pair, preceded, separated_pair, terminated};
 use nom::{IResult, Parser};
 
 use crate::response::*;
 use crate::smtp::{Cmd, Credentials};
+use crate::{BodyType, DeliverBy, DeliverByMode};
 use std::str::{self, from_utf8};
 
 //----- Parser -----------------------------------------------------------------
@@ -28,7 +30,7 @@ pub fn parse_auth_response(line: &[u8]) -> Result<&[u8], Response> {
 fn command(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     terminated(
         alt((
-            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth,
+            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth, etrn,
         )),
         tag(b"\r\n"),
     )(buf)
@@ -56,13 +58,14 @@ fn take_all(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b"\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn body_eq_8bit(buf: &[u8]) -> IResult<&[u8], bool> {
+fn body_type(buf: &[u8]) -> IResult<&[u8], BodyType> {
     let preamble = pair(space, tag_no_case(b"body="));
-    let is8bit = alt((
-        value(true, tag_no_case(b"8bitmime")),
-        value(false, tag_no_case(b"7bit")),
+    let body = alt((
+        value(BodyType::EightBitMime, tag_no_case(b"8bitmime")),
+        value(BodyType::SevenBit, tag_no_case(b"7bit")),
+        value(BodyType::BinaryMime, tag_no_case(b"binarymime")),
     ));
-    preceded(preamble, is8bit)(buf)
+    preceded(preamble, body)(buf)
 }
 
 fn message_size(buf: &[u8]) -> IResult<&[u8], usize> {
@@ -76,24 +79,96 @@ fn message_size(buf: &[u8]) -> IResult<&[u8], usize> {
     })(buf)
 }
 
-fn body_eq_8bit_and_message_size(buf: &[u8]) -> IResult<&[u8], (bool, Option<usize>)> {
-    alt((
-        |i| pair(body_eq_8bit, message_size)(i).map(|(i, (b, s))| (i, (b, Some(s)))),
-        |i| pair(message_size, body_eq_8bit)(i).map(|(i, (s, b))| (i, (b, Some(s)))),
-        |i| body_eq_8bit(i).map(|(i, b)| (i, (b, None))),
-        |i| message_size(i).map(|(i, s)| (i, (false, Some(s)))),
-        |i| Ok((i, (false, None))),
-    ))(buf)
+// MT-PRIORITY (RFC 6710), the range is checked by the state machine. A number too
+// large for an i32 becomes i32::MAX, so that it is rejected with 501 like any other
+// priority out of range
+fn mt_priority(buf: &[u8]) -> IResult<&[u8], i32> {
+    let preamble = pair(space, tag_no_case(b"mt-priority="));
+    let number = recognize(pair(
+        opt(alt((tag(b"-"), tag(b"+")))),
+        take_while1(is_digit),
+    ));
+    let priority = map(map_res(number, from_utf8), |s| {
+        str::parse(s).unwrap_or(i32::MAX)
+    });
+    preceded(preamble, priority)(buf)
+}
+
+// DELIVERBY (RFC 2852), a value with a bad syntax is passed on as an error so that
+// the state machine can reject it with 501
+fn deliver_by(buf: &[u8]) -> IResult<&[u8], Result<DeliverBy, ()>> {
+    let preamble = pair(space, tag_no_case(b"by="));
+    preceded(preamble, map(is_not(b" \t\r\n" as &[u8]), by_value))(buf)
+}
+
+// by-value = by-time ";" by-mode [by-trace], by-time = ["-" / "+"]1*9digit
+fn by_value(value: &[u8]) -> Result<DeliverBy, ()> {
+    let value = from_utf8(value).map_err(|_| ())?;
+    let (time, mode) = value.split_once(';').ok_or(())?;
+    let digits = time.strip_prefix(['-', '+']).unwrap_or(time);
+    if digits.is_empty() || digits.len() > 9 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(());
+    }
+    let seconds = time.parse().map_err(|_| ())?;
+    let (mode, trace) = match mode.to_ascii_uppercase().as_str() {
+        "R" => (DeliverByMode::Return, false),
+        "N" => (DeliverByMode::Notify, false),
+        "RT" => (DeliverByMode::Return, true),
+        "NT" => (DeliverByMode::Notify, true),
+        _ => return Err(()),
+    };
+    Ok(DeliverBy {
+        seconds,
+        mode,
+        trace,
+    })
+}
+
+#[derive(Default)]
+struct MailParameters {
+    body: Option<BodyType>,
+    size: Option<usize>,
+    priority: Option<i32>,
+    deliver_by: Option<Result<DeliverBy, ()>>,
+}
+
+enum MailParameter {
+    Body(BodyType),
+    Size(usize),
+    Priority(i32),
+    DeliverBy(Result<DeliverBy, ()>),
+}
+
+fn mail_parameters(buf: &[u8]) -> IResult<&[u8], MailParameters> {
+    let parameter = alt((
+        map(body_type, MailParameter::Body),
+        map(message_size, MailParameter::Size),
+        map(mt_priority, MailParameter::Priority),
+        map(deliver_by, MailParameter::DeliverBy),
+    ));
+    fold_many0(parameter, MailParameters::default, |mut acc, parameter| {
+        match parameter {
+            MailParameter::Body(body) => acc.body = Some(body),
+            MailParameter::Size(size) => acc.size = Some(size),
+            MailParameter::Priority(priority) => acc.priority = Some(priority),
+            MailParameter::DeliverBy(deliver_by) => acc.deliver_by = Some(deliver_by),
+        }
+        acc
+    })(buf)
 }
 
 fn mail(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     let preamble = pair(cmd(b"mail"), tag_no_case(b"from:<"));
-    let mail_path_parser = preceded(preamble, mail_path);
-    let parser = separated_pair(mail_path_parser, tag(b">"), body_eq_8bit_and_message_size);
-    map(parser, |(reverse_path, (is8bit, size))| Cmd::Mail {
+    // The reverse path is empty for bounces, "MAIL FROM:<>"
+    let reverse_path = map(opt(mail_path), Option::unwrap_or_default);
+    let mail_path_parser = preceded(preamble, reverse_path);
+    let parser = separated_pair(mail_path_parser, tag(b">"), mail_parameters);
+    map(parser, |(reverse_path, parameters)| Cmd::Mail {
         reverse_path,
-        is8bit,
-        size,
+        body: parameters.body,
+        size: parameters.size,
+        priority: parameters.priority,
+        deliver_by: parameters.deliver_by,
     })(buf)
 }
 
@@ -129,6 +204,11 @@ fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
     value(Cmd::StartTls, tag_no_case(b"starttls"))(buf)
 }
 
+fn etrn(buf: &[u8]) -> IResult<&[u8], Cmd<'_>> {
+    let parse_domain = preceded(cmd(b"etrn"), hello_domain);
+    map(parse_domain, |domain| Cmd::Etrn { domain })(buf)
+}
+
 fn is_base64(chr: u8) -> bool {
     is_alphanumeric(chr) || (chr == b'+') || (chr == b'/' || chr == b'=')
 }
@@ -234,10 +314,116 @@ fn next_string(it: &mut dyn Iterator<Item = &[u8]>) -> String {
 
 //---- Tests --------------------------------------------------------------------
 
+#[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn mail_mt_priority() {
+        let res = parse(b"MAIL FROM:<a@b> MT-PRIORITY=3\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                priority,
+                ..
+            }) => {
+                assert_eq!(reverse_path, "a@b");
+                assert_eq!(priority, Some(3));
+            }
+            _ => panic!("MT-PRIORITY incorrectly parsed"),
+        }
+        let res = parse(b"mail from:<a@b> SIZE=100 mt-priority=-9 BODY=8BITMIME\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                body,
+                size,
+                priority,
+                ..
+            }) => {
+                assert_eq!(body, Some(BodyType::EightBitMime));
+                assert_eq!(size, Some(100));
+                assert_eq!(priority, Some(-9));
+            }
+            _ => panic!("MT-PRIORITY with other parameters incorrectly parsed"),
+        }
+    }
+
+    #[test]
+    fn mail_deliver_by() {
+        let deliver_by = |line: &[u8]| match parse(line) {
+            Ok(Cmd::Mail { deliver_by, .. }) => deliver_by,
+            _ => panic!("BY incorrectly parsed"),
+        };
+        assert_eq!(
+            deliver_by(b"MAIL FROM:<a@b> BY=120;R\r\n"),
+            Some(Ok(DeliverBy {
+                seconds: 120,
+                mode: DeliverByMode::Return,
+                trace: false,
+            }))
+        );
+        assert_eq!(
+            deliver_by(b"mail from:<a@b> SIZE=100 by=-30;nt\r\n"),
+            Some(Ok(DeliverBy {
+                seconds: -30,
+                mode: DeliverByMode::Notify,
+                trace: true,
+            }))
+        );
+        assert_eq!(deliver_by(b"MAIL FROM:<a@b>\r\n"), None);
+        for line in [
+            &b"MAIL FROM:<a@b> BY=120R\r\n"[..],
+            b"MAIL FROM:<a@b> BY=120;X\r\n",
+            b"MAIL FROM:<a@b> BY=;R\r\n",
+            b"MAIL FROM:<a@b> BY=1000000000;R\r\n",
+        ] {
+            assert_eq!(deliver_by(line), Some(Err(())));
+        }
+    }
+
+    #[test]
+    fn mail_null_sender() {
+        match parse(b"MAIL FROM:<> BODY=7BIT\r\n") {
+            Ok(Cmd::Mail { reverse_path, .. }) => assert_eq!(reverse_path, ""),
+            _ => panic!("Null sender incorrectly parsed"),
+        }
+        assert!(parse(b"RCPT TO:<>\r\n").is_err());
+    }
+
+    fn mail_body(line: &[u8]) -> Option<BodyType> {
+        match parse(line) {
+            Ok(Cmd::Mail { body, .. }) => body,
+            _ => panic!("BODY incorrectly parsed"),
+        }
+    }
+
+    #[test]
+    fn mail_body_7bit() {
+        let body = mail_body(b"MAIL FROM:<a@b> BODY=7BIT\r\n");
+        assert_eq!(body, Some(BodyType::SevenBit));
+        assert!(!body.unwrap().is8bit());
+    }
+
+    #[test]
+    fn mail_body_8bitmime() {
+        let body = mail_body(b"mail from:<a@b> body=8bitmime\r\n");
+        assert_eq!(body, Some(BodyType::EightBitMime));
+        assert!(body.unwrap().is8bit());
+    }
+
+    #[test]
+    fn mail_body_binarymime() {
+        let body = mail_body(b"MAIL FROM:<a@b> SIZE=10 BODY=BINARYMIME\r\n");
+        assert_eq!(body, Some(BodyType::BinaryMime));
+        assert!(body.unwrap().is8bit());
+    }
+
+    #[test]
+    fn mail_body_absent() {
+        assert_eq!(mail_body(b"MAIL FROM:<a@b>\r\n"), None);
+    }
+
     #[test]
     fn auth_initial_plain() {
         let res = parse(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
@@ -283,4 +469,14 @@ mod tests {
             _ => panic!("Auth login without initial response incorrectly parsed"),
         };
     }
+
+    #[test]
+    fn etrn() {
+        let res = parse(b"ETRN @example.com\r\n");
+        match res {
+            Ok(Cmd::Etrn { domain }) => assert_eq!(domain, "@example.com"),
+            _ => panic!("Etrn incorrectly parsed"),
+        };
+        assert!(parse(b"etrn\r\n").is_err());
+    }
 }
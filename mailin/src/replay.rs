@@ -0,0 +1,311 @@
+use crate::{Action, Handler, Session};
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+
+/// A recorded SMTP session that can be replayed against a [`Session`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    // A line sent by the client, without the line ending
+    Client { number: usize, text: String },
+    // A reply line expected from the server, without the line ending
+    Server { number: usize, text: String },
+}
+
+/// Reason a transcript cannot be parsed or replayed. Line numbers start at 1.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The line is not a client or server line
+    Syntax {
+        /// The line in the transcript
+        line: usize,
+    },
+    /// The server replied differently, or not at all
+    Mismatch {
+        /// The line in the transcript
+        line: usize,
+        /// The expected reply line
+        expected: String,
+        /// The actual reply line, if there was one
+        actual: Option<String>,
+    },
+    /// The server sent a reply line that is not in the transcript
+    Unexpected {
+        /// The line in the transcript after which the reply was sent
+        line: usize,
+        /// The reply line
+        actual: String,
+    },
+}
+
+/// Parse a transcript of an SMTP session.
+///
+/// Lines sent by the client start with `C: ` and reply lines expected from the server
+/// start with `S: `. Each line of a multiline reply is given separately. Blank lines
+/// and lines starting with `#` are ignored. The transcript starts with the greeting
+/// of the server.
+///
+/// # Examples
+/// ```
+/// use mailin::{Handler, SessionBuilder};
+/// use mailin::replay::from_transcript;
+/// # use std::net::{IpAddr, Ipv4Addr};
+/// # struct EmptyHandler{};
+/// # impl Handler for EmptyHandler{};
+/// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+///
+/// let transcript = from_transcript(
+///     "S: 220 example.com ESMTP
+///      C: HELO client.example.com
+///      S: 250 OK
+///      C: QUIT
+///      S: 221 Goodbye",
+/// )?;
+/// let mut session = SessionBuilder::new("example.com").build(addr, EmptyHandler {});
+/// transcript.replay(&mut session)?;
+/// # Ok::<(), mailin::replay::ReplayError>(())
+/// ```
+pub fn from_transcript(transcript: &str) -> Result<Transcript, ReplayError> {
+    let mut lines = Vec::new();
+    for (i, line) in transcript.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // The space after the prefix is optional for an empty line, e.g in DATA
+        let text = |t: &str| t.strip_prefix(' ').unwrap_or(t).to_string();
+        let line = if let Some(t) = line.strip_prefix("C:") {
+            Line::Client {
+                number,
+                text: text(t),
+            }
+        } else if let Some(t) = line.strip_prefix("S:") {
+            Line::Server {
+                number,
+                text: text(t),
+            }
+        } else {
+            return Err(ReplayError::Syntax { line: number });
+        };
+        lines.push(line);
+    }
+    Ok(Transcript { lines })
+}
+
+impl Transcript {
+    /// Send the client lines to the session and check that it replies as recorded.
+    ///
+    /// TLS is not negotiated, after a successful STARTTLS the session continues as if
+    /// the connection had been upgraded.
+    pub fn replay<H: Handler>(&self, session: &mut Session<H>) -> Result<(), ReplayError> {
+        let mut replies = VecDeque::new();
+        push_reply_lines(
+            &mut replies,
+            &session.greeting().buffer().unwrap_or_default(),
+        );
+        let mut last_line = 0;
+        for line in &self.lines {
+            match line {
+                Line::Client { number, text } => {
+                    if let Some(actual) = replies.pop_front() {
+                        return Err(ReplayError::Unexpected {
+                            line: last_line,
+                            actual,
+                        });
+                    }
+                    let mut input = text.as_bytes().to_vec();
+                    input.extend_from_slice(b"\r\n");
+                    for res in session.feed(&input) {
+                        // A dropped connection gets no reply
+                        if res.action != Action::Drop {
+                            push_reply_lines(&mut replies, &res.buffer().unwrap_or_default());
+                        }
+                        if res.action == Action::UpgradeTls {
+                            session.tls_active();
+                        }
+                    }
+                    last_line = *number;
+                }
+                Line::Server { number, text } => {
+                    let actual = replies.pop_front();
+                    if actual.as_ref() != Some(text) {
+                        return Err(ReplayError::Mismatch {
+                            line: *number,
+                            expected: text.clone(),
+                            actual,
+                        });
+                    }
+                    last_line = *number;
+                }
+            }
+        }
+        match replies.pop_front() {
+            Some(actual) => Err(ReplayError::Unexpected {
+                line: last_line,
+                actual,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Split the bytes sent by the server into lines without line endings
+fn push_reply_lines(replies: &mut VecDeque<String>, buf: &[u8]) {
+    let text = String::from_utf8_lossy(buf);
+    replies.extend(text.lines().map(|line| line.to_string()));
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Syntax { line } => write!(f, "Line {line}: expected 'C: ' or 'S: '"),
+            ReplayError::Mismatch {
+                line,
+                expected,
+                actual: Some(actual),
+            } => write!(f, "Line {line}: expected '{expected}', got '{actual}'"),
+            ReplayError::Mismatch {
+                line,
+                expected,
+                actual: None,
+            } => write!(f, "Line {line}: expected '{expected}', got no reply"),
+            ReplayError::Unexpected { line, actual } => {
+                write!(f, "Line {line}: unexpected reply '{actual}'")
+            }
+        }
+    }
+}
+
+impl error::Error for ReplayError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{AUTH_OK, INVALID_CREDENTIALS, OK};
+    use crate::{AuthMechanism, Response, SessionBuilder};
+    use std::cell::RefCell;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::rc::Rc;
+
+    const SESSION: &str = "
+        S: 220 mail.example.com ESMTP
+        C: EHLO client.example.com
+        S: 250-server offers extensions:
+        S: 250-8BITMIME
+        S: 250 STARTTLS
+        C: STARTTLS
+        S: 220 Ready to start TLS
+        # The connection is now encrypted
+        C: EHLO client.example.com
+        S: 250-server offers extensions:
+        S: 250-8BITMIME
+        S: 250 AUTH PLAIN
+        C: AUTH PLAIN dGVzdAB0ZXN0ADEyMzQ=
+        S: 235 Authentication succeeded
+        C: MAIL FROM:<ship@sea.com>
+        S: 250 OK
+        C: RCPT TO:<fish@sea.com>
+        S: 250 OK
+        C: DATA
+        S: 354 Start mail input; end with <CRLF>.<CRLF>
+        C: Subject: Replayed
+        C:
+        C: Hello again
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 Goodbye
+    ";
+
+    #[derive(Default)]
+    struct SampleHandler {
+        data: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Handler for SampleHandler {
+        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.data.borrow_mut().extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn auth_plain(
+            &mut self,
+            _authorization_id: &str,
+            authentication_id: &str,
+            password: &str,
+        ) -> Response {
+            if authentication_id == "test" && password == "1234" {
+                AUTH_OK
+            } else {
+                INVALID_CREDENTIALS
+            }
+        }
+
+        fn rcpt(&mut self, _to: &str) -> Response {
+            OK
+        }
+    }
+
+    fn new_session(handler: SampleHandler) -> Session<SampleHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        SessionBuilder::new("mail.example.com")
+            .enable_start_tls()
+            .enable_auth(AuthMechanism::Plain)
+            .build(addr, handler)
+    }
+
+    #[test]
+    fn replay() {
+        let transcript = from_transcript(SESSION).unwrap();
+        let handler = SampleHandler::default();
+        let data = handler.data.clone();
+        transcript.replay(&mut new_session(handler)).unwrap();
+        assert_eq!(*data.borrow(), b"Subject: Replayed\r\n\r\nHello again\r\n");
+    }
+
+    #[test]
+    fn mismatch() {
+        let transcript = SESSION.replace("S: 235 Authentication succeeded", "S: 235 OK");
+        let transcript = from_transcript(&transcript).unwrap();
+        let err = transcript
+            .replay(&mut new_session(SampleHandler::default()))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ReplayError::Mismatch {
+                line: 15,
+                expected: "235 OK".to_string(),
+                actual: Some("235 Authentication succeeded".to_string()),
+            }
+        );
+        // A reply that is missing from the transcript
+        let transcript = SESSION.replace("S: 250 STARTTLS", "");
+        let transcript = from_transcript(&transcript).unwrap();
+        let err = transcript
+            .replay(&mut new_session(SampleHandler::default()))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ReplayError::Unexpected {
+                line: 5,
+                actual: "250 STARTTLS".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn syntax() {
+        assert_eq!(
+            from_transcript("S: 220 ready\nEHLO client.example.com"),
+            Err(ReplayError::Syntax { line: 2 })
+        );
+    }
+}
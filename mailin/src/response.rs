@@ -12,13 +12,10 @@ pub const GOODBYE: Response = Response::fixed(221, "Goodbye");
 pub const AUTH_OK: Response = Response::fixed(235, "Authentication succeeded");
 /// OK response
 pub const OK: Response = Response::fixed(250, "OK");
+// A command disabled by the server configuration
+pub(crate) const COMMAND_DISABLED: Response = Response::fixed(502, "Command disabled");
 // Non-commital response to VERIFY command
 pub(crate) const VERIFY_RESPONSE: Response = Response::fixed(252, "Maybe");
-// Empty response sent as an auth challenge.
-pub(crate) const EMPTY_AUTH_CHALLENGE: Response = Response::fixed(334, "");
-// Username response sent as an auth challenge for the login mechanism.
-// The message is a base64-encoded string "Username:"
-pub(crate) const USERNAME_AUTH_CHALLENGE: Response = Response::fixed(334, "VXNlcm5hbWU6");
 // Password response sent as an auth challenge for the login mechanism.
 // The message is a base64-encoded string "Password:"
 pub(crate) const PASSWORD_AUTH_CHALLENGE: Response = Response::fixed(334, "UGFzc3dvcmQ6");
@@ -31,23 +28,59 @@ pub(crate) const INVALID_STATE: Response =
 pub const NO_SERVICE: Response = Response::fixed(421, "Service not available, closing connection");
 /// Internal server error
 pub const INTERNAL_ERROR: Response = Response::fixed(451, "Aborted: local error in processing");
+/// The client stopped sending during DATA, the connection is closed
+pub const DATA_TIMEOUT: Response = Response::fixed_action(
+    451,
+    "Timeout waiting for data, closing connection",
+    Action::Close,
+);
 /// Insufficient system storage
 pub const OUT_OF_SPACE: Response = Response::fixed(452, "Insufficient system storage");
+/// TLS is not available for this client
+pub const TLS_NOT_AVAILABLE: Response = Response::fixed(454, "TLS not available");
 /// Authentication system is not working
 pub const TEMP_AUTH_FAILURE: Response = Response::fixed(454, "Temporary authentication failure");
 // Parser error
 pub(crate) const SYNTAX_ERROR: Response = Response::fixed(500, "Syntax error");
 // Parser found missing parameter
 pub(crate) const MISSING_PARAMETER: Response = Response::fixed(502, "Missing parameter");
+/// Syntax error in a mailbox address, see [`crate::address::parse()`]
+pub const BAD_ADDRESS_SYNTAX: Response = Response::fixed(501, "Syntax error in mailbox address");
+// The client sent "*" to cancel authentication (RFC 4954)
+pub(crate) const AUTH_ABORTED: Response = Response::fixed(501, "Authentication aborted");
+// MT-PRIORITY outside of the range -9 to 9
+pub(crate) const INVALID_PRIORITY: Response = Response::fixed(501, "Invalid MT-PRIORITY");
+// BY parameter of MAIL with a bad syntax or time
+pub(crate) const INVALID_DELIVER_BY: Response = Response::fixed(501, "Invalid BY parameter");
+/// Command not implemented
+pub const COMMAND_NOT_IMPLEMENTED: Response = Response::fixed(502, "Command not implemented");
 // Command is unexpected for the current state
 pub(crate) const BAD_SEQUENCE_COMMANDS: Response = Response::fixed(503, "Bad sequence of commands");
+// AUTH after a successful AUTH, RFC 4954 section 4
+pub(crate) const ALREADY_AUTHENTICATED: Response = Response::fixed(503, "Already authenticated");
+// The client has tried too many authentication mechanisms
+pub(crate) const TOO_MANY_AUTH_MECHANISMS: Response =
+    Response::fixed(503, "Too many authentication mechanisms tried");
+/// A line from the client exceeded the hard limit of the reader, the connection is closed
+pub const LINE_TOO_LONG: Response =
+    Response::fixed_action(500, "Line too long, closing connection", Action::Close);
+// Too many consecutive invalid commands, the connection is closed
+pub(crate) const TOO_MANY_ERRORS: Response =
+    Response::fixed_action(500, "Too many errors", Action::Close);
 /// User storage quota exceeded
 pub const NO_STORAGE: Response = Response::fixed(552, "Exceeded storage allocation");
 /// Message size limit exceeded
 pub(crate) const MESSAGE_SIZE_LIMIT_EXCEEDED: Response =
     Response::fixed(552, "Message size limit exceeded");
+// Hard limit on the amount of DATA exceeded, the connection is closed
+pub(crate) const DATA_LIMIT_EXCEEDED: Response =
+    Response::fixed_action(552, "Too much mail data, closing connection", Action::Close);
 /// Authentication required
 pub const AUTHENTICATION_REQUIRED: Response = Response::fixed(530, "Authentication required");
+/// The client used HELO on a server where it must use EHLO to discover STARTTLS and AUTH
+pub const EHLO_REQUIRED: Response = Response::fixed(530, "Must issue STARTTLS/EHLO first");
+/// The client used HELO on a server without STARTTLS where it must use EHLO to discover AUTH
+pub const EHLO_REQUIRED_NO_TLS: Response = Response::fixed(530, "Must issue EHLO first");
 /// Bad authentication attempt
 pub const INVALID_CREDENTIALS: Response = Response::fixed(535, "Invalid credentials");
 /// Unknown user
@@ -58,8 +91,42 @@ pub const BAD_HELLO: Response = Response::fixed(550, "Bad HELO");
 pub const BLOCKED_IP: Response = Response::fixed(550, "IP address on blocklists");
 /// Invalid mailbox name
 pub const BAD_MAILBOX: Response = Response::fixed(553, "Mailbox name not allowed");
+/// Reverse DNS of the client is not forward confirmed, the connection is closed
+pub const UNCONFIRMED_REVERSE_DNS: Response = Response::fixed_action(
+    554,
+    "Reverse DNS not confirmed, closing connection",
+    Action::Close,
+);
+/// The recipient is not local and the client may not relay to it
+pub const RELAYING_DENIED: Response = Response::fixed(550, "Relaying denied");
+/// Client address is listed on a DNS blocklist, the connection is closed
+pub const BLOCKLISTED_CLIENT: Response = Response::fixed_action(
+    554,
+    "Client address is on a blocklist, closing connection",
+    Action::Close,
+);
+// The HELO or EHLO domain is not fully qualified
+pub(crate) const NEED_FQDN: Response = Response::fixed(504, "Need fully-qualified hostname");
+// A second recipient for a message with a null sender, which should be a bounce
+pub(crate) const NULL_SENDER_RECIPIENTS: Response =
+    Response::fixed(550, "Null sender allowed for one recipient only");
+// A MAIL parameter for an extension that is not enabled
+pub(crate) const PARAMETER_NOT_RECOGNIZED: Response =
+    Response::fixed(555, "MAIL parameter not recognized");
+/// No recipients were accepted for the transaction
+pub const NO_VALID_RECIPIENTS: Response = Response::fixed(554, "No valid recipients");
 /// Error handling incoming message
 pub const TRANSACTION_FAILED: Response = Response::fixed(554, "Transaction failed");
+// A message with a bare CR or LF, which could hide an SMTP smuggling attack
+pub(crate) const BARE_LINE_ENDING: Response =
+    Response::fixed(554, "Message contains bare CR or LF");
+
+/// Close the connection without replying, e.g to give no feedback to a scanner
+pub const DROP_CONNECTION: Response =
+    Response::fixed_action(554, "Connection dropped", Action::Drop);
+
+// Maximum length of the text in a reply line, leaving room for the code and CRLF
+const MAX_REPLY_TEXT: usize = 512 - 4 - 2;
 
 /// Response contains a code and message to be sent back to the client
 #[derive(Clone, Debug, PartialEq)]
@@ -83,10 +150,15 @@ pub(crate) enum Message {
 }
 
 /// Action indicates the recommended action to take on a response
+///
+/// `Drop` was added in 0.7, a breaking change for exhaustive matches. Use
+/// [`Response::closes()`] to check whether a response ends the session.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Action {
     /// Send the response and close the connection
     Close,
+    /// Close the connection without sending the response
+    Drop,
     /// Upgrade the connection to use TLS
     UpgradeTls,
     /// Do not reply, wait for the client to send more data
@@ -148,27 +220,55 @@ impl Response {
         }
     }
 
-    /// Write the response to the given writer
-    pub fn write_to(&self, out: &mut dyn io::Write) -> io::Result<()> {
+    /// The human-readable text of the response.
+    ///
+    /// For a multiline response this is the text of the first line.
+    pub fn text(&self) -> &str {
         match &self.message {
-            Message::Dynamic(ref head, ref tail) => {
-                if tail.is_empty() {
-                    write!(out, "{} {}\r\n", self.code, head)?;
-                } else {
-                    write!(out, "{}-{}\r\n", self.code, head)?;
-                    for i in 0..tail.len() {
-                        if tail.len() > 1 && i < tail.len() - 1 {
-                            write!(out, "{}-{}\r\n", self.code, tail[i])?;
-                        } else {
-                            write!(out, "{} {}\r\n", self.code, tail[i])?;
-                        }
-                    }
-                }
-            }
-            Message::Fixed(s) => write!(out, "{} {}\r\n", self.code, s)?,
-            Message::Custom(s) => write!(out, "{} {}\r\n", self.code, s)?,
-            Message::Empty => (),
+            Message::Fixed(s) => s,
+            Message::Custom(s) => s,
+            Message::Dynamic(head, _) => head,
+            Message::Empty => "",
+        }
+    }
+
+    /// Create a copy of the response with different human-readable text.
+    ///
+    /// The code and action are kept, as are the extension lines of a multiline
+    /// response. An empty response stays empty.
+    pub fn with_text(&self, text: String) -> Self {
+        let message = match &self.message {
+            Message::Fixed(_) | Message::Custom(_) => Message::Custom(text),
+            Message::Dynamic(_, tail) => Message::Dynamic(text, tail.clone()),
+            Message::Empty => Message::Empty,
         };
+        Self {
+            code: self.code,
+            message,
+            is_error: self.is_error,
+            action: self.action.clone(),
+        }
+    }
+
+    /// Write the response to the given writer.
+    ///
+    /// Text that does not fit in the 512 octet limit on reply lines (RFC 5321) is
+    /// folded into a multiline response.
+    pub fn write_to(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        let lines: Vec<&str> = match &self.message {
+            Message::Dynamic(head, tail) => std::iter::once(head)
+                .chain(tail)
+                .flat_map(|line| fold(line))
+                .collect(),
+            Message::Fixed(s) => fold(s),
+            Message::Custom(s) => fold(s),
+            Message::Empty => return Ok(()),
+        };
+        let last = lines.len() - 1;
+        for (i, line) in lines.iter().enumerate() {
+            let separator = if i < last { '-' } else { ' ' };
+            write!(out, "{}{}{}\r\n", self.code, separator, line)?;
+        }
         Ok(())
     }
 
@@ -179,6 +279,12 @@ impl Response {
         Ok(buf)
     }
 
+    /// Does the session end after this response? True for [`Action::Close`] and
+    /// [`Action::Drop`].
+    pub fn closes(&self) -> bool {
+        matches!(self.action, Action::Close | Action::Drop)
+    }
+
     // Log the response
     pub(crate) fn log(&self) {
         match self.message {
@@ -190,3 +296,68 @@ impl Response {
         }
     }
 }
+
+// Split text into lines that fit into a reply line, preferring to split at spaces
+fn fold(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_REPLY_TEXT {
+        let mut end = MAX_REPLY_TEXT;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        match rest[..end].rfind(' ') {
+            Some(space) if space > 0 => {
+                lines.push(&rest[..space]);
+                rest = &rest[space + 1..];
+            }
+            _ => {
+                lines.push(&rest[..end]);
+                rest = &rest[end..];
+            }
+        }
+    }
+    lines.push(rest);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_long_response() {
+        let text = "x".repeat(1000);
+        let res = Response::custom(550, text.clone());
+        let buf = String::from_utf8(res.buffer().unwrap()).unwrap();
+        let lines: Vec<&str> = buf.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("550-"));
+        assert!(lines[1].starts_with("550 "));
+        assert!(lines.iter().all(|l| l.len() + 2 <= 512));
+        let joined: String = lines.iter().map(|l| &l[4..]).collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn fold_at_space() {
+        let word = "abcdefghi ";
+        let text = word.repeat(100);
+        let res = Response::custom(250, text.trim_end().to_string());
+        let buf = String::from_utf8(res.buffer().unwrap()).unwrap();
+        let lines: Vec<&str> = buf.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("250-abcdefghi"));
+        assert!(lines[0].ends_with("abcdefghi"));
+        assert!(lines[1].starts_with("250 abcdefghi"));
+        assert!(lines.iter().all(|l| l.len() + 2 <= 512));
+    }
+
+    #[test]
+    fn short_response() {
+        let res = Response::custom(250, "OK".to_string());
+        assert_eq!(res.buffer().unwrap(), b"250 OK\r\n");
+        let res = Response::custom(334, String::new());
+        assert_eq!(res.buffer().unwrap(), b"334 \r\n");
+    }
+}
@@ -1,9 +1,16 @@
+use std::borrow::Cow;
+use std::mem;
 use std::net::IpAddr;
 use std::str;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::address::Normalization;
 use crate::fsm::StateMachine;
 use crate::response::*;
-use crate::{AuthMechanism, Handler};
+use crate::{
+    AuthMechanism, BodyType, DeliverBy, Direction, Extension, Handler, OptionalCommand, Phase,
+};
 use either::{Left, Right};
 
 //------ Types -----------------------------------------------------------------
@@ -19,8 +26,10 @@ pub enum Cmd<'a> {
     },
     Mail {
         reverse_path: &'a str,
-        is8bit: bool,
+        body: Option<BodyType>,
         size: Option<usize>,
+        priority: Option<i32>,
+        deliver_by: Option<Result<DeliverBy, ()>>,
     },
     Rcpt {
         forward_path: &'a str,
@@ -31,6 +40,9 @@ pub enum Cmd<'a> {
     StartTls,
     Quit,
     Vrfy,
+    Etrn {
+        domain: &'a str,
+    },
     AuthLogin {
         username: String,
     },
@@ -45,23 +57,80 @@ pub enum Cmd<'a> {
     AuthResponse {
         response: &'a [u8],
     },
+    // Dummy command sent when the client cancels authentication
+    AuthAbort,
     // Dummy command to signify end of data
     DataEnd,
+    // Dummy command sent when the hard limit on received data is exceeded
+    DataLimitExceeded,
     // Dummy command sent when STARTTLS was successful
     StartedTls,
 }
 
+impl Cmd<'_> {
+    // The verb sent by the client, None for dummy commands
+    pub(crate) fn verb(&self) -> Option<&'static str> {
+        let verb = match self {
+            Cmd::Ehlo { .. } => "EHLO",
+            Cmd::Helo { .. } => "HELO",
+            Cmd::Mail { .. } => "MAIL",
+            Cmd::Rcpt { .. } => "RCPT",
+            Cmd::Data => "DATA",
+            Cmd::Rset => "RSET",
+            Cmd::Noop => "NOOP",
+            Cmd::StartTls => "STARTTLS",
+            Cmd::Quit => "QUIT",
+            Cmd::Vrfy => "VRFY",
+            Cmd::Etrn { .. } => "ETRN",
+            Cmd::AuthLogin { .. }
+            | Cmd::AuthPlain { .. }
+            | Cmd::AuthLoginEmpty
+            | Cmd::AuthPlainEmpty => "AUTH",
+            Cmd::AuthResponse { .. }
+            | Cmd::AuthAbort
+            | Cmd::DataEnd
+            | Cmd::DataLimitExceeded
+            | Cmd::StartedTls => return None,
+        };
+        Some(verb)
+    }
+}
+
 pub(crate) struct Credentials {
     pub authorization_id: String,
     pub authentication_id: String,
     pub password: String,
 }
 
+/// Information about a connection, passed to a custom greeting
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ConnInfo<'a> {
+    /// The name of the mail server
+    pub name: &'a str,
+    /// The address of the client
+    pub remote: IpAddr,
+    /// STARTTLS is offered to the client
+    pub start_tls: bool,
+}
+
+// Builds the text of the greeting for a connection
+pub(crate) type Banner = dyn Fn(&ConnInfo) -> String + Send + Sync;
+
 /// A single smtp session connected to a single client
 pub struct Session<H: Handler> {
     name: String,
+    remote: IpAddr,
+    start_tls: bool,
+    banner: Option<Arc<Banner>>,
     handler: H,
     fsm: StateMachine<H>,
+    lenient_line_endings: bool,
+    max_errors: Option<usize>,
+    // Number of consecutive invalid commands
+    errors: usize,
+    // Incomplete line received by feed()
+    pending: Vec<u8>,
 }
 
 #[derive(Clone)]
@@ -85,10 +154,30 @@ pub struct Session<H: Handler> {
 ///```
 pub struct SessionBuilder {
     name: String,
-    start_tls_extension: bool,
-    insecure_allow_plaintext_auth: bool,
-    auth_mechanisms: Vec<AuthMechanism>,
-    max_message_size: Option<usize>,
+    banner: Option<Arc<Banner>>,
+    pub(crate) start_tls_extension: bool,
+    pub(crate) insecure_allow_plaintext_auth: bool,
+    pub(crate) plaintext_auth_filter: Option<fn(IpAddr) -> bool>,
+    pub(crate) auth_mechanisms: Vec<AuthMechanism>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_data_bytes: Option<usize>,
+    pub(crate) data_progress_interval: Option<usize>,
+    pub(crate) etrn: bool,
+    pub(crate) mt_priority: bool,
+    pub(crate) deliver_by: Option<u32>,
+    #[cfg(feature = "digest")]
+    pub(crate) data_digest: bool,
+    pub(crate) restrict_null_sender: bool,
+    pub(crate) require_fqdn_helo: bool,
+    pub(crate) normalize_recipients: Option<Normalization>,
+    pub(crate) echo_commands: bool,
+    pub(crate) echo_auth_user: bool,
+    pub(crate) max_auth_mechanisms: Option<usize>,
+    pub(crate) custom_extensions: Vec<Extension>,
+    pub(crate) helo_rejection: Option<String>,
+    pub(crate) disabled_commands: Vec<OptionalCommand>,
+    max_errors: Option<usize>,
+    lenient_line_endings: bool,
 }
 
 impl SessionBuilder {
@@ -96,13 +185,60 @@ impl SessionBuilder {
     pub fn new<S: Into<String>>(name: S) -> Self {
         Self {
             name: name.into(),
+            banner: None,
             start_tls_extension: false,
             insecure_allow_plaintext_auth: false,
+            plaintext_auth_filter: None,
             auth_mechanisms: Vec::with_capacity(4),
             max_message_size: None,
+            max_data_bytes: None,
+            data_progress_interval: None,
+            etrn: false,
+            mt_priority: false,
+            deliver_by: None,
+            #[cfg(feature = "digest")]
+            data_digest: false,
+            restrict_null_sender: false,
+            require_fqdn_helo: false,
+            normalize_recipients: None,
+            echo_commands: false,
+            echo_auth_user: false,
+            max_auth_mechanisms: None,
+            custom_extensions: Vec::new(),
+            helo_rejection: None,
+            disabled_commands: Vec::new(),
+            max_errors: None,
+            lenient_line_endings: false,
         }
     }
 
+    /// Build the greeting sent to each client with the given function, instead of the
+    /// default `<name> ESMTP`.
+    ///
+    /// The function returns the text after the 220 code, e.g to include the address of
+    /// the client or whether STARTTLS is offered. Only the first line of the text is used.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{ConnInfo, Handler, SessionBuilder};
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// let session = SessionBuilder::new("example.com")
+    ///     .banner(|info: &ConnInfo| format!("{} ESMTP hello {}", info.name, info.remote))
+    ///     .build(addr, EmptyHandler {});
+    /// let greeting = session.greeting().buffer().unwrap();
+    /// assert_eq!(greeting, b"220 example.com ESMTP hello 127.0.0.1\r\n");
+    /// ```
+    pub fn banner<F>(&mut self, banner: F) -> &mut Self
+    where
+        F: Fn(&ConnInfo) -> String + Send + Sync + 'static,
+    {
+        self.banner = Some(Arc::new(banner));
+        self
+    }
+
     /// Enable support for StartTls
     pub fn enable_start_tls(&mut self) -> &mut Self {
         self.start_tls_extension = true;
@@ -115,6 +251,91 @@ impl SessionBuilder {
         self
     }
 
+    /// Enable the ETRN command (RFC 1985) and advertise it in response to EHLO.
+    ///
+    /// ETRN commands are passed to [`Handler::etrn()`].
+    pub fn enable_etrn(&mut self) -> &mut Self {
+        self.etrn = true;
+        self
+    }
+
+    /// Enable the BY parameter of MAIL (RFC 2852) and advertise DELIVERBY in response to
+    /// EHLO, with the minimum time in seconds that a client may ask for, or 0 for none.
+    ///
+    /// Deadlines are passed to [`Handler::deliver_by()`]. A deadline with a bad syntax,
+    /// or a deadline with the return mode that is shorter than the minimum or not in
+    /// the future, gets a 501 response.
+    pub fn enable_deliver_by(&mut self, min_seconds: u32) -> &mut Self {
+        self.deliver_by = Some(min_seconds);
+        self
+    }
+
+    /// Enable the MT-PRIORITY parameter of MAIL (RFC 6710) and advertise it in response
+    /// to EHLO.
+    ///
+    /// Priorities from -9 to 9 are passed to [`Handler::mt_priority()`].
+    pub fn enable_mt_priority(&mut self) -> &mut Self {
+        self.mt_priority = true;
+        self
+    }
+
+    /// Compute the SHA-256 digest of each message while it is received and pass it to
+    /// [`Handler::data_digest()`]. Requires the `digest` feature.
+    #[cfg(feature = "digest")]
+    pub fn enable_data_digest(&mut self) -> &mut Self {
+        self.data_digest = true;
+        self
+    }
+
+    /// Only allow one recipient for messages with a null sender, `MAIL FROM:<>`.
+    ///
+    /// A null sender is used for bounces (RFC 5321 section 4.5.5), which go to a single
+    /// recipient. Further recipients are rejected with a 550 response. The handler sees
+    /// a null sender as an empty `from` in [`Handler::mail()`].
+    pub fn restrict_null_sender(&mut self) -> &mut Self {
+        self.restrict_null_sender = true;
+        self
+    }
+
+    /// Only accept a fully-qualified domain name, or an address literal such as
+    /// `[192.0.2.1]`, in HELO and EHLO.
+    ///
+    /// Other names, e.g `localhost`, get a 504 response before they are passed to
+    /// [`Handler::helo()`].
+    pub fn require_fqdn_helo(&mut self) -> &mut Self {
+        self.require_fqdn_helo = true;
+        self
+    }
+
+    /// Normalize recipient addresses before they are passed to [`Handler::rcpt()`], see
+    /// [`crate::address::normalize()`].
+    ///
+    /// The envelope passed to [`Handler::data_start()`] keeps the addresses as the
+    /// client sent them.
+    pub fn normalize_recipients(&mut self, normalization: Normalization) -> &mut Self {
+        self.normalize_recipients = Some(normalization);
+        self
+    }
+
+    /// Include the command verb in the text of syntax error and bad sequence responses,
+    /// e.g. `503 Bad sequence of commands (RCPT)`.
+    ///
+    /// This helps when diagnosing misbehaving clients. It echoes client input back, so
+    /// it is off by default and should not be enabled in production.
+    pub fn enable_command_echo(&mut self) -> &mut Self {
+        self.echo_commands = true;
+        self
+    }
+
+    /// Include the authenticated username in the text of successful AUTH responses,
+    /// e.g. `235 Authentication succeeded (alice)`.
+    ///
+    /// The password is never included. Control characters in the username are replaced.
+    pub fn enable_auth_user_echo(&mut self) -> &mut Self {
+        self.echo_auth_user = true;
+        self
+    }
+
     /// Allow authentication over plaintext and advertise authentication mechanisms before a connection
     /// was upgraded to TLS with STARTTLS.
     ///
@@ -130,6 +351,24 @@ impl SessionBuilder {
         self
     }
 
+    /// Like [`Self::insecure_enable_plaintext_auth()`] but only for clients whose address
+    /// is accepted by `allow`, e.g local clients during a migration to TLS. Other clients
+    /// have to use STARTTLS before they can authenticate.
+    ///
+    /// # Examples
+    /// ```
+    /// # use mailin::{AuthMechanism, SessionBuilder};
+    /// let mut builder = SessionBuilder::new("example.com");
+    /// builder
+    ///     .enable_start_tls()
+    ///     .enable_auth(AuthMechanism::Plain)
+    ///     .insecure_enable_plaintext_auth_for(|ip| ip.is_loopback());
+    /// ```
+    pub fn insecure_enable_plaintext_auth_for(&mut self, allow: fn(IpAddr) -> bool) -> &mut Self {
+        self.plaintext_auth_filter = Some(allow);
+        self
+    }
+
     /// Specify a maximum message size.
     ///
     /// Will be reported to the client on helo/ehlo and will cause
@@ -139,18 +378,102 @@ impl SessionBuilder {
         self
     }
 
+    /// Specify a hard limit on the number of bytes received during DATA.
+    ///
+    /// Unlike [`Self::max_message_size()`], which rejects the message but waits for the end of
+    /// the data, exceeding this limit replies with a 552 and closes the connection. This
+    /// protects against clients that stream data forever without sending the terminating dot.
+    pub fn max_data_bytes(&mut self, max_data_bytes: usize) -> &mut Self {
+        self.max_data_bytes = Some(max_data_bytes);
+        self
+    }
+
+    /// Call [`Handler::data_progress()`] each time the given number of message bytes
+    /// has been received during DATA.
+    ///
+    /// The interval should be large, e.g a megabyte, to keep the overhead low. An
+    /// interval of zero disables the callback.
+    pub fn data_progress_interval(&mut self, bytes: usize) -> &mut Self {
+        self.data_progress_interval = Some(bytes).filter(|bytes| *bytes > 0);
+        self
+    }
+
+    /// Specify the number of consecutive invalid commands allowed before the connection is closed.
+    ///
+    /// Commands that fail to parse or arrive out of sequence count as errors, any other
+    /// command resets the count. When the limit is reached the client gets a 500 response
+    /// and the connection is closed. This stops clients that send garbage from keeping
+    /// the connection open.
+    pub fn max_errors(&mut self, max_errors: usize) -> &mut Self {
+        self.max_errors = Some(max_errors);
+        self
+    }
+
+    /// Specify the number of different authentication mechanisms a client may try.
+    ///
+    /// Once the limit is reached, AUTH commands that use another mechanism get a 503
+    /// response. Retrying a mechanism that was already tried is allowed. The count is
+    /// reset when authentication succeeds.
+    pub fn max_auth_mechanisms(&mut self, max_auth_mechanisms: usize) -> &mut Self {
+        self.max_auth_mechanisms = Some(max_auth_mechanisms);
+        self
+    }
+
+    /// Advertise an extension that mailin does not implement in the EHLO response,
+    /// usually a keyword created with [`Extension::custom()`]. Commands of the
+    /// extension are not recognised, the keyword is only advertised.
+    pub fn enable_extension(&mut self, extension: Extension) -> &mut Self {
+        self.custom_extensions.push(extension);
+        self
+    }
+
+    /// Specify the text of the 530 response to HELO when authentication is enabled.
+    ///
+    /// Clients must use EHLO to discover STARTTLS and AUTH, the default text is
+    /// `Must issue STARTTLS/EHLO first`, or `Must issue EHLO first` when STARTTLS is not
+    /// available. Only the first line of the text is used.
+    pub fn helo_rejection<S: Into<String>>(&mut self, text: S) -> &mut Self {
+        self.helo_rejection = Some(text.into());
+        self
+    }
+
+    /// Disable a command, e.g to reduce what a submission server exposes.
+    ///
+    /// A disabled command gets a 502 response in any state. Disabling ETRN also stops
+    /// it being advertised in response to EHLO.
+    pub fn disable_command(&mut self, command: OptionalCommand) -> &mut Self {
+        self.disabled_commands.push(command);
+        self
+    }
+
+    /// Accept lines terminated by a bare LF as if they were terminated by CRLF.
+    ///
+    /// This applies to commands as well as to the DATA terminator (`.\n`). Lines passed to
+    /// [`Handler::data()`] are normalized to end with CRLF. A lone `.` without a line
+    /// ending, the last input before the client closed the connection, also ends the data.
+    /// Strict CRLF handling is the default.
+    ///
+    /// With strict handling, only CRLF.CRLF ends the data and messages containing a bare CR
+    /// or LF are rejected, which prevents SMTP smuggling. A lenient server that relays mail
+    /// to a server that treats line endings differently can be used to smuggle messages.
+    pub fn lenient_line_endings(&mut self, lenient: bool) -> &mut Self {
+        self.lenient_line_endings = lenient;
+        self
+    }
+
     /// Build a new session to handle a connection from the given ip address
     pub fn build<H: Handler>(&self, remote: IpAddr, handler: H) -> Session<H> {
         Session {
             name: self.name.clone(),
+            remote,
+            start_tls: self.start_tls_extension,
+            banner: self.banner.clone(),
+            lenient_line_endings: self.lenient_line_endings,
+            max_errors: self.max_errors,
+            errors: 0,
+            pending: Vec::new(),
             handler,
-            fsm: StateMachine::new(
-                remote,
-                self.auth_mechanisms.clone(),
-                self.start_tls_extension,
-                self.insecure_allow_plaintext_auth,
-                self.max_message_size,
-            ),
+            fsm: StateMachine::new(remote, self),
         }
     }
 }
@@ -158,7 +481,19 @@ impl SessionBuilder {
 impl<H: Handler> Session<H> {
     /// Get a greeting to send to the client
     pub fn greeting(&self) -> Response {
-        Response::dynamic(220, format!("{} ESMTP", self.name), Vec::new())
+        let text = match &self.banner {
+            Some(banner) => {
+                let info = ConnInfo {
+                    name: &self.name,
+                    remote: self.remote,
+                    start_tls: self.start_tls,
+                };
+                let text = banner(&info);
+                text.lines().next().unwrap_or_default().to_string()
+            }
+            None => format!("{} ESMTP", self.name),
+        };
+        Response::dynamic(220, text, Vec::new())
     }
 
     /// STARTTLS active
@@ -192,36 +527,176 @@ impl<H: Handler> Session<H> {
     /// assert_eq!(&msg, b"250 OK\r\n");
     /// ```
     pub fn process(&mut self, line: &[u8]) -> Response {
+        let line = if self.lenient_line_endings && line == b"." && self.phase() == Phase::Data {
+            // A line without an ending is only seen at the end of the input
+            Cow::Borrowed(&b".\r\n"[..])
+        } else if self.lenient_line_endings {
+            normalize_line_ending(line)
+        } else {
+            Cow::Borrowed(line)
+        };
         // TODO: process within fsm
-        let response = match self.fsm.process_line(&mut self.handler, line) {
+        let response = match self.fsm.process_line(&mut self.handler, &line) {
             Left(cmd) => self.command(cmd),
             Right(res) => res,
         };
+        let response = self.count_errors(response);
         response.log();
         response
     }
 
+    /// Process bytes received from the client, which do not have to be whole lines.
+    ///
+    /// Incomplete lines are kept until the rest arrives with a later call. Returns the
+    /// responses to write back to the client, in order. Data lines, which get no reply,
+    /// do not produce a response.
+    ///
+    /// Processing stops after a response with [`Action::Close`], [`Action::Drop`] or
+    /// [`Action::UpgradeTls`], the rest of the input is discarded. A response with
+    /// [`Action::Drop`] is returned so the caller knows to close, it must not be
+    /// written. After an upgrade the caller has to start TLS and call
+    /// [`Session::tls_active()`] before feeding more input. Input is buffered without
+    /// limit, a caller reading from the network should bound the line length.
+    ///
+    /// # Examples
+    /// ```
+    /// use mailin::{Handler, SessionBuilder};
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// # struct EmptyHandler{};
+    /// # impl Handler for EmptyHandler{};
+    /// # let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    /// let mut session = SessionBuilder::new("name").build(addr, EmptyHandler {});
+    /// assert!(session.feed(b"HELO exa").is_empty());
+    /// let responses = session.feed(b"mple.com\r\nNOOP\r\n");
+    /// assert_eq!(responses.len(), 2);
+    /// assert_eq!(responses[0].buffer().unwrap(), b"250 OK\r\n");
+    /// ```
+    pub fn feed(&mut self, input: &[u8]) -> Vec<Response> {
+        let mut pending = mem::take(&mut self.pending);
+        pending.extend_from_slice(input);
+        let mut responses = Vec::new();
+        let mut start = 0;
+        while let Some(len) = pending[start..].iter().position(|c| *c == b'\n') {
+            let end = start + len + 1;
+            let response = self.process(&pending[start..end]);
+            start = end;
+            let stop = response.closes() || response.action == Action::UpgradeTls;
+            if response.action != Action::NoReply {
+                responses.push(response);
+            }
+            if stop {
+                start = pending.len();
+                break;
+            }
+        }
+        pending.drain(..start);
+        self.pending = pending;
+        responses
+    }
+
+    /// How long to wait before sending the given response to the client.
+    ///
+    /// See [`Handler::response_delay()`].
+    pub fn response_delay(&self, response: &Response) -> Option<Duration> {
+        self.handler.response_delay(response)
+    }
+
+    /// The SMTP extensions that would be advertised in response to EHLO.
+    ///
+    /// The result depends on the session configuration and on its current state, e.g.
+    /// STARTTLS is no longer offered once TLS is active.
+    pub fn supported_extensions(&self) -> Vec<Extension> {
+        self.fsm.supported_extensions()
+    }
+
+    /// What the session is doing, e.g to report the state of each connection to a
+    /// monitoring tool.
+    pub fn phase(&self) -> Phase {
+        self.fsm.phase()
+    }
+
+    /// Has the client successfully authenticated?
+    ///
+    /// Authentication is discarded when the connection is upgraded with STARTTLS. See
+    /// [`Handler::authenticated()`].
+    pub fn is_authenticated(&self) -> bool {
+        self.fsm.is_authenticated()
+    }
+
+    /// Has the connection been upgraded with STARTTLS?
+    ///
+    /// See [`Handler::secure()`].
+    pub fn is_secure(&self) -> bool {
+        self.fsm.is_secure()
+    }
+
+    /// Pass the raw bytes read from, or written to, the client to the handler.
+    ///
+    /// See [`Handler::on_wire()`].
+    pub fn on_wire(&mut self, direction: Direction, bytes: &[u8]) {
+        self.handler.on_wire(direction, bytes)
+    }
+
     /// Called in case a read/write error happens.
     pub fn io_error(&mut self) {
         self.fsm.io_error(&mut self.handler)
     }
 
     /// Called in case a eof happens.
+    ///
+    /// With lenient line endings, a lone `.` left over from [`Session::feed()`] ends the
+    /// data before the session is closed.
     pub fn eof(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        if self.lenient_line_endings && pending == b"." && self.phase() == Phase::Data {
+            self.process(&pending);
+        }
         self.fsm.eof(&mut self.handler)
     }
 
+    // Keep track of consecutive invalid commands, closing the connection if there are too many
+    fn count_errors(&mut self, response: Response) -> Response {
+        if response.action == Action::NoReply {
+            return response;
+        }
+        if !(500..=503).contains(&response.code) {
+            self.errors = 0;
+            return response;
+        }
+        self.errors += 1;
+        match self.max_errors {
+            Some(max_errors) if self.errors >= max_errors => TOO_MANY_ERRORS,
+            _ => response,
+        }
+    }
+
     fn command(&mut self, cmd: Cmd) -> Response {
         self.fsm.command(&mut self.handler, cmd)
     }
 }
 
+// Convert a line terminated by a bare LF into a line terminated by CRLF
+fn normalize_line_ending(line: &[u8]) -> Cow<'_, [u8]> {
+    match line {
+        [.., b'\r', b'\n'] => Cow::Borrowed(line),
+        [head @ .., b'\n'] => {
+            let mut normalized = Vec::with_capacity(line.len() + 1);
+            normalized.extend_from_slice(head);
+            normalized.extend_from_slice(b"\r\n");
+            Cow::Owned(normalized)
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
 //----- Tests ------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::fsm::SmtpState;
+    use crate::{DeliverByMode, KeywordError, Reason};
+    use std::io;
     use std::net::Ipv4Addr;
     use ternop::ternary;
 
@@ -235,6 +710,32 @@ mod tests {
         }
     }
 
+    // Handler that rejects messages containing spam once all the data is seen
+    struct ContentHandler(Vec<u8>);
+    impl Handler for ContentHandler {
+        fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
+            self.0.extend(buf);
+            Ok(())
+        }
+
+        fn data_end(&mut self) -> Response {
+            let is_spam = self.0.windows(4).any(|w| w == b"spam");
+            self.0.clear();
+            ternary!(is_spam, TRANSACTION_FAILED, OK)
+        }
+    }
+
+    struct EtrnHandler {}
+    impl Handler for EtrnHandler {
+        fn etrn(&mut self, domain: &str) -> Response {
+            ternary!(
+                domain == "example.com",
+                Response::custom(250, "Queuing started".to_string()),
+                Response::custom(458, "Unable to queue messages".to_string())
+            )
+        }
+    }
+
     // Check that the state machine matches the given state pattern
     macro_rules! assert_state {
         ($val:expr, $n:pat ) => {{
@@ -281,57 +782,753 @@ mod tests {
     }
 
     #[test]
-    fn domain_badchars() {
+    fn domain_badchars() {
+        let mut session = new_session();
+        let res = session.process(b"helo world\x40\xff\r\n");
+        assert_eq!(res.code, 500);
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+    }
+
+    #[test]
+    fn rcpt_to() {
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res1 = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res1.code, 250);
+        let res2 = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res2.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
+    #[derive(Default)]
+    struct RejectHandler {
+        data_start_to: Vec<String>,
+    }
+    impl Handler for RejectHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            ternary!(to.starts_with("fish"), OK, NO_MAILBOX)
+        }
+
+        fn data_start(
+            &mut self,
+            _domain: &str,
+            _from: &str,
+            _is8bit: bool,
+            to: &[String],
+        ) -> Response {
+            self.data_start_to = to.to_vec();
+            OK
+        }
+    }
+
+    #[derive(Default)]
+    struct RecipientHandler {
+        rcpt: Vec<String>,
+        data_start_to: Vec<String>,
+    }
+    impl Handler for RecipientHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            self.rcpt.push(to.to_string());
+            OK
+        }
+
+        fn data_start(
+            &mut self,
+            _domain: &str,
+            _from: &str,
+            _is8bit: bool,
+            to: &[String],
+        ) -> Response {
+            self.data_start_to = to.to_vec();
+            OK
+        }
+    }
+
+    #[derive(Default)]
+    struct PriorityHandler {
+        priority: Option<i8>,
+    }
+    impl Handler for PriorityHandler {
+        fn mt_priority(&mut self, priority: i8) {
+            self.priority = Some(priority);
+        }
+    }
+
+    #[test]
+    fn mt_priority() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_mt_priority()
+            .max_message_size(1000)
+            .build(addr, PriorityHandler::default());
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 MT-PRIORITY\r\n"));
+        let res = session.process(b"mail from:<a@b> MT-PRIORITY=99\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"mail from:<a@b> MT-PRIORITY=99999999999\r\n");
+        assert_eq!(res.code, 501);
+        // Not passed on when MAIL is rejected
+        let res = session.process(b"mail from:<a@b> SIZE=2000 MT-PRIORITY=3\r\n");
+        assert_eq!(res.code, 552);
+        assert_eq!(session.handler.priority, None);
+        let res = session.process(b"mail from:<a@b> MT-PRIORITY=3\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.priority, Some(3));
+
+        // Not accepted unless enabled
+        let mut session = SessionBuilder::new("some.name").build(addr, PriorityHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<a@b> MT-PRIORITY=3\r\n");
+        assert_eq!(res.code, 555);
+        assert_eq!(session.handler.priority, None);
+    }
+
+    #[derive(Default)]
+    struct DeliverByHandler {
+        deliver_by: Option<DeliverBy>,
+    }
+    impl Handler for DeliverByHandler {
+        fn deliver_by(&mut self, deliver_by: DeliverBy) {
+            self.deliver_by = Some(deliver_by);
+        }
+    }
+
+    #[test]
+    fn deliver_by() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_deliver_by(60)
+            .build(addr, DeliverByHandler::default());
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 DELIVERBY 60\r\n"));
+        // Over the limit of RFC 2852, shorter than the minimum and already passed
+        for line in [
+            &b"mail from:<a@b> BY=1000000000;R\r\n"[..],
+            b"mail from:<a@b> BY=30;R\r\n",
+            b"mail from:<a@b> BY=-5;R\r\n",
+            b"mail from:<a@b> BY=120\r\n",
+        ] {
+            assert_eq!(session.process(line).code, 501);
+        }
+        assert_eq!(session.handler.deliver_by, None);
+        let res = session.process(b"mail from:<a@b> BY=120;R\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(
+            session.handler.deliver_by,
+            Some(DeliverBy {
+                seconds: 120,
+                mode: DeliverByMode::Return,
+                trace: false,
+            })
+        );
+        // Not accepted unless enabled
+        let mut session = SessionBuilder::new("some.name").build(addr, DeliverByHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<a@b> BY=120;R\r\n");
+        assert_eq!(res.code, 555);
+    }
+
+    #[derive(Default)]
+    struct BodyTypeHandler {
+        body: Option<BodyType>,
+        is8bit: Option<bool>,
+    }
+    impl Handler for BodyTypeHandler {
+        fn body_type(&mut self, body: BodyType) {
+            self.body = Some(body);
+        }
+
+        fn data_start(
+            &mut self,
+            _domain: &str,
+            _from: &str,
+            is8bit: bool,
+            _to: &[String],
+        ) -> Response {
+            self.is8bit = Some(is8bit);
+            OK
+        }
+    }
+
+    #[test]
+    fn body_type() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, BodyTypeHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<a@b> BODY=BINARYMIME\r\n");
+        assert_eq!(res.code, 555);
+        assert_eq!(session.handler.body, None);
+        let res = session.process(b"mail from:<a@b> BODY=8BITMIME\r\n");
+        assert_eq!(res.code, 250);
+        assert_eq!(session.handler.body, Some(BodyType::EightBitMime));
+        session.process(b"rcpt to:<c@d>\r\n");
+        session.process(b"data\r\n");
+        assert_eq!(session.handler.is8bit, Some(true));
+    }
+
+    #[derive(Default)]
+    struct SecureHandler {
+        secure: Vec<bool>,
+    }
+    impl Handler for SecureHandler {
+        fn secure(&mut self, secure: bool) {
+            self.secure.push(secure);
+        }
+    }
+
+    #[test]
+    fn secure() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_start_tls()
+            .build(addr, SecureHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<a@b>\r\n");
+        assert_eq!(session.handler.secure, vec![false]);
+        assert!(!session.is_secure());
+        session.process(b"rset\r\n");
+        assert_eq!(session.process(b"starttls\r\n").code, 220);
+        session.tls_active();
+        assert!(session.is_secure());
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<a@b>\r\n");
+        assert_eq!(session.handler.secure, vec![false, true]);
+    }
+
+    #[test]
+    fn fqdn_helo() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .require_fqdn_helo()
+            .build(addr, EmptyHandler {});
+        for domain in ["localhost", "localhost.", ".com", "[192.0.2.300]", "[fish]"] {
+            let res = session.process(format!("ehlo {domain}\r\n").as_bytes());
+            assert_eq!(
+                res.buffer().unwrap(),
+                b"504 Need fully-qualified hostname\r\n",
+                "{domain}"
+            );
+            assert_eq!(
+                session
+                    .process(format!("helo {domain}\r\n").as_bytes())
+                    .code,
+                504
+            );
+        }
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        for domain in [
+            "mail.example.com",
+            "mail.example.com.",
+            "[192.0.2.1]",
+            "[IPv6:2001:db8::1]",
+        ] {
+            let res = session.process(format!("ehlo {domain}\r\n").as_bytes());
+            assert_eq!(res.code, 250, "{domain}");
+            assert_eq!(
+                session
+                    .process(format!("helo {domain}\r\n").as_bytes())
+                    .code,
+                250
+            );
+        }
+        // Any name is accepted unless enabled
+        let mut session = SessionBuilder::new("some.name").build(addr, EmptyHandler {});
+        assert_eq!(session.process(b"ehlo localhost\r\n").code, 250);
+    }
+
+    #[test]
+    fn null_sender() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .restrict_null_sender()
+            .build(addr, EmptyHandler {});
+        session.process(b"helo a.domain\r\n");
+        // A bounce to one recipient
+        assert_eq!(session.process(b"mail from:<>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<ship@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n").code, 550);
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        assert_eq!(session.process(b".\r\n").code, 250);
+        // Other senders can have many recipients
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<crab@sea.com>\r\n").code, 250);
+
+        // Not restricted unless enabled
+        let mut session = new_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<>\r\n");
+        assert_eq!(session.process(b"rcpt to:<ship@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n").code, 250);
+    }
+
+    #[test]
+    fn normalize_recipients() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .normalize_recipients(Normalization {
+                strip_tag: true,
+                ..Default::default()
+            })
+            .build(addr, RecipientHandler::default());
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<Fish+Tank@SEA.com>\r\n");
+        session.process(b"rcpt to:<crab@Sea.Com>\r\n");
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        assert_eq!(session.handler.rcpt, vec!["Fish@sea.com", "crab@sea.com"]);
+        // The envelope keeps the original addresses
+        assert_eq!(
+            session.handler.data_start_to,
+            vec!["Fish+Tank@SEA.com", "crab@Sea.Com"]
+        );
+    }
+
+    #[test]
+    fn phase() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_auth(AuthMechanism::Login)
+            .insecure_enable_plaintext_auth()
+            .build(addr, AuthHandler {});
+        assert_eq!(session.phase(), Phase::Connected);
+        let steps: [(&[u8], Phase); 9] = [
+            (b"ehlo a.domain\r\n", Phase::Greeted),
+            (b"auth login\r\n", Phase::Authenticating),
+            (b"dGVzdA==\r\n", Phase::Authenticating),
+            (b"MTIzNA==\r\n", Phase::Greeted),
+            (b"mail from:<ship@sea.com>\r\n", Phase::Mail),
+            (b"rcpt to:<fish@sea.com>\r\n", Phase::Rcpt),
+            (b"data\r\n", Phase::Data),
+            (b".\r\n", Phase::Greeted),
+            (b"quit\r\n", Phase::Closed),
+        ];
+        for (line, phase) in steps {
+            session.process(line);
+            assert_eq!(session.phase(), phase, "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn etrn() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_etrn()
+            .build(addr, EtrnHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.ends_with("250 ETRN\r\n"));
+        let res = session.process(b"etrn example.com\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 Queuing started\r\n");
+        assert_eq!(session.process(b"etrn other.com\r\n").code, 458);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+
+        // Disabled by default
+        let mut session = SessionBuilder::new("some.name").build(addr, EtrnHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        assert_eq!(session.process(b"etrn example.com\r\n").code, 502);
+
+        // Allowed before authentication
+        let mut session = SessionBuilder::new("some.name")
+            .enable_etrn()
+            .enable_auth(AuthMechanism::Plain)
+            .insecure_enable_plaintext_auth()
+            .build(addr, EtrnHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        assert_eq!(session.process(b"etrn example.com\r\n").code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    #[test]
+    fn data_end_reject() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, ContentHandler(vec![]));
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Buy spam now\r\n");
+        let res = session.process(b".\r\n");
+        assert_eq!(res.code, 554);
+        assert_eq!(res.action, Action::Reply);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // A new transaction can start after the rejection
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\r\n").code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Hello World\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn no_valid_recipients() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, RejectHandler::default());
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"rcpt to:<kraken@sea.com>\r\n");
+        assert_eq!(res.code, 550);
+        let res = session.process(b"data\r\n");
+        assert_eq!(res, NO_VALID_RECIPIENTS);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"data\r\n");
+        assert_eq!(res.code, 354);
+    }
+
+    #[test]
+    fn partial_recipients() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, RejectHandler::default());
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<kraken@sea.com>\r\n").code, 550);
+        assert_eq!(session.process(b"rcpt to:<fish2@sea.com>\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<squid@sea.com>\r\n").code, 550);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        assert_eq!(
+            session.handler.data_start_to,
+            vec!["fish@sea.com".to_string(), "fish2@sea.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn helo_noop() {
+        let mut session = new_session();
+        let res1 = session.process(b"helo a.domain\r\n");
+        assert_eq!(res1.code, 250);
+        let res2 = session.process(b"noop\r\n");
+        assert_eq!(res2.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res3 = session.process(b"noop\r\n");
+        assert_eq!(res3.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res4 = session.process(b"noop\r\n");
+        assert_eq!(res4.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+    }
+
+    #[test]
+    fn data() {
+        let mut session = new_data_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res1 = session.process(b"data\r\n");
+        assert_eq!(res1.code, 354);
+        let res2 = session.process(b"Hello World\r\n");
+        assert_eq!(res2.action, Action::NoReply);
+        let res3 = session.process(b".\r\n");
+        assert_eq!(res3.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
+    }
+
+    #[test]
+    fn data_prompt() {
+        struct PromptHandler;
+        impl Handler for PromptHandler {
+            fn data_start(&mut self, _: &str, _: &str, _: bool, _: &[String]) -> Response {
+                Response::fixed(354, "Go ahead, end with <CRLF>.<CRLF>")
+            }
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, PromptHandler);
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        let res = session.process(b"data\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"354 Go ahead, end with <CRLF>.<CRLF>\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        assert_eq!(session.process(b".\r\n").code, 250);
+    }
+
+    #[test]
+    fn too_many_errors() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .max_errors(3)
+            .build(addr, EmptyHandler {});
+        assert_eq!(session.process(b"\x16\x03\x01garbage\r\n").code, 500);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\r\n").code, 503);
+        // A valid command resets the count
+        assert_eq!(session.process(b"noop\r\n").code, 250);
+        assert_eq!(session.process(b"GET / HTTP/1.1\r\n").code, 500);
+        let res = session.process(b"Host: example.com\r\n");
+        assert_eq!(res.code, 500);
+        assert_eq!(res.action, Action::Reply);
+        let res = session.process(b"\r\n");
+        assert_eq!(res.code, 500);
+        assert_eq!(res.action, Action::Close);
+    }
+
+    #[test]
+    fn reauthentication() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_auth(AuthMechanism::Login)
+            .insecure_enable_plaintext_auth();
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        // EHLO and RSET keep the authentication
+        for line in [&b"ehlo a.domain\r\n"[..], b"rset\r\n"] {
+            assert_eq!(session.process(line).code, 250);
+            let auth: [&[u8]; 4] = [
+                b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n",
+                b"auth plain\r\n",
+                b"auth login dGVzdA==\r\n",
+                b"auth login\r\n",
+            ];
+            for command in auth {
+                assert_eq!(session.process(command), ALREADY_AUTHENTICATED);
+                assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+            }
+        }
+        assert!(session.is_authenticated());
+    }
+
+    #[test]
+    fn auth_user_echo() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_auth(AuthMechanism::Login)
+            .insecure_enable_plaintext_auth();
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.buffer().unwrap(), b"235 Authentication succeeded\r\n");
+
+        builder.enable_auth_user_echo();
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"235 Authentication succeeded (test)\r\n"
+        );
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth login dGVzdA==\r\n");
+        let res = session.process(b"MTIzNA==\r\n");
+        let text = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert_eq!(text, "235 Authentication succeeded (test)\r\n");
+        assert!(!text.contains("1234"));
+        // Failures do not echo the username
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+        assert_eq!(res.buffer().unwrap(), b"535 Invalid credentials\r\n");
+    }
+
+    #[test]
+    fn max_auth_mechanisms() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_auth(AuthMechanism::Login)
+            .insecure_enable_plaintext_auth();
+        // No limit by default
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+        assert_eq!(res.code, 535);
+        let res = session.process(b"auth login\r\n");
+        assert_eq!(res.code, 334);
+
+        builder.max_auth_mechanisms(1);
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0AHdyb25n\r\n");
+        assert_eq!(res.code, 535);
+        let res = session.process(b"auth login\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"503 Too many authentication mechanisms tried\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        // The mechanism already tried can be repeated
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+    }
+
+    #[test]
+    fn command_echo() {
+        fn reply(session: &mut Session<EmptyHandler>, line: &[u8]) -> Vec<u8> {
+            let mut buf = Vec::new();
+            session.process(line).write_to(&mut buf).unwrap();
+            buf
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_command_echo()
+            .build(addr, EmptyHandler {});
+        let res = reply(&mut session, b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res, b"503 Bad sequence of commands (RCPT)\r\n");
+        let res = reply(&mut session, b"bogus command\r\n");
+        assert_eq!(res, b"500 Syntax error (BOGUS)\r\n");
+        // Only verbs are echoed, not arbitrary input
+        let res = reply(&mut session, b"\x16\x03\x01garbage\r\n");
+        assert_eq!(res, b"500 Syntax error\r\n");
+        // Disabled by default
         let mut session = new_session();
-        let res = session.process(b"helo world\x40\xff\r\n");
-        assert_eq!(res.code, 500);
-        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        let res = reply(&mut session, b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res, b"503 Bad sequence of commands\r\n");
+        let res = reply(&mut session, b"bogus command\r\n");
+        assert_eq!(res, b"500 Syntax error\r\n");
     }
 
     #[test]
-    fn rcpt_to() {
-        let mut session = new_session();
+    fn data_limit_exceeded() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .max_data_bytes(20)
+            .build(addr, DataHandler(vec![]));
         session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
-        let res1 = session.process(b"rcpt to:<fish@sea.com>\r\n");
-        assert_eq!(res1.code, 250);
-        let res2 = session.process(b"rcpt to:<kraken@sea.com>\r\n");
-        assert_eq!(res2.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        let res = session.process(b"Hello World\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b"Hello again\r\n");
+        assert_eq!(res.code, 552);
+        assert_eq!(res.action, Action::Close);
+        assert_state!(session.fsm.current_state(), SmtpState::Invalid);
+        assert_eq!(&session.handler.0, b"Hello World\r\n");
     }
 
     #[test]
-    fn helo_noop() {
-        let mut session = new_session();
-        let res1 = session.process(b"helo a.domain\r\n");
-        assert_eq!(res1.code, 250);
-        let res2 = session.process(b"noop\r\n");
-        assert_eq!(res2.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    fn data_progress() {
+        #[derive(Default)]
+        struct ProgressHandler(Vec<usize>);
+        impl Handler for ProgressHandler {
+            fn data_progress(&mut self, bytes: usize) {
+                self.0.push(bytes);
+            }
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .data_progress_interval(100)
+            .build(addr, ProgressHandler::default());
+        session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
-        let res3 = session.process(b"noop\r\n");
-        assert_eq!(res3.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Mail);
         session.process(b"rcpt to:<fish@sea.com>\r\n");
-        let res4 = session.process(b"noop\r\n");
-        assert_eq!(res4.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Rcpt);
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        // 25 lines of 10 bytes
+        for _ in 0..25 {
+            session.process(b"01234567\r\n");
+        }
+        // A line longer than the interval is reported once
+        let mut line = vec![b'x'; 248];
+        line.extend_from_slice(b"\r\n");
+        session.process(&line);
+        assert_eq!(session.process(b".\r\n").code, 250);
+        assert_eq!(session.handler.0, vec![100, 200, 500]);
     }
 
     #[test]
-    fn data() {
-        let mut session = new_data_session();
+    fn data_error_response() {
+        // Maps a full disk to a temporary failure and records the reason
+        #[derive(Default)]
+        struct DiskHandler {
+            errors: Vec<io::ErrorKind>,
+            reason: Option<Reason>,
+        }
+        impl Handler for DiskHandler {
+            fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+                if buf.starts_with(b"Full") {
+                    Err(io::ErrorKind::StorageFull.into())
+                } else {
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+            }
+            fn on_error(&mut self, err: io::Error) -> Response {
+                self.errors.push(err.kind());
+                match err.kind() {
+                    io::ErrorKind::StorageFull => OUT_OF_SPACE,
+                    _ => TRANSACTION_FAILED,
+                }
+            }
+            fn data_end_error(&mut self, reason: Reason) {
+                self.reason = Some(reason);
+            }
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, DiskHandler::default());
+        session.process(b"helo a.domain\r\n");
+        for (line, code) in [(&b"Full\r\n"[..], 452), (b"Slow\r\n", 554)] {
+            session.process(b"mail from:<ship@sea.com>\r\n");
+            session.process(b"rcpt to:<fish@sea.com>\r\n");
+            assert_eq!(session.process(b"data\r\n").code, 354);
+            assert_eq!(session.process(line).code, code);
+            // Further data is discarded
+            assert_eq!(session.process(b"More\r\n").action, Action::NoReply);
+            assert_eq!(session.process(b".\r\n").action, Action::NoReply);
+            assert_eq!(session.handler.reason, Some(Reason::Processing));
+        }
+        assert_eq!(
+            session.handler.errors,
+            vec![io::ErrorKind::StorageFull, io::ErrorKind::TimedOut]
+        );
+    }
+
+    #[cfg(feature = "digest")]
+    #[test]
+    fn data_digest() {
+        #[derive(Default)]
+        struct DigestHandler(Option<[u8; 32]>);
+        impl Handler for DigestHandler {
+            fn data_digest(&mut self, digest: [u8; 32]) {
+                self.0 = Some(digest);
+            }
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .enable_data_digest()
+            .build(addr, DigestHandler::default());
         session.process(b"helo a.domain\r\n");
         session.process(b"mail from:<ship@sea.com>\r\n");
         session.process(b"rcpt to:<fish@sea.com>\r\n");
-        let res1 = session.process(b"data\r\n");
-        assert_eq!(res1.code, 354);
-        let res2 = session.process(b"Hello World\r\n");
-        assert_eq!(res2.action, Action::NoReply);
-        let res3 = session.process(b".\r\n");
-        assert_eq!(res3.code, 250);
-        assert_state!(session.fsm.current_state(), SmtpState::Hello);
-        assert_eq!(&session.handler.0, b"Hello World\r\n");
+        assert_eq!(session.process(b"data\r\n").code, 354);
+        session.process(b"Subject: Hash\r\n");
+        session.process(b"\r\n");
+        session.process(b"Hello\r\n");
+        session.process(b"..dot\r\n");
+        assert_eq!(session.process(b".\r\n").code, 250);
+        // The digest of the message after dot-unstuffing
+        let hex: String = session
+            .handler
+            .0
+            .unwrap()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            hex,
+            "ab774997b79aab0e238193c3c10a75864cd997fd89ff35e487fa369bcd3cfe81"
+        );
     }
 
     #[test]
@@ -352,6 +1549,143 @@ mod tests {
         assert_eq!(&session.handler.0, b"Hello World\r\n.\r\n");
     }
 
+    #[test]
+    fn bare_lf_lenient() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name")
+            .lenient_line_endings(true)
+            .build(addr, DataHandler(vec![]));
+        assert_eq!(session.process(b"helo a.domain\n").code, 250);
+        assert_eq!(session.process(b"mail from:<ship@sea.com>\n").code, 250);
+        assert_eq!(session.process(b"rcpt to:<fish@sea.com>\n").code, 250);
+        assert_eq!(session.process(b"data\n").code, 354);
+        let res = session.process(b"Hello World\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b"Mixed endings\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        let res = session.process(b".\n");
+        assert_eq!(res.code, 250);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        assert_eq!(&session.handler.0, b"Hello World\r\nMixed endings\r\n");
+    }
+
+    #[test]
+    fn bare_lf_strict() {
+        let mut session = new_data_session();
+        let res = session.process(b"helo a.domain\n");
+        assert_eq!(res.code, 500);
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        let res = session.process(b".\n");
+        assert_eq!(res.action, Action::NoReply);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        // Not the end of data, the previous line did not end with CRLF
+        let res = session.process(b".\r\n");
+        assert_eq!(res.action, Action::NoReply);
+        assert_state!(session.fsm.current_state(), SmtpState::Data);
+        let res = session.process(b".\r\n");
+        assert_eq!(res, BARE_LINE_ENDING);
+        assert!(session.handler.0.is_empty());
+    }
+
+    #[test]
+    fn data_terminators() {
+        let terminators: [&[u8]; 3] = [b".\r\n", b".\n", b"."];
+        for lenient in [false, true] {
+            for (i, terminator) in terminators.iter().enumerate() {
+                let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+                let mut session = SessionBuilder::new("some.name")
+                    .lenient_line_endings(lenient)
+                    .build(addr, DataHandler(vec![]));
+                session.process(b"helo a.domain\r\n");
+                session.process(b"mail from:<ship@sea.com>\r\n");
+                session.process(b"rcpt to:<fish@sea.com>\r\n");
+                session.process(b"data\r\n");
+                session.process(b"Hello\r\n");
+                let res = session.process(terminator);
+                if lenient || i == 0 {
+                    assert_eq!(res.code, 250);
+                    assert_state!(session.fsm.current_state(), SmtpState::Hello);
+                    assert_eq!(&session.handler.0, b"Hello\r\n");
+                } else {
+                    assert_eq!(res.action, Action::NoReply);
+                    assert_state!(session.fsm.current_state(), SmtpState::Data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn feed_dot_at_eof() {
+        for lenient in [false, true] {
+            let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+            let mut session = SessionBuilder::new("some.name")
+                .lenient_line_endings(lenient)
+                .build(addr, ContentHandler(vec![]));
+            let res = session.feed(
+                b"helo a.domain\r\n\
+                mail from:<ship@sea.com>\r\n\
+                rcpt to:<fish@sea.com>\r\n\
+                data\r\n\
+                Hello\r\n.",
+            );
+            assert_eq!(res.len(), 4);
+            session.eof();
+            if lenient {
+                // data_end() cleared the message
+                assert!(session.handler.0.is_empty());
+                assert_state!(session.fsm.current_state(), SmtpState::Hello);
+            } else {
+                assert_eq!(&session.handler.0, b"Hello\r\n");
+                assert_state!(session.fsm.current_state(), SmtpState::Data);
+            }
+        }
+    }
+
+    #[test]
+    fn smuggling() {
+        // Ways to hide a second message that another server could accept
+        let vectors: [&[u8]; 4] = [
+            b"Hello\n.\r\n",
+            b"Hello\r\n.\n",
+            b"Hello\r.\r\n",
+            b"Hello\r\n.\r\r\n",
+        ];
+        for lenient in [false, true] {
+            for (i, vector) in vectors.iter().enumerate() {
+                let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+                let mut session = SessionBuilder::new("some.name")
+                    .lenient_line_endings(lenient)
+                    .build(addr, DataHandler(vec![]));
+                let mut input = b"helo a.domain\r\n\
+                    mail from:<ship@sea.com>\r\n\
+                    rcpt to:<fish@sea.com>\r\n\
+                    data\r\n"
+                    .to_vec();
+                input.extend_from_slice(vector);
+                input.extend_from_slice(
+                    b"mail from:<smuggled@sea.com>\r\n\
+                    rcpt to:<fish@sea.com>\r\n\
+                    data\r\n\
+                    Smuggled\r\n\
+                    .\r\n",
+                );
+                let res = session.feed(&input);
+                let codes: Vec<u16> = res.iter().map(|r| r.code).collect();
+                if lenient && i < 2 {
+                    // A bare LF is a line ending when lenient
+                    assert_eq!(codes, vec![250, 250, 250, 354, 250, 250, 250, 354, 250]);
+                } else {
+                    // One message that is rejected
+                    assert_eq!(codes, vec![250, 250, 250, 354, 554]);
+                    assert_state!(session.fsm.current_state(), SmtpState::Hello);
+                }
+            }
+        }
+    }
+
     #[test]
     fn data_8bit() {
         let mut session = new_session();
@@ -430,6 +1764,28 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn rset_response() {
+        struct RsetHandler {
+            resets: usize,
+        }
+        impl Handler for RsetHandler {
+            fn rset(&mut self) -> Response {
+                self.resets += 1;
+                Response::custom(250, format!("Reset number {}", self.resets))
+            }
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.domain").build(addr, RsetHandler { resets: 0 });
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 Reset number 1\r\n");
+        session.process(b"helo some.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rset\r\n");
+        assert_eq!(res.buffer().unwrap(), b"250 Reset number 2\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
     #[test]
     fn rset_idle() {
         let mut session = new_session();
@@ -497,6 +1853,34 @@ mod tests {
         builder.build(addr, AuthHandler {})
     }
 
+    #[test]
+    fn plaintext_auth_filter() {
+        let mut builder = SessionBuilder::new("some.domain");
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_start_tls()
+            .insecure_enable_plaintext_auth_for(|ip| ip.is_loopback());
+        let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = builder.build(loopback, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+
+        let external = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut session = builder.build(external, AuthHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert!(!String::from_utf8(res.buffer().unwrap())
+            .unwrap()
+            .contains("AUTH"));
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 503);
+        // After STARTTLS the external client can authenticate
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+    }
+
     fn start_tls(session: &mut Session<AuthHandler>) {
         let res = session.process(b"ehlo a.domain\r\n");
         assert_eq!(res.code, 250);
@@ -506,6 +1890,66 @@ mod tests {
         session.tls_active();
     }
 
+    #[test]
+    fn supported_extensions() {
+        let mut session = new_session();
+        assert_eq!(
+            session.supported_extensions(),
+            vec![Extension::EightBitMime]
+        );
+
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        session = SessionBuilder::new("some.domain")
+            .max_message_size(1000)
+            .enable_start_tls()
+            .build(addr, EmptyHandler {});
+        assert_eq!(
+            session.supported_extensions(),
+            vec![
+                Extension::EightBitMime,
+                Extension::Size(1000),
+                Extension::StartTls
+            ]
+        );
+
+        let mut session = new_auth_session(true);
+        assert_eq!(
+            session.supported_extensions(),
+            vec![Extension::EightBitMime, Extension::StartTls]
+        );
+        start_tls(&mut session);
+        assert_eq!(
+            session.supported_extensions(),
+            vec![
+                Extension::EightBitMime,
+                Extension::Auth(vec![AuthMechanism::Plain, AuthMechanism::Login])
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_extensions() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.domain")
+            .enable_extension(Extension::custom("NO-SOLICITING net.example:ADV").unwrap())
+            .enable_extension(Extension::custom("XVENDOR").unwrap())
+            .build(addr, EmptyHandler {});
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-server offers extensions:\r\n\
+            250-8BITMIME\r\n\
+            250-NO-SOLICITING net.example:ADV\r\n\
+            250 XVENDOR\r\n"
+        );
+
+        assert_eq!(Extension::custom("auth"), Err(KeywordError::Builtin));
+        assert_eq!(Extension::custom("Mt-Priority"), Err(KeywordError::Builtin));
+        for keyword in ["", "-X", "X_Y", "X\r\n250 AUTH PLAIN", "X  Y", "X Y\t"] {
+            assert_eq!(Extension::custom(keyword), Err(KeywordError::Syntax));
+        }
+    }
+
     #[test]
     fn noauth_denied() {
         let mut session = new_auth_session(true);
@@ -515,6 +1959,93 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    #[test]
+    fn helo_requires_ehlo() {
+        let mut session = new_auth_session(true);
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res, EHLO_REQUIRED);
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"530 Must issue STARTTLS/EHLO first\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        // STARTTLS is not suggested without TLS
+        let mut session = new_auth_session(false);
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res.buffer().unwrap(), b"530 Must issue EHLO first\r\n");
+        // The text is configurable
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.domain")
+            .enable_auth(AuthMechanism::Plain)
+            .enable_start_tls()
+            .helo_rejection("Use EHLO and STARTTLS, see https://example.com/mail\r\nignored")
+            .build(addr, AuthHandler {});
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"530 Use EHLO and STARTTLS, see https://example.com/mail\r\n"
+        );
+    }
+
+    #[test]
+    fn disabled_commands() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.domain")
+            .enable_etrn()
+            .disable_command(OptionalCommand::Vrfy)
+            .disable_command(OptionalCommand::Etrn)
+            .build(addr, EtrnHandler {});
+        let res = session.process(b"VRFY fish@sea.com\r\n");
+        assert_eq!(res.buffer().unwrap(), b"502 Command disabled\r\n");
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-server offers extensions:\r\n250 8BITMIME\r\n"
+        );
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        // Disabled in any state
+        for line in [&b"vrfy fish@sea.com\r\n"[..], b"etrn a.domain\r\n"] {
+            assert_eq!(session.process(line), COMMAND_DISABLED);
+        }
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res, OK);
+    }
+
+    #[test]
+    fn banner() {
+        fn banner(info: &ConnInfo) -> String {
+            let tls = if info.start_tls {
+                ", STARTTLS available"
+            } else {
+                ""
+            };
+            format!("{} ESMTP{} for {}\r\nignored", info.name, tls, info.remote)
+        }
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let session = SessionBuilder::new("some.domain")
+            .enable_start_tls()
+            .banner(banner)
+            .build(addr, EmptyHandler {});
+        assert_eq!(
+            session.greeting().buffer().unwrap(),
+            b"220 some.domain ESMTP, STARTTLS available for 192.0.2.1\r\n"
+        );
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let session = SessionBuilder::new("some.domain")
+            .banner(banner)
+            .build(addr, EmptyHandler {});
+        assert_eq!(
+            session.greeting().buffer().unwrap(),
+            b"220 some.domain ESMTP for 192.0.2.2\r\n"
+        );
+        // The default greeting
+        assert_eq!(
+            new_session().greeting().buffer().unwrap(),
+            b"220 some.name ESMTP\r\n"
+        );
+    }
+
     #[test]
     fn auth_ehlo() {
         let mut session = new_auth_session(true);
@@ -556,6 +2087,25 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn auth_abort() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(res.code, 250);
+        let res = session.process(b"auth login\r\n");
+        assert_eq!(res.code, 334);
+        let res = session.process(b"dGVzdA==\r\n"); // "test"
+        assert_eq!(res, PASSWORD_AUTH_CHALLENGE);
+        let res = session.process(b"*\r\n");
+        assert_eq!(res, AUTH_ABORTED);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        // Authentication can be started again
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
     #[test]
     fn bad_auth_plain_param() {
         let mut session = new_auth_session(true);
@@ -592,9 +2142,7 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
         let res = session.process(b"auth plain\r\n");
         assert_eq!(res.code, 334);
-        if res != EMPTY_AUTH_CHALLENGE {
-            panic!("Server did not send empty challenge");
-        }
+        assert_eq!(res.buffer().unwrap(), b"334 \r\n");
         assert_state!(session.fsm.current_state(), SmtpState::Auth);
         let res = session.process(b"dGVzdAB0ZXN0ADEyMzQ=\r\n");
         assert_eq!(res.code, 235);
@@ -609,7 +2157,7 @@ mod tests {
         assert_eq!(res.code, 250);
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
         let res = session.process(b"auth login\r\n");
-        assert_eq!(res, USERNAME_AUTH_CHALLENGE);
+        assert_eq!(res.buffer().unwrap(), b"334 VXNlcm5hbWU6\r\n"); // "Username:"
         assert_state!(session.fsm.current_state(), SmtpState::Auth);
         let res = session.process(b"dGVzdA==\r\n"); // "test"
         assert_eq!(res, PASSWORD_AUTH_CHALLENGE);
@@ -644,6 +2192,140 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn is_authenticated() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain);
+        builder.enable_start_tls();
+        builder.insecure_enable_plaintext_auth();
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        assert!(!session.is_authenticated());
+        let res = session.process(b"auth plain eGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 535);
+        assert!(!session.is_authenticated());
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        assert!(session.is_authenticated());
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        assert!(!session.is_authenticated());
+        // The handler is told before each transaction
+        #[derive(Default)]
+        struct AuthStateHandler(Vec<bool>);
+        impl Handler for AuthStateHandler {
+            fn auth_plain(&mut self, _: &str, _: &str, password: &str) -> Response {
+                ternary!(password == "1234", AUTH_OK, INVALID_CREDENTIALS)
+            }
+            fn authenticated(&mut self, authenticated: bool) {
+                self.0.push(authenticated);
+            }
+        }
+        let mut session = builder.build(addr, AuthStateHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.handler.0, vec![true]);
+        let mut session =
+            SessionBuilder::new("some.domain").build(addr, AuthStateHandler::default());
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.handler.0, vec![false]);
+        // Without authentication enabled a session is never authenticated
+        let mut session = new_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert!(!session.is_authenticated());
+    }
+
+    #[test]
+    fn feed() {
+        let mut session = new_data_session();
+        assert!(session.feed(b"HELO a.dom").is_empty());
+        let responses = session.feed(b"ain\r\nMAIL FROM:<ship@sea.com>\r\nRCPT TO:<fish");
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 250]);
+        let responses = session.feed(b"@sea.com>\r\nDATA\r\nHello\r\n.\r\nQUIT\r\n");
+        let codes: Vec<u16> = responses.iter().map(|r| r.code).collect();
+        assert_eq!(codes, vec![250, 354, 250, 221]);
+        assert_eq!(session.handler.0, b"Hello\r\n");
+    }
+
+    #[test]
+    fn feed_discards_after_starttls() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.domain")
+            .enable_start_tls()
+            .build(addr, EmptyHandler {});
+        // Commands pipelined after STARTTLS must not be processed in plaintext
+        let responses = session.feed(b"EHLO a.domain\r\nSTARTTLS\r\nMAIL FROM:<a@b>\r\n");
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].action, Action::UpgradeTls);
+        session.tls_active();
+        let responses = session.feed(b"MAIL FROM:<a@b>\r\n");
+        assert_eq!(responses[0].code, 503);
+    }
+
+    #[test]
+    fn starttls_refused() {
+        struct TlsPolicyHandler {}
+        impl Handler for TlsPolicyHandler {
+            fn allow_starttls(&mut self, ip: IpAddr) -> bool {
+                ip != IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+            }
+        }
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_start_tls();
+        let flagged = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut session = builder.build(flagged, TlsPolicyHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 454);
+        assert_eq!(res.action, Action::Reply);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        // TLS was not started, so it is still offered
+        assert!(session
+            .supported_extensions()
+            .contains(&Extension::StartTls));
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut session = builder.build(other, TlsPolicyHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 220);
+    }
+
+    #[test]
+    fn start_tls_resets_auth() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain);
+        builder.enable_start_tls();
+        builder.insecure_enable_plaintext_auth();
+        let mut session = builder.build(addr, AuthHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.code, 220);
+        session.tls_active();
+        assert_state!(session.fsm.current_state(), SmtpState::Idle);
+        // Authentication before TLS does not carry over
+        session.process(b"ehlo a.domain\r\n");
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 503);
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.code, 250);
+    }
+
     #[test]
     fn auth_insecure_without_auth() {
         let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
@@ -668,13 +2350,36 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    struct ChallengeHandler {}
+    impl Handler for ChallengeHandler {
+        fn auth_challenge(&mut self, _mechanism: AuthMechanism) -> Vec<u8> {
+            b"<1896.697170952@postoffice.example.net>".to_vec()
+        }
+    }
+
+    #[test]
+    fn custom_auth_challenge() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.enable_auth(AuthMechanism::Plain);
+        builder.insecure_enable_plaintext_auth();
+        let mut session = builder.build(addr, ChallengeHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"334 PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UuZXhhbXBsZS5uZXQ+\r\n"
+        );
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+    }
+
     #[test]
     fn bad_auth_login_username_challenge() {
         let mut session = new_auth_session(true);
         start_tls(&mut session);
         session.process(b"ehlo a.domain\r\n");
         let res = session.process(b"auth login\r\n");
-        assert_eq!(res, USERNAME_AUTH_CHALLENGE);
+        assert_eq!(res.buffer().unwrap(), b"334 VXNlcm5hbWU6\r\n"); // "Username:"
         assert_state!(session.fsm.current_state(), SmtpState::Auth);
         let res = session.process(b"YmFkLXVzZXJuYW1l\r\n"); // "bad-username"
         assert_eq!(res, PASSWORD_AUTH_CHALLENGE);
@@ -690,7 +2395,7 @@ mod tests {
         start_tls(&mut session);
         session.process(b"ehlo a.domain\r\n");
         let res = session.process(b"auth login\r\n");
-        assert_eq!(res, USERNAME_AUTH_CHALLENGE);
+        assert_eq!(res.buffer().unwrap(), b"334 VXNlcm5hbWU6\r\n"); // "Username:"
         assert_state!(session.fsm.current_state(), SmtpState::Auth);
         let res = session.process(b"dGVzdA==\r\n"); // "test"
         assert_eq!(res, PASSWORD_AUTH_CHALLENGE);
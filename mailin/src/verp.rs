@@ -0,0 +1,58 @@
+/// Encode a recipient into a return path, e.g.
+/// `bounces@example.com` and `user@dest.org` give `bounces+user=dest.org@example.com`.
+///
+/// The local part of `base_return` should not contain a '+'. Returns `None` if either
+/// address has no domain.
+pub fn encode(base_return: &str, recipient: &str) -> Option<String> {
+    let (base_local, base_domain) = base_return.rsplit_once('@')?;
+    let (local, domain) = recipient.rsplit_once('@')?;
+    Some(format!("{base_local}+{local}={domain}@{base_domain}"))
+}
+
+/// Decode the recipient from a return path created by [`encode()`].
+///
+/// Returns `None` if the return path does not contain an encoded recipient.
+pub fn decode(return_path: &str) -> Option<String> {
+    let (local, _) = return_path.rsplit_once('@')?;
+    let (_, encoded) = local.split_once('+')?;
+    // Domains cannot contain '=' so the last '=' separates the local part and domain
+    let (rcpt_local, rcpt_domain) = encoded.rsplit_once('=')?;
+    if rcpt_local.is_empty() || rcpt_domain.is_empty() {
+        return None;
+    }
+    Some(format!("{rcpt_local}@{rcpt_domain}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let base = "bounces@example.com";
+        for rcpt in [
+            "user@dest.org",
+            "first.last@sub.dest.org",
+            "user+tag@dest.org",
+            "a=b@dest.org",
+            "x+y=z+@dest.org",
+        ] {
+            let encoded = encode(base, rcpt).unwrap();
+            assert!(encoded.starts_with("bounces+"));
+            assert!(encoded.ends_with("@example.com"));
+            assert_eq!(decode(&encoded).as_deref(), Some(rcpt));
+        }
+        assert_eq!(
+            encode(base, "user@dest.org").as_deref(),
+            Some("bounces+user=dest.org@example.com")
+        );
+    }
+
+    #[test]
+    fn not_verp() {
+        assert_eq!(encode("bounces", "user@dest.org"), None);
+        assert_eq!(decode("bounces@example.com"), None);
+        assert_eq!(decode("bounces+user@example.com"), None);
+        assert_eq!(decode("bounces+=dest.org@example.com"), None);
+    }
+}
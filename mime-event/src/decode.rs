@@ -0,0 +1,196 @@
+use std::borrow::Cow;
+
+// Undo the Content-Transfer-Encoding of a body, unknown encodings are returned as is
+pub(crate) fn transfer_decode<'a>(encoding: Option<&[u8]>, body: &'a [u8]) -> Cow<'a, [u8]> {
+    match encoding {
+        Some(e) if e.eq_ignore_ascii_case(b"base64") => Cow::Owned(base64(body)),
+        Some(e) if e.eq_ignore_ascii_case(b"quoted-printable") => {
+            Cow::Owned(quoted_printable(body))
+        }
+        _ => Cow::Borrowed(body),
+    }
+}
+
+// Convert text in the given charset to UTF-8, replacing invalid sequences
+#[cfg(feature = "encoding")]
+pub(crate) fn to_utf8(charset: Option<&[u8]>, text: &[u8]) -> String {
+    let encoding = charset
+        .and_then(encoding_rs::Encoding::for_label)
+        .unwrap_or(encoding_rs::UTF_8);
+    // A byte order mark takes precedence over the charset
+    let (decoded, _, _) = encoding.decode(text);
+    decoded.into_owned()
+}
+
+// Without the encoding feature text is assumed to be UTF-8
+#[cfg(not(feature = "encoding"))]
+pub(crate) fn to_utf8(_charset: Option<&[u8]>, text: &[u8]) -> String {
+    String::from_utf8_lossy(text).into_owned()
+}
+
+// Decode base64, ignoring line breaks and any other characters outside the alphabet
+fn base64(encoded: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    let sextets = encoded
+        .iter()
+        .take_while(|c| **c != b'=')
+        .filter_map(|c| base64_value(*c));
+    for sextet in sextets {
+        buffer = (buffer << 6) | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    decoded
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+// Decode quoted-printable (RFC 2045), an '=' that does not start an escape is kept
+fn quoted_printable(encoded: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut decoder = QuotedPrintableDecoder::new();
+    decoder.decode(encoded, &mut decoded);
+    decoder.finish(&mut decoded);
+    decoded
+}
+
+/// Decodes a quoted-printable (RFC 2045) body that arrives in chunks, e.g from
+/// [`Event::Body`](crate::Event::Body) events.
+///
+/// Escapes and soft line breaks can be split across chunks, the decoder keeps the
+/// incomplete line until the rest arrives. An `=` that does not start an escape is kept.
+///
+/// # Examples
+/// ```
+/// use mime_event::QuotedPrintableDecoder;
+///
+/// let mut decoder = QuotedPrintableDecoder::new();
+/// let mut decoded = Vec::new();
+/// decoder.decode(b"caf=", &mut decoded);
+/// decoder.decode(b"E9 soft=\r\nbreak\r\n", &mut decoded);
+/// decoder.finish(&mut decoded);
+/// assert_eq!(decoded, b"caf\xe9 softbreak\r\n");
+/// ```
+#[derive(Debug, Default)]
+pub struct QuotedPrintableDecoder {
+    // The start of a line that has not been decoded yet
+    pending: Vec<u8>,
+}
+
+impl QuotedPrintableDecoder {
+    /// Create a decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next chunk of the body, appending the complete lines to `decoded`
+    pub fn decode(&mut self, chunk: &[u8], decoded: &mut Vec<u8>) {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|c| *c == b'\n') else {
+            return;
+        };
+        for line in self.pending[..=end].split_inclusive(|c| *c == b'\n') {
+            decode_line(line, decoded);
+        }
+        self.pending.drain(..=end);
+    }
+
+    /// Decode the rest of the body, which does not have to end with a line ending
+    pub fn finish(self, decoded: &mut Vec<u8>) {
+        if !self.pending.is_empty() {
+            decode_line(&self.pending, decoded);
+        }
+    }
+}
+
+// Decode one quoted-printable line, with or without its line ending
+fn decode_line(line: &[u8], decoded: &mut Vec<u8>) {
+    let content = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"));
+    let has_ending = content.is_some();
+    let content = content.unwrap_or(line);
+    // Trailing whitespace was added in transport
+    let end = content
+        .iter()
+        .rposition(|c| *c != b' ' && *c != b'\t')
+        .map_or(0, |i| i + 1);
+    let content = &content[..end];
+    // A soft line break joins the line with the next one
+    let (content, soft_break) = match content.strip_suffix(b"=") {
+        Some(content) => (content, true),
+        None => (content, false),
+    };
+    let mut i = 0;
+    while i < content.len() {
+        let escaped = content
+            .get(i + 1..i + 3)
+            .filter(|_| content[i] == b'=')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(content[i]);
+                i += 1;
+            }
+        }
+    }
+    if has_ending && !soft_break {
+        decoded.extend_from_slice(b"\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_base64() {
+        assert_eq!(base64(b"SGVsbG8gV29y\r\nbGQh\r\n"), b"Hello World!");
+        assert_eq!(base64(b"SGk=\r\n"), b"Hi");
+        assert_eq!(base64(b"SGVsbG8="), b"Hello");
+    }
+
+    #[test]
+    fn decode_quoted_printable() {
+        assert_eq!(
+            quoted_printable(b"caf=E9 =3D soft=\r\nbreak  \r\nA=2\r\n"),
+            b"caf\xe9 = softbreak\r\nA=2\r\n"
+        );
+    }
+
+    #[test]
+    fn decode_quoted_printable_chunks() {
+        let encoded = b"caf=E9 =3D soft=\r\nbreak  \r\nA=2\r\nend=";
+        let expected = quoted_printable(encoded);
+        assert_eq!(expected, b"caf\xe9 = softbreak\r\nA=2\r\nend");
+        // Split at every position, including inside escapes and soft line breaks
+        for split in 0..=encoded.len() {
+            let mut decoder = QuotedPrintableDecoder::new();
+            let mut decoded = Vec::new();
+            decoder.decode(&encoded[..split], &mut decoded);
+            decoder.decode(&encoded[split..], &mut decoded);
+            decoder.finish(&mut decoded);
+            assert_eq!(decoded, expected, "split at {split}");
+        }
+    }
+}
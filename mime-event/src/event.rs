@@ -4,6 +4,9 @@ use std::fmt;
 use std::str;
 
 /// Events sent to a Handler
+///
+/// More events may be added in later versions.
+#[non_exhaustive]
 #[derive(PartialEq, Eq)]
 pub enum Event<'a> {
     /// Parsing has Started
@@ -12,6 +15,9 @@ pub enum Event<'a> {
     Header(Header<'a>),
     /// Start of a MIME multipart entity
     MultipartStart(Multipart),
+    /// A line of the preamble before the first boundary of a multipart entity.
+    /// Only sent if enabled with [`crate::EventParser::report_preamble_epilogue()`].
+    Preamble(&'a [u8]),
     /// Start of a MIME mulitpart part
     PartStart {
         /// Byte offset of the Part in the mail message
@@ -31,6 +37,9 @@ pub enum Event<'a> {
     },
     /// End of a MIME multipart entity
     MultipartEnd,
+    /// A line of the epilogue after the closing boundary of a multipart entity.
+    /// Only sent if enabled with [`crate::EventParser::report_preamble_epilogue()`].
+    Epilogue(&'a [u8]),
     /// Parsing has finished
     End,
 }
@@ -45,7 +54,7 @@ pub enum Mime {
 }
 
 /// Multipart MIME types
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Multipart {
     /// MIME multipart alternative e.g text vs html
     Alternative,
@@ -53,6 +62,11 @@ pub enum Multipart {
     Mixed,
     /// MIME multipart message digest
     Digest,
+    /// MIME multipart related e.g html with inline images
+    Related,
+    /// Any other MIME multipart type, such as signed or report. Holds the subtype in
+    /// lowercase, e.g `b"signed"`.
+    Other(Vec<u8>),
 }
 
 pub(crate) fn mime_type(v: &[u8]) -> Mime {
@@ -62,7 +76,11 @@ pub(crate) fn mime_type(v: &[u8]) -> Mime {
             "multipart/alternative" => Mime::Multipart(Multipart::Alternative),
             "multipart/mixed" => Mime::Multipart(Multipart::Mixed),
             "multipart/digest" => Mime::Multipart(Multipart::Digest),
-            _ => Mime::Type(v.to_vec()),
+            "multipart/related" => Mime::Multipart(Multipart::Related),
+            _ => match s.strip_prefix("multipart/") {
+                Some(subtype) => Mime::Multipart(Multipart::Other(subtype.as_bytes().to_vec())),
+                None => Mime::Type(v.to_vec()),
+            },
         }
     } else {
         Mime::Type(v.to_vec())
@@ -75,11 +93,13 @@ impl fmt::Debug for Event<'_> {
             Event::Start => write!(f, "Start"),
             Event::Header(header) => write!(f, "Header({header:?})"),
             Event::MultipartStart(multipart) => write!(f, "MultipartStart({multipart:?})"),
+            Event::Preamble(block) => write!(f, "Preamble({})", display_bytes(block)),
             Event::PartStart { offset } => write!(f, "PartStart({offset:?})"),
             Event::BodyStart { offset } => write!(f, "BodyStart({offset:?})"),
             Event::Body(block) => write!(f, "Body({})", display_bytes(block)),
             Event::PartEnd { offset } => write!(f, "PartEnd({offset:?})"),
             Event::MultipartEnd => write!(f, "MultipartEnd"),
+            Event::Epilogue(block) => write!(f, "Epilogue({})", display_bytes(block)),
             Event::End => write!(f, "End"),
         }
     }
@@ -4,6 +4,10 @@ use std::collections::HashMap;
 use std::fmt;
 
 /// Header contains information about an email header event
+///
+/// More headers may be parsed in later versions, a header that is not parsed is passed
+/// on as [`Header::Unstructured`].
+#[non_exhaustive]
 #[derive(PartialEq, Eq, Clone)]
 pub enum Header<'a> {
     /// A header containing unstructured information as key, value
@@ -30,6 +34,12 @@ pub enum Header<'a> {
     },
     /// Description of a MIME part
     ContentDescription(&'a [u8]),
+    /// Content-ID of a MIME part, without the enclosing angle brackets
+    ContentId(&'a [u8]),
+    /// Content-Language of a MIME part e.g "en, de" (RFC 3282)
+    ContentLanguage(&'a [u8]),
+    /// Content-Location of a MIME part, a URI that can be referenced by related parts (RFC 2557)
+    ContentLocation(&'a [u8]),
     /// Subject header
     Subject(&'a [u8]),
     /// The SMTP sender header
@@ -38,10 +48,45 @@ pub enum Header<'a> {
     ReplyTo(&'a [u8]),
     /// The Message-ID of the email message
     MessageId(&'a [u8]),
+    /// Authentication-Results header (RFC 8601). A header that cannot be parsed is
+    /// passed on as [`Header::Unstructured`].
+    AuthenticationResults {
+        /// The host that did the authentication checks
+        authserv_id: &'a [u8],
+        /// The method and result of each check e.g "dkim" and "pass", empty if there are
+        /// none
+        results: Vec<(&'a [u8], &'a [u8])>,
+    },
     /// End of the header
     End,
 }
 
+impl<'a> Header<'a> {
+    /// The name of the header as it is usually written, or None for [`Header::End`]. The
+    /// name of an unstructured header is as it appears in the message.
+    pub fn name(&self) -> Option<&'a [u8]> {
+        let name: &[u8] = match self {
+            Header::Unstructured(name, _) => name,
+            Header::ContentType { .. } => b"Content-Type",
+            Header::From(_) => b"From",
+            Header::To(_) => b"To",
+            Header::Date(_) => b"Date",
+            Header::ContentDisposition { .. } => b"Content-Disposition",
+            Header::ContentDescription(_) => b"Content-Description",
+            Header::ContentId(_) => b"Content-ID",
+            Header::ContentLanguage(_) => b"Content-Language",
+            Header::ContentLocation(_) => b"Content-Location",
+            Header::Subject(_) => b"Subject",
+            Header::Sender(_) => b"Sender",
+            Header::ReplyTo(_) => b"Reply-To",
+            Header::MessageId(_) => b"Message-ID",
+            Header::AuthenticationResults { .. } => b"Authentication-Results",
+            Header::End => return None,
+        };
+        Some(name)
+    }
+}
+
 impl fmt::Debug for Header<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -57,8 +102,26 @@ impl fmt::Debug for Header<'_> {
             Header::Sender(sender) => dbg_single(f, "Sender", sender),
             Header::ReplyTo(reply_to) => dbg_single(f, "ReplyTo", reply_to),
             Header::MessageId(message_id) => dbg_single(f, "MessageId", message_id),
+            Header::AuthenticationResults {
+                authserv_id,
+                results,
+            } => {
+                let mut d = f.debug_struct("AuthenticationResults");
+                d.field("authserv_id", &display_bytes_string(authserv_id));
+                let results: Vec<_> = results
+                    .iter()
+                    .map(|(method, result)| {
+                        (display_bytes_string(method), display_bytes_string(result))
+                    })
+                    .collect();
+                d.field("results", &results);
+                d.finish()
+            }
             Header::Date(date) => dbg_single(f, "Date", date),
             Header::ContentDescription(desc) => dbg_single(f, "ContentDescription", desc),
+            Header::ContentId(id) => dbg_single(f, "ContentId", id),
+            Header::ContentLanguage(language) => dbg_single(f, "ContentLanguage", language),
+            Header::ContentLocation(location) => dbg_single(f, "ContentLocation", location),
             Header::ContentDisposition {
                 disposition_type,
                 parameters,
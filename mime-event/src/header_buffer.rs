@@ -9,24 +9,25 @@ pub(crate) struct HeaderBuffer {
 }
 
 impl HeaderBuffer {
-    // Add a, possibly incomplete, incoming line and retrieve the next complete line
-    pub(crate) fn next_line(&mut self, line: &[u8]) -> Option<(Vec<u8>, usize)> {
+    // Add a, possibly incomplete, incoming line and retrieve the next complete line.
+    // The length is the length of the line as it appeared in the input.
+    pub(crate) fn next_line(&mut self, line: &[u8], length: usize) -> Option<(Vec<u8>, usize)> {
         // Check for a continuation line
         if !self.has_value {
             self.line = line.to_vec();
-            self.length = line.len();
+            self.length = length;
             self.has_value = true;
             None
-        } else if line.starts_with(b" ") {
+        } else if line.starts_with(b" ") || line.starts_with(b"\t") {
             self.line.truncate(self.line.len() - 2); // Remove \r\n
             self.line.extend_from_slice(line);
-            self.length += line.len();
+            self.length += length;
             None
         } else {
-            let length = self.length;
+            let ret_length = self.length;
             let ret = mem::replace(&mut self.line, line.to_vec());
-            self.length = line.len();
-            Some((ret, length))
+            self.length = length;
+            Some((ret, ret_length))
         }
     }
 
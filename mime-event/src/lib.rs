@@ -73,6 +73,7 @@
 #![forbid(missing_docs)]
 
 mod debug;
+mod decode;
 mod event;
 mod header;
 mod header_buffer;
@@ -82,6 +83,7 @@ mod message_handler;
 mod message_parser;
 mod parser;
 
+pub use decode::QuotedPrintableDecoder;
 pub use event::{Event, Mime, Multipart};
 pub use header::Header;
 pub use message::{HeaderFields, Message, Part};
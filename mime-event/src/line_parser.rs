@@ -1,7 +1,7 @@
 use crate::header::Header;
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
-use nom::combinator::{map, recognize};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while, take_while1};
+use nom::combinator::{map, map_opt, opt, recognize};
 use nom::multi::fold_many0;
 use nom::sequence::{pair, preceded, terminated};
 use nom::IResult;
@@ -23,6 +23,10 @@ pub(crate) fn header(line: &[u8]) -> io::Result<Header<'_>> {
         date,
         content_disposition,
         content_description,
+        content_id,
+        content_language,
+        content_location,
+        authentication_results,
         unstructured,
     ))(line);
     match res {
@@ -59,7 +63,9 @@ fn header_with_params(header: &[u8]) -> impl Fn(&[u8]) -> HeaderParams + '_ {
     move |buf: &[u8]| {
         let preamble = match_header_key(header);
         let (i, value) = preceded(preamble, header_value_with_parameters)(buf)?;
-        let mut parameter_parser = terminated(parameters, tag(b"\r\n"));
+        // Tolerate a trailing semicolon and whitespace after the last parameter
+        let end = pair(opt(pair(tag(b";"), whitespace)), tag(b"\r\n"));
+        let mut parameter_parser = terminated(parameters, end);
         let (i, params) = parameter_parser(i)?;
         Ok((i, (value, params)))
     }
@@ -77,8 +83,14 @@ fn space(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while1(|c| c == b' ')(buf)
 }
 
+// Optional spaces or tabs, e.g. left by unfolding a header
+fn whitespace(buf: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while(|c| c == b' ' || c == b'\t')(buf)
+}
+
 fn header_value_with_parameters(buf: &[u8]) -> IResult<&[u8], &[u8]> {
-    is_not(";\r\n")(buf)
+    let (i, value) = is_not(";\r\n")(buf)?;
+    Ok((i, value.trim_ascii_end()))
 }
 
 fn parameters(buf: &[u8]) -> IResult<&[u8], HashMap<&[u8], Vec<u8>>> {
@@ -89,9 +101,11 @@ fn parameters(buf: &[u8]) -> IResult<&[u8], HashMap<&[u8], Vec<u8>>> {
 }
 
 fn parameter(buf: &[u8]) -> IResult<&[u8], (&[u8], Vec<u8>)> {
-    let preamble = pair(tag(b";"), space);
+    let preamble = pair(tag(b";"), whitespace);
     let (i, attribute) = preceded(preamble, token)(buf)?;
-    let (i, value) = preceded(tag(b"="), parameter_value)(i)?;
+    let equals = recognize(pair(pair(whitespace, tag(b"=")), whitespace));
+    let (i, value) = preceded(equals, parameter_value)(i)?;
+    let (i, _) = whitespace(i)?;
     Ok((i, (attribute, value)))
 }
 
@@ -101,7 +115,7 @@ fn parameter_value(buf: &[u8]) -> IResult<&[u8], Vec<u8>> {
 }
 
 fn token(buf: &[u8]) -> IResult<&[u8], &[u8]> {
-    take_while1(|c| c != b' ' && !tspecial(c) && !ctl(c))(buf)
+    take_while1(|c| c != b' ' && c != b'\t' && !tspecial(c) && !ctl(c))(buf)
 }
 
 fn quoted_string(buf: &[u8]) -> IResult<&[u8], Vec<u8>> {
@@ -113,7 +127,8 @@ fn in_quotes(buf: &[u8]) -> IResult<&[u8], Vec<u8>> {
     let mut ret = Vec::new();
     let mut i = 0;
     while i < buf.len() && buf[i] != b'"' {
-        if buf[i] == b'\\' {
+        // A backslash quotes the next character, unless it ends the line
+        if buf[i] == b'\\' && i + 1 < buf.len() {
             i += 1;
         }
         ret.push(buf[i]);
@@ -185,6 +200,109 @@ fn content_description(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     })(buf)
 }
 
+fn content_id(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map(match_unstructured(b"Content-ID"), |v| {
+        Header::ContentId(strip_angle_brackets(v))
+    })(buf)
+}
+
+fn content_language(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map(
+        match_unstructured(b"Content-Language"),
+        Header::ContentLanguage,
+    )(buf)
+}
+
+fn content_location(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map(
+        match_unstructured(b"Content-Location"),
+        Header::ContentLocation,
+    )(buf)
+}
+
+fn authentication_results(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
+    map_opt(match_unstructured(b"Authentication-Results"), |value| {
+        let (authserv_id, results) = auth_results_value(value)?;
+        Some(Header::AuthenticationResults {
+            authserv_id,
+            results,
+        })
+    })(buf)
+}
+
+type AuthResults<'a> = (&'a [u8], Vec<(&'a [u8], &'a [u8])>);
+
+// Split an Authentication-Results value into the authserv-id and the method and result
+// of each check, None if the value is malformed. Properties and comments are skipped.
+fn auth_results_value(value: &[u8]) -> Option<AuthResults<'_>> {
+    let mut parts = split_outside_comments(value, b';').into_iter();
+    // The authserv-id may be followed by a version
+    let authserv_id = parts
+        .next()?
+        .split(u8::is_ascii_whitespace)
+        .find(|w| !w.is_empty())?;
+    let mut results = Vec::new();
+    for part in parts {
+        let part = part.trim_ascii();
+        if part.eq_ignore_ascii_case(b"none") {
+            continue;
+        }
+        let eq = part.iter().position(|c| *c == b'=')?;
+        // The method may be followed by a version e.g "dkim/1"
+        let method = part[..eq].split(|c| *c == b'/').next()?.trim_ascii();
+        let result = part[eq + 1..].trim_ascii_start();
+        let end = result
+            .iter()
+            .position(|c| c.is_ascii_whitespace() || *c == b'(')
+            .unwrap_or(result.len());
+        let result = &result[..end];
+        if !is_keyword(method) || !is_keyword(result) {
+            return None;
+        }
+        results.push((method, result));
+    }
+    Some((authserv_id, results))
+}
+
+fn is_keyword(value: &[u8]) -> bool {
+    !value.is_empty()
+        && value
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || *c == b'-' || *c == b'_' || *c == b'.')
+}
+
+// Split at a separator that is not within a comment or a quoted string
+fn split_outside_comments(value: &[u8], separator: u8) -> Vec<&[u8]> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut start = 0;
+    let mut i = 0;
+    while i < value.len() {
+        match value[i] {
+            b'\\' => i += 1,
+            b'"' => quoted = !quoted,
+            b'(' if !quoted => depth += 1,
+            b')' if !quoted => depth = depth.saturating_sub(1),
+            c if c == separator && !quoted && depth == 0 => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    parts.push(&value[start.min(value.len())..]);
+    parts
+}
+
+fn strip_angle_brackets(value: &[u8]) -> &[u8] {
+    value
+        .strip_prefix(b"<")
+        .and_then(|v| v.strip_suffix(b">"))
+        .unwrap_or(value)
+}
+
 fn unstructured(buf: &[u8]) -> IResult<&[u8], Header<'_>> {
     let (i, key) = terminated(header_key, colon_space)(buf)?;
     let (i, value) = terminated(unstructured_value, tag(b"\r\n"))(i)?;
@@ -218,6 +336,61 @@ mod tests {
         )
     }
 
+    #[test]
+    fn content_id_header() {
+        let tok = header(b"Content-ID: <part1.06090408@example.com>\r\n").unwrap();
+        assert_eq!(tok, Header::ContentId(b"part1.06090408@example.com"));
+        let tok = header(b"Content-Id: logo\r\n").unwrap();
+        assert_eq!(tok, Header::ContentId(b"logo"));
+    }
+
+    #[test]
+    fn content_language_location() {
+        let tok = header(b"Content-Language: en, de\r\n").unwrap();
+        assert_eq!(tok, Header::ContentLanguage(b"en, de"));
+        let tok = header(b"Content-Location: http://www.example.com/logo.png\r\n").unwrap();
+        assert_eq!(
+            tok,
+            Header::ContentLocation(b"http://www.example.com/logo.png")
+        );
+    }
+
+    #[test]
+    fn authentication_results_header() {
+        let tok = header(
+            b"Authentication-Results: mx.example.org 1; dkim=pass (good; signature) \
+            header.d=example.com; spf=fail smtp.mailfrom=example.net; dmarc/1=none\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            tok,
+            Header::AuthenticationResults {
+                authserv_id: b"mx.example.org",
+                results: vec![
+                    (&b"dkim"[..], &b"pass"[..]),
+                    (b"spf", b"fail"),
+                    (b"dmarc", b"none"),
+                ],
+            }
+        );
+        let tok = header(b"Authentication-Results: mx.example.org; none\r\n").unwrap();
+        assert_eq!(
+            tok,
+            Header::AuthenticationResults {
+                authserv_id: b"mx.example.org",
+                results: vec![],
+            }
+        );
+        // Malformed headers are unstructured
+        for line in [
+            &b"Authentication-Results: mx.example.org; dkim pass\r\n"[..],
+            b"Authentication-Results: ; spf=pass\r\n",
+            b"Authentication-Results: mx.example.org; spf=\r\n",
+        ] {
+            assert!(matches!(header(line), Ok(Header::Unstructured(..))));
+        }
+    }
+
     #[test]
     fn end_header() {
         let tok = header(b"\r\n").unwrap();
@@ -266,6 +439,23 @@ mod tests {
         )
     }
 
+    #[test]
+    fn folded_quoted_boundary() {
+        // As unfolded from "multipart/alternative;\r\n\tboundary=..."
+        let line = b"Content-Type: multipart/alternative;\tboundary=\"----=_Part_0 12345\";\r\n";
+        let tok = header(line).unwrap();
+        let expected_params = hashmap! {
+            b"boundary".as_ref() => b"----=_Part_0 12345".to_vec(),
+        };
+        assert_eq!(
+            tok,
+            Header::ContentType {
+                mime_type: b"multipart/alternative",
+                parameters: expected_params,
+            }
+        )
+    }
+
     #[test]
     fn quoted_boundary() {
         let tok =
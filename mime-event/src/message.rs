@@ -1,4 +1,5 @@
 use crate::debug::OptionDbg;
+use crate::decode;
 use crate::event::Mime;
 use std::collections::HashMap;
 use std::fmt;
@@ -15,6 +16,8 @@ pub struct Message {
     pub(crate) top: usize,
     pub(crate) text: Option<usize>,
     pub(crate) html: Option<usize>,
+    // Parts of the multipart/alternative body, in the order of the message
+    pub(crate) alternatives: Vec<usize>,
     pub(crate) attachments: Vec<usize>,
     pub(crate) inlines: Vec<usize>,
     pub(crate) other: Vec<usize>,
@@ -33,6 +36,14 @@ pub struct Part {
     pub content_type: Option<ContentType>,
     /// MIME content disposition
     pub content_disposition: Option<ContentDisposition>,
+    /// MIME Content-ID, used to reference the part from a "cid:" URL
+    pub content_id: Option<Vec<u8>>,
+    /// MIME Content-Language, the languages of the part e.g "en, de"
+    pub content_language: Option<Vec<u8>>,
+    /// MIME Content-Location, a URI for the part that related parts can reference
+    pub content_location: Option<Vec<u8>>,
+    /// MIME Content-Transfer-Encoding e.g "base64"
+    pub content_transfer_encoding: Option<Vec<u8>>,
     pub(crate) start: usize,
     pub(crate) body_start: usize,
     pub(crate) end: usize,
@@ -85,6 +96,13 @@ pub struct ContentDisposition {
     pub parameters: HashMap<Vec<u8>, Vec<u8>>,
 }
 
+impl ContentType {
+    /// The MIME type, e.g `Mime::Type(b"text/plain".to_vec())`
+    pub fn mime_type(&self) -> &Mime {
+        &self.mime_type
+    }
+}
+
 impl Part {
     /// Get start and length of the part
     pub fn position(&self) -> (usize, usize) {
@@ -95,6 +113,54 @@ impl Part {
     pub fn body(&self) -> (usize, usize) {
         (self.body_start, self.end - self.body_start + 1)
     }
+
+    /// Get the body from the message data that was written to the parser
+    pub fn body_bytes<'a>(&self, message: &'a [u8]) -> Option<&'a [u8]> {
+        // The end of a message that is not multipart is the end of the data
+        let end = if self.end < self.body_start {
+            message.len()
+        } else {
+            self.end
+        };
+        message.get(self.body_start..end)
+    }
+
+    /// Get the header block of the part, byte for byte as it was written to the parser,
+    /// including the empty line that ends it
+    pub fn raw_headers<'a>(&self, message: &'a [u8]) -> Option<&'a [u8]> {
+        message.get(self.start..self.body_start)
+    }
+
+    /// Get the body as UTF-8 text, after undoing the transfer encoding and converting
+    /// from the charset of the part.
+    ///
+    /// Unknown charsets are treated as UTF-8, invalid sequences are replaced. Charsets
+    /// other than UTF-8 need the `encoding` feature, which is enabled by default.
+    pub fn decoded_text(&self, message: &[u8]) -> Option<String> {
+        let body = self.body_bytes(message)?;
+        let body = decode::transfer_decode(self.content_transfer_encoding.as_deref(), body);
+        Some(decode::to_utf8(self.charset(), &body))
+    }
+
+    // Does the part have the given MIME type, without a Content-Type it is text/plain
+    fn is_mime_type(&self, mime: &Mime) -> bool {
+        match (self.content_type.as_ref().map(|c| &c.mime_type), mime) {
+            (Some(Mime::Type(a)), Mime::Type(b)) => a.eq_ignore_ascii_case(b),
+            (Some(Mime::Multipart(a)), Mime::Multipart(b)) => a == b,
+            (None, Mime::Type(b)) => b.eq_ignore_ascii_case(b"text/plain"),
+            _ => false,
+        }
+    }
+
+    // The charset parameter of the Content-Type
+    fn charset(&self) -> Option<&[u8]> {
+        let content_type = self.content_type.as_ref()?;
+        content_type
+            .parameters
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(b"charset"))
+            .map(|(_, value)| value.as_slice())
+    }
 }
 
 impl Message {
@@ -113,10 +179,72 @@ impl Message {
         self.html.and_then(|i| self.parts.get(i))
     }
 
+    /// The Subject header of the message
+    pub fn subject(&self) -> Option<&[u8]> {
+        self.top()?.header.subject.as_deref()
+    }
+
+    /// The From header of the message
+    pub fn from(&self) -> Option<&[u8]> {
+        self.top()?.header.from.as_deref()
+    }
+
+    /// The body of the first text part, `message` is the data that was written to the parser
+    pub fn text_body<'a>(&self, message: &'a [u8]) -> Option<&'a [u8]> {
+        self.text()?.body_bytes(message)
+    }
+
+    /// The text of the first text part in UTF-8, see [`Part::decoded_text()`]
+    pub fn decoded_text(&self, message: &[u8]) -> Option<String> {
+        self.text()?.decoded_text(message)
+    }
+
+    /// The body of the first HTML part, `message` is the data that was written to the parser
+    pub fn html_body<'a>(&self, message: &'a [u8]) -> Option<&'a [u8]> {
+        self.html()?.body_bytes(message)
+    }
+
+    /// The parts of a multipart/alternative body, in the order of the message. By
+    /// convention the parts go from the simplest to the richest, e.g text then HTML.
+    pub fn alternatives(&self) -> impl Iterator<Item = &Part> {
+        self.alternatives
+            .iter()
+            .flat_map(move |i| self.parts.get(*i))
+    }
+
+    /// Pick the alternative to display given the MIME types that can be displayed,
+    /// most preferred first. A message without alternatives has its top part as the
+    /// only alternative.
+    pub fn best_alternative(&self, preferred: &[Mime]) -> Option<&Part> {
+        let candidates: Vec<&Part> = if self.alternatives.is_empty() {
+            self.top().into_iter().collect()
+        } else {
+            self.alternatives().collect()
+        };
+        preferred.iter().find_map(|mime| {
+            candidates
+                .iter()
+                .find(|part| part.is_mime_type(mime))
+                .copied()
+        })
+    }
+
     /// Parts with disposition type "attachment"
     pub fn attachments(&self) -> impl Iterator<Item = &Part> {
         self.attachments
             .iter()
             .flat_map(move |i| self.parts.get(*i))
     }
+
+    /// Parts that are displayed inline, such as images in a multipart/related message
+    pub fn inlines(&self) -> impl Iterator<Item = &Part> {
+        self.inlines.iter().flat_map(move |i| self.parts.get(*i))
+    }
+
+    /// Find the part with the given Content-ID, e.g. to resolve a "cid:" URL
+    pub fn content_id(&self, content_id: &[u8]) -> Option<&Part> {
+        self.parts
+            .iter()
+            .find(|p| p.content_id.as_deref() == Some(content_id))
+    }
 }
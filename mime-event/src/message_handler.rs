@@ -1,6 +1,6 @@
 use crate::event::{mime_type, Event, Mime, Multipart};
 use crate::header::Header;
-use crate::message::{ContentType, Message, Part};
+use crate::message::{ContentDisposition, ContentType, Message, Part};
 use crate::parser::Handler;
 use std::collections::HashMap;
 use std::mem;
@@ -10,17 +10,24 @@ use std::mem;
 pub struct MessageHandler {
     is_multipart: bool,
     target: Target,
+    // Targets to restore when nested multiparts end
+    parent_targets: Vec<Target>,
+    // Set when a nested multipart ends, the next PartEnd closes its container
+    multipart_ended: bool,
     current_part: Part,
     message: Message,
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 enum Target {
     #[default]
     Top,
     TopAlternative,
     Alternative,
     FirstMixed,
+    FirstRelated,
+    // The first part of multipart/related inside the body multipart/alternative
+    AlternativeRelated,
     Attachments,
     Inlines,
     Other,
@@ -35,8 +42,8 @@ impl Handler for MessageHandler {
             Event::PartStart { offset } => self.part_start(offset),
             Event::PartEnd { offset } => self.part_end(offset),
             Event::BodyStart { offset } => self.body_start(offset),
-            Event::Body(_) => (),
-            Event::MultipartEnd => (),
+            Event::Body(_) | Event::Preamble(_) | Event::Epilogue(_) => (),
+            Event::MultipartEnd => self.multipart_end(),
             Event::End => self.end(),
         }
     }
@@ -58,13 +65,26 @@ impl MessageHandler {
             Header::Sender(sender) => target.sender = Some(sender.to_vec()),
             Header::ReplyTo(reply_to) => target.reply_to = Some(reply_to.to_vec()),
             Header::MessageId(msg_id) => target.message_id = Some(msg_id.to_vec()),
+            Header::ContentId(id) => self.current_part.content_id = Some(id.to_vec()),
+            Header::ContentLanguage(language) => {
+                self.current_part.content_language = Some(language.to_vec())
+            }
+            Header::ContentLocation(location) => {
+                self.current_part.content_location = Some(location.to_vec())
+            }
+            Header::Unstructured(name, value)
+                if name.eq_ignore_ascii_case(b"Content-Transfer-Encoding") =>
+            {
+                self.current_part.content_transfer_encoding = Some(value.trim_ascii().to_vec())
+            }
             Header::ContentType {
                 mime_type,
                 parameters,
             } => self.content_type(mime_type, parameters),
             Header::ContentDisposition {
-                disposition_type, ..
-            } => self.content_disposition(disposition_type),
+                disposition_type,
+                parameters,
+            } => self.content_disposition(disposition_type, parameters),
             _ => (),
         }
     }
@@ -81,9 +101,24 @@ impl MessageHandler {
         });
     }
 
-    fn content_disposition(&mut self, disposition_type: &[u8]) {
+    fn content_disposition(
+        &mut self,
+        disposition_type: &[u8],
+        parameter_refs: HashMap<&[u8], Vec<u8>>,
+    ) {
+        let parameters = parameter_refs
+            .into_iter()
+            .map(|(k, v)| (k.to_vec(), v))
+            .collect();
+        self.current_part.content_disposition = Some(ContentDisposition {
+            disposition_type: disposition_type.to_vec(),
+            parameters,
+        });
         // Use the content disposition to set a more accurate target for this part
-        if self.target != Target::Top && self.target != Target::TopAlternative {
+        if !matches!(
+            self.target,
+            Target::Top | Target::TopAlternative | Target::AlternativeRelated
+        ) {
             self.target = match disposition_type {
                 b"inline" => Target::Inlines,
                 b"attachment" => Target::Attachments,
@@ -93,22 +128,55 @@ impl MessageHandler {
     }
 
     fn multipart_start(&mut self, multipart: Multipart) {
+        // A multipart that is the first part of multipart/mixed holds the
+        // message body, e.g. multipart/alternative followed by attachments
+        let is_body = matches!(self.target, Target::Top | Target::FirstMixed);
+        let parent = match self.target {
+            Target::FirstMixed => Target::Attachments,
+            target => target,
+        };
+        self.parent_targets.push(parent);
         // Set the default target for all parts in this multipart
         self.target = match multipart {
-            Multipart::Alternative if self.target == Target::Top => Target::TopAlternative,
+            Multipart::Alternative if is_body => Target::TopAlternative,
             Multipart::Alternative => Target::Alternative,
-            Multipart::Mixed if self.target == Target::Top => Target::FirstMixed,
-            Multipart::Mixed => Target::Attachments,
+            // Unknown types are treated as mixed, e.g the first part of
+            // multipart/signed is the signed content and the second the signature
+            Multipart::Mixed | Multipart::Other(_) if self.target == Target::Top => {
+                Target::FirstMixed
+            }
+            Multipart::Mixed | Multipart::Other(_) => Target::Attachments,
             Multipart::Digest => Target::Attachments,
+            Multipart::Related if is_body => Target::FirstRelated,
+            // e.g HTML with images as an alternative to plain text, the root is
+            // one of the alternatives
+            Multipart::Related if self.target == Target::TopAlternative => {
+                Target::AlternativeRelated
+            }
+            Multipart::Related => Target::Inlines,
         }
     }
 
+    fn multipart_end(&mut self) {
+        if let Some(parent) = self.parent_targets.pop() {
+            self.target = parent;
+        }
+        self.multipart_ended = true;
+    }
+
     fn part_start(&mut self, offset: usize) {
         self.is_multipart = true;
         self.current_part.start = offset;
     }
 
     fn part_end(&mut self, offset: usize) {
+        if self.multipart_ended {
+            // End of the part that contained a nested multipart, its
+            // parts have already been added
+            self.multipart_ended = false;
+            self.take_current();
+            return;
+        }
         self.current_part.end = offset;
         let content_type = self.current_part.content_type.clone();
         let part_index = self.add_part();
@@ -119,18 +187,26 @@ impl MessageHandler {
                     self.message.text = Some(part_index);
                 }
             }
-            Target::TopAlternative if is_content_text(&content_type) => {
-                self.message.top = part_index;
-                self.message.text = Some(part_index);
-            }
-            Target::TopAlternative if is_content(&content_type, b"text/html") => {
-                self.message.html = Some(part_index);
-            }
-            Target::TopAlternative => self.message.top = part_index,
+            Target::TopAlternative => self.add_alternative(part_index, &content_type),
             Target::FirstMixed => {
                 self.message.top = part_index;
                 self.target = Target::Attachments;
             }
+            // The first part of multipart/related is the root, the rest are
+            // referenced from it
+            Target::FirstRelated => {
+                self.message.top = part_index;
+                if is_content_text(&content_type) {
+                    self.message.text = Some(part_index);
+                } else if is_content(&content_type, b"text/html") {
+                    self.message.html = Some(part_index);
+                }
+                self.target = Target::Inlines;
+            }
+            Target::AlternativeRelated => {
+                self.add_alternative(part_index, &content_type);
+                self.target = Target::Inlines;
+            }
             Target::Alternative => self.message.attachments.push(part_index),
             Target::Attachments => self.message.attachments.push(part_index),
             Target::Inlines => self.message.inlines.push(part_index),
@@ -138,6 +214,18 @@ impl MessageHandler {
         }
     }
 
+    fn add_alternative(&mut self, part_index: usize, content_type: &Option<ContentType>) {
+        self.message.alternatives.push(part_index);
+        if is_content_text(content_type) {
+            self.message.top = part_index;
+            self.message.text = Some(part_index);
+        } else if is_content(content_type, b"text/html") {
+            self.message.html = Some(part_index);
+        } else {
+            self.message.top = part_index;
+        }
+    }
+
     fn body_start(&mut self, offset: usize) {
         self.current_part.body_start = offset;
     }
@@ -5,6 +5,11 @@ use std::io;
 use std::io::Write;
 
 /// Wraps an event parser to parse messages
+///
+/// Only the structure of the message is kept in memory: header fields and the offsets
+/// of each part. Bodies are passed to the writer and not buffered, so messages larger
+/// than memory can be parsed while they are written to disk. Use
+/// [`crate::Part::body_bytes()`] to read a body back from the written data.
 /// # Example
 /// ```
 /// use mime_event::MessageParser;
@@ -39,10 +44,23 @@ impl<W: Write> MessageParser<W> {
         }
     }
 
+    /// Treat lines terminated by a bare LF as if they were terminated by CRLF.
+    pub fn lenient_line_endings(&mut self, lenient: bool) -> &mut Self {
+        self.event_parser.lenient_line_endings(lenient);
+        self
+    }
+
     /// Call this method to signal the end of a message. Will return the parsed message.
     pub fn end(self) -> Message {
         self.event_parser.end().get_message()
     }
+
+    /// Like [`MessageParser::end()`] but also returns the writer, for writers that need
+    /// to be finished explicitly, such as compressors.
+    pub fn finish(self) -> (Message, W) {
+        let (handler, writer) = self.event_parser.finish();
+        (handler.get_message(), writer)
+    }
 }
 
 /// Write data to the MessageParser to parse a Message
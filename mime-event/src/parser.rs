@@ -2,6 +2,7 @@ use crate::event::*;
 use crate::header::Header;
 use crate::header_buffer::HeaderBuffer;
 use crate::line_parser;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::io;
 use std::io::Write;
@@ -20,6 +21,7 @@ enum State {
     MultipartPreamble,
     PartStart,
     Body,
+    Epilogue,
 }
 
 struct MultipartState {
@@ -68,8 +70,14 @@ pub struct EventParser<W: Write, H: Handler> {
     handler: H,
     content_type: Mime,
     boundary: Option<Vec<u8>>,
+    // The multipart that boundary belongs to
+    multipart: Option<Multipart>,
     multipart_stack: Vec<MultipartState>,
     header_buffer: HeaderBuffer,
+    lenient_line_endings: bool,
+    report_preamble_epilogue: bool,
+    // Set after a nested multipart ends, until the next boundary of the enclosing multipart
+    in_epilogue: bool,
 }
 
 impl<W: Write, H: Handler> EventParser<W, H> {
@@ -84,11 +92,32 @@ impl<W: Write, H: Handler> EventParser<W, H> {
             handler,
             content_type: Mime::Type(b"text/plain".to_vec()),
             boundary: None,
+            multipart: None,
             multipart_stack: Vec::default(),
             header_buffer: HeaderBuffer::default(),
+            lenient_line_endings: false,
+            report_preamble_epilogue: false,
+            in_epilogue: false,
         }
     }
 
+    /// Treat lines terminated by a bare LF as if they were terminated by CRLF.
+    ///
+    /// The data written to the writer is not changed and offsets refer to the
+    /// original input. Strict CRLF handling is the default.
+    pub fn lenient_line_endings(&mut self, lenient: bool) -> &mut Self {
+        self.lenient_line_endings = lenient;
+        self
+    }
+
+    /// Send [`Event::Preamble`] and [`Event::Epilogue`] events for the text outside of
+    /// the boundaries of multipart entities, including nested ones, which is ignored by
+    /// default.
+    pub fn report_preamble_epilogue(&mut self, report: bool) -> &mut Self {
+        self.report_preamble_epilogue = report;
+        self
+    }
+
     /// Call when message has finished and there is no more input.
     /// Returns the handler.
     pub fn end(mut self) -> H {
@@ -96,6 +125,13 @@ impl<W: Write, H: Handler> EventParser<W, H> {
         self.handler
     }
 
+    /// Like [`EventParser::end()`] but also returns the writer, so that it can be
+    /// finished by the caller.
+    pub fn finish(mut self) -> (H, W) {
+        self.handler.event(Event::End);
+        (self.handler, self.writer)
+    }
+
     fn is_open_boundary(&self, buf: &[u8]) -> bool {
         self.boundary
             .as_ref()
@@ -113,13 +149,13 @@ impl<W: Write, H: Handler> EventParser<W, H> {
             .is_some()
     }
 
-    fn header_field(&mut self, buf: &[u8], state: State) -> io::Result<State> {
+    fn header_field(&mut self, buf: &[u8], buf_len: usize, state: State) -> io::Result<State> {
         if buf.starts_with(b"\r\n") {
             self.state = match state {
                 State::MultipartHeader => State::MultipartPreamble,
                 _ => {
                     self.handler.event(Event::BodyStart {
-                        offset: self.offset + 2,
+                        offset: self.offset + buf_len,
                     });
                     State::Body
                 }
@@ -145,15 +181,22 @@ impl<W: Write, H: Handler> EventParser<W, H> {
 
     // Handle Content-Type headers
     fn content_type(&mut self, mtype: &[u8], params: HashMap<&[u8], Vec<u8>>) {
-        if let (Mime::Multipart(m), Some(b)) = (&self.content_type, &self.boundary) {
-            self.multipart_stack.push(MultipartState {
-                content_type: *m,
-                boundary: b.clone(),
-            })
-        }
         self.content_type = mime_type(mtype);
-        if let Mime::Multipart(_) = &self.content_type {
-            self.boundary = params.get(&(b"boundary")[..]).map(|boundary| {
+        if let Mime::Multipart(m) = &self.content_type {
+            // A nested multipart, save the enclosing multipart
+            if let (Some(parent), Some(b)) = (self.multipart.take(), self.boundary.take()) {
+                self.multipart_stack.push(MultipartState {
+                    content_type: parent,
+                    boundary: b,
+                })
+            }
+            self.multipart = Some(m.clone());
+            // Parameter names are case insensitive
+            let boundary = params
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(b"boundary"))
+                .map(|(_, value)| value);
+            self.boundary = boundary.map(|boundary| {
                 let mut full = b"--".to_vec();
                 full.extend_from_slice(boundary);
                 full
@@ -164,24 +207,32 @@ impl<W: Write, H: Handler> EventParser<W, H> {
     // Called when data is written to the writer
     fn handle_write(&mut self, buf: &[u8]) -> io::Result<()> {
         self.writer.write_all(buf)?;
+        let length = buf.len();
+        let buf = if self.lenient_line_endings {
+            normalize_line_ending(buf)
+        } else {
+            Cow::Borrowed(buf)
+        };
         match self.state {
             State::Start => {
                 self.handler.event(Event::Start);
                 self.state = State::Header;
-                self.handle_header(buf)
+                self.handle_header(&buf, length)
+            }
+            State::Header | State::MultipartHeader | State::PartStart => {
+                self.handle_header(&buf, length)
             }
-            State::Header | State::MultipartHeader | State::PartStart => self.handle_header(buf),
-            _ => self.handle_line(buf, buf.len()),
+            _ => self.handle_line(&buf, length),
         }
     }
 
-    fn handle_header(&mut self, buf: &[u8]) -> io::Result<()> {
+    fn handle_header(&mut self, buf: &[u8], buf_len: usize) -> io::Result<()> {
         if buf.starts_with(b"\r\n") {
             if let Some((line, length)) = self.header_buffer.take() {
                 self.handle_line(&line, length)?;
             }
-            self.handle_line(buf, buf.len())
-        } else if let Some((line, length)) = self.header_buffer.next_line(buf) {
+            self.handle_line(buf, buf_len)
+        } else if let Some((line, length)) = self.header_buffer.next_line(buf, buf_len) {
             self.handle_line(&line, length)
         } else {
             Ok(())
@@ -192,46 +243,66 @@ impl<W: Write, H: Handler> EventParser<W, H> {
     fn handle_line(&mut self, buf: &[u8], buf_len: usize) -> io::Result<()> {
         let next_state = match self.state {
             State::Start => unreachable!(),
-            State::MultipartHeader => self.header_field(buf, State::MultipartHeader)?,
-            State::Header => self.header_field(buf, State::Header)?,
+            State::MultipartHeader => self.header_field(buf, buf_len, State::MultipartHeader)?,
+            State::Header => self.header_field(buf, buf_len, State::Header)?,
             State::PartStart => {
                 self.handler.event(Event::PartStart {
                     offset: self.offset,
                 });
-                self.header_field(buf, State::Header)?
+                self.header_field(buf, buf_len, State::Header)?
             }
             State::MultipartPreamble => {
                 if self.is_open_boundary(buf) {
-                    if let Mime::Multipart(m) = self.content_type {
-                        self.handler.event(Event::MultipartStart(m));
+                    if let Mime::Multipart(m) = &self.content_type {
+                        self.handler.event(Event::MultipartStart(m.clone()));
                     }
                     State::PartStart
                 } else {
+                    if self.report_preamble_epilogue {
+                        self.handler.event(Event::Preamble(buf));
+                    }
                     State::MultipartPreamble
                 }
             }
             State::Body => {
                 if self.is_close_boundary(buf) {
+                    self.in_epilogue = false;
                     self.handler.event(Event::PartEnd {
                         offset: self.offset,
                     });
                     self.handler.event(Event::MultipartEnd);
-                    // Use last multipart if available
+                    // Use last multipart if available, the enclosing part continues
+                    // until the next boundary of that multipart
                     if let Some(last) = self.multipart_stack.pop() {
-                        self.content_type = Mime::Multipart(last.content_type);
+                        self.content_type = Mime::Multipart(last.content_type.clone());
+                        self.multipart = Some(last.content_type);
                         self.boundary = Some(last.boundary);
+                        self.in_epilogue = true;
+                        State::Body
+                    } else {
+                        State::Epilogue
                     }
-                    State::Header
                 } else if self.is_open_boundary(buf) {
+                    self.in_epilogue = false;
                     self.handler.event(Event::PartEnd {
                         offset: self.offset,
                     });
                     State::PartStart
                 } else {
-                    self.handler.event(Event::Body(buf));
+                    if !self.in_epilogue {
+                        self.handler.event(Event::Body(buf));
+                    } else if self.report_preamble_epilogue {
+                        self.handler.event(Event::Epilogue(buf));
+                    }
                     State::Body
                 }
             }
+            State::Epilogue => {
+                if self.report_preamble_epilogue {
+                    self.handler.event(Event::Epilogue(buf));
+                }
+                State::Epilogue
+            }
         };
         self.state = next_state;
         self.offset += buf_len;
@@ -239,6 +310,20 @@ impl<W: Write, H: Handler> EventParser<W, H> {
     }
 }
 
+// Convert a line terminated by a bare LF into a line terminated by CRLF
+fn normalize_line_ending(line: &[u8]) -> Cow<'_, [u8]> {
+    match line {
+        [.., b'\r', b'\n'] => Cow::Borrowed(line),
+        [head @ .., b'\n'] => {
+            let mut normalized = Vec::with_capacity(line.len() + 1);
+            normalized.extend_from_slice(head);
+            normalized.extend_from_slice(b"\r\n");
+            Cow::Owned(normalized)
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
 /// Write data to the EventParser to get parsing events.
 impl<W: Write, H: Handler> Write for EventParser<W, H> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
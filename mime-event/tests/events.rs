@@ -20,6 +20,44 @@ fn multipart_mixed() {
     handler.final_check()
 }
 
+#[test]
+fn nested_multipart() {
+    let msg = include_bytes!("multipart_alternative_attachment.msg");
+    let handler = TestHandler::new(nested_multipart_events());
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.final_check()
+}
+
+#[test]
+fn multipart_signed() {
+    let msg = include_bytes!("multipart_signed.msg");
+    let handler = TestHandler::new(multipart_signed_events());
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.final_check()
+}
+
+#[test]
+fn preamble_epilogue() {
+    let msg = include_bytes!("multipart_preamble.msg");
+    let handler = TestHandler::new(preamble_epilogue_events());
+    let mut parser = EventParser::new(io::sink(), handler);
+    parser.report_preamble_epilogue(true);
+    for line in msg.split(|ch| *ch == b'\n') {
+        let mut buf = line.to_vec();
+        buf.extend_from_slice(b"\r\n");
+        parser.write_all(&buf).unwrap();
+    }
+    parser.end().final_check()
+}
+
+#[test]
+fn nested_epilogue() {
+    let msg = include_bytes!("multipart_nested_epilogue.msg");
+    let handler = TestHandler::new(nested_epilogue_events());
+    let handler = parse_message(&msg[..], handler).unwrap();
+    handler.final_check()
+}
+
 #[test]
 fn swaks() {
     let msg = include_bytes!("swaks.msg");
@@ -145,6 +183,125 @@ fn multipart_mixed_events() -> Vec<Event<'static>> {
         body("\r\n"),
         Event::PartEnd { offset: 683 },
         Event::MultipartEnd,
+        Event::PartEnd { offset: 711 },
+        Event::MultipartEnd,
+        Event::End,
+    ]
+}
+
+// After the inner multipart closes, the part containing it continues until the next
+// boundary of the outer multipart, which is not followed by headers
+fn nested_multipart_events() -> Vec<Event<'static>> {
+    vec![
+        Event::Start,
+        from(r#""Ship" <ship@sea.com>"#),
+        to("fish@sea.com"),
+        subject("Report with attachment"),
+        date("Mon, 12 Oct 2026 10:00:00 +0000"),
+        message_id("<report.1@sea.com>"),
+        unstructured_header("MIME-Version", "1.0"),
+        content_type("multipart/mixed", "boundary", "mixed-boundary"),
+        Event::MultipartStart(Multipart::Mixed),
+        Event::PartStart { offset: 294 },
+        content_type("multipart/alternative", "boundary", "alt-boundary"),
+        Event::MultipartStart(Multipart::Alternative),
+        Event::PartStart { offset: 374 },
+        content_type("text/plain", "charset", "us-ascii"),
+        Event::BodyStart { offset: 420 },
+        body("Please find the report attached.\r\n"),
+        Event::PartEnd { offset: 454 },
+        Event::PartStart { offset: 470 },
+        content_type("text/html", "charset", "us-ascii"),
+        Event::BodyStart { offset: 515 },
+        body("<p>Please find the report attached.</p>\r\n"),
+        Event::PartEnd { offset: 556 },
+        Event::MultipartEnd,
+        Event::PartEnd { offset: 574 },
+        Event::PartStart { offset: 592 },
+        content_type("application/pdf", "name", "report.pdf"),
+        header(Header::ContentDisposition {
+            disposition_type: b"attachment",
+            parameters: parameter_map("filename", "report.pdf"),
+        }),
+        unstructured_header("Content-Transfer-Encoding", "base64"),
+        Event::BodyStart { offset: 735 },
+        body("JVBERi0xLjQKJcOkw7zDtsOfCjIgMCBvYmoKPDwvTGVuZ3RoIDMgMCBSPj4Kc3RyZWFtCg==\r\n"),
+        Event::PartEnd { offset: 809 },
+        Event::MultipartEnd,
+        Event::End,
+    ]
+}
+
+fn multipart_signed_events() -> Vec<Event<'static>> {
+    let mut parameters = parameter_map("protocol", "application/pkcs7-signature");
+    parameters.insert(b"micalg", b"sha-256".to_vec());
+    parameters.insert(b"boundary", b"signed-boundary".to_vec());
+    vec![
+        Event::Start,
+        from("sender@example.com"),
+        to("recipient@example.com"),
+        subject("Signed"),
+        unstructured_header("MIME-Version", "1.0"),
+        header(Header::ContentType {
+            mime_type: b"multipart/signed",
+            parameters,
+        }),
+        Event::MultipartStart(Multipart::Other(b"signed".to_vec())),
+        Event::PartStart { offset: 226 },
+        content_type_only("text/plain"),
+        Event::BodyStart { offset: 254 },
+        body("Signed text\r\n"),
+        Event::PartEnd { offset: 267 },
+        Event::PartStart { offset: 286 },
+        content_type_only("application/pkcs7-signature"),
+        Event::BodyStart { offset: 331 },
+        body("U2lnbmF0dXJl\r\n"),
+        Event::PartEnd { offset: 345 },
+        Event::MultipartEnd,
+        Event::End,
+    ]
+}
+
+fn preamble_epilogue_events() -> Vec<Event<'static>> {
+    vec![
+        Event::Start,
+        from("sender@example.com"),
+        subject("Preamble and epilogue"),
+        unstructured_header("MIME-Version", "1.0"),
+        content_type("multipart/mixed", "boundary", "outer"),
+        Event::Preamble(b"This is a multi-part message in MIME format.\r\n"),
+        Event::MultipartStart(Multipart::Mixed),
+        Event::PartStart { offset: 183 },
+        content_type_only("text/plain"),
+        Event::BodyStart { offset: 211 },
+        body("Visible text\r\n"),
+        Event::PartEnd { offset: 225 },
+        Event::MultipartEnd,
+        Event::Epilogue(b"Hidden text\r\n"),
+        Event::End,
+    ]
+}
+
+// The epilogue of the nested multipart is not part of the body
+fn nested_epilogue_events() -> Vec<Event<'static>> {
+    vec![
+        Event::Start,
+        from("sender@example.com"),
+        subject("Nested epilogue"),
+        unstructured_header("MIME-Version", "1.0"),
+        content_type("multipart/mixed", "boundary", "outer"),
+        Event::MultipartStart(Multipart::Mixed),
+        Event::PartStart { offset: 131 },
+        content_type("multipart/alternative", "boundary", "inner"),
+        Event::MultipartStart(Multipart::Alternative),
+        Event::PartStart { offset: 197 },
+        content_type_only("text/plain"),
+        Event::BodyStart { offset: 225 },
+        body("Visible text\r\n"),
+        Event::PartEnd { offset: 239 },
+        Event::MultipartEnd,
+        Event::PartEnd { offset: 263 },
+        Event::MultipartEnd,
         Event::End,
     ]
 }
@@ -173,27 +330,27 @@ fn unstructured_header<'a>(key: &'a str, value: &'a str) -> Event<'a> {
     Event::Header(Header::Unstructured(key.as_bytes(), value.as_bytes()))
 }
 
-fn header(header: Header) -> Event {
+fn header(header: Header<'_>) -> Event<'_> {
     Event::Header(header)
 }
 
-fn from(from: &str) -> Event {
+fn from(from: &str) -> Event<'_> {
     header(Header::From(from.as_bytes()))
 }
 
-fn to(to: &str) -> Event {
+fn to(to: &str) -> Event<'_> {
     header(Header::To(to.as_bytes()))
 }
 
-fn message_id(message_id: &str) -> Event {
+fn message_id(message_id: &str) -> Event<'_> {
     header(Header::MessageId(message_id.as_bytes()))
 }
 
-fn subject(subject: &str) -> Event {
+fn subject(subject: &str) -> Event<'_> {
     header(Header::Subject(subject.as_bytes()))
 }
 
-fn date(date: &str) -> Event {
+fn date(date: &str) -> Event<'_> {
     header(Header::Date(date.as_bytes()))
 }
 
@@ -204,7 +361,14 @@ fn content_type<'a>(mime: &'a str, param_key: &'a str, param_value: &str) -> Eve
     })
 }
 
-fn body(block: &str) -> Event {
+fn content_type_only(mime: &str) -> Event<'_> {
+    Event::Header(Header::ContentType {
+        mime_type: mime.as_bytes(),
+        parameters: HashMap::new(),
+    })
+}
+
+fn body(block: &str) -> Event<'_> {
     Event::Body(block.as_bytes())
 }
 
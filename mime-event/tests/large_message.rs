@@ -0,0 +1,72 @@
+use mime_event::MessageParser;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Keeps track of the bytes allocated by this test binary
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(current, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+const ATTACHMENT_LINES: usize = 64 * 1024;
+const LINE: &[u8] = b"QUJDREVGR0hJSktMTU5PUFFSU1RVVldYWVphYmNkZWZnaGlqa2xtbm9wcXJzdHV2d3h5ejAx\r\n";
+
+#[test]
+fn large_message() {
+    let header: &[u8] = b"From: ship@sea.com\r\n\
+        Subject: Large attachment\r\n\
+        Content-Type: multipart/mixed; boundary=\"large\"\r\n\
+        \r\n\
+        --large\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        See attached\r\n\
+        --large\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Content-Disposition: attachment; filename=\"large.bin\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n";
+    let mut parser = MessageParser::new(io::sink());
+    let start = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(start, Ordering::SeqCst);
+    for line in header.split_inclusive(|c| *c == b'\n') {
+        parser.write_all(line).unwrap();
+    }
+    // Over 4MB of body, written a line at a time like an SMTP server would
+    for _ in 0..ATTACHMENT_LINES {
+        parser.write_all(LINE).unwrap();
+    }
+    parser.write_all(b"--large--\r\n").unwrap();
+    let message = parser.end();
+    let peak = PEAK.load(Ordering::SeqCst) - start;
+    assert!(peak < 64 * 1024, "Parsing allocated {peak} bytes");
+
+    let attachment = message.attachments().next().unwrap();
+    let (_, len) = attachment.body();
+    assert_eq!(len, ATTACHMENT_LINES * LINE.len() + 1);
+    assert_eq!(
+        message.top().unwrap().body().1,
+        b"See attached\r\n".len() + 1
+    );
+}
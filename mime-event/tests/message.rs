@@ -1,4 +1,4 @@
-use mime_event::{HeaderFields, Message, MessageParser};
+use mime_event::{HeaderFields, Message, MessageParser, Mime};
 use pretty_assertions::assert_eq;
 use std::io;
 use std::io::Write;
@@ -24,6 +24,38 @@ fn multipart_alternative() {
     assert_eq!(message.html().unwrap().body(), (768, 137));
 }
 
+#[test]
+fn best_alternative() {
+    let msg = include_bytes!("multipart_alternative_attachment.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    let types: Vec<_> = message
+        .alternatives()
+        .map(|part| part.content_type.as_ref().unwrap().mime_type().clone())
+        .collect();
+    assert!(matches!(&types[..], [Mime::Type(plain), Mime::Type(html)]
+        if plain == b"text/plain" && html == b"text/html"));
+    let html = Mime::Type(b"text/html".to_vec());
+    let plain = Mime::Type(b"text/plain".to_vec());
+    let best = message.best_alternative(&[html.clone(), plain.clone()]);
+    assert_eq!(
+        best.unwrap().body_bytes(&written),
+        Some(&b"<p>Please find the report attached.</p>\r\n"[..])
+    );
+    let best = message.best_alternative(&[plain.clone(), html]);
+    assert_eq!(
+        best.unwrap().body_bytes(&written),
+        Some(&b"Please find the report attached.\r\n"[..])
+    );
+    assert!(message
+        .best_alternative(&[Mime::Type(b"text/enriched".to_vec())])
+        .is_none());
+    // A single part message is its own alternative
+    let message = parse_message(include_bytes!("swaks.msg")).unwrap();
+    assert_eq!(message.alternatives().count(), 0);
+    assert!(message.best_alternative(&[plain]).is_some());
+}
+
 #[test]
 fn multipart_mixed() {
     let msg = include_bytes!("multipart_mixed.msg");
@@ -68,10 +100,248 @@ fn swaks() {
     assert_eq!(header, &expected_header);
 }
 
+#[test]
+fn multipart_related() {
+    let msg = include_bytes!("multipart_related.msg");
+    let message = parse_message(&msg[..]).unwrap();
+    let html = message.html().unwrap();
+    assert_eq!(html.content_id, None);
+    assert_eq!(html.content_language, field(b"en"));
+    let image = message.content_id(b"logo.1234@example.com").unwrap();
+    assert_eq!(image.content_id, field(b"logo.1234@example.com"));
+    assert_eq!(
+        image.content_location,
+        field(b"http://www.example.com/logo.png")
+    );
+    let inlines: Vec<_> = message.inlines().collect();
+    assert_eq!(inlines.len(), 1);
+    assert_eq!(inlines[0].position(), image.position());
+}
+
+#[test]
+fn alternative_related() {
+    let msg = include_bytes!("alternative_related.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    assert_eq!(message.text_body(&written), Some(&b"Our logo\r\n"[..]));
+    assert_eq!(
+        message.html_body(&written),
+        Some(&b"<p>Our logo</p><img src=\"cid:logo.5678@example.com\">\r\n"[..])
+    );
+    // The HTML root is an alternative, the image is inline
+    let html = message.html().unwrap();
+    let alternatives: Vec<_> = message.alternatives().collect();
+    assert_eq!(alternatives.len(), 2);
+    assert_eq!(alternatives[1].position(), html.position());
+    let image = message.content_id(b"logo.5678@example.com").unwrap();
+    let inlines: Vec<_> = message.inlines().collect();
+    assert_eq!(inlines.len(), 1);
+    assert_eq!(inlines[0].position(), image.position());
+    assert_eq!(message.attachments().count(), 0);
+}
+
+#[test]
+fn multipart_alternative_attachment() {
+    let msg = include_bytes!("multipart_alternative_attachment.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    assert_eq!(message.subject(), Some(&b"Report with attachment"[..]));
+    assert_eq!(message.from(), Some(&br#""Ship" <ship@sea.com>"#[..]));
+    assert_eq!(
+        message.text_body(&written),
+        Some(&b"Please find the report attached.\r\n"[..])
+    );
+    assert_eq!(
+        message.html_body(&written),
+        Some(&b"<p>Please find the report attached.</p>\r\n"[..])
+    );
+    let attachments: Vec<_> = message.attachments().collect();
+    assert_eq!(attachments.len(), 1);
+    let attachment = attachments[0].body_bytes(&written).unwrap();
+    assert!(attachment.starts_with(b"JVBERi0xLjQK"));
+}
+
+#[test]
+fn content_disposition() {
+    let msg = b"Subject: Dispositions\n\
+        Content-Type: multipart/mixed; boundary=\"parts\"\n\
+        \n\
+        --parts\n\
+        Content-Type: text/plain\n\
+        \n\
+        Body\n\
+        --parts\n\
+        Content-Type: image/png\n\
+        Content-Disposition: inline; filename=\"logo.png\"\n\
+        \n\
+        iVBORw0KGgo=\n\
+        --parts\n\
+        Content-Type: application/pdf\n\
+        Content-Disposition: attachment; filename=\"report.pdf\"; size=4\n\
+        \n\
+        JVBERi0xLjQK\n\
+        --parts--";
+    let message = parse_message(msg).unwrap();
+    assert!(message.top().unwrap().content_disposition.is_none());
+    let inlines: Vec<_> = message.inlines().collect();
+    assert_eq!(inlines.len(), 1);
+    let inline = inlines[0].content_disposition.as_ref().unwrap();
+    assert_eq!(inline.disposition_type, b"inline");
+    assert_eq!(
+        inline.parameters.get(&b"filename"[..]),
+        Some(&b"logo.png".to_vec())
+    );
+    let attachments: Vec<_> = message.attachments().collect();
+    assert_eq!(attachments.len(), 1);
+    let attachment = attachments[0].content_disposition.as_ref().unwrap();
+    assert_eq!(attachment.disposition_type, b"attachment");
+    assert_eq!(
+        attachment.parameters.get(&b"filename"[..]),
+        Some(&b"report.pdf".to_vec())
+    );
+    assert_eq!(
+        attachment.parameters.get(&b"size"[..]),
+        Some(&b"4".to_vec())
+    );
+}
+
+#[test]
+fn single_part_body() {
+    let msg = include_bytes!("swaks.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    assert_eq!(message.from(), field(b"saul@fish.localdomain").as_deref());
+    let body = message.text_body(&written).unwrap();
+    assert!(body.starts_with(b"This is a test mailing"));
+    assert_eq!(message.html_body(&written), None);
+}
+
+#[test]
+fn quoted_boundary() {
+    let msg = include_bytes!("multipart_quoted_boundary.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    assert_eq!(message.text_body(&written), Some(&b"Plain text\r\n"[..]));
+    assert_eq!(
+        message.html_body(&written),
+        Some(&b"<p>HTML text</p>\r\n"[..])
+    );
+}
+
+#[test]
+fn raw_headers() {
+    let msg = include_bytes!("multipart_signed.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    let signed = message.top().unwrap();
+    assert_eq!(
+        signed.raw_headers(&written),
+        Some(&b"Content-Type: text/plain\r\n\r\n"[..])
+    );
+    let signature: Vec<_> = message.attachments().collect();
+    assert_eq!(
+        signature[0].raw_headers(&written),
+        Some(&b"Content-Type: application/pkcs7-signature\r\n\r\n"[..])
+    );
+    // The verbatim header block followed by the body is the signed content
+    let (start, len) = signed.position();
+    let headers = signed.raw_headers(&written).unwrap();
+    let body = signed.body_bytes(&written).unwrap();
+    assert_eq!([headers, body].concat(), &written[start..start + len - 1]);
+
+    let msg = include_bytes!("swaks.msg");
+    let written = crlf_lines(&msg[..]);
+    let message = parse_message(&msg[..]).unwrap();
+    let headers = message.top().unwrap().raw_headers(&written).unwrap();
+    assert!(headers.starts_with(b"Date: Fri, 04 Oct 2019 17:38:32 +0200\r\n"));
+    assert!(headers.ends_with(b"X-Mailer: swaks v20181104.0 jetmore.org/john/code/swaks/\r\n\r\n"));
+}
+
+#[test]
+fn bare_lf() {
+    let msg = include_bytes!("multipart_alternative.msg");
+    let mut parser = MessageParser::new(io::sink());
+    parser.lenient_line_endings(true);
+    let mut written = Vec::new();
+    for line in msg.split(|ch| *ch == b'\n') {
+        let mut buf = line.to_vec();
+        buf.push(b'\n');
+        parser.write_all(&buf).unwrap();
+        written.extend_from_slice(&buf);
+    }
+    let message = parser.end();
+    let header = &message.top().unwrap().header;
+    assert_eq!(header.subject, field(b"Sample Multi-Part"));
+    let (start, len) = message.top().unwrap().body();
+    assert_eq!(&written[start..start + len - 1], b"Sample Text Content\n");
+    let (start, len) = message.html().unwrap().body();
+    assert!(written[start..start + len - 1].starts_with(b"<html>\n<head>\n"));
+    assert!(written[start..start + len - 1].ends_with(b"</html>\n\n"));
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn decoded_text_latin1() {
+    let msg = b"Subject: Menu\n\
+        Content-Type: text/plain; charset=ISO-8859-1\n\
+        Content-Transfer-Encoding: quoted-printable\n\
+        \n\
+        Caf=E9 cr=E8me br=FBl=E9e, a long line with a soft =\n\
+        break";
+    let written = crlf_lines(msg);
+    let message = parse_message(msg).unwrap();
+    assert_eq!(
+        message.decoded_text(&written).unwrap(),
+        "Café crème brûlée, a long line with a soft break\r\n"
+    );
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn decoded_text_utf16() {
+    // "Grüße" in UTF-16 with a little endian byte order mark
+    let msg = b"Subject: Greeting\n\
+        Content-Type: multipart/alternative; boundary=\"utf16\"\n\
+        \n\
+        --utf16\n\
+        Content-Type: text/plain; charset=\"utf-16\"\n\
+        Content-Transfer-Encoding: base64\n\
+        \n\
+        //5HAHIA/ADfAGUA\n\
+        --utf16--";
+    let written = crlf_lines(msg);
+    let message = parse_message(msg).unwrap();
+    let text = message.decoded_text(&written).unwrap();
+    assert_eq!(text, "Grüße");
+}
+
+#[test]
+fn decoded_text_unknown_charset() {
+    let msg = b"Content-Type: text/plain; charset=x-unknown\n\
+        \n\
+        Hello \xff";
+    let written = crlf_lines(msg);
+    let message = parse_message(msg).unwrap();
+    assert_eq!(
+        message.decoded_text(&written).unwrap(),
+        "Hello \u{fffd}\r\n"
+    );
+}
+
 fn field(value: &[u8]) -> Option<Vec<u8>> {
     Some(value.to_vec())
 }
 
+// The data written to the parser by parse_message
+fn crlf_lines(message: &[u8]) -> Vec<u8> {
+    let mut written = Vec::new();
+    for line in message.split(|ch| *ch == b'\n') {
+        written.extend_from_slice(line);
+        written.extend_from_slice(b"\r\n");
+    }
+    written
+}
+
 fn parse_message(message: &[u8]) -> io::Result<Message> {
     let writer = io::sink();
     let mut parser = MessageParser::new(writer);
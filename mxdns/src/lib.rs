@@ -1,6 +1,6 @@
 //! DNS utilities for email servers.
 //!
-//! Currently, DNS based blocklists and reverse DNS lookups are supported.
+//! Currently, DNS based blocklists, reverse DNS, TXT and MX lookups are supported.
 //! The crate also supports forward confirmed reverse dns checks.
 //!
 //! Because blocklists are IP4 based, these utilities only support IP4
@@ -32,6 +32,7 @@
 mod blocklist;
 mod err;
 mod join_all;
+mod query;
 
 pub use crate::err::{Error, Result};
 use crate::{blocklist::BlockList, join_all::join_all};
@@ -40,7 +41,12 @@ use log::Level::Debug;
 use log::{debug, log_enabled};
 use smol::future::FutureExt;
 use std::io::ErrorKind;
-use std::{fs::File, io::Read, matches, net::IpAddr};
+use std::{
+    fs::File,
+    io::Read,
+    matches,
+    net::{IpAddr, SocketAddr},
+};
 
 const RESOLV_CONF: &str = "/etc/resolv.conf";
 
@@ -48,6 +54,7 @@ const RESOLV_CONF: &str = "/etc/resolv.conf";
 #[derive(Clone)]
 pub struct MxDns {
     bootstrap: DNSClient,
+    nameserver: SocketAddr,
     blocklists: Vec<String>,
 }
 
@@ -105,6 +112,7 @@ impl MxDns {
         let blocklists: Vec<String> = blocklists_fqdn.into_iter().map(|i| i.into()).collect();
         Self {
             bootstrap,
+            nameserver: socket_addr,
             blocklists,
         }
     }
@@ -172,6 +180,32 @@ impl MxDns {
         }
     }
 
+    /// Looks up the IPv4 addresses (A records) of the given domain name
+    pub fn forward_dns(&self, fqdn: &str) -> Result<Vec<IpAddr>> {
+        smol::block_on(self.bootstrap.query_a(fqdn))
+            .map_err(|e| Error::DnsQuery("forward_dns".to_string(), e))
+    }
+
+    /// Looks up the IPv6 addresses (AAAA records) of the given domain name
+    pub fn forward_dns6(&self, fqdn: &str) -> Result<Vec<IpAddr>> {
+        smol::block_on(self.bootstrap.query_aaaa(fqdn))
+            .map_err(|e| Error::DnsQuery("forward_dns6".to_string(), e))
+    }
+
+    /// Looks up the TXT records of the given domain name, each record as one string.
+    /// Returns an empty Vec if the domain does not exist.
+    pub fn txt_dns(&self, fqdn: &str) -> Result<Vec<String>> {
+        smol::block_on(query::txt(self.nameserver, fqdn))
+            .map_err(|e| Error::DnsQuery("txt_dns".to_string(), e))
+    }
+
+    /// Looks up the host names of the MX records of the given domain name, the most
+    /// preferred first. Returns an empty Vec if the domain does not exist.
+    pub fn mx_dns(&self, fqdn: &str) -> Result<Vec<String>> {
+        smol::block_on(query::mx(self.nameserver, fqdn))
+            .map_err(|e| Error::DnsQuery("mx_dns".to_string(), e))
+    }
+
     /// Does a Forward Confirmed Reverse DNS check on the given ip address
     /// This checks that the reverse lookup on the ip address gives a domain
     /// name that will resolve to the original ip address.
@@ -186,8 +220,7 @@ impl MxDns {
             Some(s) => s,
         };
         debug!("reverse lookup for {} = {}", ipaddr, fqdn);
-        let forward = smol::block_on(self.bootstrap.query_a(&fqdn))
-            .map_err(|e| Error::DnsQuery("fcrdns".to_string(), e))?;
+        let forward = self.forward_dns(&fqdn)?;
         let is_confirmed = forward.contains(&ipaddr);
         if is_confirmed {
             Ok(FCrDNS::Confirmed(fqdn))
@@ -0,0 +1,270 @@
+// TXT and MX lookups, which dnsclientx does not provide
+use smol::future::FutureExt;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{TcpStream, UdpSocket};
+use smol::Timer;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const HEADER_SIZE: usize = 12;
+const MAX_UDP_SIZE: usize = 4096;
+const FLAG_TC: u8 = 0x02;
+const RCODE_NXDOMAIN: u8 = 3;
+// Limits the pointers followed in a compressed name
+const MAX_POINTERS: usize = 16;
+
+/// Queries the given nameserver for the TXT records of a domain, each record
+/// as one string. Returns an empty Vec if the domain does not exist.
+pub async fn txt(server: SocketAddr, name: &str) -> io::Result<Vec<String>> {
+    let response = query(server, name, TYPE_TXT).await?;
+    let records = answers(&response, TYPE_TXT)?
+        .into_iter()
+        .map(|rdata| txt_record(&response[rdata.0..rdata.1]))
+        .collect();
+    Ok(records)
+}
+
+/// Queries the given nameserver for the MX hosts of a domain, most preferred
+/// first. Returns an empty Vec if the domain does not exist.
+pub async fn mx(server: SocketAddr, name: &str) -> io::Result<Vec<String>> {
+    let response = query(server, name, TYPE_MX).await?;
+    let mut hosts = answers(&response, TYPE_MX)?
+        .into_iter()
+        .map(|(start, end)| {
+            if end - start < 3 {
+                return Err(invalid("short MX record"));
+            }
+            let preference = u16::from_be_bytes([response[start], response[start + 1]]);
+            let host = read_name(&response, start + 2)?.0;
+            Ok((preference, host))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    hosts.sort_by_key(|h| h.0);
+    Ok(hosts.into_iter().map(|h| h.1).collect())
+}
+
+async fn query(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    let packet = encode_query(id, name, qtype)?;
+    let response = query_udp(server, &packet).or(timeout()).await?;
+    check_response(&response, id)?;
+    if response[2] & FLAG_TC == 0 {
+        return Ok(response);
+    }
+    // A truncated response is retried over TCP
+    let response = query_tcp(server, &packet).or(timeout()).await?;
+    check_response(&response, id)?;
+    Ok(response)
+}
+
+async fn query_udp(server: SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        ([0; 4], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(packet).await?;
+    let mut response = vec![0; MAX_UDP_SIZE];
+    let len = socket.recv(&mut response).await?;
+    response.truncate(len);
+    Ok(response)
+}
+
+async fn query_tcp(server: SocketAddr, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await?;
+    let len = u16::try_from(packet.len()).map_err(|_| invalid("query too long"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(packet).await?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+async fn timeout() -> io::Result<Vec<u8>> {
+    Timer::after(TIMEOUT).await;
+    Err(ErrorKind::TimedOut.into())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Builds a recursive query for one question
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 || !label.is_ascii() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("bad domain name {}", name),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    // Class IN
+    packet.extend_from_slice(&[0, 1]);
+    Ok(packet)
+}
+
+fn check_response(response: &[u8], id: u16) -> io::Result<()> {
+    if response.len() < HEADER_SIZE {
+        return Err(invalid("short DNS response"));
+    }
+    if response[..2] != id.to_be_bytes() {
+        return Err(invalid("DNS response for another query"));
+    }
+    Ok(())
+}
+
+// The (start, end) offsets of the rdata of the answers with the given type
+fn answers(response: &[u8], qtype: u16) -> io::Result<Vec<(usize, usize)>> {
+    match response[3] & 0x0f {
+        0 => (),
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(io::Error::other(format!("DNS error, rcode {}", rcode))),
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let count = u16::from_be_bytes([response[6], response[7]]);
+    let mut pos = HEADER_SIZE;
+    for _ in 0..questions {
+        pos = read_name(response, pos)?.1 + 4;
+    }
+    let mut ret = Vec::new();
+    for _ in 0..count {
+        pos = read_name(response, pos)?.1;
+        let header = response
+            .get(pos..pos + 10)
+            .ok_or_else(|| invalid("truncated DNS answer"))?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let start = pos + 10;
+        let end = start + len;
+        if end > response.len() {
+            return Err(invalid("truncated DNS answer"));
+        }
+        // Skips CNAME records, the resolver has already followed them
+        if rtype == qtype {
+            ret.push((start, end));
+        }
+        pos = end;
+    }
+    Ok(ret)
+}
+
+// Reads a possibly compressed name, returns the name and the position after it
+fn read_name(packet: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(pos).ok_or_else(|| invalid("truncated name"))?;
+        match len {
+            0 => break,
+            l if l & 0xc0 == 0xc0 => {
+                let low = *packet
+                    .get(pos + 1)
+                    .ok_or_else(|| invalid("truncated name"))?;
+                end.get_or_insert(pos + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(invalid("name compression loop"));
+                }
+                pos = usize::from(u16::from_be_bytes([l & 0x3f, low]));
+            }
+            l => {
+                let label = packet
+                    .get(pos + 1..pos + 1 + usize::from(l))
+                    .ok_or_else(|| invalid("truncated name"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(l);
+            }
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+// A TXT record is one or more character strings that are joined, RFC 7208 section 3.3
+fn txt_record(mut rdata: &[u8]) -> String {
+    let mut ret = Vec::with_capacity(rdata.len());
+    while let Some((&len, rest)) = rdata.split_first() {
+        let len = usize::from(len).min(rest.len());
+        ret.extend_from_slice(&rest[..len]);
+        rdata = &rest[len..];
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A response to a query with id 0x1234 for example.com, ending with the given answers
+    fn response(qtype: u16, answers: &[&[u8]]) -> Vec<u8> {
+        let mut packet = encode_query(0x1234, "example.com", qtype).unwrap();
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = answers.len() as u8;
+        for answer in answers {
+            // Pointer to the question name
+            packet.extend_from_slice(&[0xc0, 12]);
+            packet.extend_from_slice(&qtype.to_be_bytes());
+            packet.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+            packet.extend_from_slice(&(answer.len() as u16).to_be_bytes());
+            packet.extend_from_slice(answer);
+        }
+        packet
+    }
+
+    #[test]
+    fn txt_answers() {
+        let packet = response(TYPE_TXT, &[b"\x07v=spf1 \x04-all", b"\x05other"]);
+        check_response(&packet, 0x1234).unwrap();
+        let records: Vec<String> = answers(&packet, TYPE_TXT)
+            .unwrap()
+            .into_iter()
+            .map(|(start, end)| txt_record(&packet[start..end]))
+            .collect();
+        assert_eq!(records, vec!["v=spf1 -all", "other"]);
+    }
+
+    #[test]
+    fn mx_answers() {
+        // mx.example.com, with the domain compressed
+        let packet = response(TYPE_MX, &[b"\x00\x0a\x02mx\xc0\x0c"]);
+        let rdata = answers(&packet, TYPE_MX).unwrap();
+        let (name, _) = read_name(&packet, rdata[0].0 + 2).unwrap();
+        assert_eq!(name, "mx.example.com");
+    }
+
+    #[test]
+    fn nxdomain() {
+        let mut packet = response(TYPE_TXT, &[]);
+        packet[3] |= RCODE_NXDOMAIN;
+        assert!(answers(&packet, TYPE_TXT).unwrap().is_empty());
+        packet[3] = 0x82;
+        assert!(answers(&packet, TYPE_TXT).is_err());
+    }
+
+    #[test]
+    fn compression_loop() {
+        let packet = [0xc0, 0x00];
+        assert!(read_name(&packet, 0).is_err());
+    }
+}